@@ -23,6 +23,8 @@ const BLUE: u8 = 2;
 struct MyMainWorld;
 
 impl VoxelWorldConfig for MyMainWorld {
+    type Voxel = WorldVoxel;
+
     fn texture_index_mapper(&self) -> Arc<dyn Fn(u8) -> [u32; 3] + Send + Sync> {
         Arc::new(|vox_mat: u8| match vox_mat {
             RED => [1, 1, 1],
@@ -26,6 +26,8 @@ const BLUE: u8 = 2;
 struct MainWorld;
 
 impl VoxelWorldConfig for MainWorld {
+    type Voxel = WorldVoxel;
+
     fn spawning_distance(&self) -> u32 {
         10
     }
@@ -40,6 +42,8 @@ impl VoxelWorldConfig for MainWorld {
 struct SecondWorld;
 
 impl VoxelWorldConfig for SecondWorld {
+    type Voxel = WorldVoxel;
+
     fn texture_index_mapper(&self) -> Arc<dyn Fn(u8) -> [u32; 3] + Send + Sync> {
         Arc::new(|vox_mat: u8| match vox_mat {
             RED => [1, 1, 1],
@@ -6,6 +6,8 @@ use noise::{HybridMulti, NoiseFn, Perlin};
 struct MainWorld;
 
 impl VoxelWorldConfig for MainWorld {
+    type Voxel = WorldVoxel;
+
     fn spawning_distance(&self) -> u32 {
         25
     }
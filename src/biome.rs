@@ -0,0 +1,515 @@
+///
+/// Biomes
+/// A terrain generator where every column of the world belongs to a biome, picked from a seeded
+/// climate map. Biomes have their own materials, terrain height and decorations, and the terrain
+/// blends where biomes meet.
+///
+use std::sync::Arc;
+
+use bevy::prelude::*;
+
+use crate::{
+    chunk::FillType,
+    configuration::ChunkGeneratorFn,
+    generation::{position_random, PaddedVoxelBuffer, StageContext},
+    voxel::{VoxelData, WorldVoxel},
+};
+
+/// The index of a biome in its `BiomeLayer`, in the order the biomes were added
+pub type BiomeId = u16;
+
+// Salts keeping the noise fields of a layer independent of each other
+const TEMPERATURE_SALT: u32 = 0x7e3a_0001;
+const HUMIDITY_SALT: u32 = 0x7e3a_0002;
+const HEIGHT_SALT: u32 = 0x7e3a_0003;
+const DITHER_SALT: u32 = 0x7e3a_0004;
+const DECORATION_SALT: u32 = 0x7e3a_0005;
+
+/// A column of voxels placed on top of the surface, like grass, flowers or a cactus
+#[derive(Clone, Debug)]
+pub struct Decoration<V = WorldVoxel> {
+    voxels: Vec<V>,
+    chance: f32,
+}
+
+impl<V: VoxelData> Decoration<V> {
+    /// A decoration placed on a column with the given chance, between 0 and 1. `voxels` are
+    /// placed from the bottom up, starting right above the surface.
+    pub fn new(voxels: impl IntoIterator<Item = V>, chance: f32) -> Self {
+        Self {
+            voxels: voxels.into_iter().collect(),
+            chance,
+        }
+    }
+
+    pub fn voxels(&self) -> &[V] {
+        &self.voxels
+    }
+
+    pub fn chance(&self) -> f32 {
+        self.chance
+    }
+}
+
+/// The materials, terrain shape and decorations of a biome
+#[derive(Clone, Debug)]
+pub struct Biome<V = WorldVoxel> {
+    name: String,
+    climate: Vec2,
+    surface: V,
+    filler: V,
+    filler_depth: u32,
+    base: V,
+    height: f32,
+    height_variation: f32,
+    roughness: f32,
+    decorations: Vec<Decoration<V>>,
+}
+
+impl<V: VoxelData> Biome<V> {
+    /// A flat biome at height 0, with a single `surface` voxel on top of three `filler` voxels,
+    /// on top of `base` voxels all the way down
+    pub fn new(name: impl Into<String>, surface: V, filler: V, base: V) -> Self {
+        Self {
+            name: name.into(),
+            climate: Vec2::splat(0.5),
+            surface,
+            filler,
+            filler_depth: 3,
+            base,
+            height: 0.0,
+            height_variation: 0.0,
+            roughness: 1.0 / 64.0,
+            decorations: Vec::new(),
+        }
+    }
+
+    /// The temperature and humidity the biome appears at, both between 0 and 1. Each column
+    /// belongs to the biome closest to its climate.
+    pub fn with_climate(mut self, temperature: f32, humidity: f32) -> Self {
+        self.climate = Vec2::new(temperature, humidity);
+        self
+    }
+
+    /// The average height of the surface, and how far it goes up and down from it
+    pub fn with_height(mut self, height: f32, variation: f32) -> Self {
+        self.height = height;
+        self.height_variation = variation;
+        self
+    }
+
+    /// How quickly the surface goes up and down. This is the frequency of the height noise, so
+    /// `1.0 / 64.0` gives hills about 64 voxels apart.
+    pub fn with_roughness(mut self, roughness: f32) -> Self {
+        self.roughness = roughness;
+        self
+    }
+
+    /// The number of `filler` voxels below the surface voxel
+    pub fn with_filler_depth(mut self, depth: u32) -> Self {
+        self.filler_depth = depth;
+        self
+    }
+
+    /// Add a decoration. Decorations are tried in the order they were added, and at most one is
+    /// placed on each column.
+    pub fn with_decoration(mut self, decoration: Decoration<V>) -> Self {
+        self.decorations.push(decoration);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn surface(&self) -> V {
+        self.surface
+    }
+
+    pub fn filler(&self) -> V {
+        self.filler
+    }
+
+    pub fn base(&self) -> V {
+        self.base
+    }
+
+    pub fn decorations(&self) -> &[Decoration<V>] {
+        &self.decorations
+    }
+
+    fn decoration_height(&self) -> i32 {
+        self.decorations
+            .iter()
+            .map(|decoration| decoration.voxels.len())
+            .max()
+            .unwrap_or(0) as i32
+    }
+}
+
+/// A column of the world, with the height of its surface and the biomes it belongs to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BiomeColumn {
+    /// The biome closest to the climate of the column
+    pub biome: BiomeId,
+
+    /// The biome the materials and decorations of the surface come from. Near the border of
+    /// another biome, this is picked at random between the biomes, weighted by how close the
+    /// column is to each of them, so borders blend.
+    pub surface_biome: BiomeId,
+
+    /// The height of the top voxel of the terrain
+    pub height: i32,
+}
+
+/// Terrain generation with biomes. Each column of the world gets a temperature and humidity
+/// from a seeded climate map, and belongs to the biome closest to that climate. Where biomes
+/// meet, the terrain heights of the biomes are blended, and their surface materials dithered.
+///
+/// With a 3D biome map, the climate also changes with height, so the materials below the surface
+/// can belong to another biome than the surface, like caves under a desert. The terrain height
+/// always comes from the climate at height 0.
+///
+/// ```
+/// # use bevy::prelude::*;
+/// # use bevy_voxel_world::prelude::*;
+/// let biomes = BiomeLayer::<WorldVoxel>::new(1234)
+///     .with_biome(
+///         Biome::new("plains", WorldVoxel::Solid(0), WorldVoxel::Solid(1), WorldVoxel::Solid(2))
+///             .with_climate(0.5, 0.6)
+///             .with_height(4.0, 3.0)
+///             .with_decoration(Decoration::new([WorldVoxel::Solid(5)], 0.1)),
+///     )
+///     .with_biome(
+///         Biome::new("desert", WorldVoxel::Solid(3), WorldVoxel::Solid(3), WorldVoxel::Solid(2))
+///             .with_climate(0.8, 0.1)
+///             .with_height(2.0, 1.0),
+///     );
+/// let column = biomes.column(10, -20);
+/// assert!(biomes.voxel(IVec3::new(10, column.height, -20)).is_solid());
+/// ```
+#[derive(Clone, Debug)]
+pub struct BiomeLayer<V = WorldVoxel> {
+    seed: u32,
+    biomes: Vec<Biome<V>>,
+    biome_size: f32,
+    blend: f32,
+    vertical_size: Option<f32>,
+}
+
+impl<V: VoxelData> BiomeLayer<V> {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            seed,
+            biomes: Vec::new(),
+            biome_size: 256.0,
+            blend: 0.1,
+            vertical_size: None,
+        }
+    }
+
+    /// Add a biome. Its id is the number of biomes added before it.
+    pub fn with_biome(mut self, biome: Biome<V>) -> Self {
+        self.biomes.push(biome);
+        self
+    }
+
+    /// The rough size of a biome in voxels. 256 by default.
+    pub fn with_biome_size(mut self, voxels: f32) -> Self {
+        self.biome_size = voxels.max(1.0);
+        self
+    }
+
+    /// How wide the borders between biomes are, as a distance in climate. Columns whose climate
+    /// is within this distance of the border blend both biomes. 0.1 by default, and 0 gives hard
+    /// borders.
+    pub fn with_blend(mut self, blend: f32) -> Self {
+        self.blend = blend.max(0.0);
+        self
+    }
+
+    /// Use a 3D biome map, where the climate changes every `voxels` along the Y axis as well
+    pub fn with_vertical_size(mut self, voxels: f32) -> Self {
+        self.vertical_size = Some(voxels.max(1.0));
+        self
+    }
+
+    pub fn biomes(&self) -> &[Biome<V>] {
+        &self.biomes
+    }
+
+    pub fn biome(&self, id: BiomeId) -> Option<&Biome<V>> {
+        self.biomes.get(id as usize)
+    }
+
+    /// The temperature and humidity at a position, between 0 and 1. With a 2D biome map, this is
+    /// the same for the whole column.
+    pub fn climate(&self, position: IVec3) -> Vec2 {
+        let vertical = self
+            .vertical_size
+            .map_or(0.0, |size| position.y as f32 / size);
+        let point = Vec3::new(
+            position.x as f32 / self.biome_size,
+            vertical,
+            position.z as f32 / self.biome_size,
+        );
+        Vec2::new(
+            fractal_noise(self.seed ^ TEMPERATURE_SALT, point),
+            fractal_noise(self.seed ^ HUMIDITY_SALT, point),
+        ) + 0.5
+    }
+
+    /// The biome at a position, or `None` if the layer has no biomes
+    pub fn biome_at(&self, position: IVec3) -> Option<BiomeId> {
+        let climate = self.climate(position);
+        self.biomes
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                let a = a.climate.distance_squared(climate);
+                a.total_cmp(&b.climate.distance_squared(climate))
+            })
+            .map(|(id, _)| id as BiomeId)
+    }
+
+    /// The biomes close to the climate at a position, and how much each of them counts. The
+    /// weights add up to 1.
+    pub fn biome_weights(&self, position: IVec3) -> Vec<(BiomeId, f32)> {
+        let climate = self.climate(position);
+        let distances: Vec<f32> = self
+            .biomes
+            .iter()
+            .map(|biome| biome.climate.distance(climate))
+            .collect();
+        let closest = distances.iter().copied().fold(f32::INFINITY, f32::min);
+        let mut weights: Vec<(BiomeId, f32)> = distances
+            .iter()
+            .enumerate()
+            .map(|(id, distance)| (id as BiomeId, self.blend - (distance - closest)))
+            .filter(|(_, weight)| *weight > 0.0)
+            .collect();
+        if weights.is_empty() {
+            // Without blending, only the closest biome counts
+            weights = distances
+                .iter()
+                .position(|distance| *distance == closest)
+                .map(|id| (id as BiomeId, 1.0))
+                .into_iter()
+                .collect();
+        }
+        let total: f32 = weights.iter().map(|(_, weight)| weight).sum();
+        for (_, weight) in weights.iter_mut() {
+            *weight /= total;
+        }
+        weights
+    }
+
+    /// The surface height and biomes of a column, or the default column if the layer has no
+    /// biomes
+    pub fn column(&self, x: i32, z: i32) -> BiomeColumn {
+        let position = IVec3::new(x, 0, z);
+        let weights = self.biome_weights(position);
+        let Some(biome) = self.biome_at(position) else {
+            return BiomeColumn {
+                biome: 0,
+                surface_biome: 0,
+                height: 0,
+            };
+        };
+        let height = weights
+            .iter()
+            .map(|(id, weight)| {
+                let biome = &self.biomes[*id as usize];
+                let point = Vec3::new(x as f32, 0.0, z as f32) * biome.roughness;
+                let noise = fractal_noise(self.seed ^ HEIGHT_SALT, point) * 2.0;
+                (biome.height + biome.height_variation * noise) * weight
+            })
+            .sum::<f32>()
+            .round() as i32;
+        let surface_position = IVec3::new(x, height, z);
+        let surface_biome = match self.vertical_size {
+            Some(_) => self.dithered_biome(surface_position),
+            None => self.pick_weighted(&weights, surface_position),
+        };
+        BiomeColumn {
+            biome,
+            surface_biome,
+            height,
+        }
+    }
+
+    /// The voxel the layer generates at a position, which is unset above the terrain and its
+    /// decorations. Filling whole chunks with `chunk_generator` or `fill_stage` is faster, since
+    /// they work out each column once.
+    pub fn voxel(&self, position: IVec3) -> V {
+        if self.biomes.is_empty() {
+            return V::default();
+        }
+        self.column_voxel(position, &self.column(position.x, position.z))
+    }
+
+    /// A chunk generator for `VoxelWorldConfig::chunk_generator`. Chunks above the terrain are
+    /// reported as empty, and chunks below the filler of all their columns as uniform when the
+    /// biomes share their base voxel.
+    pub fn chunk_generator(self: &Arc<Self>) -> ChunkGeneratorFn<V> {
+        let layer = self.clone();
+        Arc::new(move |chunk| layer.fill_chunk(chunk))
+    }
+
+    /// Generate the terrain of a chunk in a `GenerationPipeline` stage, replacing all of its
+    /// voxels
+    pub fn fill_stage(&self, chunk: &mut StageContext<V>) {
+        let (min, max) = (chunk.min(), chunk.max());
+        for z in min.z..=max.z {
+            for x in min.x..=max.x {
+                let column = self.column(x, z);
+                for y in min.y..=max.y {
+                    let position = IVec3::new(x, y, z);
+                    chunk.set(position, self.column_voxel(position, &column));
+                }
+            }
+        }
+    }
+
+    fn fill_chunk(&self, chunk: &mut PaddedVoxelBuffer<V>) -> FillType<V> {
+        if self.biomes.is_empty() {
+            return FillType::Empty;
+        }
+        let size = chunk.size();
+        let columns: Vec<BiomeColumn> = (0..size.z)
+            .flat_map(|z| (0..size.x).map(move |x| UVec3::new(x, 0, z)))
+            .map(|sample| {
+                let position = chunk.world_position(sample);
+                self.column(position.x, position.z)
+            })
+            .collect();
+
+        let min_y = chunk.world_position(UVec3::ZERO).y;
+        let max_y = chunk.world_position(size - 1).y;
+        let top = columns
+            .iter()
+            .map(|column| column.height + self.surface_biome(column).decoration_height())
+            .max()
+            .unwrap_or(i32::MIN);
+        if min_y > top {
+            return FillType::Empty;
+        }
+        // With a 3D biome map, the voxels under the surface can have the filler of any biome
+        let deepest_filler = self.biomes.iter().map(|biome| biome.filler_depth).max();
+        let filler_bottom = columns
+            .iter()
+            .map(|column| {
+                let filler_depth = match self.vertical_size {
+                    Some(_) => deepest_filler.unwrap_or(0),
+                    None => self.surface_biome(column).filler_depth,
+                };
+                column.height - filler_depth as i32
+            })
+            .min()
+            .unwrap_or(i32::MAX);
+        if max_y < filler_bottom {
+            let base = self.surface_biome(&columns[0]).base;
+            let uniform = match self.vertical_size {
+                Some(_) => self.biomes.iter().all(|biome| biome.base == base),
+                None => columns
+                    .iter()
+                    .all(|column| self.surface_biome(column).base == base),
+            };
+            if uniform {
+                return FillType::Uniform(base);
+            }
+        }
+
+        for z in 0..size.z {
+            for x in 0..size.x {
+                let column = columns[(z * size.x + x) as usize];
+                for y in 0..size.y {
+                    let sample = UVec3::new(x, y, z);
+                    let voxel = self.column_voxel(chunk.world_position(sample), &column);
+                    chunk.set(sample, voxel);
+                }
+            }
+        }
+        FillType::Mixed
+    }
+
+    fn column_voxel(&self, position: IVec3, column: &BiomeColumn) -> V {
+        let depth = column.height - position.y;
+        if depth < 0 {
+            return self.decoration(position, column);
+        }
+        let biome = if depth > 0 && self.vertical_size.is_some() {
+            &self.biomes[self.dithered_biome(position) as usize]
+        } else {
+            self.surface_biome(column)
+        };
+        match depth {
+            0 => biome.surface,
+            depth if depth <= biome.filler_depth as i32 => biome.filler,
+            _ => biome.base,
+        }
+    }
+
+    fn decoration(&self, position: IVec3, column: &BiomeColumn) -> V {
+        let surface = IVec3::new(position.x, column.height, position.z);
+        let height = (position.y - column.height - 1) as usize;
+        self.surface_biome(column)
+            .decorations
+            .iter()
+            .enumerate()
+            .find(|(index, decoration)| {
+                let seed = self.seed ^ DECORATION_SALT.wrapping_add(*index as u32);
+                position_random(seed, surface) < decoration.chance
+            })
+            .and_then(|(_, decoration)| decoration.voxels.get(height).copied())
+            .unwrap_or_default()
+    }
+
+    fn surface_biome(&self, column: &BiomeColumn) -> &Biome<V> {
+        &self.biomes[column.surface_biome as usize]
+    }
+
+    fn dithered_biome(&self, position: IVec3) -> BiomeId {
+        self.pick_weighted(&self.biome_weights(position), position)
+    }
+
+    fn pick_weighted(&self, weights: &[(BiomeId, f32)], position: IVec3) -> BiomeId {
+        let mut remaining = position_random(self.seed ^ DITHER_SALT, position);
+        for (id, weight) in weights {
+            remaining -= weight;
+            if remaining < 0.0 {
+                return *id;
+            }
+        }
+        weights.last().map_or(0, |(id, _)| *id)
+    }
+}
+
+/// Smoothly interpolated value noise, between -0.5 and 0.5
+fn value_noise(seed: u32, point: Vec3) -> f32 {
+    let cell = point.floor();
+    let t = point - cell;
+    let t = t * t * (3.0 - 2.0 * t);
+    let cell = cell.as_ivec3();
+    let corner = |x, y, z| position_random(seed, cell + IVec3::new(x, y, z)) - 0.5;
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let side = |y| {
+        lerp(
+            lerp(corner(0, y, 0), corner(1, y, 0), t.x),
+            lerp(corner(0, y, 1), corner(1, y, 1), t.x),
+            t.z,
+        )
+    };
+    lerp(side(0), side(1), t.y)
+}
+
+/// Three octaves of value noise, between -0.5 and 0.5
+fn fractal_noise(seed: u32, point: Vec3) -> f32 {
+    let mut total = 0.0;
+    let mut amplitude = 1.0;
+    for octave in 0..3 {
+        total += value_noise(seed.wrapping_add(octave), point * (1 << octave) as f32) * amplitude;
+        amplitude *= 0.5;
+    }
+    total / 1.75
+}
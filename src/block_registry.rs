@@ -0,0 +1,337 @@
+///
+/// Block registry
+/// A data driven description of the materials of a world, loaded from a RON asset file. Blocks
+/// in the registry override the material mappers of the world configuration.
+///
+use std::{collections::HashMap, io, marker::PhantomData, sync::Arc};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    utils::{BoxedFuture, HashSet},
+};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{
+    chunk::{Chunk, FillType, NeedsRemesh},
+    chunk_map::ChunkMap,
+    configuration::{EmissionMapperFn, LightEmissionFn, TextureIndexMapperFn, VoxelWorldConfig},
+    lighting::LightMap,
+    mesh_cache::MeshCache,
+    voxel::{MaterialId, VoxelData, WorldVoxel},
+    voxel_material::VoxelEmission,
+};
+
+/// One block of a `BlockRegistry`
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct BlockDefinition {
+    /// Unique name of the block, like `"grass"`
+    pub name: String,
+
+    /// The material id of the block. Ids are stored in saved worlds, so they should not change
+    /// once a world has been saved.
+    pub id: u32,
+
+    /// Texture array indexes for the top, sides and bottom of the block, like the values
+    /// returned by `VoxelWorldConfig::texture_index_mapper`
+    #[serde(default)]
+    pub textures: [u32; 3],
+
+    /// Light emitted by the faces of the block, see `VoxelWorldConfig::emission_mapper`
+    #[serde(default)]
+    pub emission: Option<VoxelEmission>,
+
+    /// The light level the block lights its surroundings with, see
+    /// `VoxelWorldConfig::light_emission_mapper`
+    #[serde(default)]
+    pub light: u8,
+
+    /// Any other data for the block, for use by the game
+    #[serde(default)]
+    pub properties: HashMap<String, ron::Value>,
+}
+
+impl BlockDefinition {
+    /// Get a custom property of the block. `None` if the block has no such property, or if it
+    /// can't be read as a `T`.
+    pub fn property<T: DeserializeOwned>(&self, name: &str) -> Option<T> {
+        self.properties.get(name)?.clone().into_rust().ok()
+    }
+}
+
+/// The layout of a block registry file
+#[derive(Deserialize)]
+struct BlockRegistryFile {
+    blocks: Vec<BlockDefinition>,
+}
+
+/// Maps block names to material ids, and holds the textures, emission and custom properties of
+/// each block.
+///
+/// Whether a voxel is opaque or translucent isn't part of the registry. Meshing, lighting and
+/// physics take it from the voxel itself, see `VoxelData::visibility`, so for `WorldVoxel` it
+/// comes from the variant, like `WorldVoxel::Translucent`.
+///
+/// Registries are loaded from `.blocks.ron` files, either through the asset server or with
+/// `BlockRegistry::from_ron`:
+///
+/// ```ron
+/// (
+///     blocks: [
+///         (name: "stone", id: 0, textures: (0, 0, 0)),
+///         (name: "grass", id: 1, textures: (1, 2, 3)),
+///         (name: "glass", id: 2, textures: (4, 4, 4)),
+///         (
+///             name: "lava",
+///             id: 3,
+///             textures: (5, 5, 5),
+///             emission: Some((color: Rgba(red: 1.0, green: 0.3, blue: 0.0, alpha: 1.0), strength: 2.0)),
+///             light: 14,
+///             properties: {"damage": 4},
+///         ),
+///     ],
+/// )
+/// ```
+#[derive(Asset, TypePath, Clone, Debug, Default, PartialEq)]
+pub struct BlockRegistry {
+    blocks: Arc<HashMap<u32, BlockDefinition>>,
+    ids: Arc<HashMap<String, u32>>,
+}
+
+impl BlockRegistry {
+    /// Create a registry from a list of blocks. Fails if two blocks share a name or an id.
+    pub fn from_blocks(blocks: impl IntoIterator<Item = BlockDefinition>) -> io::Result<Self> {
+        let mut by_id = HashMap::new();
+        let mut ids = HashMap::new();
+        for block in blocks {
+            if ids.insert(block.name.clone(), block.id).is_some() {
+                return Err(invalid_data(format!("Duplicate block name {}", block.name)));
+            }
+            if let Some(other) = by_id.insert(block.id, block) {
+                return Err(invalid_data(format!("Duplicate block id {}", other.id)));
+            }
+        }
+        Ok(Self {
+            blocks: Arc::new(by_id),
+            ids: Arc::new(ids),
+        })
+    }
+
+    /// Parse a registry from the contents of a `.blocks.ron` file
+    pub fn from_ron(ron: &str) -> io::Result<Self> {
+        let file: BlockRegistryFile = ron::from_str(ron).map_err(invalid_data)?;
+        Self::from_blocks(file.blocks)
+    }
+
+    /// Get the block with the given material id
+    pub fn get(&self, id: u32) -> Option<&BlockDefinition> {
+        self.blocks.get(&id)
+    }
+
+    /// Get the block with the given name
+    pub fn get_by_name(&self, name: &str) -> Option<&BlockDefinition> {
+        self.get(self.id(name)?)
+    }
+
+    /// Get the material id of the block with the given name
+    pub fn id(&self, name: &str) -> Option<u32> {
+        self.ids.get(name).copied()
+    }
+
+    /// Create a `WorldVoxel::Solid` voxel of the block with the given name. For translucent
+    /// blocks, create a `WorldVoxel::Translucent` voxel with the `id` of the block instead.
+    /// `None` if there is no such block, or its id doesn't fit in the material id type.
+    pub fn voxel<I: MaterialId + TryFrom<u32>>(&self, name: &str) -> Option<WorldVoxel<I>> {
+        let block = self.get_by_name(name)?;
+        Some(WorldVoxel::Solid(I::try_from(block.id).ok()?))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BlockDefinition> {
+        self.blocks.values()
+    }
+
+    /// The ids of all blocks that were added, removed or changed in `other`
+    pub(crate) fn changed_ids(&self, other: &BlockRegistry) -> HashSet<u32> {
+        let ids = self.blocks.keys().chain(other.blocks.keys());
+        ids.filter(|id| self.get(**id) != other.get(**id))
+            .copied()
+            .collect()
+    }
+}
+
+fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Loads `BlockRegistry` assets from `.blocks.ron` files
+#[derive(Default)]
+pub(crate) struct BlockRegistryLoader;
+
+impl AssetLoader for BlockRegistryLoader {
+    type Asset = BlockRegistry;
+    type Settings = ();
+    type Error = io::Error;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<BlockRegistry, io::Error>> {
+        Box::pin(async move {
+            let mut ron = String::new();
+            reader.read_to_string(&mut ron).await?;
+            BlockRegistry::from_ron(&ron)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["blocks.ron"]
+    }
+}
+
+/// The block registry of the world `C`. Inserted when `VoxelWorldConfig::block_registry` is set,
+/// but it can also be inserted manually with any registry handle.
+///
+/// Chunks are meshed with the last version of the registry that finished loading. When the
+/// registry asset changes, for example when it's hot-reloaded, all chunks containing blocks
+/// that changed are remeshed.
+#[derive(Resource)]
+pub struct BlockRegistryHandle<C> {
+    pub handle: Handle<BlockRegistry>,
+    applied: Option<BlockRegistry>,
+    _marker: PhantomData<C>,
+}
+
+impl<C> BlockRegistryHandle<C> {
+    pub fn new(handle: Handle<BlockRegistry>) -> Self {
+        Self {
+            handle,
+            applied: None,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The registry chunks are currently meshed with
+    pub fn registry(&self) -> Option<&BlockRegistry> {
+        self.applied.as_ref()
+    }
+}
+
+/// The material mappers used for meshing a world. Blocks in the block registry take precedence
+/// over the mappers of the configuration.
+pub(crate) struct MaterialMappers<V: VoxelData> {
+    pub texture_index: TextureIndexMapperFn<V>,
+    pub emission: EmissionMapperFn<V>,
+    pub light_emission: LightEmissionFn<V>,
+}
+
+impl<V: VoxelData> MaterialMappers<V> {
+    pub fn new<C: VoxelWorldConfig<Voxel = V>>(
+        configuration: &C,
+        registry: Option<&BlockRegistry>,
+    ) -> Self {
+        let texture_index = configuration.texture_index_mapper();
+        let emission = configuration.emission_mapper();
+        let light_emission = configuration.light_emission_mapper();
+
+        let Some(registry) = registry.cloned() else {
+            return Self {
+                texture_index,
+                emission,
+                light_emission,
+            };
+        };
+
+        let textures = registry.clone();
+        let emissions = registry.clone();
+        Self {
+            texture_index: Arc::new(move |material| match textures.get(material.into()) {
+                Some(block) => block.textures,
+                None => texture_index(material),
+            }),
+            emission: Arc::new(move |material| match emissions.get(material.into()) {
+                Some(block) => block.emission,
+                None => emission(material),
+            }),
+            light_emission: Arc::new(move |material| match registry.get(material.into()) {
+                Some(block) => block.light,
+                None => light_emission(material),
+            }),
+        }
+    }
+}
+
+/// Start loading the block registry of the world, if it has one
+pub(crate) fn load_block_registry<C: VoxelWorldConfig>(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    configuration: Res<C>,
+) {
+    if let Some(path) = configuration.block_registry() {
+        commands.insert_resource(BlockRegistryHandle::<C>::new(asset_server.load(path)));
+    }
+}
+
+/// Switch to the latest version of the block registry once it's loaded, and remesh the chunks
+/// containing blocks that changed
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn apply_block_registry_changes<C: VoxelWorldConfig>(
+    mut commands: Commands,
+    mut asset_events: EventReader<AssetEvent<BlockRegistry>>,
+    registries: Res<Assets<BlockRegistry>>,
+    registry_handle: Option<ResMut<BlockRegistryHandle<C>>>,
+    chunks: Query<(Entity, &Chunk<C>)>,
+    chunk_map: Res<ChunkMap<C>>,
+    mesh_cache: Res<MeshCache<C>>,
+    mut light_map: ResMut<LightMap<C>>,
+) {
+    let Some(mut registry_handle) = registry_handle else {
+        asset_events.clear();
+        return;
+    };
+    let id = registry_handle.handle.id();
+    let changed = asset_events.read().any(|event| match event {
+        AssetEvent::Added { id: event_id }
+        | AssetEvent::Modified { id: event_id }
+        | AssetEvent::LoadedWithDependencies { id: event_id } => *event_id == id,
+        _ => false,
+    });
+    let Some(registry) = registries.get(id).filter(|_| changed) else {
+        return;
+    };
+
+    let changed_ids = match &registry_handle.applied {
+        Some(applied) => applied.changed_ids(registry),
+        None => registry.iter().map(|block| block.id).collect(),
+    };
+    registry_handle.applied = Some(registry.clone());
+    if changed_ids.is_empty() {
+        return;
+    }
+
+    // Cached meshes were built with the old registry
+    mesh_cache.clear();
+
+    let uses_changed_block = |voxel: &C::Voxel| {
+        voxel
+            .material()
+            .is_some_and(|material| changed_ids.contains(&material.into()))
+    };
+    let read_lock = chunk_map.get_read_lock();
+    for (entity, chunk) in chunks.iter() {
+        let Some(chunk_data) = ChunkMap::<C>::get(&chunk.position, &read_lock) else {
+            continue;
+        };
+        let affected = match (&chunk_data.fill_type, &chunk_data.voxels) {
+            (FillType::Uniform(voxel), _) => uses_changed_block(voxel),
+            (_, Some(voxels)) => voxels.iter().any(uses_changed_block),
+            _ => false,
+        };
+        if affected {
+            commands.entity(entity).try_insert(NeedsRemesh);
+            // The light the blocks emit might have changed
+            light_map.queue_chunk(chunk.position);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+///
+/// Character controller
+/// A kinematic character controller that walks on the solid voxels of a world.
+///
+use std::marker::PhantomData;
+
+use bevy::{math::bounding::Aabb3d, prelude::*};
+
+use crate::{
+    configuration::VoxelWorldConfig,
+    voxel::VoxelData,
+    voxel_physics::{ground_check, is_grounded, sweep_aabb, SweepResult},
+    voxel_world::VoxelWorld,
+};
+
+// How far below a crouching character there can be ground for it to still walk there
+const EDGE_DROP: f32 = 0.1;
+
+/// Moves the entity through the voxel world `C`, colliding with solid voxels.
+///
+/// Set `movement` to the horizontal velocity the character should walk at, and `jump` to make it
+/// jump. The entity's `Transform` is moved every frame by the character controller system. The
+/// collision box is centered on the translation of the entity.
+#[derive(Component, Clone, Debug)]
+pub struct VoxelCharacterController<C> {
+    /// Half the size of the collision box of the character
+    pub half_size: Vec3,
+
+    /// The horizontal velocity the character should walk at. The y component is ignored.
+    pub movement: Vec3,
+
+    /// Set to make the character jump as soon as it stands on the ground. Reset by the
+    /// controller once the jump starts.
+    pub jump: bool,
+
+    /// While crouching, the character won't walk off ledges
+    pub crouch: bool,
+
+    /// Upwards velocity at the start of a jump
+    pub jump_speed: f32,
+
+    pub gravity: f32,
+
+    pub max_fall_speed: f32,
+
+    /// Ledges up to this height are stepped onto instead of blocking the character
+    pub step_height: f32,
+
+    /// The current velocity of the character
+    pub velocity: Vec3,
+
+    /// Whether the character is standing on solid ground
+    pub grounded: bool,
+
+    _marker: PhantomData<C>,
+}
+
+impl<C> Default for VoxelCharacterController<C> {
+    fn default() -> Self {
+        Self {
+            half_size: Vec3::new(0.3, 0.9, 0.3),
+            movement: Vec3::ZERO,
+            jump: false,
+            crouch: false,
+            jump_speed: 9.0,
+            gravity: 30.0,
+            max_fall_speed: 50.0,
+            step_height: 1.0,
+            velocity: Vec3::ZERO,
+            grounded: false,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<C> VoxelCharacterController<C> {
+    /// The collision box of the character at the given translation
+    pub fn aabb(&self, translation: Vec3) -> Aabb3d {
+        Aabb3d::new(translation, self.half_size)
+    }
+}
+
+/// Move all character controllers of the world `C`
+pub(crate) fn update_character_controllers<C: VoxelWorldConfig>(
+    time: Res<Time>,
+    voxel_world: VoxelWorld<C>,
+    mut controllers: Query<(&mut VoxelCharacterController<C>, &mut Transform)>,
+) {
+    let dt = time.delta_seconds();
+    if dt == 0.0 || controllers.is_empty() {
+        return;
+    }
+
+    let get_voxel = voxel_world.get_voxel_fn();
+    let get_voxel = &*get_voxel;
+
+    for (mut controller, mut transform) in controllers.iter_mut() {
+        let aabb = controller.aabb(transform.translation);
+
+        if controller.jump && controller.grounded {
+            controller.velocity.y = controller.jump_speed;
+            controller.grounded = false;
+            controller.jump = false;
+        }
+        controller.velocity.y =
+            (controller.velocity.y - controller.gravity * dt).max(-controller.max_fall_speed);
+        controller.velocity.x = controller.movement.x;
+        controller.velocity.z = controller.movement.z;
+
+        let mut motion = controller.velocity * dt;
+
+        if controller.crouch && controller.grounded {
+            keep_on_ground(get_voxel, aabb, &mut motion);
+        }
+
+        let mut result = sweep_aabb(get_voxel, aabb, motion);
+
+        let blocked_horizontally = result.normal.x != 0.0 || result.normal.z != 0.0;
+        if controller.grounded && blocked_horizontally {
+            if let Some(stepped) = step_up(get_voxel, aabb, motion, controller.step_height) {
+                if stepped.motion.xz().length() > result.motion.xz().length() {
+                    result = stepped;
+                }
+            }
+        }
+
+        transform.translation += result.motion;
+        if result.normal.x != 0.0 {
+            controller.velocity.x = 0.0;
+        }
+        if result.normal.y != 0.0 {
+            controller.velocity.y = 0.0;
+        }
+        if result.normal.z != 0.0 {
+            controller.velocity.z = 0.0;
+        }
+        controller.grounded = result.normal.y > 0.0 || is_grounded(get_voxel, result.aabb);
+    }
+}
+
+/// Drop the horizontal movement along any axis that would move the box off the ground
+fn keep_on_ground<V: VoxelData>(get_voxel: impl Fn(IVec3) -> V, aabb: Aabb3d, motion: &mut Vec3) {
+    let mut aabb = aabb;
+    for axis in [0, 2] {
+        let mut moved = aabb;
+        moved.min[axis] += motion[axis];
+        moved.max[axis] += motion[axis];
+        if ground_check(&get_voxel, moved, EDGE_DROP).is_some() {
+            aabb = moved;
+        } else {
+            motion[axis] = 0.0;
+        }
+    }
+}
+
+/// Try to move the box over a ledge: lift it by `step_height`, move it horizontally, then put it
+/// back down on the ledge. Returns `None` if there's no room to lift the box.
+fn step_up<V: VoxelData>(
+    get_voxel: impl Fn(IVec3) -> V,
+    aabb: Aabb3d,
+    motion: Vec3,
+    step_height: f32,
+) -> Option<SweepResult> {
+    let raised = sweep_aabb(&get_voxel, aabb, Vec3::Y * step_height);
+    if raised.collided() {
+        return None;
+    }
+
+    let moved = sweep_aabb(&get_voxel, raised.aabb, Vec3::new(motion.x, 0.0, motion.z));
+    let lowered = sweep_aabb(&get_voxel, moved.aabb, Vec3::NEG_Y * step_height);
+
+    Some(SweepResult {
+        aabb: lowered.aabb,
+        motion: lowered.aabb.min - aabb.min,
+        normal: moved.normal + lowered.normal,
+    })
+}
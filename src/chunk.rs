@@ -1,34 +1,85 @@
-use bevy::{prelude::*, render::primitives::Aabb, tasks::Task, utils::HashSet};
-use ndshape::{ConstShape, ConstShape3u32};
+use bevy::{prelude::*, render::primitives::Aabb, tasks::Task};
+use ndshape::{RuntimeShape, Shape};
 use std::{
     hash::{Hash, Hasher},
     marker::PhantomData,
     sync::Arc,
 };
 
-use crate::{meshing, voxel::WorldVoxel, voxel_world_internal::ModifiedVoxels};
+use crate::{
+    configuration::{
+        ChunkGeneratorFn, EmissionMapperFn, LightEmissionFn, MeshingMode, TextureIndexMapperFn,
+        VoxelWorldConfig,
+    },
+    edit_storage::ChunkEdits,
+    generation::{run_chunk_generator, ChunkSource},
+    lighting::{self, ChunkLight, VoxelLight},
+    meshing::{self, OpenBorders},
+    voxel::{VoxelData, WorldVoxel},
+};
 
-// The size of a chunk in voxels
-// TODO: implement a way to change this though the configuration
+// The default size of a chunk in voxels.
+// The actual size can be changed through `VoxelWorldConfig::chunk_size`
 pub const CHUNK_SIZE_U: u32 = 32;
-pub const CHUNK_SIZE_I: i32 = CHUNK_SIZE_U as i32;
-pub const CHUNK_SIZE_F: f32 = CHUNK_SIZE_U as f32;
+
+/// The coarsest level of detail. Chunks at this level are sampled at every 8th voxel.
+pub const MAX_LOD: u8 = 3;
+
+// How far, in chunks, the camera needs to move past a LOD distance before a chunk switches to
+// another level of detail. Keeps chunks on a border from flipping back and forth.
+const LOD_HYSTERESIS: f32 = 1.0;
 
 // A chunk with 1-voxel boundary padding.
-pub(crate) const PADDED_CHUNK_SIZE: u32 = CHUNK_SIZE_U + 2;
-pub(crate) type PaddedChunkShape =
-    ConstShape3u32<PADDED_CHUNK_SIZE, PADDED_CHUNK_SIZE, PADDED_CHUNK_SIZE>;
+pub(crate) type PaddedChunkShape = RuntimeShape<u32, 3>;
+
+pub(crate) type VoxelArray<V> = [V];
 
-pub(crate) type VoxelArray = [WorldVoxel; PaddedChunkShape::SIZE as usize];
+/// Get the shape of a chunk of the given size, including the 1-voxel boundary padding.
+#[inline]
+pub(crate) fn padded_chunk_shape(chunk_size: UVec3) -> PaddedChunkShape {
+    PaddedChunkShape::new((chunk_size + 2).to_array())
+}
+
+/// The number of voxels a single sample of a chunk at the given level of detail covers along
+/// each axis
+#[inline]
+pub fn lod_scale(lod: u8) -> u32 {
+    1 << lod
+}
+
+/// The number of samples along each axis of a chunk at the given level of detail
+#[inline]
+pub(crate) fn lod_chunk_size(chunk_size: UVec3, lod: u8) -> UVec3 {
+    let scale = lod_scale(lod);
+    (chunk_size + scale - 1) / scale
+}
+
+/// The level of detail of a chunk at the given distance from the camera, in chunks
+pub(crate) fn lod_at_distance(lod_distances: &[u32], distance: f32) -> u8 {
+    let lod = lod_distances
+        .iter()
+        .filter(|lod_distance| distance >= **lod_distance as f32)
+        .count();
+    lod.min(MAX_LOD as usize) as u8
+}
+
+/// The level of detail a chunk with the `current` level should switch to. The level only changes
+/// once the chunk is further than `LOD_HYSTERESIS` past a LOD distance.
+pub(crate) fn next_lod(lod_distances: &[u32], current: u8, distance: f32) -> u8 {
+    let closer = lod_at_distance(lod_distances, distance - LOD_HYSTERESIS);
+    let further = lod_at_distance(lod_distances, distance + LOD_HYSTERESIS);
+    if (closer..=further).contains(&current) {
+        current
+    } else {
+        lod_at_distance(lod_distances, distance)
+    }
+}
 
 #[derive(Component)]
 #[component(storage = "SparseSet")]
-pub(crate) struct ChunkThread<C>(pub Task<ChunkTask<C>>, PhantomData<C>);
+pub(crate) struct ChunkThread<C: VoxelWorldConfig>(pub Task<ChunkTask<C>>, PhantomData<C>);
 
-impl<C> ChunkThread<C>
-where
-    C: Send + Sync + 'static,
-{
+impl<C: VoxelWorldConfig> ChunkThread<C> {
     pub fn new(task: Task<ChunkTask<C>>, _pos: IVec3) -> Self {
         Self(task, PhantomData)
     }
@@ -42,26 +93,34 @@ pub struct NeedsRemesh;
 pub struct NeedsDespawn;
 
 #[derive(Clone, Debug)]
-pub enum FillType {
+pub enum FillType<V = WorldVoxel> {
     Empty,
     Mixed,
-    Uniform(WorldVoxel),
+    Uniform(V),
 }
 
 /// This is used to lookup voxel data from spawned chunks. Does not persist after
 /// the chunk is despawned.
 #[derive(Clone, Debug)]
-pub struct ChunkData {
+pub struct ChunkData<V = WorldVoxel> {
     pub position: IVec3,
-    pub voxels: Option<Arc<VoxelArray>>,
+    pub voxels: Option<Arc<VoxelArray<V>>>,
     pub voxels_hash: u64,
     pub is_full: bool,
     pub is_empty: bool,
-    pub fill_type: FillType,
+    pub fill_type: FillType<V>,
     pub entity: Entity,
+    pub chunk_size: UVec3,
+    /// The level of detail the voxels were sampled at. At level 0 every voxel is stored, and
+    /// every next level only stores every 2nd, 4th and 8th voxel along each axis.
+    pub lod: u8,
+    /// The packed light levels the voxels were meshed with, when voxel lighting is enabled
+    pub light: Option<Arc<[u8]>>,
+    /// Whether any of the voxels are fluid
+    pub has_fluid: bool,
 }
 
-impl ChunkData {
+impl<V: VoxelData> ChunkData<V> {
     pub fn new() -> Self {
         Self {
             position: IVec3::ZERO,
@@ -71,41 +130,73 @@ impl ChunkData {
             is_empty: true,
             fill_type: FillType::Empty,
             entity: Entity::PLACEHOLDER,
+            chunk_size: UVec3::splat(CHUNK_SIZE_U),
+            lod: 0,
+            light: None,
+            has_fluid: false,
         }
     }
 
-    pub fn with_entity(entity: Entity) -> Self {
+    pub fn with_entity(entity: Entity, chunk_size: UVec3) -> Self {
         let new = Self::new();
-        Self { entity, ..new }
+        Self {
+            entity,
+            chunk_size,
+            ..new
+        }
+    }
+
+    /// The shape of the voxel array of this chunk, including the boundary padding
+    pub(crate) fn shape(&self) -> PaddedChunkShape {
+        padded_chunk_shape(self.lod_size())
+    }
+
+    /// The number of voxels stored along each axis at the level of detail of this chunk
+    pub fn lod_size(&self) -> UVec3 {
+        lod_chunk_size(self.chunk_size, self.lod)
     }
 
     pub fn generate_hash(&mut self) {
         if let Some(voxels) = &self.voxels {
             let mut hasher = std::collections::hash_map::DefaultHasher::new();
             voxels.hash(&mut hasher);
+            self.lod.hash(&mut hasher);
+            self.light.hash(&mut hasher);
             self.voxels_hash = hasher.finish();
         }
     }
 
-    pub fn get_voxel(&self, position: UVec3) -> WorldVoxel {
-        if self.voxels.is_some() {
-            self.voxels.as_ref().unwrap()[PaddedChunkShape::linearize(position.to_array()) as usize]
+    /// Get the voxel at the given (padded) position in the chunk. For chunks at a lower level of
+    /// detail, this is the sample covering the position.
+    pub fn get_voxel(&self, position: UVec3) -> V {
+        if let Some(voxels) = &self.voxels {
+            let scale = lod_scale(self.lod) as i32;
+            let position = (position.as_ivec3() - 1).div_euclid(IVec3::splat(scale)) + 1;
+            voxels[self.shape().linearize(position.as_uvec3().to_array()) as usize]
         } else {
             match self.fill_type {
                 FillType::Uniform(voxel) => voxel,
-                FillType::Empty => WorldVoxel::Unset,
+                FillType::Empty => V::default(),
                 FillType::Mixed => unreachable!(),
             }
         }
     }
 
+    /// Get the light level the voxel at the given (padded) position was meshed with. `None` if
+    /// the chunk was meshed without lighting.
+    pub fn get_light(&self, position: UVec3) -> Option<VoxelLight> {
+        let light = self.light.as_ref()?;
+        let index = self.shape().linearize(position.to_array()) as usize;
+        Some(VoxelLight::unpack(light[index]))
+    }
+
     pub fn world_position(&self) -> Vec3 {
-        self.position.as_vec3() * CHUNK_SIZE_F
+        self.position.as_vec3() * self.chunk_size.as_vec3()
     }
 
     pub fn aabb(&self) -> Aabb {
         let min = Vec3::ZERO;
-        let max = min + Vec3::splat(CHUNK_SIZE_F);
+        let max = min + self.chunk_size.as_vec3();
         Aabb::from_min_max(min, max)
     }
 
@@ -123,7 +214,7 @@ impl ChunkData {
     }
 }
 
-impl Default for ChunkData {
+impl<V: VoxelData> Default for ChunkData<V> {
     fn default() -> Self {
         Self::new()
     }
@@ -134,14 +225,19 @@ impl Default for ChunkData {
 pub struct Chunk<C> {
     pub position: IVec3,
     pub entity: Entity,
+    pub size: UVec3,
+    /// The level of detail the chunk is meshed at, see `VoxelWorldConfig::lod_distances`
+    pub lod: u8,
     _marker: PhantomData<C>,
 }
 
 impl<C> Chunk<C> {
-    pub fn new(position: IVec3, entity: Entity) -> Self {
+    pub fn new(position: IVec3, entity: Entity, size: UVec3) -> Self {
         Self {
             position,
             entity,
+            size,
+            lod: 0,
             _marker: PhantomData,
         }
     }
@@ -150,102 +246,272 @@ impl<C> Chunk<C> {
         Self {
             position: chunk.position,
             entity: chunk.entity,
+            size: chunk.size,
+            lod: chunk.lod,
             _marker: PhantomData,
         }
     }
 
+    /// The transform of the chunk meshes at the given level of detail. Meshes are built in units
+    /// of samples and start at the padding, so they get offset by one sample and scaled up.
+    pub fn mesh_transform(&self, lod: u8) -> Transform {
+        let scale = lod_scale(lod) as f32;
+        Transform::from_translation(self.position.as_vec3() * self.size.as_vec3() - scale)
+            .with_scale(Vec3::splat(scale))
+    }
+
     pub fn aabb(&self) -> Aabb {
         let min = Vec3::ZERO;
-        let max = min + Vec3::splat(CHUNK_SIZE_F);
+        let max = min + self.size.as_vec3();
         Aabb::from_min_max(min, max)
     }
 }
 
 /// Holds all data needed to generate and mesh a chunk
 #[derive(Component)]
-pub(crate) struct ChunkTask<C> {
+pub(crate) struct ChunkTask<C: VoxelWorldConfig> {
     pub position: IVec3,
-    pub chunk_data: ChunkData,
-    pub modified_voxels: ModifiedVoxels<C>,
+    pub chunk_data: ChunkData<C::Voxel>,
+    pub modified_voxels: ChunkEdits<C::Voxel>,
     pub mesh: Option<Mesh>,
+    pub translucent_mesh: Option<Mesh>,
+    open_borders: OpenBorders,
+    light: Option<ChunkLight>,
     _marker: PhantomData<C>,
 }
 
-impl<C: Send + Sync + 'static> ChunkTask<C> {
-    pub fn new(entity: Entity, position: IVec3, modified_voxels: ModifiedVoxels<C>) -> Self {
+impl<C: VoxelWorldConfig> ChunkTask<C> {
+    pub fn new(
+        entity: Entity,
+        position: IVec3,
+        chunk_size: UVec3,
+        modified_voxels: ChunkEdits<C::Voxel>,
+    ) -> Self {
         Self {
             position,
-            chunk_data: ChunkData::with_entity(entity),
+            chunk_data: ChunkData::with_entity(entity, chunk_size),
             modified_voxels,
             mesh: None,
+            translucent_mesh: None,
+            open_borders: OpenBorders::default(),
+            light: None,
             _marker: PhantomData,
         }
     }
 
-    /// Generate voxel data for the chunk. The supplied `modified_voxels` map is first checked,
+    /// Mesh the chunk with the given light, as kept by the `LightMap`. A chunk that isn't lit
+    /// yet gets its light baked with `bake_light` before it is meshed. Only chunks at full
+    /// resolution are lit.
+    pub fn with_light(mut self, light: ChunkLight) -> Self {
+        self.light = Some(light);
+        self
+    }
+
+    /// Sample the chunk at the given level of detail. The faces on the `open_borders` of the chunk
+    /// are always meshed, which hides the seams towards neighbours at another level of detail.
+    pub fn with_lod(mut self, lod: u8, open_borders: OpenBorders) -> Self {
+        self.chunk_data.lod = lod;
+        self.open_borders = open_borders;
+        self
+    }
+
+    /// Generate voxel data for the chunk. The supplied `modified_voxels` pages are first checked,
     /// and where no voxeles are modified, the `voxel_data_fn` is called to get data from the
-    /// consumer.
+    /// consumer. At lower levels of detail, only the first voxel of every sample is looked up.
     pub fn generate<F>(&mut self, mut voxel_data_fn: F)
     where
-        F: FnMut(IVec3) -> WorldVoxel + Send + 'static,
+        F: FnMut(IVec3) -> C::Voxel + Send + 'static,
     {
-        let mut filled_count = 0;
-        let modified_voxels = (*self.modified_voxels).read().unwrap();
-        let mut voxels = [WorldVoxel::Unset; PaddedChunkShape::SIZE as usize];
-        let mut material_count = HashSet::new();
+        let modified_voxels = &self.modified_voxels;
+        let shape = self.chunk_data.shape();
+        let chunk_origin = self.position * self.chunk_data.chunk_size.as_ivec3();
+        let scale = lod_scale(self.chunk_data.lod) as i32;
+
+        let mut voxels = vec![C::Voxel::default(); shape.usize()];
 
-        for i in 0..PaddedChunkShape::SIZE {
-            let chunk_block = PaddedChunkShape::delinearize(i);
+        for i in 0..shape.size() {
+            let chunk_block = shape.delinearize(i);
 
             let block_pos = IVec3 {
-                x: chunk_block[0] as i32 + (self.position.x * CHUNK_SIZE_I) - 1,
-                y: chunk_block[1] as i32 + (self.position.y * CHUNK_SIZE_I) - 1,
-                z: chunk_block[2] as i32 + (self.position.z * CHUNK_SIZE_I) - 1,
+                x: (chunk_block[0] as i32 - 1) * scale + chunk_origin.x,
+                y: (chunk_block[1] as i32 - 1) * scale + chunk_origin.y,
+                z: (chunk_block[2] as i32 - 1) * scale + chunk_origin.z,
+            };
+
+            voxels[i as usize] = match modified_voxels.get(&block_pos) {
+                Some(voxel) => voxel,
+                None => voxel_data_fn(block_pos),
             };
+        }
+
+        self.set_voxels(voxels);
+    }
 
-            if let Some(voxel) = modified_voxels.get(&block_pos) {
-                voxels[i as usize] = *voxel;
-                if !voxel.is_unset() && !voxel.is_air() {
-                    filled_count += 1;
+    /// Generate voxel data for the chunk with a generator filling the whole chunk at once.
+    /// Chunks the generator reports as empty or uniform are not looked at voxel by voxel, unless
+    /// voxels in or around them were modified.
+    pub fn generate_chunk(&mut self, generator: &ChunkGeneratorFn<C::Voxel>) {
+        let (fill_type, mut voxels) = run_chunk_generator(
+            generator,
+            self.position,
+            self.chunk_data.chunk_size,
+            self.chunk_data.lod,
+        );
+        let edited = !self.modified_voxels.is_empty();
+        let uniform = match fill_type {
+            FillType::Empty => Some(C::Voxel::default()),
+            FillType::Uniform(voxel) => Some(voxel),
+            FillType::Mixed => None,
+        };
+        if let Some(voxel) = uniform {
+            if !edited && voxel.is_solid() == voxel.is_opaque() {
+                self.set_uniform(voxel);
+                return;
+            }
+            voxels.fill(voxel);
+        }
+
+        if edited {
+            let shape = self.chunk_data.shape();
+            let chunk_origin = self.position * self.chunk_data.chunk_size.as_ivec3();
+            let scale = lod_scale(self.chunk_data.lod) as i32;
+            for (i, voxel) in voxels.iter_mut().enumerate() {
+                let sample = UVec3::from(shape.delinearize(i as u32)).as_ivec3();
+                if let Some(modified) = self
+                    .modified_voxels
+                    .get(&((sample - 1) * scale + chunk_origin))
+                {
+                    *voxel = modified;
                 }
-                continue;
             }
+        }
+        self.set_voxels(voxels);
+    }
 
-            let voxel = voxel_data_fn(block_pos);
+    /// Generate the chunk from whichever source the world generates chunks with
+    pub fn generate_from(&mut self, source: ChunkSource<C::Voxel>) {
+        match source {
+            ChunkSource::Lookup(voxel_data_fn) => self.generate(voxel_data_fn),
+            ChunkSource::Chunk(generator) => self.generate_chunk(&generator),
+        }
+    }
 
-            voxels[i as usize] = voxel;
+    /// Store a chunk where every voxel is the same, without allocating its voxels. Only used for
+    /// voxels that are either opaque or not rendered at all.
+    fn set_uniform(&mut self, voxel: C::Voxel) {
+        self.chunk_data.is_empty = !voxel.is_solid();
+        self.chunk_data.is_full = voxel.is_opaque();
+        self.chunk_data.has_fluid = voxel.fluid_level().is_some();
+        self.chunk_data.fill_type = if voxel.is_opaque() {
+            FillType::Uniform(voxel)
+        } else {
+            FillType::Empty
+        };
+        self.chunk_data.voxels = None;
+        self.chunk_data.light = None;
+        self.chunk_data.voxels_hash = 0;
+    }
 
-            if let WorldVoxel::Solid(m) = voxel {
-                filled_count += 1;
-                material_count.insert(m);
+    /// Store the generated voxels and their light, and work out how the chunk is filled
+    fn set_voxels(&mut self, voxels: Vec<C::Voxel>) {
+        let light = match self.light.take() {
+            Some(ChunkLight::Lit(light)) => Some(light),
+            unlit => {
+                // Baked once the voxels are known
+                self.light = unlit;
+                None
             }
-        }
+        };
+        let shape = self.chunk_data.shape();
+        let filled_count = voxels.iter().filter(|voxel| voxel.is_solid()).count();
+        let opaque_count = voxels.iter().filter(|voxel| voxel.is_opaque()).count();
+        let is_uniform = voxels.iter().all(|voxel| *voxel == voxels[0]);
+        self.chunk_data.has_fluid = voxels.iter().any(|voxel| voxel.fluid_level().is_some());
 
         self.chunk_data.is_empty = filled_count == 0;
-        self.chunk_data.is_full = filled_count == PaddedChunkShape::SIZE;
+        // Only opaque voxels make a chunk full, since a chunk full of translucent voxels can
+        // still have visible faces, and can be seen through
+        self.chunk_data.is_full = opaque_count == shape.usize();
 
-        if self.chunk_data.is_full && material_count.len() == 1 {
+        if self.chunk_data.is_full && is_uniform {
             self.chunk_data.fill_type = FillType::Uniform(voxels[0]);
             self.chunk_data.voxels = None;
         } else if filled_count > 0 {
             self.chunk_data.fill_type = FillType::Mixed;
-            self.chunk_data.voxels = Some(Arc::new(voxels));
+            self.chunk_data.voxels = Some(voxels.into());
+            self.chunk_data.light = light.map(Into::into);
         } else {
             self.chunk_data.fill_type = FillType::Empty;
             self.chunk_data.voxels = None;
+            self.chunk_data.light = light.map(Into::into);
         };
+        self.generate_hash();
+    }
 
+    fn generate_hash(&mut self) {
         self.chunk_data.generate_hash();
+        if self.open_borders != OpenBorders::default() {
+            // Open borders change the mesh, so they need to be part of the mesh cache key
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            (self.chunk_data.voxels_hash, self.open_borders).hash(&mut hasher);
+            self.chunk_data.voxels_hash = hasher.finish();
+        }
+    }
+
+    /// Light a chunk that isn't lit yet from its generated voxels, so it is meshed with its
+    /// light right away. See `lighting::bake_light`. Chunks full of opaque voxels stay dark.
+    pub fn bake_light(&mut self, light_emission: &LightEmissionFn<C::Voxel>) {
+        let Some(ChunkLight::Unlit {
+            padding: mut light,
+            lit_neighbours,
+        }) = self.light.take()
+        else {
+            return;
+        };
+        if self.chunk_data.is_full {
+            return;
+        }
+
+        let shape = self.chunk_data.shape();
+        let voxels = match &self.chunk_data.voxels {
+            Some(voxels) => voxels.clone(),
+            None => vec![self.chunk_data.get_voxel(UVec3::ONE); shape.usize()].into(),
+        };
+        lighting::bake_light(
+            &voxels,
+            &mut light,
+            self.chunk_data.chunk_size,
+            lit_neighbours,
+            |voxel| {
+                voxel
+                    .material()
+                    .map_or(0, |material| light_emission(material))
+            },
+        );
+        self.chunk_data.light = Some(light.into());
+        self.generate_hash();
     }
 
-    /// Generate a mesh for the chunk based on the currect voxel data
-    pub fn mesh(&mut self, texture_index_mapper: Arc<dyn Fn(u8) -> [u32; 3] + Send + Sync>) {
-        if self.mesh.is_none() && self.chunk_data.voxels.is_some() {
-            self.mesh = Some(meshing::generate_chunk_mesh(
-                self.chunk_data.voxels.as_ref().unwrap().clone(),
-                self.position,
+    /// Generate a mesh for the chunk based on the currect voxel data. Translucent voxels are
+    /// meshed separately into `translucent_mesh`.
+    pub fn mesh(
+        &mut self,
+        texture_index_mapper: TextureIndexMapperFn<C::Voxel>,
+        emission_mapper: EmissionMapperFn<C::Voxel>,
+        meshing_mode: MeshingMode,
+    ) {
+        if let (None, Some(voxels)) = (&self.mesh, &self.chunk_data.voxels) {
+            let (mesh, translucent_mesh) = meshing::generate_chunk_mesh(
+                voxels.clone(),
+                self.chunk_data.light.clone(),
+                self.chunk_data.lod_size(),
+                self.open_borders,
                 texture_index_mapper,
-            ));
+                emission_mapper,
+                meshing_mode,
+            );
+            self.mesh = Some(mesh);
+            self.translucent_mesh = translucent_mesh;
         }
     }
 
@@ -6,61 +6,83 @@ use std::{
 use bevy::{math::bounding::Aabb3d, prelude::*, utils::hashbrown::HashMap};
 
 use crate::{
-    chunk::{self, ChunkData, CHUNK_SIZE_F},
-    voxel::VOXEL_SIZE,
+    chunk::{self, ChunkData, CHUNK_SIZE_U},
+    configuration::VoxelWorldConfig,
+    voxel::{WorldVoxel, VOXEL_SIZE},
     voxel_world::ChunkWillSpawn,
 };
 
 #[derive(Deref, DerefMut)]
-pub struct ChunkMapData {
+pub struct ChunkMapData<V = WorldVoxel> {
     #[deref]
-    data: HashMap<IVec3, chunk::ChunkData>,
+    data: HashMap<IVec3, chunk::ChunkData<V>>,
     bounds: Aabb3d,
+    chunk_size: UVec3,
 }
 
 /// Holds a map of all chunks that are currently spawned spawned
 /// The chunks also exist as entities that can be queried in the ECS,
 /// but having this map in addition allows for faster spatial lookups
 #[derive(Resource)]
-pub struct ChunkMap<C> {
-    map: Arc<RwLock<ChunkMapData>>,
+pub struct ChunkMap<C: VoxelWorldConfig> {
+    map: Arc<RwLock<ChunkMapData<C::Voxel>>>,
     _marker: PhantomData<C>,
 }
 
-impl<C: Send + Sync + 'static> ChunkMap<C> {
+impl<C: VoxelWorldConfig> ChunkMap<C> {
+    pub fn new(chunk_size: UVec3) -> Self {
+        Self {
+            map: Arc::new(RwLock::new(ChunkMapData {
+                data: HashMap::with_capacity(1000),
+                bounds: Aabb3d::new(Vec3::ZERO, Vec3::ZERO),
+                chunk_size,
+            })),
+            _marker: PhantomData,
+        }
+    }
+
     pub fn get(
         position: &IVec3,
-        read_lock: &RwLockReadGuard<ChunkMapData>,
-    ) -> Option<chunk::ChunkData> {
+        read_lock: &RwLockReadGuard<ChunkMapData<C::Voxel>>,
+    ) -> Option<chunk::ChunkData<C::Voxel>> {
         read_lock.data.get(position).cloned()
     }
 
-    pub fn contains_chunk(position: &IVec3, read_lock: &RwLockReadGuard<ChunkMapData>) -> bool {
+    pub fn contains_chunk(
+        position: &IVec3,
+        read_lock: &RwLockReadGuard<ChunkMapData<C::Voxel>>,
+    ) -> bool {
         read_lock.data.contains_key(position)
     }
 
     /// Get the current bounding box of loaded chunks in this map.
     ///
     /// Expressed in **chunk coordinates**. Bounds are **inclusive**.
-    pub fn get_bounds(read_lock: &RwLockReadGuard<ChunkMapData>) -> Aabb3d {
+    pub fn get_bounds(read_lock: &RwLockReadGuard<ChunkMapData<C::Voxel>>) -> Aabb3d {
         read_lock.bounds
     }
 
     /// Get the current bounding box of loaded chunks in this map.
     ///
     /// Expressed in **world units**. Bounds are **inclusive**.
-    pub fn get_world_bounds(read_lock: &RwLockReadGuard<ChunkMapData>) -> Aabb3d {
+    pub fn get_world_bounds(read_lock: &RwLockReadGuard<ChunkMapData<C::Voxel>>) -> Aabb3d {
+        let chunk_size = read_lock.chunk_size.as_vec3();
         let mut world_bounds = ChunkMap::<C>::get_bounds(read_lock);
-        world_bounds.min *= CHUNK_SIZE_F * VOXEL_SIZE;
-        world_bounds.max = (world_bounds.max + Vec3::ONE) * CHUNK_SIZE_F * VOXEL_SIZE;
+        world_bounds.min *= chunk_size * VOXEL_SIZE;
+        world_bounds.max = (world_bounds.max + Vec3::ONE) * chunk_size * VOXEL_SIZE;
         world_bounds
     }
 
-    pub fn get_read_lock(&self) -> RwLockReadGuard<ChunkMapData> {
+    /// Get the size of the chunks in this map, in voxels.
+    pub fn get_chunk_size(read_lock: &RwLockReadGuard<ChunkMapData<C::Voxel>>) -> UVec3 {
+        read_lock.chunk_size
+    }
+
+    pub fn get_read_lock(&self) -> RwLockReadGuard<'_, ChunkMapData<C::Voxel>> {
         self.map.read().unwrap()
     }
 
-    pub fn get_map(&self) -> Arc<RwLock<ChunkMapData>> {
+    pub fn get_map(&self) -> Arc<RwLock<ChunkMapData<C::Voxel>>> {
         self.map.clone()
     }
 
@@ -134,24 +156,21 @@ impl<C: Send + Sync + 'static> ChunkMap<C> {
     }
 }
 
-impl<C> Default for ChunkMap<C> {
+impl<C: VoxelWorldConfig> Default for ChunkMap<C> {
     fn default() -> Self {
-        Self {
-            map: Arc::new(RwLock::new(ChunkMapData {
-                data: HashMap::with_capacity(1000),
-                bounds: Aabb3d::new(Vec3::ZERO, Vec3::ZERO),
-            })),
-            _marker: PhantomData,
-        }
+        Self::new(UVec3::splat(CHUNK_SIZE_U))
     }
 }
 
 #[derive(Resource, Deref, DerefMut, Default, Debug)]
-pub(crate) struct ChunkMapInsertBuffer<C>(#[deref] Vec<(IVec3, chunk::ChunkData)>, PhantomData<C>);
+pub(crate) struct ChunkMapInsertBuffer<C: VoxelWorldConfig>(
+    #[deref] Vec<(IVec3, chunk::ChunkData<C::Voxel>)>,
+    PhantomData<C>,
+);
 
 #[derive(Resource, Deref, DerefMut, Default)]
-pub(crate) struct ChunkMapUpdateBuffer<C>(
-    #[deref] Vec<(IVec3, chunk::ChunkData, ChunkWillSpawn<C>)>,
+pub(crate) struct ChunkMapUpdateBuffer<C: VoxelWorldConfig>(
+    #[deref] Vec<(IVec3, chunk::ChunkData<C::Voxel>, ChunkWillSpawn<C>)>,
     PhantomData<C>,
 );
 
@@ -1,10 +1,32 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
-use crate::voxel::WorldVoxel;
+use crate::{
+    biome::BiomeLayer,
+    chunk::{FillType, CHUNK_SIZE_U},
+    fluid::FluidProperties,
+    generation::{GenerationPipeline, PaddedVoxelBuffer},
+    structure::StructureLayer,
+    voxel::{VoxelData, WorldVoxel},
+    voxel_material::VoxelEmission,
+};
 use bevy::prelude::*;
 
-pub type VoxelLookupFn = Box<dyn FnMut(IVec3) -> WorldVoxel + Send + Sync>;
-pub type VoxelLookupDelegate = Box<dyn Fn(IVec3) -> VoxelLookupFn + Send + Sync>;
+pub type VoxelLookupFn<V = WorldVoxel> = Box<dyn FnMut(IVec3) -> V + Send + Sync>;
+pub type VoxelLookupDelegate<V = WorldVoxel> = Box<dyn Fn(IVec3) -> VoxelLookupFn<V> + Send + Sync>;
+pub type ChunkGeneratorFn<V = WorldVoxel> =
+    Arc<dyn Fn(&mut PaddedVoxelBuffer<V>) -> FillType<V> + Send + Sync>;
+pub type TextureIndexMapperFn<V = WorldVoxel> =
+    Arc<dyn Fn(<V as VoxelData>::Material) -> [u32; 3] + Send + Sync>;
+pub type EmissionMapperFn<V = WorldVoxel> =
+    Arc<dyn Fn(<V as VoxelData>::Material) -> Option<VoxelEmission> + Send + Sync>;
+pub type LightEmissionFn<V = WorldVoxel> =
+    Arc<dyn Fn(<V as VoxelData>::Material) -> u8 + Send + Sync>;
+pub type GravityMapperFn<V = WorldVoxel> =
+    Arc<dyn Fn(<V as VoxelData>::Material) -> bool + Send + Sync>;
+pub type AnchorMapperFn<V = WorldVoxel> =
+    Arc<dyn Fn(<V as VoxelData>::Material) -> bool + Send + Sync>;
+pub type FluidPropertiesFn<V = WorldVoxel> =
+    Arc<dyn Fn(<V as VoxelData>::Material) -> FluidProperties + Send + Sync>;
 
 #[derive(Default, PartialEq, Eq)]
 pub enum ChunkDespawnStrategy {
@@ -31,13 +53,68 @@ pub enum ChunkSpawnStrategy {
     Close,
 }
 
+#[derive(Default, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum MeshingMode {
+    /// Emit one quad for every visible voxel face
+    #[default]
+    PerFace,
+
+    /// Merge neighbouring faces with the same merge value and ambient occlusion into larger
+    /// quads. This greatly reduces the vertex count of large flat areas, at the cost of some
+    /// extra work when meshing. The voxel texture is repeated over merged quads.
+    Greedy,
+}
+
 /// `bevy_voxel_world` configuation structs need to implement this trait
 pub trait VoxelWorldConfig: Resource + Default + Clone {
+    /// The type of voxel stored in this world. Use `WorldVoxel` unless you need to store
+    /// more data per voxel, in which case you can implement `VoxelData` for your own type.
+    type Voxel: VoxelData;
+
     /// Distance in chunks to spawn chunks around the camera
     fn spawning_distance(&self) -> u32 {
         10
     }
 
+    /// Distances in chunks from the camera where chunks switch to a lower level of detail. Every
+    /// entry adds a level, and every level halves the resolution of the chunks along each axis.
+    /// For example, `vec![8, 16, 24]` meshes chunks closer than 8 chunks at full resolution, and
+    /// chunks further away at 2x, 4x and 8x downsampled resolution. At most 3 levels are used.
+    ///
+    /// Chunks at a lower level of detail only sample `voxel_lookup_delegate` once per sample, so
+    /// the chunk size should be a multiple of 8 when using all levels. Empty by default, which
+    /// meshes all chunks at full resolution.
+    fn lod_distances(&self) -> Vec<u32> {
+        Vec::new()
+    }
+
+    /// Size of a chunk in voxels along each axis. Chunks don't need to be cubic.
+    /// Smaller chunks are faster to remesh, which suits heavily edited worlds, while larger
+    /// chunks mean fewer entities and draw calls for worlds with a large view distance.
+    fn chunk_size(&self) -> UVec3 {
+        UVec3::splat(CHUNK_SIZE_U)
+    }
+
+    /// Directory where modified voxels are stored as region files. When this is set, modified
+    /// voxels are loaded from disk when the camera approaches them, and written back to disk and
+    /// dropped from memory when the camera moves far away.
+    ///
+    /// When `None`, all modified voxels are kept in memory for as long as the app runs.
+    fn region_storage_path(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Size of a region, in chunks. Each region file holds the modified voxels of this many chunks.
+    fn region_size(&self) -> UVec3 {
+        UVec3::splat(8)
+    }
+
+    /// The number of transactions `VoxelWorld::undo` can go back. Older transactions are
+    /// forgotten.
+    fn max_undo_steps(&self) -> usize {
+        100
+    }
+
     /// Strategy for despawning chunks
     fn chunk_despawn_strategy(&self) -> ChunkDespawnStrategy {
         ChunkDespawnStrategy::default()
@@ -69,18 +146,44 @@ pub trait VoxelWorldConfig: Resource + Default + Clone {
         25
     }
 
+    /// How chunks are turned into meshes
+    fn meshing_mode(&self) -> MeshingMode {
+        MeshingMode::default()
+    }
+
+    /// Light voxels with sunlight and light emitted by voxels. Light is baked into the chunk
+    /// meshes, so caves are dark unless something lights them up. Chunks at a lower level of
+    /// detail are always fully lit by the sun.
+    ///
+    /// Light is kept for every chunk at full resolution, and updated around edited voxels, so only
+    /// chunks whose light changes are remeshed. Until the chunk above a chunk is loaded, the sky
+    /// above it is assumed to be open.
+    fn voxel_lighting(&self) -> bool {
+        false
+    }
+
+    /// A function that maps voxel materials to the light level they emit, from 0 to 15.
+    /// Only used when `voxel_lighting` is enabled.
+    ///
+    /// This is the light spread to the voxels around a material, baked into the chunk meshes. It
+    /// is independent of `emission_mapper`, which makes the faces of the material itself glow, so
+    /// a lamp usually gets both.
+    fn light_emission_mapper(&self) -> LightEmissionFn<Self::Voxel> {
+        Arc::new(|_| 0)
+    }
+
     /// Debugging aids
     fn debug_draw_chunks(&self) -> bool {
         false
     }
 
     /// A function that maps voxel materials to texture coordinates.
-    /// The input is the material index, and the output is a slice of three indexes into an array texture.
+    /// The input is the material of the voxel, and the output is a slice of three indexes into an array texture.
     /// The three values correspond to the top, sides and bottom of the voxel. For example,
     /// if the slice is `[1,2,2]`, the top will use texture index 1 and the sides and bottom will use texture
     /// index 2.
-    fn texture_index_mapper(&self) -> Arc<dyn Fn(u8) -> [u32; 3] + Send + Sync> {
-        Arc::new(|mat| match mat {
+    fn texture_index_mapper(&self) -> TextureIndexMapperFn<Self::Voxel> {
+        Arc::new(|mat| match mat.into() {
             0 => [0, 0, 0],
             1 => [1, 1, 1],
             2 => [2, 2, 2],
@@ -89,12 +192,142 @@ pub trait VoxelWorldConfig: Resource + Default + Clone {
         })
     }
 
+    /// A function that maps voxel materials to the light they emit by themselves, or `None` for
+    /// materials that don't glow. This only makes the faces of the voxels glow; to also light up
+    /// the voxels around them, use `light_emission_mapper`. The emission is added to the
+    /// `emissive` color of the base material.
+    ///
+    /// ```
+    /// # use bevy::prelude::*;
+    /// # use bevy_voxel_world::prelude::*;
+    /// # use std::sync::Arc;
+    /// # let _: EmissionMapperFn =
+    /// Arc::new(|mat| match mat {
+    ///     // Lava glows all over
+    ///     4 => Some(VoxelEmission::new(Color::ORANGE_RED, 2.0)),
+    ///     // Only the bright specks in texture layer 6 of an ore glow
+    ///     5 => Some(VoxelEmission::new(Color::CYAN, 1.0).with_texture(6)),
+    ///     _ => None,
+    /// })
+    /// # ;
+    /// ```
+    fn emission_mapper(&self) -> EmissionMapperFn<Self::Voxel> {
+        Arc::new(|_| None)
+    }
+
+    /// Path to a block registry asset, a `.blocks.ron` file relative to the assets folder. Blocks
+    /// in the registry override the texture, emission and light emission mappers for their
+    /// material ids. See `BlockRegistry` for the file format.
+    ///
+    /// The registry is loaded through the asset server, so it's hot-reloaded when Bevy's asset
+    /// watching is enabled. Chunks using blocks that changed are remeshed. `None` by default.
+    fn block_registry(&self) -> Option<String> {
+        None
+    }
+
     /// A function that returns a function that returns true if a voxel exists at the given position
     /// The delegate will be called every time a new chunk needs to be computed. The delegate should
     /// return a function that can be called to check if a voxel exists at a given position. This function
     /// needs to be thread-safe, since chunk computation happens on a separate thread.
-    fn voxel_lookup_delegate(&self) -> VoxelLookupDelegate {
-        Box::new(|_| Box::new(|_| WorldVoxel::Unset))
+    fn voxel_lookup_delegate(&self) -> VoxelLookupDelegate<Self::Voxel> {
+        Box::new(|_| Box::new(|_| Self::Voxel::default()))
+    }
+
+    /// A function that generates a whole chunk at once, used instead of `voxel_lookup_delegate`.
+    /// It gets a buffer with the voxels of the chunk and a one voxel border around it, which
+    /// suits generators that work on columns or use SIMD noise.
+    ///
+    /// The function returns how the chunk is filled. Chunks reported as `FillType::Empty` or
+    /// `FillType::Uniform` don't need their buffer filled, and skip the per-voxel work when
+    /// nothing in them was modified. Only `FillType::Mixed` chunks use the buffer.
+    ///
+    /// ```
+    /// # use bevy::prelude::*;
+    /// # use bevy_voxel_world::prelude::*;
+    /// # use std::sync::Arc;
+    /// # let _: ChunkGeneratorFn =
+    /// Arc::new(|chunk: &mut PaddedVoxelBuffer| {
+    ///     let min = chunk.world_position(UVec3::ZERO);
+    ///     let max = chunk.world_position(chunk.size() - 1);
+    ///     if min.y >= 0 {
+    ///         return FillType::Empty;
+    ///     }
+    ///     if max.y < 0 {
+    ///         return FillType::Uniform(WorldVoxel::Solid(0));
+    ///     }
+    ///     let (size, scale) = (chunk.size(), 1 << chunk.lod());
+    ///     for (i, voxel) in chunk.voxels_mut().iter_mut().enumerate() {
+    ///         let y = (i as u32 / size.x) % size.y;
+    ///         *voxel = if min.y + y as i32 * scale < 0 {
+    ///             WorldVoxel::Solid(0)
+    ///         } else {
+    ///             WorldVoxel::Air
+    ///         };
+    ///     }
+    ///     FillType::Mixed
+    /// })
+    /// # ;
+    /// ```
+    ///
+    /// Ignored when `generation_pipeline` is set. `None` by default.
+    fn chunk_generator(&self) -> Option<ChunkGeneratorFn<Self::Voxel>> {
+        None
+    }
+
+    /// The biomes of the world. When the world has neither a `generation_pipeline` nor a
+    /// `chunk_generator`, its terrain is generated by the biome layer. Either way, the biome at a
+    /// position can be looked up with `VoxelWorld::get_biome`.
+    ///
+    /// To use the biomes in a pipeline, fill the first stage with `BiomeLayer::fill_stage`.
+    ///
+    /// Only called once, when the world is set up. `None` by default.
+    fn biomes(&self) -> Option<Arc<BiomeLayer<Self::Voxel>>> {
+        None
+    }
+
+    /// Structures placed over the generated terrain, like trees, houses and dungeons. They are
+    /// placed after the rest of the world is generated, whichever way that is.
+    ///
+    /// Only called once, when the world is set up. `None` by default.
+    fn structures(&self) -> Option<Arc<StructureLayer<Self::Voxel>>> {
+        None
+    }
+
+    /// A staged generation pipeline, used instead of `voxel_lookup_delegate` to generate the
+    /// voxels of the world. Stages can read the output of earlier stages in neighbouring chunks,
+    /// which allows for things like caves carved after the terrain, and trees spanning chunk
+    /// borders. See `GenerationPipeline`. `None` by default.
+    ///
+    /// Called once, when the world is set up. Chunks at a lower level of detail are generated at
+    /// full resolution by the pipeline, and then sampled.
+    fn generation_pipeline(&self) -> Option<GenerationPipeline<Self::Voxel>> {
+        None
+    }
+
+    /// A function that maps voxel materials to whether they are affected by gravity, like sand
+    /// and gravel. Such voxels fall as `FallingVoxel` entities when the voxel under them is
+    /// removed, and are put back in the world where they land. Nothing falls by default.
+    fn gravity_mapper(&self) -> GravityMapperFn<Self::Voxel> {
+        Arc::new(|_| false)
+    }
+
+    /// A function that maps voxel materials to whether they anchor the voxels connected to them,
+    /// like bedrock. Groups of voxels connected to neither an anchor nor the ground are found by
+    /// `VoxelWorld::find_floating_voxels`. Nothing is an anchor by default.
+    fn anchor_mapper(&self) -> AnchorMapperFn<Self::Voxel> {
+        Arc::new(|_| false)
+    }
+
+    /// How often the fluid simulation steps. Each step, fluid falls or flows one voxel further,
+    /// in chunks loaded at full detail. With `None`, the simulation only runs when it is stepped
+    /// by hand with `VoxelWorld::step_fluids`. `None` by default.
+    fn fluid_step_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// A function that maps fluid materials to how they flow, see `FluidProperties`
+    fn fluid_properties(&self) -> FluidPropertiesFn<Self::Voxel> {
+        Arc::new(|_| FluidProperties::default())
     }
 
     /// A tuple of the path to the texture and the number of indexes in the texture. `None` if no texture is used.
@@ -119,4 +352,6 @@ pub struct DefaultWorld;
 
 impl DefaultWorld {}
 
-impl VoxelWorldConfig for DefaultWorld {}
+impl VoxelWorldConfig for DefaultWorld {
+    type Voxel = WorldVoxel;
+}
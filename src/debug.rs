@@ -18,12 +18,18 @@ pub struct ChunkAabbGizmo {
 }
 
 fn draw_aabbs<C: Send + Sync + 'static>(
-    query: Query<(&Chunk<C>, &GlobalTransform, &ChunkAabbGizmo)>,
+    query: Query<(&Chunk<C>, &Transform, &GlobalTransform, &ChunkAabbGizmo)>,
     mut gizmos: Gizmos,
 ) {
-    for (chunk, &transform, gizmo) in &query {
+    for (chunk, local_transform, &transform, gizmo) in &query {
         let color = gizmo.color.unwrap_or(Color::WHITE);
-        gizmos.cuboid(aabb_transform(chunk.aabb(), transform), color);
+        // Chunks at a lower level of detail are scaled up, see `Chunk::mesh_transform`
+        let scale = local_transform.scale;
+        let aabb = Aabb::from_min_max(
+            (scale - 1.0) / scale,
+            (chunk.size.as_vec3() + scale - 1.0) / scale,
+        );
+        gizmos.cuboid(aabb_transform(aabb, transform), color);
     }
 }
 
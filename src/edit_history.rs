@@ -0,0 +1,161 @@
+///
+/// Edit history
+/// Undo and redo of voxel edits. Edits made with `VoxelWorld::set_voxel` while a transaction is
+/// open are recorded, and the whole transaction is undone or redone at once.
+///
+use std::collections::VecDeque;
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::{configuration::VoxelWorldConfig, voxel::WorldVoxel};
+
+/// A single recorded voxel edit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VoxelChange<V = WorldVoxel> {
+    pub position: IVec3,
+
+    /// The modified voxel at the position before the edit, or `None` if the position wasn't
+    /// modified before, in which case undoing the edit removes the modification.
+    pub before: Option<V>,
+
+    pub after: V,
+}
+
+/// A named group of voxel edits that are undone and redone together
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VoxelTransaction<V = WorldVoxel> {
+    pub name: String,
+
+    /// The edits of the transaction, in the order they were first made. A position that was set
+    /// several times only has one change, from the value before the first edit to the last value.
+    pub changes: Vec<VoxelChange<V>>,
+}
+
+/// The undo and redo stacks of the world `C`. Use `VoxelWorld::begin_transaction`,
+/// `VoxelWorld::undo` and `VoxelWorld::redo` to use it.
+///
+/// Only edits made while a transaction is open are recorded. The undo stack holds at most
+/// `VoxelWorldConfig::max_undo_steps` transactions; older transactions are dropped.
+#[derive(Resource)]
+pub struct VoxelEditHistory<C: VoxelWorldConfig> {
+    undo: VecDeque<VoxelTransaction<C::Voxel>>,
+    redo: Vec<VoxelTransaction<C::Voxel>>,
+    open: Option<OpenTransaction<C::Voxel>>,
+    max_undo_steps: usize,
+}
+
+struct OpenTransaction<V> {
+    transaction: VoxelTransaction<V>,
+    // Index of the change of each position in `transaction.changes`
+    changed: HashMap<IVec3, usize>,
+}
+
+impl<C: VoxelWorldConfig> VoxelEditHistory<C> {
+    pub fn new(max_undo_steps: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            open: None,
+            max_undo_steps,
+        }
+    }
+
+    /// The transactions that can be undone, oldest first
+    pub fn undo_stack(&self) -> impl Iterator<Item = &VoxelTransaction<C::Voxel>> {
+        self.undo.iter()
+    }
+
+    /// The transactions that can be redone, next one last
+    pub fn redo_stack(&self) -> impl Iterator<Item = &VoxelTransaction<C::Voxel>> {
+        self.redo.iter()
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty() || self.open.is_some()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// The name of the transaction edits are currently recorded in
+    pub fn open_transaction(&self) -> Option<&str> {
+        self.open
+            .as_ref()
+            .map(|open| open.transaction.name.as_str())
+    }
+
+    /// Forget all recorded transactions, including the open one
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.open = None;
+    }
+
+    /// Start recording edits in a new transaction. An already open transaction is committed.
+    pub(crate) fn begin(&mut self, name: String) {
+        self.commit();
+        self.open = Some(OpenTransaction {
+            transaction: VoxelTransaction {
+                name,
+                changes: Vec::new(),
+            },
+            changed: HashMap::new(),
+        });
+    }
+
+    /// Push the open transaction onto the undo stack. Transactions without edits are dropped.
+    pub(crate) fn commit(&mut self) {
+        let Some(open) = self.open.take() else {
+            return;
+        };
+        if open.transaction.changes.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        self.undo.push_back(open.transaction);
+        while self.undo.len() > self.max_undo_steps {
+            self.undo.pop_front();
+        }
+    }
+
+    /// Whether edits are currently being recorded
+    pub(crate) fn is_recording(&self) -> bool {
+        self.open.is_some()
+    }
+
+    /// Record an edit in the open transaction, if there is one
+    pub(crate) fn record(&mut self, position: IVec3, before: Option<C::Voxel>, after: C::Voxel) {
+        let Some(open) = &mut self.open else {
+            return;
+        };
+        let changes = &mut open.transaction.changes;
+        match open.changed.get(&position) {
+            Some(index) => changes[*index].after = after,
+            None => {
+                open.changed.insert(position, changes.len());
+                changes.push(VoxelChange {
+                    position,
+                    before,
+                    after,
+                });
+            }
+        }
+    }
+
+    /// Take the transaction to undo, and move it to the redo stack
+    pub(crate) fn undo(&mut self) -> Option<VoxelTransaction<C::Voxel>> {
+        self.commit();
+        let transaction = self.undo.pop_back()?;
+        self.redo.push(transaction.clone());
+        Some(transaction)
+    }
+
+    /// Take the transaction to redo, and move it back to the undo stack
+    pub(crate) fn redo(&mut self) -> Option<VoxelTransaction<C::Voxel>> {
+        self.commit();
+        let transaction = self.redo.pop()?;
+        self.undo.push_back(transaction.clone());
+        Some(transaction)
+    }
+}
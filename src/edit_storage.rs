@@ -0,0 +1,623 @@
+///
+/// Edit storage
+/// Paged storage of modified voxels, and reading and writing of them to a compact binary
+/// format, so that edits can persist between sessions.
+///
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    chunk::CHUNK_SIZE_U,
+    voxel::{VoxelData, WorldVoxel},
+    voxel_world_internal::get_chunk_voxel_position,
+};
+
+/// The modified voxels of a single chunk, keyed by their world position
+pub type EditPage<V = WorldVoxel> = HashMap<IVec3, V>;
+
+/// Modified voxels, paged by the chunk they belong to.
+///
+/// Pages are reference counted and copy-on-write, so chunk generation can take a cheap snapshot
+/// of the few pages it needs without holding on to a lock on all edits.
+///
+/// Optionally, the pages can be backed by region files on disk. A region is a box of chunks,
+/// `region_size` chunks wide, stored in a single file. Regions are then loaded when they come
+/// close to the camera, and written back to disk and evicted from memory when they are far away.
+/// Regions can also be requested for voxel lookups far from the camera. They are then loaded on
+/// the next `stream_regions` pass, and kept in memory for a while, see
+/// [`REQUESTED_REGION_PASSES`].
+pub struct EditPages<V = WorldVoxel> {
+    chunk_size: UVec3,
+    pages: HashMap<IVec3, Arc<EditPage<V>>>,
+    region_files: Option<RegionFiles>,
+}
+
+struct RegionFiles {
+    directory: PathBuf,
+    region_size: UVec3,
+    /// The regions currently in memory, and whether they have changed since they were loaded
+    loaded: HashMap<IVec3, bool>,
+    /// Regions whose file couldn't be read. Their edits stay in memory, and their files are
+    /// never written, so the edits on disk aren't lost.
+    failed: HashSet<IVec3>,
+    /// Regions requested by voxel lookups, to be loaded by the next `stream_regions` pass
+    requested: Mutex<HashSet<IVec3>>,
+    /// Regions loaded because they were requested, and the number of `stream_regions` passes
+    /// they are kept in memory for
+    pinned: HashMap<IVec3, u32>,
+}
+
+/// The number of `stream_regions` passes a region loaded for a voxel lookup is kept in memory
+/// for, even when it is far from the camera. Regions are streamed every frame, so this is about
+/// five seconds at 60 frames per second.
+pub const REQUESTED_REGION_PASSES: u32 = 300;
+
+impl<V: VoxelData> EditPages<V> {
+    /// Create an in-memory storage for chunks of the given size
+    pub fn new(chunk_size: UVec3) -> Self {
+        Self {
+            chunk_size,
+            pages: HashMap::new(),
+            region_files: None,
+        }
+    }
+
+    /// Create a storage for chunks of the given size that is backed by region files in `directory`.
+    /// Each region file holds the edits of `region_size` chunks.
+    pub fn with_region_files(
+        chunk_size: UVec3,
+        directory: impl Into<PathBuf>,
+        region_size: UVec3,
+    ) -> Self {
+        Self {
+            chunk_size,
+            pages: HashMap::new(),
+            region_files: Some(RegionFiles {
+                directory: directory.into(),
+                region_size: region_size.max(UVec3::ONE),
+                loaded: HashMap::new(),
+                failed: HashSet::new(),
+                requested: Mutex::new(HashSet::new()),
+                pinned: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn chunk_size(&self) -> UVec3 {
+        self.chunk_size
+    }
+
+    /// Get the modified voxel at the given position, if there is one in memory
+    pub fn get(&self, position: &IVec3) -> Option<V> {
+        let (chunk_pos, _) = get_chunk_voxel_position(*position, self.chunk_size);
+        self.pages.get(&chunk_pos)?.get(position).copied()
+    }
+
+    /// Set a modified voxel. If the voxel belongs to a region that is not loaded yet, the region
+    /// is loaded first so the edit doesn't get lost when the region is later loaded from disk.
+    pub fn insert(&mut self, position: IVec3, voxel: V) {
+        let (chunk_pos, _) = get_chunk_voxel_position(position, self.chunk_size);
+        self.ensure_region_loaded(chunk_pos, true);
+        Arc::make_mut(self.pages.entry(chunk_pos).or_default()).insert(position, voxel);
+    }
+
+    /// Remove a modified voxel, so the voxel is generated by the voxel lookup delegate again.
+    /// Returns the removed voxel.
+    pub fn remove(&mut self, position: &IVec3) -> Option<V> {
+        let (chunk_pos, _) = get_chunk_voxel_position(*position, self.chunk_size);
+        self.ensure_region_loaded(chunk_pos, true);
+        let page = self.pages.get_mut(&chunk_pos)?;
+        let voxel = Arc::make_mut(page).remove(position);
+        if page.is_empty() {
+            self.pages.remove(&chunk_pos);
+        }
+        voxel
+    }
+
+    /// Whether the modified voxels of the chunk at `chunk_pos` are in memory. Always true when
+    /// there are no region files.
+    pub fn is_chunk_loaded(&self, chunk_pos: IVec3) -> bool {
+        let Some(region_files) = &self.region_files else {
+            return true;
+        };
+        let region = chunk_pos.div_euclid(region_files.region_size.as_ivec3());
+        region_files.loaded.contains_key(&region)
+    }
+
+    /// Request the region holding the chunk at `chunk_pos` to be loaded by the next
+    /// `stream_regions` pass, if it isn't in memory. The region is then kept in memory for
+    /// [`REQUESTED_REGION_PASSES`] passes. Does nothing if there are no region files.
+    pub fn request_chunk(&self, chunk_pos: IVec3) {
+        let Some(region_files) = &self.region_files else {
+            return;
+        };
+        let region = chunk_pos.div_euclid(region_files.region_size.as_ivec3());
+        if !region_files.loaded.contains_key(&region) && !region_files.failed.contains(&region) {
+            region_files.requested.lock().unwrap().insert(region);
+        }
+    }
+
+    /// Get the page of modified voxels for the given chunk
+    pub fn page(&self, chunk_pos: &IVec3) -> Option<&Arc<EditPage<V>>> {
+        self.pages.get(chunk_pos)
+    }
+
+    /// Get a snapshot of the pages needed to generate the chunk at `chunk_pos`. Besides the page of
+    /// the chunk itself, this includes the pages of its neighbours, for the 1-voxel padding.
+    pub fn chunk_edits(&self, chunk_pos: IVec3) -> ChunkEdits<V> {
+        let mut pages = Vec::new();
+        if let Some(page) = self.pages.get(&chunk_pos) {
+            pages.push((chunk_pos, page.clone()));
+        }
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    let neighbour_pos = chunk_pos + IVec3::new(x, y, z);
+                    if neighbour_pos == chunk_pos {
+                        continue;
+                    }
+                    if let Some(page) = self.pages.get(&neighbour_pos) {
+                        pages.push((neighbour_pos, page.clone()));
+                    }
+                }
+            }
+        }
+        ChunkEdits {
+            chunk_size: self.chunk_size,
+            pages,
+        }
+    }
+
+    /// Iterate all modified voxels currently in memory
+    pub fn iter(&self) -> impl Iterator<Item = (&IVec3, &V)> {
+        self.pages.values().flat_map(|page| page.iter())
+    }
+
+    /// Collect all modified voxels currently in memory into a single map
+    pub fn to_map(&self) -> HashMap<IVec3, V> {
+        self.iter().map(|(pos, voxel)| (*pos, *voxel)).collect()
+    }
+
+    /// Load the region files close to `center_chunk` and the requested regions, and write back
+    /// and evict regions that are far away. Distances are given in chunks. Does nothing if there
+    /// are no region files.
+    pub fn stream_regions(&mut self, center_chunk: IVec3, load_distance: u32) {
+        let Some(region_files) = &mut self.region_files else {
+            return;
+        };
+        region_files.pinned.retain(|_, passes| {
+            *passes -= 1;
+            *passes > 0
+        });
+        let requested = std::mem::take(region_files.requested.get_mut().unwrap());
+
+        let region_size = region_files.region_size.as_ivec3();
+        let center_region = center_chunk.div_euclid(region_size);
+        let load_radius = (UVec3::splat(load_distance).as_ivec3() + region_size - 1) / region_size;
+
+        let far_away: Vec<IVec3> = region_files
+            .loaded
+            .keys()
+            .filter(|region| {
+                // One extra region of slack, so regions don't get loaded and evicted repeatedly
+                // when the camera moves back and forth over a region border
+                ((**region - center_region).abs() - load_radius)
+                    .cmpgt(IVec3::ONE)
+                    .any()
+                    && !region_files.pinned.contains_key(*region)
+            })
+            .copied()
+            .collect();
+        for region in far_away {
+            if let Err(err) = self.evict_region(region) {
+                error!("Failed to write voxel region {region}: {err}");
+            }
+        }
+
+        for x in -load_radius.x..=load_radius.x {
+            for y in -load_radius.y..=load_radius.y {
+                for z in -load_radius.z..=load_radius.z {
+                    let region = center_region + IVec3::new(x, y, z);
+                    self.ensure_region_loaded(region * region_size, false);
+                }
+            }
+        }
+
+        for region in requested {
+            self.ensure_region_loaded(region * region_size, false);
+            if let Some(region_files) = &mut self.region_files {
+                if region_files.loaded.contains_key(&region) {
+                    region_files.pinned.insert(region, REQUESTED_REGION_PASSES);
+                }
+            }
+        }
+    }
+
+    /// Write all regions that have changed since they were loaded to disk. Does nothing if there
+    /// are no region files.
+    pub fn flush_regions(&mut self) -> io::Result<()> {
+        let Some(region_files) = &self.region_files else {
+            return Ok(());
+        };
+        let dirty: Vec<IVec3> = region_files
+            .loaded
+            .iter()
+            .filter(|(_, dirty)| **dirty)
+            .map(|(region, _)| *region)
+            .collect();
+        for region in dirty {
+            self.write_region(region)?;
+            if let Some(region_files) = &mut self.region_files {
+                region_files.loaded.insert(region, false);
+            }
+        }
+        Ok(())
+    }
+
+    /// Collect all modified voxels into a single map, including the ones in region files that
+    /// aren't in memory. Changed regions are written to disk first. Fails if a region file can't
+    /// be read, so no edits are silently left out.
+    pub fn collect_all(&mut self) -> io::Result<HashMap<IVec3, V>> {
+        self.flush_regions()?;
+        let mut voxels = HashMap::new();
+        if let Some(region_files) = &self.region_files {
+            let entries = match std::fs::read_dir(&region_files.directory) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(self.to_map()),
+                Err(err) => return Err(err),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let file_name = entry.file_name();
+                let Some(region) = file_name.to_str().and_then(parse_region_file_name) else {
+                    continue;
+                };
+                if !region_files.loaded.contains_key(&region) {
+                    voxels.extend(load_voxels::<V>(entry.path())?);
+                }
+            }
+        }
+        voxels.extend(self.iter().map(|(pos, voxel)| (*pos, *voxel)));
+        Ok(voxels)
+    }
+
+    /// Load the modified voxels of the region holding `chunk_pos` from its region file, if the
+    /// region isn't in memory yet. If the file can't be read, the region is marked as failed
+    /// and its file is never written.
+    fn ensure_region_loaded(&mut self, chunk_pos: IVec3, mark_dirty: bool) {
+        let Some(region_files) = &mut self.region_files else {
+            return;
+        };
+        let region = chunk_pos.div_euclid(region_files.region_size.as_ivec3());
+
+        if let Some(dirty) = region_files.loaded.get_mut(&region) {
+            *dirty |= mark_dirty;
+            return;
+        }
+        if region_files.failed.contains(&region) {
+            return;
+        }
+
+        let path = region_files.directory.join(region_file_name(region));
+        let voxels = match load_voxels(&path) {
+            Ok(voxels) => voxels,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                error!(
+                    "Failed to read voxel region file {path:?}, edits in the region won't be \
+                     saved: {err}"
+                );
+                region_files.failed.insert(region);
+                return;
+            }
+        };
+        region_files.loaded.insert(region, mark_dirty);
+
+        for (position, voxel) in voxels {
+            let (chunk_pos, _) = get_chunk_voxel_position(position, self.chunk_size);
+            Arc::make_mut(self.pages.entry(chunk_pos).or_default()).insert(position, voxel);
+        }
+    }
+
+    fn evict_region(&mut self, region: IVec3) -> io::Result<()> {
+        let Some(region_files) = &self.region_files else {
+            return Ok(());
+        };
+        if region_files.loaded.get(&region) == Some(&true) {
+            self.write_region(region)?;
+        }
+
+        let region_size = region_files.region_size.as_ivec3();
+        self.pages
+            .retain(|chunk_pos, _| chunk_pos.div_euclid(region_size) != region);
+        if let Some(region_files) = &mut self.region_files {
+            region_files.loaded.remove(&region);
+        }
+        Ok(())
+    }
+
+    fn write_region(&self, region: IVec3) -> io::Result<()> {
+        let Some(region_files) = &self.region_files else {
+            return Ok(());
+        };
+        let region_size = region_files.region_size.as_ivec3();
+        let voxels: HashMap<IVec3, V> = self
+            .pages
+            .iter()
+            .filter(|(chunk_pos, _)| chunk_pos.div_euclid(region_size) == region)
+            .flat_map(|(_, page)| page.iter().map(|(pos, voxel)| (*pos, *voxel)))
+            .collect();
+
+        std::fs::create_dir_all(&region_files.directory)?;
+        save_voxels(
+            region_files.directory.join(region_file_name(region)),
+            &voxels,
+            self.chunk_size,
+        )
+    }
+}
+
+impl<V: VoxelData> Default for EditPages<V> {
+    fn default() -> Self {
+        Self::new(UVec3::splat(CHUNK_SIZE_U))
+    }
+}
+
+/// A snapshot of the edit pages needed to generate a single chunk
+#[derive(Clone)]
+pub struct ChunkEdits<V = WorldVoxel> {
+    chunk_size: UVec3,
+    pages: Vec<(IVec3, Arc<EditPage<V>>)>,
+}
+
+impl<V> Default for ChunkEdits<V> {
+    fn default() -> Self {
+        Self {
+            chunk_size: UVec3::ZERO,
+            pages: Vec::new(),
+        }
+    }
+}
+
+impl<V: VoxelData> ChunkEdits<V> {
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Get the modified voxel at the given position
+    pub fn get(&self, position: &IVec3) -> Option<V> {
+        if self.pages.is_empty() {
+            return None;
+        }
+        let (chunk_pos, _) = get_chunk_voxel_position(*position, self.chunk_size);
+        self.pages
+            .iter()
+            .find(|(page_pos, _)| *page_pos == chunk_pos)
+            .and_then(|(_, page)| page.get(position).copied())
+    }
+}
+
+/// Get the file name of the region file for the given region
+pub fn region_file_name(region: IVec3) -> String {
+    format!("r.{}.{}.{}.bvw", region.x, region.y, region.z)
+}
+
+/// Get the region of a region file from its file name, the reverse of [`region_file_name`]
+fn parse_region_file_name(file_name: &str) -> Option<IVec3> {
+    let mut coords = file_name
+        .strip_prefix("r.")?
+        .strip_suffix(".bvw")?
+        .split('.')
+        .map(|coord| coord.parse().ok());
+    let region = IVec3::new(coords.next()??, coords.next()??, coords.next()??);
+    coords.next().is_none().then_some(region)
+}
+
+/// Magic bytes at the start of every edit file
+pub const EDIT_FILE_MAGIC: [u8; 4] = *b"BVWE";
+
+/// Current version of the edit file format. Bumped whenever the layout changes.
+pub const EDIT_FILE_VERSION: u16 = 2;
+
+/// Write a set of modified voxels to `writer`.
+///
+/// The voxels are grouped by the chunk they belong to, using the given `chunk_size`. Layout:
+///
+/// ```text
+/// magic "BVWE" | version: u16 | material id width in bytes: u8 | chunk size: 3 x u32 |
+/// chunk count: varint
+/// per chunk:  chunk position: 3 x i32 | voxel count: varint
+/// per voxel:  index within chunk: varint | voxel data, as written by `VoxelData::write_to`
+/// ```
+///
+/// All fixed size integers are little endian. The material id width is the size of
+/// `VoxelData::Material`, so edits aren't read back with a voxel type of another width.
+pub fn write_voxels<V: VoxelData, W: Write>(
+    mut writer: W,
+    voxels: &HashMap<IVec3, V>,
+    chunk_size: UVec3,
+) -> io::Result<()> {
+    let mut chunks = HashMap::<IVec3, Vec<(u32, V)>>::new();
+    for (position, voxel) in voxels.iter() {
+        let (chunk_pos, vox_pos) = get_chunk_voxel_position(*position, chunk_size);
+        let local = vox_pos - 1;
+        let index = local.x + local.y * chunk_size.x + local.z * chunk_size.x * chunk_size.y;
+        chunks.entry(chunk_pos).or_default().push((index, *voxel));
+    }
+
+    // Sort everything so that the same set of edits always produces the same file
+    let mut chunks: Vec<_> = chunks.into_iter().collect();
+    chunks.sort_unstable_by_key(|(pos, _)| pos.to_array());
+
+    writer.write_all(&EDIT_FILE_MAGIC)?;
+    writer.write_all(&EDIT_FILE_VERSION.to_le_bytes())?;
+    writer.write_all(&[material_id_width::<V>()])?;
+    for size in chunk_size.to_array() {
+        writer.write_all(&size.to_le_bytes())?;
+    }
+    write_varint(&mut writer, chunks.len() as u64)?;
+
+    for (chunk_pos, mut chunk_voxels) in chunks {
+        chunk_voxels.sort_unstable_by_key(|(index, _)| *index);
+
+        for coord in chunk_pos.to_array() {
+            writer.write_all(&coord.to_le_bytes())?;
+        }
+        write_varint(&mut writer, chunk_voxels.len() as u64)?;
+
+        for (index, voxel) in chunk_voxels {
+            write_varint(&mut writer, index as u64)?;
+            voxel.write_to(&mut writer)?;
+        }
+    }
+
+    writer.flush()
+}
+
+/// Read a set of modified voxels previously written by [`write_voxels`].
+pub fn read_voxels<V: VoxelData, R: Read>(mut reader: R) -> io::Result<HashMap<IVec3, V>> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != EDIT_FILE_MAGIC {
+        return Err(invalid_data("not a voxel edit file"));
+    }
+
+    let version = u16::from_le_bytes(read_array(&mut reader)?);
+    if version != EDIT_FILE_VERSION {
+        return Err(invalid_data(format!(
+            "unsupported edit file version {version}"
+        )));
+    }
+
+    let [width] = read_array(&mut reader)?;
+    if width != material_id_width::<V>() {
+        return Err(invalid_data(format!(
+            "edit file has {width} byte material ids, expected {}",
+            material_id_width::<V>()
+        )));
+    }
+
+    let chunk_size = UVec3::new(
+        u32::from_le_bytes(read_array(&mut reader)?),
+        u32::from_le_bytes(read_array(&mut reader)?),
+        u32::from_le_bytes(read_array(&mut reader)?),
+    );
+    if chunk_size.cmpeq(UVec3::ZERO).any() {
+        return Err(invalid_data("invalid chunk size"));
+    }
+    let layer_size = chunk_size
+        .x
+        .checked_mul(chunk_size.y)
+        .ok_or_else(|| invalid_data("invalid chunk size"))?;
+    let chunk_volume = layer_size as u64 * chunk_size.z as u64;
+
+    let chunk_count = read_varint(&mut reader)?;
+    let mut voxels = HashMap::new();
+
+    for _ in 0..chunk_count {
+        let chunk_pos = IVec3::new(
+            i32::from_le_bytes(read_array(&mut reader)?),
+            i32::from_le_bytes(read_array(&mut reader)?),
+            i32::from_le_bytes(read_array(&mut reader)?),
+        );
+
+        let voxel_count = read_varint(&mut reader)?;
+        for _ in 0..voxel_count {
+            let index = read_varint(&mut reader)?;
+            if index >= chunk_volume {
+                return Err(invalid_data("voxel index out of chunk bounds"));
+            }
+            let local = UVec3::new(
+                (index % chunk_size.x as u64) as u32,
+                (index / chunk_size.x as u64 % chunk_size.y as u64) as u32,
+                (index / layer_size as u64) as u32,
+            );
+            let position = voxel_position(chunk_pos, chunk_size, local)
+                .ok_or_else(|| invalid_data("voxel position out of range"))?;
+            let voxel = V::read_from(&mut reader)?;
+            voxels.insert(position, voxel);
+        }
+    }
+
+    Ok(voxels)
+}
+
+/// The world position of the voxel at `local` in the given chunk, or `None` if it doesn't fit
+/// in an `IVec3`
+fn voxel_position(chunk_pos: IVec3, chunk_size: UVec3, local: UVec3) -> Option<IVec3> {
+    let coord = |chunk_pos: i32, size: u32, local: u32| {
+        chunk_pos
+            .checked_mul(i32::try_from(size).ok()?)?
+            .checked_add(i32::try_from(local).ok()?)
+    };
+    Some(IVec3::new(
+        coord(chunk_pos.x, chunk_size.x, local.x)?,
+        coord(chunk_pos.y, chunk_size.y, local.y)?,
+        coord(chunk_pos.z, chunk_size.z, local.z)?,
+    ))
+}
+
+/// The width in bytes of the material ids of the voxel type `V`
+fn material_id_width<V: VoxelData>() -> u8 {
+    std::mem::size_of::<V::Material>() as u8
+}
+
+/// Save a set of modified voxels to a file at `path`. See [`write_voxels`] for the format.
+pub fn save_voxels<V: VoxelData>(
+    path: impl AsRef<Path>,
+    voxels: &HashMap<IVec3, V>,
+    chunk_size: UVec3,
+) -> io::Result<()> {
+    let file = File::create(path)?;
+    write_voxels(BufWriter::new(file), voxels, chunk_size)
+}
+
+/// Load a set of modified voxels from a file at `path`. See [`write_voxels`] for the format.
+pub fn load_voxels<V: VoxelData>(path: impl AsRef<Path>) -> io::Result<HashMap<IVec3, V>> {
+    let file = File::open(path)?;
+    read_voxels(BufReader::new(file))
+}
+
+/// Write an unsigned LEB128 encoded integer
+pub(crate) fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return writer.write_all(&[byte]);
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+/// Read an unsigned LEB128 encoded integer
+pub(crate) fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let [byte] = read_array(reader)?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("varint is too long"))
+}
+
+pub(crate) fn read_array<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub(crate) fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
@@ -0,0 +1,245 @@
+///
+/// Falling voxels
+/// Voxels of materials affected by gravity, like sand and gravel, fall when the voxel under them
+/// is removed. They fall as entities, and are put back in the world where they land.
+///
+use std::{marker::PhantomData, sync::Arc};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    block_registry::{BlockRegistryHandle, MaterialMappers},
+    configuration::{GravityMapperFn, VoxelWorldConfig},
+    mesh_cache::{ChunkMeshes, MeshRef},
+    meshing::generate_voxel_mesh,
+    voxel::VoxelData,
+    voxel_world::VoxelWorld,
+    voxel_world_internal::{NeedsMaterial, WorldRoot},
+};
+
+const GRAVITY: f32 = 30.0;
+const MAX_FALL_SPEED: f32 = 50.0;
+
+/// A voxel falling through the world, after the voxel under it was removed. The translation of
+/// the entity is the minimum corner of the voxel. When it lands, the voxel is put back in the
+/// world and the entity is despawned.
+#[derive(Component)]
+pub struct FallingVoxel<C: VoxelWorldConfig> {
+    pub voxel: C::Voxel,
+
+    /// Where the voxel started falling from
+    pub origin: IVec3,
+
+    /// The speed the voxel is falling at
+    pub velocity: f32,
+}
+
+impl<C: VoxelWorldConfig> FallingVoxel<C> {
+    pub fn new(voxel: C::Voxel, origin: IVec3) -> Self {
+        Self {
+            voxel,
+            origin,
+            velocity: 0.0,
+        }
+    }
+}
+
+/// Fired when a voxel starts falling. The voxel has been removed from `position`, and `entity` is
+/// its `FallingVoxel` entity.
+#[derive(Event)]
+pub struct VoxelWillFall<C: VoxelWorldConfig> {
+    pub position: IVec3,
+    pub voxel: C::Voxel,
+    pub entity: Entity,
+}
+
+impl<C: VoxelWorldConfig> VoxelWillFall<C> {
+    pub fn new(position: IVec3, voxel: C::Voxel, entity: Entity) -> Self {
+        Self {
+            position,
+            voxel,
+            entity,
+        }
+    }
+}
+
+/// Fired when a falling voxel lands. The voxel is put back in the world at `position`, and
+/// `entity` is despawned.
+#[derive(Event)]
+pub struct VoxelWillLand<C: VoxelWorldConfig> {
+    pub position: IVec3,
+    pub voxel: C::Voxel,
+    pub entity: Entity,
+}
+
+impl<C: VoxelWorldConfig> VoxelWillLand<C> {
+    pub fn new(position: IVec3, voxel: C::Voxel, entity: Entity) -> Self {
+        Self {
+            position,
+            voxel,
+            entity,
+        }
+    }
+}
+
+/// The positions where a voxel may have lost the voxel under it
+#[derive(Resource)]
+pub struct SupportChecks<C: VoxelWorldConfig> {
+    pending: HashSet<IVec3>,
+    falls: GravityMapperFn<C::Voxel>,
+}
+
+impl<C: VoxelWorldConfig> SupportChecks<C> {
+    pub fn from_config(configuration: &C) -> Self {
+        Self {
+            pending: HashSet::new(),
+            falls: configuration.gravity_mapper(),
+        }
+    }
+
+    /// Check a changed position, and the voxel on top of it
+    pub fn schedule_around(&mut self, position: IVec3) {
+        self.pending.insert(position);
+        self.pending.insert(position + IVec3::Y);
+    }
+
+    /// Take the voxels affected by gravity that have nothing under them. Positions above chunks
+    /// that aren't loaded are checked again later.
+    pub(crate) fn take_unsupported(
+        &mut self,
+        get_voxel: &dyn Fn(IVec3) -> C::Voxel,
+        is_loaded: impl Fn(IVec3) -> bool,
+    ) -> Vec<(IVec3, C::Voxel)> {
+        let checked: Vec<IVec3> = self
+            .pending
+            .iter()
+            .copied()
+            .filter(|position| is_loaded(*position - IVec3::Y))
+            .collect();
+
+        let mut unsupported = Vec::new();
+        for position in checked {
+            self.pending.remove(&position);
+            let voxel = get_voxel(position);
+            let falls = voxel
+                .material()
+                .is_some_and(|material| (self.falls)(material));
+            if falls && !supports(&get_voxel(position - IVec3::Y)) {
+                unsupported.push((position, voxel));
+            }
+        }
+        unsupported.sort_by_key(|(position, _)| (position.y, position.z, position.x));
+        unsupported
+    }
+}
+
+/// Whether a voxel holds up the voxel on top of it. Voxels fall through empty voxels and fluids.
+pub(crate) fn supports<V: VoxelData>(voxel: &V) -> bool {
+    voxel.is_solid() && voxel.fluid_level().is_none()
+}
+
+/// Turns the voxels that lost their support into `FallingVoxel` entities
+pub(crate) fn drop_unsupported_voxels<C: VoxelWorldConfig>(
+    mut commands: Commands,
+    mut voxel_world: VoxelWorld<C>,
+    world_root: Query<Entity, With<WorldRoot<C>>>,
+    mut ev_voxel_will_fall: EventWriter<VoxelWillFall<C>>,
+) {
+    let Ok(world_root) = world_root.get_single() else {
+        return;
+    };
+
+    for (position, voxel) in voxel_world.take_unsupported_voxels() {
+        let transform = Transform::from_translation(position.as_vec3());
+        let entity = commands
+            .spawn((
+                FallingVoxel::<C>::new(voxel, position),
+                SpatialBundle::from_transform(transform),
+            ))
+            .set_parent(world_root)
+            .id();
+        ev_voxel_will_fall.send(VoxelWillFall::new(position, voxel, entity));
+    }
+}
+
+/// Moves the falling voxels down, and puts them back in the world where they land
+pub(crate) fn update_falling_voxels<C: VoxelWorldConfig>(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut voxel_world: VoxelWorld<C>,
+    mut falling_voxels: Query<(Entity, &mut FallingVoxel<C>, &mut Transform)>,
+    mut ev_voxel_will_land: EventWriter<VoxelWillLand<C>>,
+) {
+    let dt = time.delta_seconds();
+    if dt == 0.0 || falling_voxels.is_empty() {
+        return;
+    }
+
+    let get_voxel = voxel_world.get_voxel_fn();
+    for (entity, mut falling_voxel, mut transform) in falling_voxels.iter_mut() {
+        falling_voxel.velocity = (falling_voxel.velocity + GRAVITY * dt).min(MAX_FALL_SPEED);
+        let column = transform.translation.floor().as_ivec3();
+        let from = transform.translation.y;
+        let to = from - falling_voxel.velocity * dt;
+
+        // The highest position the voxel passes where it can come to rest
+        let rest = (to.ceil() as i32..=from.floor() as i32).rev().find(|y| {
+            let below = IVec3::new(column.x, y - 1, column.z);
+            !voxel_world.is_loaded(below) || supports(&get_voxel(below))
+        });
+        let Some(y) = rest else {
+            transform.translation.y = to;
+            continue;
+        };
+
+        let position = IVec3::new(column.x, y, column.z);
+        if !voxel_world.is_loaded(position - IVec3::Y) {
+            // Wait above chunks that aren't loaded, instead of falling through them
+            transform.translation.y = y as f32;
+            falling_voxel.velocity = 0.0;
+            continue;
+        }
+        voxel_world.land_voxel(position, falling_voxel.voxel);
+        ev_voxel_will_land.send(VoxelWillLand::new(position, falling_voxel.voxel, entity));
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+/// Gives falling voxels a mesh. Meshes are shared between falling voxels of the same kind.
+pub(crate) fn mesh_falling_voxels<C: VoxelWorldConfig>(
+    mut commands: Commands,
+    falling_voxels: Query<(Entity, &FallingVoxel<C>), Without<MeshRef>>,
+    mut mesh_assets: ResMut<Assets<Mesh>>,
+    mut meshes: Local<HashMap<C::Voxel, Arc<ChunkMeshes>>>,
+    block_registry: Option<Res<BlockRegistryHandle<C>>>,
+    configuration: Res<C>,
+) {
+    if falling_voxels.is_empty() {
+        return;
+    }
+
+    let mappers = MaterialMappers::new(
+        configuration.as_ref(),
+        block_registry.as_ref().and_then(|handle| handle.registry()),
+    );
+    for (entity, falling_voxel) in falling_voxels.iter() {
+        let meshes = meshes.entry(falling_voxel.voxel).or_insert_with(|| {
+            let (opaque, translucent) = generate_voxel_mesh(
+                UVec3::ONE,
+                |_| falling_voxel.voxel,
+                mappers.texture_index.clone(),
+                mappers.emission.clone(),
+            );
+            Arc::new(ChunkMeshes {
+                opaque: mesh_assets.add(opaque),
+                translucent: translucent.map(|mesh| mesh_assets.add(mesh)),
+            })
+        });
+        commands
+            .entity(entity)
+            .try_insert((MeshRef(meshes.clone()), NeedsMaterial::<C>(PhantomData)));
+    }
+}
@@ -0,0 +1,320 @@
+///
+/// Fluids
+/// A cellular automaton spreading fluid voxels like water and lava. Fluid falls down first, and
+/// flows sideways from sources with a level that drops with every voxel. Only positions near a
+/// change are updated, and only in chunks loaded at full detail.
+///
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    configuration::{FluidPropertiesFn, VoxelWorldConfig},
+    voxel::{FluidLevel, VoxelData},
+    voxel_world::VoxelWorld,
+};
+
+const HORIZONTAL: [IVec3; 4] = [IVec3::X, IVec3::NEG_X, IVec3::Z, IVec3::NEG_Z];
+
+/// How the fluid of a material flows, see `VoxelWorldConfig::fluid_properties`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FluidProperties {
+    /// How much the level drops for every voxel the fluid flows sideways. With a drop of 1, fluid
+    /// flows 7 voxels away from its source, and with a drop of 2, 3 voxels.
+    pub level_drop: u8,
+
+    /// The fluid only moves every this many steps of the simulation, so thick fluids like lava
+    /// can flow slower than water
+    pub steps_per_move: u32,
+
+    /// Whether fluid between two sources, on top of solid ground or another source, becomes a
+    /// source too
+    pub infinite: bool,
+}
+
+impl Default for FluidProperties {
+    fn default() -> Self {
+        Self {
+            level_drop: 1,
+            steps_per_move: 1,
+            infinite: false,
+        }
+    }
+}
+
+/// The positions waiting for a fluid update, grouped by chunk
+#[derive(Resource)]
+pub struct FluidSimulation<C: VoxelWorldConfig> {
+    pending: HashMap<IVec3, HashSet<IVec3>>,
+    /// Chunks generated at full detail without any fluid, so edits in them don't need to look
+    /// for fluid around them
+    dry_chunks: HashSet<IVec3>,
+    chunk_size: UVec3,
+    properties: FluidPropertiesFn<C::Voxel>,
+    step: u64,
+    _marker: PhantomData<C>,
+}
+
+impl<C: VoxelWorldConfig> FluidSimulation<C> {
+    pub fn from_config(configuration: &C) -> Self {
+        Self {
+            pending: HashMap::new(),
+            dry_chunks: HashSet::new(),
+            chunk_size: configuration.chunk_size(),
+            properties: configuration.fluid_properties(),
+            step: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Update a changed position in the next step, along with every position whose fluid can
+    /// depend on it. The voxel at the position might be fluid now, so its chunk is no longer
+    /// known to be dry.
+    pub fn schedule_around(&mut self, position: IVec3) {
+        self.dry_chunks
+            .remove(&position.div_euclid(self.chunk_size.as_ivec3()));
+        for position in dependents(position) {
+            self.schedule(position);
+        }
+    }
+
+    /// Like `schedule_around`, but only if the edit can make fluid move: when fluid is placed,
+    /// or the edited position or one that depends on it holds fluid. `get_voxel` returns the
+    /// voxels before the edit. It is only called when the edit is next to a chunk that isn't
+    /// known to be dry, so edits in worlds without fluid don't look up any voxels.
+    pub(crate) fn schedule_edit(
+        &mut self,
+        get_voxel: &dyn Fn(IVec3) -> C::Voxel,
+        position: IVec3,
+        voxel: &C::Voxel,
+    ) {
+        let near_fluid = voxel.fluid_level().is_some()
+            || (!self.is_dry_around(position)
+                && dependents(position)
+                    .any(|position| get_voxel(position).fluid_level().is_some()));
+        if near_fluid {
+            self.schedule_around(position);
+        }
+    }
+
+    /// Called when the voxels of a chunk were generated. A chunk at full detail without fluid
+    /// is known to be dry until fluid is placed in it.
+    pub(crate) fn chunk_generated(&mut self, chunk_pos: IVec3, lod: u8, has_fluid: bool) {
+        if lod == 0 && !has_fluid {
+            self.dry_chunks.insert(chunk_pos);
+        } else {
+            self.dry_chunks.remove(&chunk_pos);
+        }
+    }
+
+    /// Forget the pending positions in a chunk, like when it despawns
+    pub(crate) fn remove_chunk(&mut self, chunk_pos: IVec3) {
+        self.pending.remove(&chunk_pos);
+        self.dry_chunks.remove(&chunk_pos);
+    }
+
+    /// Whether the chunks of a position and of every position depending on it are known to be
+    /// dry
+    fn is_dry_around(&self, position: IVec3) -> bool {
+        let chunk_size = self.chunk_size.as_ivec3();
+        let first = (position - 1).div_euclid(chunk_size);
+        let last = (position + 1).div_euclid(chunk_size);
+        (first.z..=last.z).all(|z| {
+            (first.y..=last.y)
+                .all(|y| (first.x..=last.x).all(|x| self.dry_chunks.contains(&IVec3::new(x, y, z))))
+        })
+    }
+
+    /// The number of positions waiting for an update
+    pub fn pending(&self) -> usize {
+        self.pending.values().map(|positions| positions.len()).sum()
+    }
+
+    /// Work out one step of the simulation for the pending positions in active chunks. All
+    /// positions are updated from the voxels before the step, so the order they are updated in
+    /// doesn't matter. Positions waiting for a slow fluid or an inactive neighbouring chunk stay
+    /// pending.
+    pub(crate) fn step(
+        &mut self,
+        get_voxel: &dyn Fn(IVec3) -> C::Voxel,
+        mut is_active: impl FnMut(IVec3) -> bool,
+    ) -> Vec<(IVec3, C::Voxel)> {
+        self.step += 1;
+        let chunk_size = self.chunk_size.as_ivec3();
+        let active_chunks: Vec<IVec3> = self
+            .pending
+            .keys()
+            .copied()
+            .filter(|chunk| is_active(*chunk))
+            .collect();
+
+        let mut changes = Vec::new();
+        let mut waiting = Vec::new();
+        for chunk in active_chunks {
+            for position in self.pending.remove(&chunk).unwrap_or_default() {
+                // Positions on the border of the chunk read the voxels of its neighbours
+                let first = (position - 1).div_euclid(chunk_size);
+                let last = (position + 1).div_euclid(chunk_size);
+                let mut neighbours_active = true;
+                for z in first.z..=last.z {
+                    for y in first.y..=last.y {
+                        for x in first.x..=last.x {
+                            neighbours_active &= is_active(IVec3::new(x, y, z));
+                        }
+                    }
+                }
+                if !neighbours_active {
+                    waiting.push(position);
+                    continue;
+                }
+
+                let Some(next) = next_voxel(get_voxel, position, &self.properties) else {
+                    continue;
+                };
+                let fluid = match next.fluid_level() {
+                    Some(_) => next,
+                    None => get_voxel(position),
+                };
+                let steps_per_move = fluid_properties(&self.properties, &fluid).steps_per_move;
+                if self.step.rem_euclid(steps_per_move.max(1) as u64) == 0 {
+                    changes.push((position, next));
+                } else {
+                    waiting.push(position);
+                }
+            }
+        }
+
+        for position in waiting {
+            self.schedule(position);
+        }
+        changes
+    }
+
+    fn schedule(&mut self, position: IVec3) {
+        let chunk = position.div_euclid(self.chunk_size.as_ivec3());
+        self.pending.entry(chunk).or_default().insert(position);
+    }
+}
+
+/// A position along with every position whose fluid can depend on it
+fn dependents(position: IVec3) -> impl Iterator<Item = IVec3> {
+    let neighbours = HORIZONTAL
+        .into_iter()
+        .chain([IVec3::Y, IVec3::NEG_Y])
+        .map(move |offset| position + offset);
+    // Fluid only flows sideways when the voxel under it is blocked
+    let above = HORIZONTAL
+        .into_iter()
+        .map(move |offset| position + IVec3::Y + offset);
+    std::iter::once(position).chain(neighbours).chain(above)
+}
+
+fn fluid_properties<V: VoxelData>(properties: &FluidPropertiesFn<V>, voxel: &V) -> FluidProperties {
+    voxel
+        .material()
+        .map_or_else(FluidProperties::default, |material| properties(material))
+}
+
+/// The voxel at a position after a step, or `None` if it stays the same. Solid voxels and
+/// sources never change.
+fn next_voxel<V: VoxelData>(
+    get_voxel: &dyn Fn(IVec3) -> V,
+    position: IVec3,
+    properties: &FluidPropertiesFn<V>,
+) -> Option<V> {
+    let current = get_voxel(position);
+    let level = current.fluid_level();
+    if (current.is_solid() && level.is_none()) || level == Some(FluidLevel::Source) {
+        return None;
+    }
+
+    let next = match incoming_fluid(get_voxel, position, properties) {
+        Some(fluid) => fluid,
+        None if level.is_some() => current.drained(),
+        None => return None,
+    };
+    (next != current).then_some(next)
+}
+
+/// Whether fluid above a voxel can't fall into it, and flows sideways instead
+fn is_blocking<V: VoxelData>(voxel: &V) -> bool {
+    match voxel.fluid_level() {
+        Some(level) => level != FluidLevel::Falling,
+        None => voxel.is_solid(),
+    }
+}
+
+/// The fluid flowing into a position: falling fluid if there is fluid above it, or else the
+/// highest level flowing in from its sides
+fn incoming_fluid<V: VoxelData>(
+    get_voxel: &dyn Fn(IVec3) -> V,
+    position: IVec3,
+    properties: &FluidPropertiesFn<V>,
+) -> Option<V> {
+    let above = get_voxel(position + IVec3::Y);
+    if above.fluid_level().is_some() {
+        return Some(above.with_fluid_level(FluidLevel::Falling));
+    }
+
+    let mut incoming: Option<(u8, V)> = None;
+    let mut sources = Vec::new();
+    for offset in HORIZONTAL {
+        let neighbour = get_voxel(position + offset);
+        let Some(level) = neighbour.fluid_level() else {
+            continue;
+        };
+        if level == FluidLevel::Source {
+            sources.push(neighbour);
+        }
+        // Fluid only flows sideways when it can't fall, so falling fluid spreads at the bottom
+        if !is_blocking(&get_voxel(position + offset - IVec3::Y)) {
+            continue;
+        }
+        let drop = fluid_properties(properties, &neighbour).level_drop.max(1);
+        let spread = level.level().saturating_sub(drop);
+        let highest = match incoming {
+            Some((highest, _)) => spread > highest,
+            None => true,
+        };
+        if spread > 0 && highest {
+            incoming = Some((
+                spread,
+                neighbour.with_fluid_level(FluidLevel::Flowing(spread)),
+            ));
+        }
+    }
+
+    let below = get_voxel(position - IVec3::Y);
+    let supported = match below.fluid_level() {
+        Some(level) => level == FluidLevel::Source,
+        None => below.is_solid(),
+    };
+    let infinite_source = sources.iter().find(|source| {
+        let count = sources.iter().filter(|other| other == source).count();
+        count >= 2 && fluid_properties(properties, *source).infinite
+    });
+    match infinite_source {
+        Some(source) if supported => Some(*source),
+        _ => incoming.map(|(_, fluid)| fluid),
+    }
+}
+
+/// Steps the fluid simulation every `VoxelWorldConfig::fluid_step_interval`
+pub(crate) fn simulate_fluids<C: VoxelWorldConfig>(
+    time: Res<Time>,
+    mut timer: Local<Option<Timer>>,
+    mut voxel_world: VoxelWorld<C>,
+    configuration: Res<C>,
+) {
+    let Some(interval) = configuration.fluid_step_interval() else {
+        return;
+    };
+    let timer = timer.get_or_insert_with(|| Timer::new(interval, TimerMode::Repeating));
+    timer.tick(time.delta());
+    for _ in 0..timer.times_finished_this_tick() {
+        voxel_world.step_fluids();
+    }
+}
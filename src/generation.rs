@@ -0,0 +1,571 @@
+///
+/// World generation
+/// The ways a world can generate its voxels: one voxel at a time with `voxel_lookup_delegate`,
+/// one chunk at a time with `chunk_generator`, or in stages with `generation_pipeline`. Stages
+/// work on whole chunks and can read the output of the previous stage in neighbouring chunks.
+///
+use std::{
+    collections::BTreeMap,
+    marker::PhantomData,
+    sync::{Arc, Condvar, Mutex},
+};
+
+use bevy::{prelude::*, utils::HashMap};
+use ndshape::Shape;
+
+use crate::{
+    biome::BiomeLayer,
+    chunk::{lod_chunk_size, lod_scale, padded_chunk_shape, FillType, PaddedChunkShape},
+    configuration::{ChunkGeneratorFn, VoxelLookupFn, VoxelWorldConfig},
+    structure::{structure_chunk_generator, structure_lookup, StructureLayer},
+    voxel::{VoxelData, WorldVoxel},
+    voxel_world_internal::get_chunk_voxel_position,
+};
+
+/// The function of a generation stage
+pub type GenerationStageFn<V = WorldVoxel> = Arc<dyn Fn(&mut StageContext<V>) + Send + Sync>;
+
+/// The number of chunks of each stage kept in memory by default, see
+/// `GenerationPipeline::with_cache_size`
+pub const DEFAULT_GENERATION_CACHE_SIZE: usize = 512;
+
+struct GenerationStage<V> {
+    name: String,
+    reach: u32,
+    generate: GenerationStageFn<V>,
+}
+
+/// A list of stages that generate the voxels of a world one chunk at a time, like terrain,
+/// then carving, decoration and structures.
+///
+/// Each stage starts from the output of the previous stage for its chunk, and can read the
+/// output of the previous stage in the chunks within its `reach`. Stages only write to their
+/// own chunk, so something spanning a chunk border, like a tree, is placed by every chunk it
+/// overlaps, each writing its own part. Stages need to be deterministic for this to work.
+///
+/// ```
+/// # use bevy::prelude::*;
+/// # use bevy_voxel_world::prelude::*;
+/// let pipeline = GenerationPipeline::<WorldVoxel>::new()
+///     .with_stage("terrain", 0, |chunk| {
+///         for position in chunk.positions() {
+///             if position.y < 0 {
+///                 chunk.set(position, WorldVoxel::Solid(0));
+///             }
+///         }
+///     })
+///     .with_stage("grass", 1, |chunk| {
+///         for position in chunk.positions() {
+///             // The voxel above can be in the chunk above
+///             if chunk.get(position).is_solid() && !chunk.get(position + IVec3::Y).is_solid() {
+///                 chunk.set(position, WorldVoxel::Solid(1));
+///             }
+///         }
+///     });
+/// ```
+pub struct GenerationPipeline<V = WorldVoxel> {
+    stages: Vec<GenerationStage<V>>,
+    cache_size: usize,
+}
+
+impl<V: VoxelData> GenerationPipeline<V> {
+    pub fn new() -> Self {
+        Self {
+            stages: Vec::new(),
+            cache_size: DEFAULT_GENERATION_CACHE_SIZE,
+        }
+    }
+
+    /// Add a stage after all stages added so far. `reach` is the distance in chunks around the
+    /// chunk being generated where the stage can read the output of the previous stage.
+    ///
+    /// Every chunk of reach makes the previous stages generate more chunks, so keep it as small
+    /// as the stage allows. The reach of the first stage is ignored, since it has nothing to read.
+    pub fn with_stage(
+        mut self,
+        name: impl Into<String>,
+        reach: u32,
+        generate: impl Fn(&mut StageContext<V>) + Send + Sync + 'static,
+    ) -> Self {
+        self.stages.push(GenerationStage {
+            name: name.into(),
+            reach,
+            generate: Arc::new(generate),
+        });
+        self
+    }
+
+    /// The number of generated chunks kept in memory for each stage, so neighbouring chunks
+    /// don't have to generate them again. The least recently used chunks are dropped first.
+    pub fn with_cache_size(mut self, chunks: usize) -> Self {
+        self.cache_size = chunks.max(1);
+        self
+    }
+
+    /// The names of the stages, in the order they run
+    pub fn stages(&self) -> impl Iterator<Item = &str> {
+        self.stages.iter().map(|stage| stage.name.as_str())
+    }
+}
+
+impl<V: VoxelData> Default for GenerationPipeline<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The chunk a generation stage is working on
+pub struct StageContext<V = WorldVoxel> {
+    chunk_position: IVec3,
+    chunk_size: UVec3,
+    voxels: Vec<V>,
+    previous: HashMap<IVec3, Arc<[V]>>,
+}
+
+impl<V: VoxelData> StageContext<V> {
+    /// The position of the chunk, in chunks
+    pub fn chunk_position(&self) -> IVec3 {
+        self.chunk_position
+    }
+
+    pub fn chunk_size(&self) -> UVec3 {
+        self.chunk_size
+    }
+
+    /// The world position of the first voxel of the chunk
+    pub fn min(&self) -> IVec3 {
+        self.chunk_position * self.chunk_size.as_ivec3()
+    }
+
+    /// The world position of the last voxel of the chunk
+    pub fn max(&self) -> IVec3 {
+        self.min() + self.chunk_size.as_ivec3() - 1
+    }
+
+    /// Whether the world position is inside the chunk
+    pub fn contains(&self, position: IVec3) -> bool {
+        position.cmpge(self.min()).all() && position.cmple(self.max()).all()
+    }
+
+    /// The world positions of all voxels of the chunk
+    pub fn positions(&self) -> impl Iterator<Item = IVec3> {
+        let (min, size) = (self.min(), self.chunk_size);
+        (0..size.x * size.y * size.z).map(move |index| {
+            min + UVec3::new(
+                index % size.x,
+                (index / size.x) % size.y,
+                index / (size.x * size.y),
+            )
+            .as_ivec3()
+        })
+    }
+
+    /// Get the voxel at a world position. Inside the chunk, this includes the voxels set by this
+    /// stage. Outside of it, this is the output of the previous stage, or the default (unset)
+    /// voxel if the position is beyond the reach of the stage.
+    pub fn get(&self, position: IVec3) -> V {
+        match self.local_index(position) {
+            Some(index) => self.voxels[index],
+            None => self.previous(position),
+        }
+    }
+
+    /// Get the output of the previous stage at a world position, or the default (unset) voxel if
+    /// the position is beyond the reach of the stage
+    pub fn previous(&self, position: IVec3) -> V {
+        let (chunk_position, index) = chunk_index(position, self.chunk_size);
+        self.previous
+            .get(&chunk_position)
+            .map_or(V::default(), |voxels| voxels[index])
+    }
+
+    /// Set the voxel at a world position. Positions outside of the chunk are ignored, and
+    /// return false.
+    pub fn set(&mut self, position: IVec3, voxel: V) -> bool {
+        let Some(index) = self.local_index(position) else {
+            return false;
+        };
+        self.voxels[index] = voxel;
+        true
+    }
+
+    fn local_index(&self, position: IVec3) -> Option<usize> {
+        let (chunk_position, index) = chunk_index(position, self.chunk_size);
+        (chunk_position == self.chunk_position).then_some(index)
+    }
+}
+
+/// The chunk containing a world position, and the index of the position in the chunk
+fn chunk_index(position: IVec3, chunk_size: UVec3) -> (IVec3, usize) {
+    let size = chunk_size.as_ivec3();
+    let local = position.rem_euclid(size).as_uvec3();
+    let index = local.x + local.y * chunk_size.x + local.z * chunk_size.x * chunk_size.y;
+    (position.div_euclid(size), index as usize)
+}
+
+/// A hash of a position, the same on every platform for the same seed. Used for seeded
+/// generation that doesn't depend on the order chunks are generated in.
+pub(crate) fn position_hash(seed: u32, position: IVec3) -> u32 {
+    let mut hash = seed.wrapping_mul(0x9e37_79b9);
+    for value in position.to_array() {
+        hash ^= (value as u32).wrapping_mul(0x85eb_ca6b);
+        hash = hash.rotate_left(13).wrapping_mul(0xc2b2_ae35);
+    }
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x7feb_352d);
+    hash ^ (hash >> 15)
+}
+
+/// A random number between 0 and 1 for a position, see `position_hash`
+pub(crate) fn position_random(seed: u32, position: IVec3) -> f32 {
+    (position_hash(seed, position) >> 8) as f32 / (1 << 24) as f32
+}
+
+/// The voxels of a chunk and a one sample wide border around it, filled by
+/// `VoxelWorldConfig::chunk_generator`. At lower levels of detail, each sample covers several
+/// voxels, and should be filled with the first voxel it covers.
+pub struct PaddedVoxelBuffer<'a, V = WorldVoxel> {
+    chunk_position: IVec3,
+    chunk_size: UVec3,
+    lod: u8,
+    shape: PaddedChunkShape,
+    voxels: &'a mut [V],
+}
+
+impl<'a, V: VoxelData> PaddedVoxelBuffer<'a, V> {
+    /// The position of the chunk, in chunks
+    pub fn chunk_position(&self) -> IVec3 {
+        self.chunk_position
+    }
+
+    /// The size of the chunk in voxels, without the border
+    pub fn chunk_size(&self) -> UVec3 {
+        self.chunk_size
+    }
+
+    /// The level of detail the chunk is sampled at
+    pub fn lod(&self) -> u8 {
+        self.lod
+    }
+
+    /// The number of samples along each axis, including the border
+    pub fn size(&self) -> UVec3 {
+        UVec3::from(self.shape.as_array())
+    }
+
+    /// The world position of the first voxel covered by a sample. Sample 0 along an axis is the
+    /// border before the chunk.
+    pub fn world_position(&self, sample: UVec3) -> IVec3 {
+        let scale = lod_scale(self.lod) as i32;
+        (sample.as_ivec3() - 1) * scale + self.chunk_position * self.chunk_size.as_ivec3()
+    }
+
+    pub fn get(&self, sample: UVec3) -> V {
+        self.voxels[self.shape.linearize(sample.to_array()) as usize]
+    }
+
+    pub fn set(&mut self, sample: UVec3, voxel: V) {
+        self.voxels[self.shape.linearize(sample.to_array()) as usize] = voxel;
+    }
+
+    /// All samples, ordered by x first, then y, then z
+    pub fn voxels_mut(&mut self) -> &mut [V] {
+        self.voxels
+    }
+}
+
+/// Run a chunk generator for the chunk at `chunk_position`. The voxels are only valid if the
+/// generator reports the chunk as `FillType::Mixed`.
+pub(crate) fn run_chunk_generator<V: VoxelData>(
+    generator: &ChunkGeneratorFn<V>,
+    chunk_position: IVec3,
+    chunk_size: UVec3,
+    lod: u8,
+) -> (FillType<V>, Vec<V>) {
+    let shape = padded_chunk_shape(lod_chunk_size(chunk_size, lod));
+    let mut voxels = vec![V::default(); shape.usize()];
+    let fill_type = generator(&mut PaddedVoxelBuffer {
+        chunk_position,
+        chunk_size,
+        lod,
+        shape,
+        voxels: &mut voxels,
+    });
+    (fill_type, voxels)
+}
+
+/// A lookup function reading the voxels of the chunks generated by a chunk generator at full
+/// resolution. Each chunk is generated the first time the function reaches it.
+pub(crate) fn chunk_generator_lookup<V: VoxelData>(
+    generator: ChunkGeneratorFn<V>,
+    chunk_size: UVec3,
+) -> VoxelLookupFn<V> {
+    let shape = padded_chunk_shape(chunk_size);
+    let mut chunks = HashMap::new();
+    Box::new(move |position| {
+        let (chunk_position, padded) = get_chunk_voxel_position(position, chunk_size);
+        let (fill_type, voxels) = chunks
+            .entry(chunk_position)
+            .or_insert_with(|| run_chunk_generator(&generator, chunk_position, chunk_size, 0));
+        match fill_type {
+            FillType::Empty => V::default(),
+            FillType::Uniform(voxel) => *voxel,
+            FillType::Mixed => voxels[shape.linearize(padded.to_array()) as usize],
+        }
+    })
+}
+
+/// The chunks of one stage that were used most recently
+struct StageCache<V> {
+    chunks: Mutex<StageChunks<V>>,
+    /// Notified whenever a chunk is done generating
+    generated: Condvar,
+}
+
+struct StageChunks<V> {
+    chunks: HashMap<IVec3, CachedChunk<V>>,
+    /// The generated chunks, by the time they were last used
+    used: BTreeMap<u64, IVec3>,
+    time: u64,
+}
+
+enum CachedChunk<V> {
+    /// Another task is generating the chunk
+    Generating,
+    Generated {
+        voxels: Arc<[V]>,
+        last_used: u64,
+    },
+}
+
+impl<V> StageChunks<V> {
+    /// The voxels of a generated chunk, marking it as the most recently used
+    fn use_chunk(&mut self, chunk_position: IVec3) -> Option<Arc<[V]>> {
+        let time = self.time + 1;
+        let Some(CachedChunk::Generated { voxels, last_used }) =
+            self.chunks.get_mut(&chunk_position)
+        else {
+            return None;
+        };
+        self.used.remove(last_used);
+        self.used.insert(time, chunk_position);
+        *last_used = time;
+        self.time = time;
+        Some(voxels.clone())
+    }
+
+    /// Add a generated chunk, dropping the least recently used chunks above `cache_size`
+    fn insert(&mut self, chunk_position: IVec3, voxels: Arc<[V]>, cache_size: usize) {
+        self.time += 1;
+        let generated = CachedChunk::Generated {
+            voxels,
+            last_used: self.time,
+        };
+        self.chunks.insert(chunk_position, generated);
+        self.used.insert(self.time, chunk_position);
+        while self.used.len() > cache_size {
+            if let Some((_, oldest)) = self.used.pop_first() {
+                self.chunks.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Marks a chunk as being generated for as long as it lives. If the generation panics, the
+/// mark is removed so tasks waiting for the chunk generate it themselves.
+struct Generating<'a, V> {
+    cache: &'a StageCache<V>,
+    chunk_position: IVec3,
+}
+
+impl<V> Drop for Generating<'_, V> {
+    fn drop(&mut self) {
+        if let Ok(mut chunks) = self.cache.chunks.lock() {
+            if let Some(CachedChunk::Generating) = chunks.chunks.get(&self.chunk_position) {
+                chunks.chunks.remove(&self.chunk_position);
+            }
+        }
+        self.cache.generated.notify_all();
+    }
+}
+
+/// Runs a generation pipeline, and caches the output of each stage
+pub(crate) struct PipelineRunner<V> {
+    pipeline: GenerationPipeline<V>,
+    chunk_size: UVec3,
+    caches: Vec<StageCache<V>>,
+}
+
+impl<V: VoxelData> PipelineRunner<V> {
+    pub fn new(pipeline: GenerationPipeline<V>, chunk_size: UVec3) -> Self {
+        let caches = pipeline
+            .stages
+            .iter()
+            .map(|_| StageCache {
+                chunks: Mutex::new(StageChunks {
+                    chunks: HashMap::new(),
+                    used: BTreeMap::new(),
+                    time: 0,
+                }),
+                generated: Condvar::new(),
+            })
+            .collect();
+        Self {
+            pipeline,
+            chunk_size,
+            caches,
+        }
+    }
+
+    /// The output of `stage` for a chunk, generating it and the chunks it depends on if they
+    /// aren't cached. Tasks asking for a chunk that is being generated wait for it.
+    pub fn chunk(&self, stage: usize, chunk_position: IVec3) -> Arc<[V]> {
+        let cache = &self.caches[stage];
+        let mut chunks = cache.chunks.lock().unwrap();
+        loop {
+            if let Some(voxels) = chunks.use_chunk(chunk_position) {
+                return voxels;
+            }
+            match chunks.chunks.get(&chunk_position) {
+                Some(CachedChunk::Generating) => chunks = cache.generated.wait(chunks).unwrap(),
+                _ => break,
+            }
+        }
+        chunks
+            .chunks
+            .insert(chunk_position, CachedChunk::Generating);
+        drop(chunks);
+
+        // Stages only read chunks of the stage before them, so waiting can't go in circles
+        let generating = Generating {
+            cache,
+            chunk_position,
+        };
+        let voxels = self.generate(stage, chunk_position);
+        let mut chunks = cache.chunks.lock().unwrap();
+        chunks.insert(chunk_position, voxels.clone(), self.pipeline.cache_size);
+        drop(chunks);
+        drop(generating);
+        voxels
+    }
+
+    /// Run `stage` for a chunk
+    fn generate(&self, stage: usize, chunk_position: IVec3) -> Arc<[V]> {
+        let mut previous = HashMap::new();
+        if stage > 0 {
+            let reach = self.pipeline.stages[stage].reach as i32;
+            for z in -reach..=reach {
+                for y in -reach..=reach {
+                    for x in -reach..=reach {
+                        let neighbour = chunk_position + IVec3::new(x, y, z);
+                        previous.insert(neighbour, self.chunk(stage - 1, neighbour));
+                    }
+                }
+            }
+        }
+
+        let volume = (self.chunk_size.x * self.chunk_size.y * self.chunk_size.z) as usize;
+        let voxels = match previous.get(&chunk_position) {
+            Some(voxels) => voxels.to_vec(),
+            None => vec![V::default(); volume],
+        };
+        let mut context = StageContext {
+            chunk_position,
+            chunk_size: self.chunk_size,
+            voxels,
+            previous,
+        };
+        (self.pipeline.stages[stage].generate)(&mut context);
+        context.voxels.into()
+    }
+
+    /// A lookup function reading the output of the last stage. The chunks it reads are kept
+    /// for as long as the function lives.
+    pub fn lookup_fn(self: &Arc<Self>) -> VoxelLookupFn<V> {
+        let runner = self.clone();
+        let last_stage = self.pipeline.stages.len().checked_sub(1);
+        let mut chunks = HashMap::new();
+        Box::new(move |position| {
+            let Some(last_stage) = last_stage else {
+                return V::default();
+            };
+            let (chunk_position, index) = chunk_index(position, runner.chunk_size);
+            let voxels = chunks
+                .entry(chunk_position)
+                .or_insert_with(|| runner.chunk(last_stage, chunk_position));
+            voxels[index]
+        })
+    }
+}
+
+/// Where the voxels of a chunk come from
+pub enum ChunkSource<V> {
+    Lookup(VoxelLookupFn<V>),
+    Chunk(ChunkGeneratorFn<V>),
+}
+
+/// The generator of the world `C`. Chunks are generated by the pipeline of the world when it
+/// has one, then by the chunk generator, then by the biome layer, and by `voxel_lookup_delegate`
+/// otherwise. Structures are placed over the chunks however they are generated.
+#[derive(Resource)]
+pub struct WorldGenerator<C: VoxelWorldConfig> {
+    pipeline: Option<Arc<PipelineRunner<C::Voxel>>>,
+    chunk_generator: Option<ChunkGeneratorFn<C::Voxel>>,
+    biomes: Option<Arc<BiomeLayer<C::Voxel>>>,
+    structures: Option<Arc<StructureLayer<C::Voxel>>>,
+    _marker: PhantomData<C>,
+}
+
+impl<C: VoxelWorldConfig> WorldGenerator<C> {
+    pub fn from_config(configuration: &C) -> Self {
+        let pipeline = configuration
+            .generation_pipeline()
+            .map(|pipeline| Arc::new(PipelineRunner::new(pipeline, configuration.chunk_size())));
+        let biomes = configuration.biomes();
+        let structures = configuration.structures();
+        let chunk_generator = configuration
+            .chunk_generator()
+            .or_else(|| biomes.as_ref().map(|biomes| biomes.chunk_generator()))
+            .map(|generator| match &structures {
+                Some(structures) => structure_chunk_generator(structures.clone(), generator),
+                None => generator,
+            });
+        Self {
+            pipeline,
+            chunk_generator,
+            biomes,
+            structures,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The biome layer of the world, if it has one
+    pub fn biomes(&self) -> Option<&BiomeLayer<C::Voxel>> {
+        self.biomes.as_deref()
+    }
+
+    /// The source used to generate the voxels of a chunk
+    pub fn chunk_source(&self, configuration: &C, chunk_position: IVec3) -> ChunkSource<C::Voxel> {
+        match (&self.pipeline, &self.chunk_generator) {
+            (None, Some(generator)) => ChunkSource::Chunk(generator.clone()),
+            _ => ChunkSource::Lookup(self.lookup_fn(configuration, chunk_position)),
+        }
+    }
+
+    /// A function generating the voxels of a chunk one at a time
+    pub fn lookup_fn(&self, configuration: &C, chunk_position: IVec3) -> VoxelLookupFn<C::Voxel> {
+        let chunk_size = configuration.chunk_size();
+        let lookup = match (&self.pipeline, &self.chunk_generator) {
+            (Some(pipeline), _) => pipeline.lookup_fn(),
+            (None, Some(generator)) => {
+                // The chunk generator already places the structures
+                return chunk_generator_lookup(generator.clone(), chunk_size);
+            }
+            (None, None) => (configuration.voxel_lookup_delegate())(chunk_position),
+        };
+        match &self.structures {
+            Some(structures) => structure_lookup(structures.clone(), chunk_size, lookup),
+            None => lookup,
+        }
+    }
+}
@@ -0,0 +1,220 @@
+///
+/// Structural integrity
+/// Finds the groups of solid voxels that are no longer connected to the ground or to an anchor
+/// material after an edit, like terrain left floating after an explosion. Floating groups can be
+/// detached from the world as `VoxelBody` entities.
+///
+use std::{collections::VecDeque, marker::PhantomData, sync::Arc};
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::{
+    block_registry::{BlockRegistryHandle, MaterialMappers},
+    configuration::{AnchorMapperFn, VoxelWorldConfig},
+    falling_voxel::supports,
+    mesh_cache::{ChunkMeshes, MeshRef},
+    meshing::generate_voxel_mesh,
+    voxel::VoxelData,
+    voxel_prefab::VoxelPrefab,
+    voxel_world_internal::NeedsMaterial,
+};
+
+const NEIGHBOURS: [IVec3; 6] = [
+    IVec3::X,
+    IVec3::NEG_X,
+    IVec3::Y,
+    IVec3::NEG_Y,
+    IVec3::Z,
+    IVec3::NEG_Z,
+];
+
+/// Options for `VoxelWorld::find_floating_voxels`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IntegrityOptions {
+    /// Voxels at or below this height are anchored to the ground
+    pub ground_level: i32,
+
+    /// Groups with more voxels than this are assumed to be anchored, which bounds the search
+    /// through large connected terrain
+    pub max_group_size: usize,
+}
+
+impl Default for IntegrityOptions {
+    fn default() -> Self {
+        Self {
+            ground_level: 0,
+            max_group_size: 4096,
+        }
+    }
+}
+
+/// A group of connected solid voxels that isn't anchored to anything
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FloatingVoxels<V: VoxelData> {
+    pub voxels: Vec<(IVec3, V)>,
+
+    /// The minimum corner of the box around the voxels
+    pub min: IVec3,
+
+    /// The maximum corner of the box around the voxels, inclusive
+    pub max: IVec3,
+}
+
+impl<V: VoxelData> FloatingVoxels<V> {
+    fn new(voxels: Vec<(IVec3, V)>) -> Self {
+        let (min, max) = voxels
+            .iter()
+            .fold((IVec3::MAX, IVec3::MIN), |(min, max), (position, _)| {
+                (min.min(*position), max.max(*position))
+            });
+        Self { voxels, min, max }
+    }
+
+    pub fn len(&self) -> usize {
+        self.voxels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.voxels.is_empty()
+    }
+
+    /// The size of the box around the voxels
+    pub fn size(&self) -> UVec3 {
+        (self.max - self.min + 1).as_uvec3()
+    }
+
+    /// Capture the voxels into a prefab with its minimum corner at `min`. The rest of the box is
+    /// left unset.
+    pub fn to_prefab(&self) -> VoxelPrefab<V> {
+        let voxels = self
+            .voxels
+            .iter()
+            .map(|(position, voxel)| ((*position - self.min).as_uvec3(), *voxel));
+        VoxelPrefab::from_voxels(self.size(), voxels)
+    }
+}
+
+/// A group of voxels detached from the world with `VoxelWorld::detach_voxels`. The translation
+/// of the entity is the minimum corner of the voxels, and the entity can be moved freely, for
+/// example by a physics engine.
+#[derive(Component)]
+pub struct VoxelBody<C: VoxelWorldConfig> {
+    pub voxels: VoxelPrefab<C::Voxel>,
+
+    /// Where the minimum corner of the voxels was in the world
+    pub origin: IVec3,
+}
+
+impl<C: VoxelWorldConfig> VoxelBody<C> {
+    pub fn new(voxels: VoxelPrefab<C::Voxel>, origin: IVec3) -> Self {
+        Self { voxels, origin }
+    }
+}
+
+/// Flood fill the solid voxels around the edited positions, and return the groups that reach
+/// neither the ground nor an anchor material. Groups that reach a chunk that isn't loaded are
+/// treated as anchored, since what lies beyond is unknown.
+pub(crate) fn find_floating<V: VoxelData>(
+    get_voxel: &dyn Fn(IVec3) -> V,
+    is_loaded: impl Fn(IVec3) -> bool,
+    anchors: &AnchorMapperFn<V>,
+    edited: impl IntoIterator<Item = IVec3>,
+    options: IntegrityOptions,
+) -> Vec<FloatingVoxels<V>> {
+    let is_anchor = |position: IVec3, voxel: &V| {
+        position.y <= options.ground_level
+            || voxel.material().is_some_and(|material| anchors(material))
+    };
+
+    // The group each visited voxel belongs to. Floating groups are explored completely, so
+    // reaching a voxel of an earlier group means it was anchored.
+    let mut groups: HashMap<IVec3, usize> = HashMap::new();
+    let mut floating = Vec::new();
+    let starts = edited
+        .into_iter()
+        .flat_map(|position| std::iter::once(position).chain(NEIGHBOURS.map(|n| position + n)));
+
+    for (group, start) in starts.enumerate() {
+        if groups.contains_key(&start) || !is_loaded(start) {
+            continue;
+        }
+        let voxel = get_voxel(start);
+        if !supports(&voxel) {
+            continue;
+        }
+
+        let mut voxels = vec![(start, voxel)];
+        let mut queue = VecDeque::from([start]);
+        groups.insert(start, group);
+        let mut anchored = is_anchor(start, &voxel);
+        'fill: while let Some(position) = queue.pop_front() {
+            if anchored {
+                break;
+            }
+            for offset in NEIGHBOURS {
+                let neighbour = position + offset;
+                match groups.get(&neighbour) {
+                    Some(other) if *other == group => continue,
+                    Some(_) => {
+                        anchored = true;
+                        break 'fill;
+                    }
+                    None => {}
+                }
+                if !is_loaded(neighbour) {
+                    anchored = true;
+                    break 'fill;
+                }
+                let voxel = get_voxel(neighbour);
+                if !supports(&voxel) {
+                    continue;
+                }
+                groups.insert(neighbour, group);
+                voxels.push((neighbour, voxel));
+                queue.push_back(neighbour);
+                if is_anchor(neighbour, &voxel) || voxels.len() > options.max_group_size {
+                    anchored = true;
+                    break 'fill;
+                }
+            }
+        }
+
+        if !anchored {
+            floating.push(FloatingVoxels::new(voxels));
+        }
+    }
+    floating
+}
+
+/// Gives voxel bodies a mesh
+pub(crate) fn mesh_voxel_bodies<C: VoxelWorldConfig>(
+    mut commands: Commands,
+    bodies: Query<(Entity, &VoxelBody<C>), Without<MeshRef>>,
+    mut mesh_assets: ResMut<Assets<Mesh>>,
+    block_registry: Option<Res<BlockRegistryHandle<C>>>,
+    configuration: Res<C>,
+) {
+    if bodies.is_empty() {
+        return;
+    }
+
+    let mappers = MaterialMappers::new(
+        configuration.as_ref(),
+        block_registry.as_ref().and_then(|handle| handle.registry()),
+    );
+    for (entity, body) in bodies.iter() {
+        let (opaque, translucent) = generate_voxel_mesh(
+            body.voxels.size(),
+            |position| body.voxels.get(position),
+            mappers.texture_index.clone(),
+            mappers.emission.clone(),
+        );
+        let meshes = ChunkMeshes {
+            opaque: mesh_assets.add(opaque),
+            translucent: translucent.map(|mesh| mesh_assets.add(mesh)),
+        };
+        commands
+            .entity(entity)
+            .try_insert((MeshRef(Arc::new(meshes)), NeedsMaterial::<C>(PhantomData)));
+    }
+}
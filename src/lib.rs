@@ -1,32 +1,78 @@
+mod biome;
+mod block_registry;
+mod character_controller;
 mod chunk;
 mod chunk_map;
 mod configuration;
 mod debug;
+mod edit_history;
+mod edit_storage;
+mod falling_voxel;
+mod fluid;
+mod generation;
+mod integrity;
+mod lighting;
 mod mesh_cache;
 mod meshing;
 mod plugin;
+mod schematic;
+mod structure;
+mod vox_model;
 mod voxel;
 mod voxel_material;
+mod voxel_physics;
+mod voxel_prefab;
 mod voxel_traversal;
 mod voxel_world;
 mod voxel_world_internal;
 
 pub mod prelude {
-    pub use crate::chunk::{Chunk, NeedsDespawn};
+    pub use crate::biome::{Biome, BiomeColumn, BiomeId, BiomeLayer, Decoration};
+    pub use crate::block_registry::{BlockDefinition, BlockRegistry, BlockRegistryHandle};
+    pub use crate::character_controller::VoxelCharacterController;
+    pub use crate::chunk::{Chunk, FillType, NeedsDespawn};
     pub use crate::configuration::*;
     pub use crate::debug::{ChunkAabbGizmo, VoxelWorldGizmoPlugin};
+    pub use crate::edit_history::{VoxelChange, VoxelEditHistory, VoxelTransaction};
+    pub use crate::falling_voxel::{FallingVoxel, VoxelWillFall, VoxelWillLand};
+    pub use crate::fluid::FluidProperties;
+    pub use crate::generation::{
+        GenerationPipeline, GenerationStageFn, PaddedVoxelBuffer, StageContext,
+        DEFAULT_GENERATION_CACHE_SIZE,
+    };
+    pub use crate::integrity::{FloatingVoxels, IntegrityOptions, VoxelBody};
+    pub use crate::lighting::{VoxelLight, MAX_LIGHT};
     pub use crate::plugin::VoxelWorldPlugin;
-    pub use crate::voxel::{VoxelFace, WorldVoxel, VOXEL_SIZE};
-    pub use crate::voxel_world::{ChunkWillDespawn, ChunkWillRemesh, ChunkWillSpawn};
+    pub use crate::schematic::{Schematic, SchematicBlockMapping};
+    pub use crate::structure::{Structure, StructureHeightFn, StructureLayer, StructurePlacement};
+    pub use crate::vox_model::{VoxModel, VoxPaletteMapping};
+    pub use crate::voxel::{
+        FluidLevel, MaterialId, VoxelData, VoxelFace, VoxelVisibility, WorldVoxel, MAX_FLUID_LEVEL,
+        VOXEL_SIZE,
+    };
+    pub use crate::voxel_material::VoxelEmission;
+    pub use crate::voxel_prefab::{PasteOptions, PrefabVoxels, VoxelPrefab};
+    pub use crate::voxel_world::{
+        ChunkWillChangeLod, ChunkWillDespawn, ChunkWillRemesh, ChunkWillSpawn,
+    };
     pub use crate::voxel_world::{VoxelRaycastResult, VoxelWorld, VoxelWorldCamera};
 }
 
 pub mod rendering {
-    pub use crate::plugin::VoxelWorldMaterialHandle;
+    pub use crate::plugin::{VoxelWorldMaterialHandle, VoxelWorldTranslucentMaterialHandle};
     pub use crate::voxel_material::vertex_layout;
     pub use crate::voxel_material::VOXEL_TEXTURE_SHADER_HANDLE;
 }
 
+pub mod persistence {
+    pub use crate::edit_storage::*;
+    pub use crate::voxel_world_internal::ModifiedVoxels;
+}
+
+pub mod physics {
+    pub use crate::voxel_physics::*;
+}
+
 pub mod traversal_alg {
     pub use crate::voxel_traversal::*;
 }
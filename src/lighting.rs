@@ -0,0 +1,502 @@
+///
+/// Voxel lighting
+/// Flood fill lighting from the sky and from light emitting voxels. The light of every chunk at
+/// full resolution is kept in the `LightMap`, and updated incrementally around edited voxels, so
+/// only the chunks whose light actually changes need to be remeshed.
+///
+use std::{collections::VecDeque, marker::PhantomData};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use ndshape::Shape;
+
+use crate::{
+    chunk::padded_chunk_shape, configuration::VoxelWorldConfig, voxel::VoxelData,
+    voxel_world_internal::get_chunk_voxel_position,
+};
+
+/// The brightest light level. Light gets one level dimmer for every voxel it travels, so it
+/// reaches at most this many voxels from its source.
+pub const MAX_LIGHT: u8 = 15;
+
+const NEIGHBOURS: [IVec3; 6] = [
+    IVec3::X,
+    IVec3::NEG_X,
+    IVec3::Y,
+    IVec3::NEG_Y,
+    IVec3::Z,
+    IVec3::NEG_Z,
+];
+
+/// The light level of a voxel
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VoxelLight {
+    /// Light from the sky. Sunlight falls straight down through non-opaque voxels without getting
+    /// dimmer, and spreads sideways from there.
+    pub sky: u8,
+
+    /// Light from light emitting voxels
+    pub block: u8,
+}
+
+impl VoxelLight {
+    /// Full sunlight, which is what every voxel gets when lighting is disabled
+    pub const SKY: Self = Self {
+        sky: MAX_LIGHT,
+        block: 0,
+    };
+
+    /// Pack the light into a single byte, with the skylight in the high bits
+    pub(crate) fn pack(self) -> u8 {
+        (self.sky << 4) | self.block
+    }
+
+    pub(crate) fn unpack(packed: u8) -> Self {
+        Self {
+            sky: packed >> 4,
+            block: packed & 0x0f,
+        }
+    }
+}
+
+/// One of the two kinds of light, which spread independently of each other
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Channel {
+    Sky,
+    Block,
+}
+
+impl Channel {
+    fn get(self, packed: u8) -> u8 {
+        match self {
+            Channel::Sky => packed >> 4,
+            Channel::Block => packed & 0x0f,
+        }
+    }
+
+    fn set(self, packed: u8, level: u8) -> u8 {
+        match self {
+            Channel::Sky => (packed & 0x0f) | (level << 4),
+            Channel::Block => (packed & 0xf0) | level,
+        }
+    }
+}
+
+/// The light a chunk task meshes a chunk at full resolution with
+pub(crate) enum ChunkLight {
+    /// The padded light of a lit chunk, as kept by the `LightMap`
+    Lit(Vec<u8>),
+    /// A chunk that isn't lit yet. Its light is baked by the chunk task with `bake_light`, from
+    /// its voxels and the `padding`, which holds the light of the lit neighbours. Light only
+    /// enters through the sides in `lit_neighbours`, in the order of `NEIGHBOURS`.
+    Unlit {
+        padding: Vec<u8>,
+        lit_neighbours: [bool; 6],
+    },
+}
+
+/// Holds the light of every lit chunk. Chunks are lit once their voxels are generated at full
+/// resolution, and forget their light when they despawn or switch to a lower level of detail.
+/// The light of a new chunk is baked by its chunk task, and then spread into its neighbours.
+///
+/// Light only spreads through lit chunks. Where the chunk above a lit chunk isn't lit, the sky is
+/// assumed to be open, so sunlight enters through the top of the chunk. Once the chunk above is
+/// lit, that sunlight is corrected.
+#[derive(Resource)]
+pub(crate) struct LightMap<C: VoxelWorldConfig> {
+    chunk_size: UVec3,
+
+    /// The packed light of the voxels of each lit chunk, without padding
+    chunks: HashMap<IVec3, Box<[u8]>>,
+
+    /// Chunks whose voxels were generated since the last `relight`
+    generated: Vec<IVec3>,
+
+    /// Positions whose light needs to be updated, because their voxel was edited
+    pending: HashSet<IVec3>,
+
+    /// Positions whose light only needs to spread into their neighbours, like the borders of
+    /// newly lit chunks
+    spreading: HashSet<IVec3>,
+
+    /// Positions whose light can only be updated once their chunk is generated again, like
+    /// positions where a modification was removed
+    awaiting_chunks: HashMap<IVec3, Vec<IVec3>>,
+
+    _marker: PhantomData<C>,
+}
+
+impl<C: VoxelWorldConfig> LightMap<C> {
+    pub fn new(chunk_size: UVec3) -> Self {
+        Self {
+            chunk_size,
+            chunks: HashMap::new(),
+            generated: Vec::new(),
+            pending: HashSet::new(),
+            spreading: HashSet::new(),
+            awaiting_chunks: HashMap::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn is_lit(&self, chunk_pos: IVec3) -> bool {
+        self.chunks.contains_key(&chunk_pos)
+    }
+
+    /// Get the light at the given position, or `None` if its chunk isn't lit
+    pub fn get(&self, position: IVec3) -> Option<VoxelLight> {
+        self.packed(position).map(VoxelLight::unpack)
+    }
+
+    /// Forget the light of a chunk
+    pub fn remove_chunk(&mut self, chunk_pos: IVec3) {
+        self.chunks.remove(&chunk_pos);
+        self.awaiting_chunks.remove(&chunk_pos);
+    }
+
+    /// Whether there are positions waiting for `relight`
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty() || !self.spreading.is_empty()
+    }
+
+    /// Update the light around an edited voxel with the next `relight`
+    pub fn queue_edit(&mut self, position: IVec3) {
+        self.pending.insert(position);
+    }
+
+    /// Queue a chunk whose voxels were just generated, see `chunk_generated`
+    pub fn queue_generated(&mut self, chunk_pos: IVec3) {
+        self.generated.push(chunk_pos);
+    }
+
+    /// Take the chunks queued with `queue_generated`
+    pub fn take_generated(&mut self) -> Vec<IVec3> {
+        std::mem::take(&mut self.generated)
+    }
+
+    /// Update the light around a voxel once its chunk is generated again
+    pub fn queue_after_generation(&mut self, position: IVec3) {
+        let (chunk_pos, _) = get_chunk_voxel_position(position, self.chunk_size);
+        self.awaiting_chunks
+            .entry(chunk_pos)
+            .or_default()
+            .push(position);
+    }
+
+    /// Update the light of every voxel in a lit chunk with the next `relight`, like when the
+    /// light its materials emit has changed
+    pub fn queue_chunk(&mut self, chunk_pos: IVec3) {
+        if self.is_lit(chunk_pos) {
+            let positions = self.chunk_positions(chunk_pos);
+            self.pending.extend(positions);
+        }
+    }
+
+    /// Called when the voxels of a chunk were generated, with the padded light its chunk task
+    /// meshed it with. A chunk at full resolution that isn't lit yet takes the light baked by
+    /// its chunk task, see `bake_light`. With the next `relight`, that light spreads into the
+    /// neighbours across the borders, and the top of the chunk below, which no longer sees the
+    /// open sky, is updated.
+    pub fn chunk_generated(&mut self, chunk_pos: IVec3, padded_light: Option<&[u8]>) {
+        if let Some(positions) = self.awaiting_chunks.remove(&chunk_pos) {
+            self.pending.extend(positions);
+        }
+        if self.is_lit(chunk_pos) {
+            return;
+        }
+
+        let size = self.chunk_size;
+        let mut light = vec![0; (size.x * size.y * size.z) as usize];
+        if let Some(padded_light) = padded_light {
+            let shape = padded_chunk_shape(size);
+            for (i, local) in self.chunk_positions(IVec3::ZERO).enumerate() {
+                let padded = (local + 1).as_uvec3().to_array();
+                light[i] = padded_light[shape.linearize(padded) as usize];
+            }
+        }
+        self.chunks.insert(chunk_pos, light.into());
+
+        let size = size.as_ivec3();
+        let origin = chunk_pos * size;
+        for local in self.chunk_positions(IVec3::ZERO) {
+            if local.cmpeq(IVec3::ZERO).any() || local.cmpeq(size - 1).any() {
+                let position = origin + local;
+                self.spreading.insert(position);
+                for offset in NEIGHBOURS {
+                    let (neighbour_pos, _) =
+                        get_chunk_voxel_position(position + offset, self.chunk_size);
+                    if neighbour_pos != chunk_pos {
+                        self.spreading.insert(position + offset);
+                    }
+                }
+            }
+        }
+
+        let below = chunk_pos - IVec3::Y;
+        if self.is_lit(below) {
+            for x in 0..size.x {
+                for z in 0..size.z {
+                    self.pending.insert(origin + IVec3::new(x, -1, z));
+                }
+            }
+        }
+    }
+
+    /// The light a chunk task meshes the chunk at `chunk_pos` with
+    pub fn chunk_light(&self, chunk_pos: IVec3) -> ChunkLight {
+        let padded_light = self.padded_light(chunk_pos);
+        if self.is_lit(chunk_pos) {
+            return ChunkLight::Lit(padded_light);
+        }
+        ChunkLight::Unlit {
+            padding: padded_light,
+            lit_neighbours: NEIGHBOURS.map(|offset| self.is_lit(chunk_pos + offset)),
+        }
+    }
+
+    /// The packed light of a chunk including the 1-voxel padding, as used for meshing. The
+    /// padding towards chunks that aren't lit gets full sunlight, like chunks without lighting.
+    pub fn padded_light(&self, chunk_pos: IVec3) -> Vec<u8> {
+        let shape = padded_chunk_shape(self.chunk_size);
+        let origin = chunk_pos * self.chunk_size.as_ivec3() - 1;
+        (0..shape.size())
+            .map(|i| {
+                let position = origin + UVec3::from(shape.delinearize(i)).as_ivec3();
+                self.packed(position).unwrap_or(VoxelLight::SKY.pack())
+            })
+            .collect()
+    }
+
+    /// Update the light around all queued positions, and return the positions whose light
+    /// changed. Light is first removed from the queued positions and everything it reached, and
+    /// then spread again from the sources left at the edge of the removed area, so the work is
+    /// proportional to the area the light of the changed voxels covers.
+    ///
+    /// `get_voxel` is only called for positions in lit chunks.
+    pub fn relight(
+        &mut self,
+        mut get_voxel: impl FnMut(IVec3) -> C::Voxel,
+        emission: impl Fn(&C::Voxel) -> u8,
+    ) -> HashSet<IVec3> {
+        let seeds: Vec<IVec3> = self.pending.drain().collect();
+        let spreading: Vec<IVec3> = self.spreading.drain().collect();
+        // The light of every position the update touched, before it was touched
+        let mut before = HashMap::new();
+
+        for channel in [Channel::Sky, Channel::Block] {
+            let mut remove_queue = VecDeque::new();
+            let mut add_queue = VecDeque::new();
+            let mut removed = Vec::new();
+
+            for position in seeds.iter().copied() {
+                let Some(level) = self.level(position, channel) else {
+                    continue;
+                };
+                self.set_level(position, channel, 0, &mut before);
+                remove_queue.push_back((position, level));
+                removed.push(position);
+            }
+
+            // Remove all light that could have come from the removed light. Brighter neighbours
+            // have another source, and spread their light back in afterwards.
+            while let Some((position, level)) = remove_queue.pop_front() {
+                for offset in NEIGHBOURS {
+                    let neighbour = position + offset;
+                    let neighbour_level = match self.level(neighbour, channel) {
+                        Some(0) | None => continue,
+                        Some(neighbour_level) => neighbour_level,
+                    };
+                    if neighbour_level < level || falls_from(channel, offset, level) {
+                        self.set_level(neighbour, channel, 0, &mut before);
+                        remove_queue.push_back((neighbour, neighbour_level));
+                        removed.push(neighbour);
+                    } else {
+                        add_queue.push_back(neighbour);
+                    }
+                }
+            }
+
+            // Light sources within the removed area shine again
+            for position in removed {
+                let voxel = get_voxel(position);
+                let source = match channel {
+                    Channel::Sky => {
+                        let (above, _) =
+                            get_chunk_voxel_position(position + IVec3::Y, self.chunk_size);
+                        if !voxel.is_opaque() && !self.is_lit(above) {
+                            MAX_LIGHT
+                        } else {
+                            0
+                        }
+                    }
+                    Channel::Block => emission(&voxel).min(MAX_LIGHT),
+                };
+                if source > self.level(position, channel).unwrap_or(0) {
+                    self.set_level(position, channel, source, &mut before);
+                    add_queue.push_back(position);
+                }
+            }
+            add_queue.extend(spreading.iter().copied());
+
+            while let Some(position) = add_queue.pop_front() {
+                let level = self.level(position, channel).unwrap_or(0);
+                if level <= 1 {
+                    continue;
+                }
+                for offset in NEIGHBOURS {
+                    let neighbour = position + offset;
+                    let Some(neighbour_level) = self.level(neighbour, channel) else {
+                        continue;
+                    };
+                    let spread = if falls_from(channel, offset, level) {
+                        MAX_LIGHT
+                    } else {
+                        level - 1
+                    };
+                    if neighbour_level >= spread || get_voxel(neighbour).is_opaque() {
+                        continue;
+                    }
+                    self.set_level(neighbour, channel, spread, &mut before);
+                    add_queue.push_back(neighbour);
+                }
+            }
+        }
+
+        before
+            .into_iter()
+            .filter(|(position, packed)| self.packed(*position) != Some(*packed))
+            .map(|(position, _)| position)
+            .collect()
+    }
+
+    fn chunk_positions(&self, chunk_pos: IVec3) -> impl Iterator<Item = IVec3> {
+        let size = self.chunk_size.as_ivec3();
+        let origin = chunk_pos * size;
+        (0..size.z).flat_map(move |z| {
+            (0..size.y).flat_map(move |y| (0..size.x).map(move |x| origin + IVec3::new(x, y, z)))
+        })
+    }
+
+    fn index(&self, position: IVec3) -> (IVec3, usize) {
+        let (chunk_pos, padded) = get_chunk_voxel_position(position, self.chunk_size);
+        let local = padded - 1;
+        let index = local.x + self.chunk_size.x * (local.y + self.chunk_size.y * local.z);
+        (chunk_pos, index as usize)
+    }
+
+    fn packed(&self, position: IVec3) -> Option<u8> {
+        let (chunk_pos, index) = self.index(position);
+        self.chunks.get(&chunk_pos).map(|light| light[index])
+    }
+
+    fn level(&self, position: IVec3, channel: Channel) -> Option<u8> {
+        self.packed(position).map(|packed| channel.get(packed))
+    }
+
+    fn set_level(
+        &mut self,
+        position: IVec3,
+        channel: Channel,
+        level: u8,
+        before: &mut HashMap<IVec3, u8>,
+    ) {
+        let (chunk_pos, index) = self.index(position);
+        if let Some(light) = self.chunks.get_mut(&chunk_pos) {
+            before.entry(position).or_insert(light[index]);
+            light[index] = channel.set(light[index], level);
+        }
+    }
+}
+
+/// Full sunlight falls straight down without getting dimmer
+fn falls_from(channel: Channel, offset: IVec3, level: u8) -> bool {
+    channel == Channel::Sky && offset == IVec3::NEG_Y && level == MAX_LIGHT
+}
+
+/// Bake the light of a chunk that isn't lit yet, as a chunk task does before meshing it. The
+/// interior of the padded `light` is replaced with the light of the chunk, and the padding is
+/// kept. Light spreads from the light emitting voxels of the chunk, and enters from the padding
+/// on the sides in `lit_neighbours`, in the order of `NEIGHBOURS`. Where the chunk above isn't
+/// lit, sunlight falls in through the top.
+pub(crate) fn bake_light<V: VoxelData>(
+    voxels: &[V],
+    light: &mut [u8],
+    chunk_size: UVec3,
+    lit_neighbours: [bool; 6],
+    emission: impl Fn(&V) -> u8,
+) {
+    let shape = padded_chunk_shape(chunk_size);
+    let size = chunk_size.as_ivec3();
+    let index = |position: IVec3| shape.linearize((position + 1).as_uvec3().to_array()) as usize;
+    let is_inside =
+        |position: IVec3| position.cmpge(IVec3::ZERO).all() && position.cmplt(size).all();
+    let positions = (0..size.z).flat_map(move |z| {
+        (0..size.y).flat_map(move |y| (0..size.x).map(move |x| IVec3::new(x, y, z)))
+    });
+
+    for position in positions.clone() {
+        light[index(position)] = 0;
+    }
+
+    for channel in [Channel::Sky, Channel::Block] {
+        let mut queue = VecDeque::new();
+        for position in positions.clone() {
+            let i = index(position);
+            let mut level = match channel {
+                Channel::Sky => 0,
+                Channel::Block => emission(&voxels[i]).min(MAX_LIGHT),
+            };
+            if !voxels[i].is_opaque() {
+                for (offset, lit) in NEIGHBOURS.into_iter().zip(lit_neighbours) {
+                    let neighbour = position + offset;
+                    if is_inside(neighbour) {
+                        continue;
+                    }
+                    let neighbour_level = if lit {
+                        channel.get(light[index(neighbour)])
+                    } else if channel == Channel::Sky && offset == IVec3::Y {
+                        MAX_LIGHT
+                    } else {
+                        continue;
+                    };
+                    let spread = if falls_from(channel, -offset, neighbour_level) {
+                        MAX_LIGHT
+                    } else {
+                        neighbour_level.saturating_sub(1)
+                    };
+                    level = level.max(spread);
+                }
+            }
+            if level > 0 {
+                light[i] = channel.set(light[i], level);
+                queue.push_back(position);
+            }
+        }
+
+        while let Some(position) = queue.pop_front() {
+            let level = channel.get(light[index(position)]);
+            if level <= 1 {
+                continue;
+            }
+            for offset in NEIGHBOURS {
+                let neighbour = position + offset;
+                if !is_inside(neighbour) {
+                    continue;
+                }
+                let i = index(neighbour);
+                let spread = if falls_from(channel, offset, level) {
+                    MAX_LIGHT
+                } else {
+                    level - 1
+                };
+                if channel.get(light[i]) >= spread || voxels[i].is_opaque() {
+                    continue;
+                }
+                light[i] = channel.set(light[i], spread);
+                queue.push_back(neighbour);
+            }
+        }
+    }
+}
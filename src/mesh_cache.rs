@@ -6,12 +6,19 @@ use std::{
 use bevy::prelude::*;
 use weak_table::WeakValueHashMap;
 
+/// The mesh handles of a chunk. Translucent voxels are rendered from a separate mesh, so they
+/// can use an alpha blended material.
+pub(crate) struct ChunkMeshes {
+    pub opaque: Handle<Mesh>,
+    pub translucent: Option<Handle<Mesh>>,
+}
+
 /// This is used to keep a reference to a mesh handle in each chunk entity. This ensures that the WeakMap
 /// we use to look up mesh handles can drop handles that no chunks are using anymore.
 #[derive(Component)]
-pub(crate) struct MeshRef(pub Arc<Handle<Mesh>>);
+pub(crate) struct MeshRef(pub Arc<ChunkMeshes>);
 
-type WeakMeshMap = WeakValueHashMap<u64, Weak<Handle<Mesh>>>;
+type WeakMeshMap = WeakValueHashMap<u64, Weak<ChunkMeshes>>;
 
 /// MeshCache uses a weak map to keep track of mesh handles generated for a certain configuration of voxels.
 /// Using this map, we can avoid generating the same mesh multiple times, and reusing mesh handles
@@ -36,10 +43,15 @@ impl<C: Send + Sync + 'static> MeshCache<C> {
         }
     }
 
-    pub fn get(&self, voxels_hash: &u64) -> Option<Arc<Handle<Mesh>>> {
+    pub fn get(&self, voxels_hash: &u64) -> Option<Arc<ChunkMeshes>> {
         self.map.read().unwrap().get(voxels_hash)
     }
 
+    /// Forget all cached meshes, so chunks get meshed again even if their voxels didn't change
+    pub fn clear(&self) {
+        self.map.write().unwrap().clear();
+    }
+
     pub fn get_map(&self) -> Arc<RwLock<WeakMeshMap>> {
         self.map.clone()
     }
@@ -55,4 +67,4 @@ impl<C> Default for MeshCache<C> {
 }
 
 #[derive(Resource, Deref, DerefMut, Default)]
-pub(crate) struct MeshCacheInsertBuffer<C>(#[deref] Vec<(u64, Arc<Handle<Mesh>>)>, PhantomData<C>);
+pub(crate) struct MeshCacheInsertBuffer<C>(#[deref] Vec<(u64, Arc<ChunkMeshes>)>, PhantomData<C>);
@@ -1,9 +1,6 @@
 use std::sync::Arc;
 
-use block_mesh::{
-    visible_block_faces, OrientedBlockFace, UnitQuadBuffer, Voxel, VoxelVisibility,
-    RIGHT_HANDED_Y_UP_CONFIG,
-};
+use block_mesh::{OrientedBlockFace, QuadBuffer, UnorientedQuad, RIGHT_HANDED_Y_UP_CONFIG};
 
 use bevy::{
     prelude::*,
@@ -13,43 +10,383 @@ use bevy::{
         render_resource::PrimitiveTopology,
     },
 };
-use ndshape::ConstShape;
+use ndshape::Shape;
 
 use crate::{
-    chunk::{PaddedChunkShape, CHUNK_SIZE_U},
-    voxel::WorldVoxel,
-    voxel_material::ATTRIBUTE_TEX_INDEX,
+    chunk::{padded_chunk_shape, PaddedChunkShape},
+    configuration::{EmissionMapperFn, MeshingMode, TextureIndexMapperFn},
+    lighting::{VoxelLight, MAX_LIGHT},
+    voxel::{VoxelData, VoxelVisibility},
+    voxel_material::{
+        ATTRIBUTE_EMISSIVE, ATTRIBUTE_EMISSIVE_TEX_INDEX, ATTRIBUTE_TEX_INDEX,
+        ATTRIBUTE_VOXEL_LIGHT, NO_EMISSIVE_TEXTURE,
+    },
 };
 
-type VoxelArray = Arc<[WorldVoxel; PaddedChunkShape::SIZE as usize]>;
+type VoxelArray<V> = Arc<crate::chunk::VoxelArray<V>>;
 
-/// Generate a mesh for the given chunks, or None of the chunk is empty
-pub(super) fn generate_chunk_mesh(
-    voxels: VoxelArray,
-    _pos: IVec3,
-    texture_index_mapper: Arc<dyn Fn(u8) -> [u32; 3] + Send + Sync>,
-) -> Mesh {
+/// The sides of a chunk where all faces are meshed, regardless of the voxels in the padding.
+///
+/// Chunks at different levels of detail don't line up at their shared border, which would leave
+/// holes in the terrain. Meshing the faces on those borders closes the holes with a skirt.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub(crate) struct OpenBorders(u8);
+
+impl OpenBorders {
+    fn bit(normal: IVec3) -> u8 {
+        let axis = normal
+            .abs()
+            .to_array()
+            .iter()
+            .position(|n| *n != 0)
+            .unwrap();
+        1 << (axis * 2 + (normal.max_element() > 0) as usize)
+    }
+
+    /// Open the border in the direction of the given face normal
+    pub fn open(&mut self, normal: IVec3) {
+        self.0 |= Self::bit(normal);
+    }
+
+    pub fn is_open(&self, normal: IVec3) -> bool {
+        self.0 & Self::bit(normal) != 0
+    }
+}
+
+/// Generate the meshes for the given chunk. The first mesh holds the opaque voxels, and the
+/// second one the translucent voxels, or `None` if the chunk has no visible translucent faces.
+///
+/// Faces are lit by the `light` of the voxel in front of them, or by full sunlight if the chunk
+/// has no light.
+pub(super) fn generate_chunk_mesh<V: VoxelData>(
+    voxels: VoxelArray<V>,
+    light: Option<Arc<[u8]>>,
+    chunk_size: UVec3,
+    open_borders: OpenBorders,
+    texture_index_mapper: TextureIndexMapperFn<V>,
+    emission_mapper: EmissionMapperFn<V>,
+    meshing_mode: MeshingMode,
+) -> (Mesh, Option<Mesh>) {
     let faces = RIGHT_HANDED_Y_UP_CONFIG.faces;
-    let mut buffer = UnitQuadBuffer::new();
-
-    visible_block_faces(
-        &*voxels,
-        &PaddedChunkShape {},
-        [0; 3],
-        [CHUNK_SIZE_U + 1; 3],
-        &faces,
-        &mut buffer,
+    let shape = padded_chunk_shape(chunk_size);
+    let light = light.as_deref();
+
+    let (opaque, translucent) = match meshing_mode {
+        MeshingMode::PerFace => visible_faces(&voxels, &shape, chunk_size, open_borders, &faces),
+        MeshingMode::Greedy => {
+            greedy_faces(&voxels, light, &shape, chunk_size, open_borders, &faces)
+        }
+    };
+
+    let translucent_mesh = (translucent.num_quads() > 0).then(|| {
+        mesh_from_quads(
+            translucent,
+            faces,
+            voxels.clone(),
+            light,
+            &shape,
+            texture_index_mapper.clone(),
+            emission_mapper.clone(),
+        )
+    });
+    let opaque_mesh = mesh_from_quads(
+        opaque,
+        faces,
+        voxels,
+        light,
+        &shape,
+        texture_index_mapper,
+        emission_mapper,
+    );
+
+    (opaque_mesh, translucent_mesh)
+}
+
+/// Generate the meshes for a box of voxels, with its minimum corner at the origin. Used for voxels
+/// that move on their own, like falling voxels and detached voxel bodies.
+pub(crate) fn generate_voxel_mesh<V: VoxelData>(
+    size: UVec3,
+    voxel_at: impl Fn(UVec3) -> V,
+    texture_index_mapper: TextureIndexMapperFn<V>,
+    emission_mapper: EmissionMapperFn<V>,
+) -> (Mesh, Option<Mesh>) {
+    let shape = padded_chunk_shape(size);
+    let mut voxels = vec![V::default(); shape.size() as usize];
+    for z in 0..size.z {
+        for y in 0..size.y {
+            for x in 0..size.x {
+                let index = shape.linearize([x + 1, y + 1, z + 1]) as usize;
+                voxels[index] = voxel_at(UVec3::new(x, y, z));
+            }
+        }
+    }
+
+    let (mut opaque, mut translucent) = generate_chunk_mesh(
+        voxels.into(),
+        None,
+        size,
+        OpenBorders::default(),
+        texture_index_mapper,
+        emission_mapper,
+        MeshingMode::PerFace,
     );
+    // Meshes start at the padding, so move the box back to the origin
+    for mesh in std::iter::once(&mut opaque).chain(translucent.as_mut()) {
+        if let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute_mut(Mesh::ATTRIBUTE_POSITION)
+        {
+            for position in positions.iter_mut() {
+                *position = (Vec3::from(*position) - Vec3::ONE).to_array();
+            }
+        }
+    }
+    (opaque, translucent)
+}
+
+/// Find the visible faces of all voxels in the chunk, split into faces of opaque voxels and
+/// faces of translucent voxels.
+fn visible_faces<V: VoxelData>(
+    voxels: &[V],
+    shape: &PaddedChunkShape,
+    chunk_size: UVec3,
+    open_borders: OpenBorders,
+    faces: &[OrientedBlockFace; 6],
+) -> (QuadBuffer, QuadBuffer) {
+    let mut opaque = QuadBuffer::new();
+    let mut translucent = QuadBuffer::new();
+
+    for z in 1..=chunk_size.z {
+        for y in 1..=chunk_size.y {
+            for x in 1..=chunk_size.x {
+                let voxel = &voxels[shape.linearize([x, y, z]) as usize];
+                let buffer = match voxel.visibility() {
+                    VoxelVisibility::Empty => continue,
+                    VoxelVisibility::Opaque => &mut opaque,
+                    VoxelVisibility::Translucent => &mut translucent,
+                };
 
-    mesh_from_quads(buffer, faces, voxels, texture_index_mapper)
+                for (group, face) in buffer.groups.iter_mut().zip(faces) {
+                    let normal = face.signed_normal();
+                    let normal = IVec3::new(normal.x, normal.y, normal.z);
+                    let pos = [x, y, z];
+                    if face_at_is_visible(voxels, shape, chunk_size, open_borders, pos, normal) {
+                        group.push(UnorientedQuad {
+                            minimum: [x, y, z],
+                            width: 1,
+                            height: 1,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    (opaque, translucent)
+}
+
+/// A visible voxel face found while greedy meshing: the index of the voxel, the ambient
+/// occlusion values of the face, and the packed light of the face.
+type GreedyFace = (usize, [u32; 4], u8);
+
+/// Find the visible faces of all voxels in the chunk like `visible_faces`, but merge neighbouring
+/// faces into larger quads.
+///
+/// Faces are only merged when their voxels have the same visibility and merge value, and when the
+/// faces have the same light and the same ambient occlusion on all four corners. Faces with occlusion that varies
+/// over the face are kept as single quads, so the occlusion doesn't get smeared over a larger quad.
+fn greedy_faces<V: VoxelData>(
+    voxels: &[V],
+    light: Option<&[u8]>,
+    shape: &PaddedChunkShape,
+    chunk_size: UVec3,
+    open_borders: OpenBorders,
+    faces: &[OrientedBlockFace; 6],
+) -> (QuadBuffer, QuadBuffer) {
+    let mut opaque = QuadBuffer::new();
+    let mut translucent = QuadBuffer::new();
+    let size = chunk_size.to_array().map(|s| s as usize);
+
+    for (face_index, face) in faces.iter().enumerate() {
+        let normal = face.signed_normal();
+        let normal_ivec = IVec3::new(normal.x, normal.y, normal.z);
+        let [n_axis, u_axis, v_axis] = face_axes(face);
+        let (u_len, v_len) = (size[u_axis], size[v_axis]);
+
+        let voxel_pos = |n: usize, u: usize, v: usize| {
+            let mut pos = [0; 3];
+            pos[n_axis] = n as u32;
+            pos[u_axis] = u as u32 + 1;
+            pos[v_axis] = v as u32 + 1;
+            pos
+        };
+
+        let mut mask: Vec<Option<GreedyFace>> = vec![None; u_len * v_len];
+
+        for n in 1..=size[n_axis] {
+            // Find the visible faces in this slice of the chunk
+            for v in 0..v_len {
+                for u in 0..u_len {
+                    let pos = voxel_pos(n, u, v);
+                    let index = shape.linearize(pos) as usize;
+                    let visible = face_at_is_visible(
+                        voxels,
+                        shape,
+                        chunk_size,
+                        open_borders,
+                        pos,
+                        normal_ivec,
+                    );
+
+                    mask[u + v * u_len] = visible.then(|| {
+                        (
+                            index,
+                            face_aos(&pos, &normal_ivec, voxels, shape),
+                            face_light(light, shape, pos, normal_ivec),
+                        )
+                    });
+                }
+            }
+
+            // Then grow each face into the largest rectangle of faces it can merge with
+            for v in 0..v_len {
+                for u in 0..u_len {
+                    let Some(current) = mask[u + v * u_len] else {
+                        continue;
+                    };
+                    let can_merge = |other: &Option<GreedyFace>| {
+                        other.is_some_and(|other| {
+                            faces_can_merge(voxels, &current, &other, normal_ivec)
+                        })
+                    };
+
+                    let mut width = 1;
+                    let mut height = 1;
+                    if current.1.iter().all(|ao| *ao == current.1[0]) {
+                        while u + width < u_len && can_merge(&mask[u + width + v * u_len]) {
+                            width += 1;
+                        }
+                        while v + height < v_len
+                            && (u..u + width).all(|u| can_merge(&mask[u + (v + height) * u_len]))
+                        {
+                            height += 1;
+                        }
+                    }
+
+                    for merged_v in v..v + height {
+                        for merged_u in u..u + width {
+                            mask[merged_u + merged_v * u_len] = None;
+                        }
+                    }
+
+                    let buffer = match voxels[current.0].visibility() {
+                        VoxelVisibility::Translucent => &mut translucent,
+                        _ => &mut opaque,
+                    };
+                    buffer.groups[face_index].push(UnorientedQuad {
+                        minimum: voxel_pos(n, u, v),
+                        width: width as u32,
+                        height: height as u32,
+                    });
+                }
+            }
+        }
+    }
+
+    (opaque, translucent)
+}
+
+/// Whether the face of the voxel at `pos` in the direction of `normal` should be drawn. Faces on an
+/// open border are drawn for all voxels that aren't empty.
+fn face_at_is_visible<V: VoxelData>(
+    voxels: &[V],
+    shape: &PaddedChunkShape,
+    chunk_size: UVec3,
+    open_borders: OpenBorders,
+    pos: [u32; 3],
+    normal: IVec3,
+) -> bool {
+    let voxel = &voxels[shape.linearize(pos) as usize];
+    let neighbour = (UVec3::from(pos).as_ivec3() + normal).as_uvec3();
+    let in_padding = neighbour.cmpeq(UVec3::ZERO).any() || neighbour.cmpgt(chunk_size).any();
+    if in_padding && open_borders.is_open(normal) {
+        return voxel.visibility() != VoxelVisibility::Empty;
+    }
+    let neighbour = &voxels[shape.linearize(neighbour.to_array()) as usize];
+    match (voxel.fluid_level(), neighbour.fluid_level()) {
+        // Within a body of fluid, only the sides standing above lower fluid are visible
+        (Some(level), Some(neighbour_level)) if voxel.material() == neighbour.material() => {
+            normal.y == 0 && neighbour_level.height() < level.height()
+        }
+        _ => face_is_visible(voxel, neighbour),
+    }
+}
+
+/// Faces of fluids only merge on the top and bottom of fluids at the same level, since the sides
+/// of a fluid voxel are cut off at the height of its surface
+fn faces_can_merge<V: VoxelData>(
+    voxels: &[V],
+    a: &GreedyFace,
+    b: &GreedyFace,
+    normal: IVec3,
+) -> bool {
+    let (voxel_a, voxel_b) = (&voxels[a.0], &voxels[b.0]);
+    let fluids_match = match (voxel_a.fluid_level(), voxel_b.fluid_level()) {
+        (None, None) => true,
+        (level_a, level_b) => normal.y != 0 && level_a == level_b,
+    };
+    a.1 == b.1
+        && a.2 == b.2
+        && fluids_match
+        && voxel_a.visibility() == voxel_b.visibility()
+        && voxel_a.merge_value() == voxel_b.merge_value()
+}
+
+/// Get the indexes of the normal, U and V axes of a face
+fn face_axes(face: &OrientedBlockFace) -> [usize; 3] {
+    let [min, max_u, max_v, _] = face.quad_corners(&UnorientedQuad {
+        minimum: [0; 3],
+        width: 1,
+        height: 1,
+    });
+    let axis_of = |delta: [u32; 3]| delta.iter().position(|d| *d != 0).unwrap();
+    let u_axis = axis_of((max_u - min).to_array());
+    let v_axis = axis_of((max_v - min).to_array());
+    [3 - u_axis - v_axis, u_axis, v_axis]
+}
+
+/// Whether the face of `voxel` that touches `neighbour` should be drawn.
+///
+/// Opaque voxels show their faces towards empty and translucent voxels. Translucent voxels only
+/// show their faces towards empty voxels and translucent voxels that are not identical to them,
+/// so the inside of a body of water or a glass wall isn't drawn.
+pub(crate) fn face_is_visible<V: VoxelData>(voxel: &V, neighbour: &V) -> bool {
+    match (voxel.visibility(), neighbour.visibility()) {
+        (VoxelVisibility::Empty, _) => false,
+        (_, VoxelVisibility::Empty) => true,
+        (VoxelVisibility::Opaque, VoxelVisibility::Translucent) => true,
+        (VoxelVisibility::Translucent, VoxelVisibility::Translucent) => voxel != neighbour,
+        (_, VoxelVisibility::Opaque) => false,
+    }
+}
+
+/// The packed light of the voxel in front of a face, which is the light the face receives
+fn face_light(light: Option<&[u8]>, shape: &PaddedChunkShape, pos: [u32; 3], normal: IVec3) -> u8 {
+    let Some(light) = light else {
+        return VoxelLight::SKY.pack();
+    };
+    let neighbour = (UVec3::from(pos).as_ivec3() + normal).as_uvec3();
+    light[shape.linearize(neighbour.to_array()) as usize]
 }
 
 /// Convert a QuadBuffer into a Bevy Mesh
-fn mesh_from_quads(
-    quads: UnitQuadBuffer,
+fn mesh_from_quads<V: VoxelData>(
+    quads: QuadBuffer,
     faces: [OrientedBlockFace; 6],
-    voxels: VoxelArray,
-    texture_index_mapper: Arc<dyn Fn(u8) -> [u32; 3] + Send + Sync>,
+    voxels: VoxelArray<V>,
+    light: Option<&[u8]>,
+    shape: &PaddedChunkShape,
+    texture_index_mapper: TextureIndexMapperFn<V>,
+    emission_mapper: EmissionMapperFn<V>,
 ) -> Mesh {
     let num_indices = quads.num_quads() * 6;
     let num_vertices = quads.num_quads() * 4;
@@ -60,8 +397,11 @@ fn mesh_from_quads(
     let mut tex_coords = Vec::with_capacity(num_vertices);
     let mut material_types = Vec::with_capacity(num_vertices);
     let mut aos = Vec::with_capacity(num_vertices);
+    let mut lights = Vec::with_capacity(num_vertices);
+    let mut emissives = Vec::with_capacity(num_vertices);
+    let mut emissive_tex_indexes = Vec::with_capacity(num_vertices);
 
-    for (group, face) in quads.groups.into_iter().zip(faces.into_iter()) {
+    for (group, face) in quads.groups.into_iter().zip(faces) {
         for quad in group.into_iter() {
             let normal = IVec3::from([
                 face.signed_normal().x,
@@ -69,28 +409,60 @@ fn mesh_from_quads(
                 face.signed_normal().z,
             ]);
 
-            let ao = face_aos(&quad.minimum, &normal, &voxels);
+            let ao = face_aos(&quad.minimum, &normal, &voxels, shape);
             aos.extend_from_slice(&ao);
 
+            // Merged quads always have the same occlusion on all corners, so the occlusion of the
+            // first voxel of the quad applies to the whole quad
             // TODO: Fix AO anisotropy
             indices.extend_from_slice(&face.quad_mesh_indices(positions.len() as u32));
 
-            positions.extend_from_slice(&face.quad_mesh_positions(&quad.into(), 1.0));
+            let mut quad_positions = face.quad_mesh_positions(&quad, 1.0);
+            let surface = fluid_surface_height(&voxels, shape, quad.minimum);
+            if surface < 1.0 {
+                // Lower the top edge of the quad to the surface of the fluid
+                let top = (quad.minimum[1] + 1) as f32;
+                for position in quad_positions.iter_mut() {
+                    if position[1] >= top {
+                        position[1] -= 1.0 - surface;
+                    }
+                }
+            }
+            positions.extend_from_slice(&quad_positions);
 
             normals.extend_from_slice(&face.quad_mesh_normals());
 
             tex_coords.extend_from_slice(&face.tex_coords(
                 RIGHT_HANDED_Y_UP_CONFIG.u_flip_face,
                 true,
-                &quad.into(),
+                &quad,
             ));
 
-            let voxel_index = PaddedChunkShape::linearize(quad.minimum) as usize;
-            let material_type = match voxels[voxel_index] {
-                WorldVoxel::Solid(mt) => texture_index_mapper(mt),
-                _ => [0, 0, 0],
+            let voxel_index = shape.linearize(quad.minimum) as usize;
+            let material = voxels[voxel_index].material();
+            let material_type = match material {
+                Some(mt) => texture_index_mapper(mt),
+                None => [0, 0, 0],
             };
-            material_types.extend(std::iter::repeat(material_type).take(4));
+            material_types.extend([material_type; 4]);
+
+            let emission = material.and_then(|mt| emission_mapper(mt));
+            let (emissive, emissive_tex_index) = match emission {
+                Some(emission) => (
+                    emission.linear_rgb(),
+                    emission.texture_index.unwrap_or(NO_EMISSIVE_TEXTURE),
+                ),
+                None => ([0.0; 3], NO_EMISSIVE_TEXTURE),
+            };
+            emissives.extend([emissive; 4]);
+            emissive_tex_indexes.extend([emissive_tex_index; 4]);
+
+            let face_light = VoxelLight::unpack(face_light(light, shape, quad.minimum, normal));
+            let face_light = [
+                face_light.sky as f32 / MAX_LIGHT as f32,
+                face_light.block as f32 / MAX_LIGHT as f32,
+            ];
+            lights.extend([face_light; 4]);
         }
     }
 
@@ -115,6 +487,18 @@ fn mesh_from_quads(
         ATTRIBUTE_TEX_INDEX,
         VertexAttributeValues::Uint32x3(material_types),
     );
+    render_mesh.insert_attribute(
+        ATTRIBUTE_VOXEL_LIGHT,
+        VertexAttributeValues::Float32x2(lights),
+    );
+    render_mesh.insert_attribute(
+        ATTRIBUTE_EMISSIVE,
+        VertexAttributeValues::Float32x3(emissives),
+    );
+    render_mesh.insert_attribute(
+        ATTRIBUTE_EMISSIVE_TEX_INDEX,
+        VertexAttributeValues::Uint32(emissive_tex_indexes),
+    );
 
     // Apply ambient occlusion values
     {
@@ -137,6 +521,24 @@ fn mesh_from_quads(
     render_mesh
 }
 
+/// The height of the surface of the fluid in a voxel, between 0 and 1. Voxels that aren't a fluid,
+/// and fluid under more of the same fluid, are filled all the way up.
+fn fluid_surface_height<V: VoxelData>(
+    voxels: &[V],
+    shape: &PaddedChunkShape,
+    pos: [u32; 3],
+) -> f32 {
+    let voxel = &voxels[shape.linearize(pos) as usize];
+    let Some(level) = voxel.fluid_level() else {
+        return 1.0;
+    };
+    let above = &voxels[shape.linearize([pos[0], pos[1] + 1, pos[2]]) as usize];
+    if above.fluid_level().is_some() && above.material() == voxel.material() {
+        return 1.0;
+    }
+    level.height()
+}
+
 fn ao_value(side1: bool, corner: bool, side2: bool) -> u32 {
     match (side1, corner, side2) {
         (true, _, true) => 0,
@@ -146,16 +548,18 @@ fn ao_value(side1: bool, corner: bool, side2: bool) -> u32 {
     }
 }
 
-fn side_aos(neighbours: [WorldVoxel; 8]) -> [u32; 4] {
+/// Ambient occlusion for the 4 corners of a face, from the 8 voxels around it. Only opaque
+/// voxels occlude, so translucent voxels like glass or water don't darken their neighbours.
+fn side_aos<V: VoxelData>(neighbours: [V; 8]) -> [u32; 4] {
     let ns = [
-        neighbours[0].get_visibility() == VoxelVisibility::Opaque,
-        neighbours[1].get_visibility() == VoxelVisibility::Opaque,
-        neighbours[2].get_visibility() == VoxelVisibility::Opaque,
-        neighbours[3].get_visibility() == VoxelVisibility::Opaque,
-        neighbours[4].get_visibility() == VoxelVisibility::Opaque,
-        neighbours[5].get_visibility() == VoxelVisibility::Opaque,
-        neighbours[6].get_visibility() == VoxelVisibility::Opaque,
-        neighbours[7].get_visibility() == VoxelVisibility::Opaque,
+        neighbours[0].visibility() == VoxelVisibility::Opaque,
+        neighbours[1].visibility() == VoxelVisibility::Opaque,
+        neighbours[2].visibility() == VoxelVisibility::Opaque,
+        neighbours[3].visibility() == VoxelVisibility::Opaque,
+        neighbours[4].visibility() == VoxelVisibility::Opaque,
+        neighbours[5].visibility() == VoxelVisibility::Opaque,
+        neighbours[6].visibility() == VoxelVisibility::Opaque,
+        neighbours[7].visibility() == VoxelVisibility::Opaque,
     ];
 
     [
@@ -166,69 +570,74 @@ fn side_aos(neighbours: [WorldVoxel; 8]) -> [u32; 4] {
     ]
 }
 
-fn face_aos(voxel_pos: &[u32; 3], face_normal: &IVec3, voxels: &VoxelArray) -> [u32; 4] {
+fn face_aos<V: VoxelData>(
+    voxel_pos: &[u32; 3],
+    face_normal: &IVec3,
+    voxels: &[V],
+    shape: &PaddedChunkShape,
+) -> [u32; 4] {
     let [x, y, z] = *voxel_pos;
 
     match *face_normal {
         IVec3::NEG_X => side_aos([
-            voxels[PaddedChunkShape::linearize([x - 1, y, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y - 1, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y - 1, z]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y - 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y + 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y + 1, z]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y + 1, z - 1]) as usize],
+            voxels[shape.linearize([x - 1, y, z - 1]) as usize],
+            voxels[shape.linearize([x - 1, y - 1, z - 1]) as usize],
+            voxels[shape.linearize([x - 1, y - 1, z]) as usize],
+            voxels[shape.linearize([x - 1, y - 1, z + 1]) as usize],
+            voxels[shape.linearize([x - 1, y, z + 1]) as usize],
+            voxels[shape.linearize([x - 1, y + 1, z + 1]) as usize],
+            voxels[shape.linearize([x - 1, y + 1, z]) as usize],
+            voxels[shape.linearize([x - 1, y + 1, z - 1]) as usize],
         ]),
         IVec3::X => side_aos([
-            voxels[PaddedChunkShape::linearize([x + 1, y, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y - 1, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y - 1, z]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y - 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y + 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y + 1, z]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y + 1, z - 1]) as usize],
+            voxels[shape.linearize([x + 1, y, z - 1]) as usize],
+            voxels[shape.linearize([x + 1, y - 1, z - 1]) as usize],
+            voxels[shape.linearize([x + 1, y - 1, z]) as usize],
+            voxels[shape.linearize([x + 1, y - 1, z + 1]) as usize],
+            voxels[shape.linearize([x + 1, y, z + 1]) as usize],
+            voxels[shape.linearize([x + 1, y + 1, z + 1]) as usize],
+            voxels[shape.linearize([x + 1, y + 1, z]) as usize],
+            voxels[shape.linearize([x + 1, y + 1, z - 1]) as usize],
         ]),
         IVec3::NEG_Y => side_aos([
-            voxels[PaddedChunkShape::linearize([x, y - 1, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y - 1, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y - 1, z]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y - 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x, y - 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y - 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y - 1, z]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y - 1, z - 1]) as usize],
+            voxels[shape.linearize([x, y - 1, z - 1]) as usize],
+            voxels[shape.linearize([x - 1, y - 1, z - 1]) as usize],
+            voxels[shape.linearize([x - 1, y - 1, z]) as usize],
+            voxels[shape.linearize([x - 1, y - 1, z + 1]) as usize],
+            voxels[shape.linearize([x, y - 1, z + 1]) as usize],
+            voxels[shape.linearize([x + 1, y - 1, z + 1]) as usize],
+            voxels[shape.linearize([x + 1, y - 1, z]) as usize],
+            voxels[shape.linearize([x + 1, y - 1, z - 1]) as usize],
         ]),
         IVec3::Y => side_aos([
-            voxels[PaddedChunkShape::linearize([x, y + 1, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y + 1, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y + 1, z]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y + 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x, y + 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y + 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y + 1, z]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y + 1, z - 1]) as usize],
+            voxels[shape.linearize([x, y + 1, z - 1]) as usize],
+            voxels[shape.linearize([x - 1, y + 1, z - 1]) as usize],
+            voxels[shape.linearize([x - 1, y + 1, z]) as usize],
+            voxels[shape.linearize([x - 1, y + 1, z + 1]) as usize],
+            voxels[shape.linearize([x, y + 1, z + 1]) as usize],
+            voxels[shape.linearize([x + 1, y + 1, z + 1]) as usize],
+            voxels[shape.linearize([x + 1, y + 1, z]) as usize],
+            voxels[shape.linearize([x + 1, y + 1, z - 1]) as usize],
         ]),
         IVec3::NEG_Z => side_aos([
-            voxels[PaddedChunkShape::linearize([x - 1, y, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y - 1, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x, y - 1, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y - 1, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y + 1, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x, y + 1, z - 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y + 1, z - 1]) as usize],
+            voxels[shape.linearize([x - 1, y, z - 1]) as usize],
+            voxels[shape.linearize([x - 1, y - 1, z - 1]) as usize],
+            voxels[shape.linearize([x, y - 1, z - 1]) as usize],
+            voxels[shape.linearize([x + 1, y - 1, z - 1]) as usize],
+            voxels[shape.linearize([x + 1, y, z - 1]) as usize],
+            voxels[shape.linearize([x + 1, y + 1, z - 1]) as usize],
+            voxels[shape.linearize([x, y + 1, z - 1]) as usize],
+            voxels[shape.linearize([x - 1, y + 1, z - 1]) as usize],
         ]),
         IVec3::Z => side_aos([
-            voxels[PaddedChunkShape::linearize([x - 1, y, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y - 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x, y - 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y - 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x + 1, y + 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x, y + 1, z + 1]) as usize],
-            voxels[PaddedChunkShape::linearize([x - 1, y + 1, z + 1]) as usize],
+            voxels[shape.linearize([x - 1, y, z + 1]) as usize],
+            voxels[shape.linearize([x - 1, y - 1, z + 1]) as usize],
+            voxels[shape.linearize([x, y - 1, z + 1]) as usize],
+            voxels[shape.linearize([x + 1, y - 1, z + 1]) as usize],
+            voxels[shape.linearize([x + 1, y, z + 1]) as usize],
+            voxels[shape.linearize([x + 1, y + 1, z + 1]) as usize],
+            voxels[shape.linearize([x, y + 1, z + 1]) as usize],
+            voxels[shape.linearize([x - 1, y + 1, z + 1]) as usize],
         ]),
         _ => unreachable!(),
     }
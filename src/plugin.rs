@@ -9,11 +9,24 @@ use bevy::{
 };
 
 use crate::{
-    configuration::{DefaultWorld, VoxelWorldConfig},
+    block_registry::{
+        apply_block_registry_changes, load_block_registry, BlockRegistry, BlockRegistryLoader,
+    },
+    character_controller::update_character_controllers,
+    configuration::{DefaultWorld, MeshingMode, VoxelWorldConfig},
+    falling_voxel::{
+        drop_unsupported_voxels, mesh_falling_voxels, update_falling_voxels, VoxelWillFall,
+        VoxelWillLand,
+    },
+    fluid::simulate_fluids,
+    integrity::mesh_voxel_bodies,
+    schematic::{Schematic, SchematicLoader},
+    vox_model::{VoxModel, VoxModelLoader},
     voxel_material::{
-        prepare_texture, LoadingTexture, StandardVoxelMaterial, TextureLayers,
-        VOXEL_TEXTURE_SHADER_HANDLE,
+        prepare_texture, set_repeating_sampler, DefaultSampler, LoadingTexture,
+        StandardVoxelMaterial, TextureLayers, VOXEL_TEXTURE_SHADER_HANDLE,
     },
+    voxel_prefab::{VoxelPrefab, VoxelPrefabLoader},
     voxel_world::*,
     voxel_world_internal::Internals,
 };
@@ -23,6 +36,13 @@ pub struct VoxelWorldMaterialHandle<M: Material> {
     pub handle: Handle<M>,
 }
 
+/// The material used for the translucent voxels of a world. When this resource doesn't exist,
+/// translucent voxels are rendered with the material in `VoxelWorldMaterialHandle`.
+#[derive(Resource)]
+pub struct VoxelWorldTranslucentMaterialHandle<M: Material> {
+    pub handle: Handle<M>,
+}
+
 /// The main plugin for the voxel world. This plugin sets up the voxel world and its dependencies.
 /// The type parameter `C` is used to differentiate between different voxel worlds with different configs.
 pub struct VoxelWorldPlugin<C, M = StandardMaterial>
@@ -34,6 +54,7 @@ where
     use_custom_material: bool,
     config: C,
     material: M,
+    translucent_material: Option<M>,
 }
 
 impl<C> VoxelWorldPlugin<C, StandardMaterial>
@@ -46,6 +67,7 @@ where
             spawn_meshes: true,
             use_custom_material: false,
             material: StandardMaterial::default(),
+            translucent_material: None,
         }
     }
 
@@ -55,6 +77,7 @@ where
             use_custom_material: false,
             config: C::default(),
             material: StandardMaterial::default(),
+            translucent_material: None,
         }
     }
 }
@@ -79,6 +102,18 @@ where
             use_custom_material: true,
             config: self.config,
             material,
+            translucent_material: None,
+        }
+    }
+
+    /// Use a separate material for translucent voxels, like glass or water. Usually this is the
+    /// same kind of material as the one given to `with_material`, but with alpha blending.
+    ///
+    /// Without this, translucent voxels are rendered with the main material.
+    pub fn with_translucent_material(self, material: M) -> Self {
+        Self {
+            translucent_material: Some(material),
+            ..self
         }
     }
 }
@@ -90,6 +125,7 @@ impl Default for VoxelWorldPlugin<DefaultWorld, StandardMaterial> {
             use_custom_material: false,
             config: DefaultWorld,
             material: StandardMaterial::default(),
+            translucent_material: None,
         }
     }
 }
@@ -106,8 +142,14 @@ where
                 PreUpdate,
                 (
                     (
-                        (Internals::<C>::spawn_chunks, Internals::<C>::retire_chunks).chain(),
-                        Internals::<C>::remesh_dirty_chunks,
+                        (
+                            Internals::<C>::stream_edit_regions,
+                            Internals::<C>::spawn_chunks,
+                            Internals::<C>::retire_chunks,
+                            Internals::<C>::update_chunk_lods,
+                        )
+                            .chain(),
+                        Internals::<C>::remesh_dirty_chunks.after(Internals::<C>::update_light),
                     )
                         .chain(),
                     (
@@ -117,13 +159,55 @@ where
                             Internals::<C>::flush_chunk_map_buffers,
                             Internals::<C>::flush_mesh_cache_buffers,
                         ),
+                        Internals::<C>::update_light,
                     )
                         .chain(),
                 ),
             )
+            .add_systems(
+                Update,
+                (
+                    update_character_controllers::<C>,
+                    simulate_fluids::<C>,
+                    (drop_unsupported_voxels::<C>, update_falling_voxels::<C>).chain(),
+                ),
+            )
+            .add_systems(Last, Internals::<C>::flush_edit_regions_on_exit)
             .add_event::<ChunkWillSpawn<C>>()
             .add_event::<ChunkWillDespawn<C>>()
-            .add_event::<ChunkWillRemesh<C>>();
+            .add_event::<ChunkWillRemesh<C>>()
+            .add_event::<ChunkWillChangeLod<C>>()
+            .add_event::<VoxelWillFall<C>>()
+            .add_event::<VoxelWillLand<C>>();
+
+        // The block registry, prefabs, vox models and schematics are assets, so they need the
+        // asset plugin
+        if app.world.contains_resource::<AssetServer>() {
+            if !app.world.contains_resource::<Assets<BlockRegistry>>() {
+                app.init_asset::<BlockRegistry>()
+                    .init_asset_loader::<BlockRegistryLoader>();
+            }
+            if !app.world.contains_resource::<Assets<VoxModel>>() {
+                app.init_asset::<VoxModel>()
+                    .init_asset_loader::<VoxModelLoader>();
+            }
+            if !app.world.contains_resource::<Assets<Schematic>>() {
+                app.init_asset::<Schematic>()
+                    .init_asset_loader::<SchematicLoader>();
+            }
+            if !app
+                .world
+                .contains_resource::<Assets<VoxelPrefab<C::Voxel>>>()
+            {
+                app.init_asset::<VoxelPrefab<C::Voxel>>()
+                    .init_asset_loader::<VoxelPrefabLoader<C::Voxel>>();
+            }
+            app.add_systems(Startup, load_block_registry::<C>)
+                .add_systems(
+                    PreUpdate,
+                    apply_block_registry_changes::<C>.before(Internals::<C>::remesh_dirty_chunks),
+                );
+        }
 
         // Spawning of meshes is optional, mainly to simplify testing.
         // This makes voxel_world work with a MinimalPlugins setup.
@@ -135,7 +219,14 @@ where
                 Shader::from_wgsl
             );
 
-            app.add_systems(Update, Internals::<C>::spawn_meshes);
+            app.add_systems(
+                Update,
+                (
+                    Internals::<C>::spawn_meshes,
+                    mesh_falling_voxels::<C>,
+                    mesh_voxel_bodies::<C>,
+                ),
+            );
         }
 
         if !self.use_custom_material && self.spawn_meshes {
@@ -148,6 +239,14 @@ where
                 >::default());
             }
 
+            // Greedy meshing tiles the texture over merged quads, so it needs to repeat
+            let default_sampler = (self.config.meshing_mode() == MeshingMode::Greedy).then(|| {
+                app.get_added_plugins::<ImagePlugin>()
+                    .first()
+                    .map(|plugin| plugin.default_sampler.clone())
+                    .unwrap_or_default()
+            });
+
             let mut preloaded_texture = true;
             let texture_conf = self.config.voxel_texture();
             let mut texture_layers = 0;
@@ -164,6 +263,9 @@ where
                 )
                 .unwrap();
                 image.reinterpret_stacked_2d_as_array(4);
+                if let Some(default_sampler) = &default_sampler {
+                    set_repeating_sampler(&mut image, default_sampler);
+                }
                 let mut image_assets = app.world.resource_mut::<Assets<Image>>();
                 image_assets.add(image)
             } else {
@@ -179,12 +281,25 @@ where
                 .resource_mut::<Assets<ExtendedMaterial<StandardMaterial, StandardVoxelMaterial>>>(
                 );
 
+            let base_material = StandardMaterial {
+                reflectance: 0.05,
+                metallic: 0.05,
+                perceptual_roughness: 0.95,
+                ..default()
+            };
+
             let mat_handle = material_assets.add(ExtendedMaterial {
+                base: base_material.clone(),
+                extension: StandardVoxelMaterial {
+                    voxels_texture: image_handle.clone(),
+                },
+            });
+
+            // Translucent voxels get their opacity from the alpha channel of the texture
+            let translucent_mat_handle = material_assets.add(ExtendedMaterial {
                 base: StandardMaterial {
-                    reflectance: 0.05,
-                    metallic: 0.05,
-                    perceptual_roughness: 0.95,
-                    ..default()
+                    alpha_mode: AlphaMode::Blend,
+                    ..base_material
                 },
                 extension: StandardVoxelMaterial {
                     voxels_texture: image_handle.clone(),
@@ -196,7 +311,13 @@ where
                 handle: image_handle,
             });
             app.insert_resource(VoxelWorldMaterialHandle { handle: mat_handle });
+            app.insert_resource(VoxelWorldTranslucentMaterialHandle {
+                handle: translucent_mat_handle,
+            });
             app.insert_resource(TextureLayers(texture_layers));
+            if let Some(default_sampler) = default_sampler {
+                app.insert_resource(DefaultSampler(default_sampler));
+            }
 
             app.insert_resource(self.config.clone());
 
@@ -214,7 +335,15 @@ where
             if self.config.init_custom_materials() {
                 let mut custom_material_assets = app.world.resource_mut::<Assets<M>>();
                 let handle = custom_material_assets.add(self.material.clone());
+                let translucent_handle = self
+                    .translucent_material
+                    .as_ref()
+                    .map(|material| custom_material_assets.add(material.clone()));
                 app.insert_resource(VoxelWorldMaterialHandle { handle });
+
+                if let Some(handle) = translucent_handle {
+                    app.insert_resource(VoxelWorldTranslucentMaterialHandle { handle });
+                }
             }
 
             app.insert_resource(LoadingTexture {
@@ -0,0 +1,469 @@
+///
+/// Schematics
+/// Import of Sponge `.schem` files and vanilla structure `.nbt` files, the formats most
+/// community builds are shared in. Block names are mapped to material ids, and schematics can be
+/// pasted into a world like prefabs.
+///
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    utils::BoxedFuture,
+};
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    edit_storage::{invalid_data, read_array, read_varint},
+    voxel::VoxelData,
+    voxel_prefab::{is_pasted, pasted_position, PasteOptions, VoxelPrefab},
+};
+
+/// Maps the block names of schematics to material ids.
+///
+/// A block is looked up by its full name first, like `"minecraft:oak_stairs[facing=north]"`,
+/// then without its block state (`"minecraft:oak_stairs"`), and finally without the
+/// `minecraft:` namespace (`"oak_stairs"`). This is also the settings type of the schematic
+/// asset loader.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SchematicBlockMapping {
+    /// Material id for each block name
+    pub blocks: HashMap<String, u32>,
+}
+
+impl SchematicBlockMapping {
+    /// Map the block `name` to `material`
+    pub fn with(mut self, name: impl Into<String>, material: u32) -> Self {
+        self.blocks.insert(name.into(), material);
+        self
+    }
+
+    /// The material id of a block, or `None` if the block isn't mapped
+    pub fn material(&self, name: &str) -> Option<u32> {
+        let without_state = name.split_once('[').map_or(name, |(name, _)| name);
+        let without_namespace = without_state.strip_prefix("minecraft:");
+        [Some(name), Some(without_state), without_namespace]
+            .into_iter()
+            .flatten()
+            .find_map(|name| self.blocks.get(name).copied())
+    }
+}
+
+/// A box of blocks imported from a Sponge schematic (`.schem`, versions 2 and 3) or a
+/// vanilla structure file (`.nbt`).
+///
+/// Air blocks are kept, and pasted as empty voxels unless `air_is_transparent` is set. Blocks
+/// without a material in the mapping are left unset, and their names are kept in
+/// `unmapped_blocks` to help building the mapping. Block entities and entities are ignored.
+#[derive(Asset, TypePath, Clone, Debug, PartialEq, Eq)]
+pub struct Schematic {
+    size: UVec3,
+    voxels: Vec<(UVec3, u32)>,
+    air: Vec<UVec3>,
+    unmapped_blocks: Vec<String>,
+}
+
+impl Schematic {
+    /// Convert the schematic to a prefab, creating a voxel from the material id of each filled
+    /// position with `voxel`. Air positions are empty, and unmapped positions are unset, so they
+    /// are never pasted.
+    ///
+    /// The prefab holds every position of the box, so `pasted_voxels` is cheaper for pasting.
+    pub fn to_prefab<V: VoxelData>(&self, voxel: impl Fn(u32) -> V) -> VoxelPrefab<V> {
+        VoxelPrefab::from_voxels(self.size, self.schematic_voxels(voxel))
+    }
+
+    /// The world positions and voxels written when pasting the schematic with its minimum corner
+    /// at `position`, like `VoxelPrefab::pasted_voxels`. Only the filled and air voxels are
+    /// visited.
+    pub fn pasted_voxels<'a, V: VoxelData>(
+        &'a self,
+        position: IVec3,
+        options: PasteOptions,
+        voxel: impl Fn(u32) -> V + 'a,
+    ) -> impl Iterator<Item = (IVec3, V)> + 'a {
+        self.schematic_voxels(voxel)
+            .filter(move |(_, voxel)| is_pasted(voxel, &options))
+            .map(move |(local, voxel)| {
+                (
+                    position + pasted_position(local, self.size, &options),
+                    voxel,
+                )
+            })
+    }
+
+    pub fn size(&self) -> UVec3 {
+        self.size
+    }
+
+    /// The position and material id of each filled voxel
+    pub fn voxels(&self) -> &[(UVec3, u32)] {
+        &self.voxels
+    }
+
+    /// The position of each air block
+    pub fn air(&self) -> &[UVec3] {
+        &self.air
+    }
+
+    /// The names of all blocks that weren't air and had no material in the mapping
+    pub fn unmapped_blocks(&self) -> &[String] {
+        &self.unmapped_blocks
+    }
+
+    /// Read a schematic, mapping its block names to material ids with `mapping`. The file can
+    /// be gzip compressed, like the files written by Minecraft, or uncompressed NBT. Schematics
+    /// covering more than 2^26 positions are rejected.
+    pub fn read_from<R: Read>(mut reader: R, mapping: &SchematicBlockMapping) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let root = if bytes.starts_with(&GZIP_MAGIC) {
+            Nbt::read_root(&mut GzDecoder::new(bytes.as_slice()))?
+        } else {
+            Nbt::read_root(&mut bytes.as_slice())?
+        };
+
+        let blocks = if root.get("blocks").is_some() {
+            read_structure(&root)?
+        } else {
+            read_sponge_schematic(&root)?
+        };
+
+        let mut voxels = Vec::new();
+        let mut air = Vec::new();
+        let mut unmapped_blocks = Vec::new();
+        for (position, name) in blocks.blocks {
+            match mapping.material(name) {
+                Some(material) => voxels.push((position, material)),
+                None if is_air(name) => air.push(position),
+                None => {
+                    if !unmapped_blocks.iter().any(|unmapped| unmapped == name) {
+                        unmapped_blocks.push(name.to_string());
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            size: blocks.size,
+            voxels,
+            air,
+            unmapped_blocks,
+        })
+    }
+
+    /// Load a schematic from a file at `path`. See `read_from`.
+    pub fn load(path: impl AsRef<Path>, mapping: &SchematicBlockMapping) -> io::Result<Self> {
+        let file = File::open(path)?;
+        Self::read_from(BufReader::new(file), mapping)
+    }
+
+    /// The local position and voxel of each filled and air block
+    fn schematic_voxels<'a, V: VoxelData>(
+        &'a self,
+        voxel: impl Fn(u32) -> V + 'a,
+    ) -> impl Iterator<Item = (UVec3, V)> + 'a {
+        let filled = self.voxels.iter();
+        let filled = filled.map(move |(local, material)| (*local, voxel(*material)));
+        filled.chain(self.air.iter().map(|local| (*local, V::empty())))
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Nested compounds and lists deeper than this are rejected
+const NBT_MAX_DEPTH: usize = 512;
+
+/// Schematics covering more positions than this are rejected
+const SCHEMATIC_MAX_VOLUME: u64 = 1 << 26;
+
+fn check_volume(size: UVec3) -> io::Result<()> {
+    let volume = (size.x as u64 * size.y as u64).checked_mul(size.z as u64);
+    match volume {
+        Some(volume) if volume <= SCHEMATIC_MAX_VOLUME => Ok(()),
+        _ => Err(invalid_data("schematic is too large")),
+    }
+}
+
+fn is_air(name: &str) -> bool {
+    let name = name.split_once('[').map_or(name, |(name, _)| name);
+    matches!(
+        name,
+        "minecraft:air" | "minecraft:cave_air" | "minecraft:void_air" | "minecraft:structure_void"
+    )
+}
+
+/// The block names of a schematic, before they are mapped to materials
+struct SchematicBlocks<'a> {
+    size: UVec3,
+    blocks: Vec<(UVec3, &'a str)>,
+}
+
+/// Read a Sponge schematic. Version 3 nests everything in a `Schematic` compound, and moves
+/// the palette and block data into a `Blocks` compound.
+fn read_sponge_schematic(root: &Nbt) -> io::Result<SchematicBlocks<'_>> {
+    let schematic = root.get("Schematic").unwrap_or(root);
+    let dimension = |name: &str| {
+        let dimension = schematic.get(name).and_then(Nbt::as_int);
+        match dimension.and_then(|dimension| u16::try_from(dimension).ok()) {
+            Some(dimension) => Ok(dimension as u32),
+            None => Err(invalid_data(format!("schematic has no valid {name}"))),
+        }
+    };
+    let size = UVec3::new(
+        dimension("Width")?,
+        dimension("Height")?,
+        dimension("Length")?,
+    );
+    check_volume(size)?;
+
+    let (palette, data) = match schematic.get("Blocks") {
+        Some(blocks) => (blocks.get("Palette"), blocks.get("Data")),
+        None => (schematic.get("Palette"), schematic.get("BlockData")),
+    };
+    let (Some(Nbt::Compound(palette)), Some(Nbt::ByteArray(data))) = (palette, data) else {
+        return Err(invalid_data("schematic has no block palette or data"));
+    };
+
+    let mut names = HashMap::new();
+    for (name, index) in palette {
+        let index = index
+            .as_int()
+            .ok_or_else(|| invalid_data("invalid palette index"))?;
+        names.insert(index as u64, name.as_str());
+    }
+
+    // Blocks are stored by x first, then z, then y
+    let mut data = data.as_slice();
+    let mut blocks = Vec::new();
+    for y in 0..size.y {
+        for z in 0..size.z {
+            for x in 0..size.x {
+                let index = read_varint(&mut data)?;
+                let name = names
+                    .get(&index)
+                    .ok_or_else(|| invalid_data("schematic palette index out of bounds"))?;
+                blocks.push((UVec3::new(x, y, z), *name));
+            }
+        }
+    }
+    Ok(SchematicBlocks { size, blocks })
+}
+
+/// Read a vanilla structure file. Structures with several palettes, like shipwrecks, use the
+/// first one.
+fn read_structure(root: &Nbt) -> io::Result<SchematicBlocks<'_>> {
+    let size = read_position(root.get("size"))
+        .ok_or_else(|| invalid_data("structure has no valid size"))?;
+    check_volume(size)?;
+
+    let palette = match root.get("palette") {
+        Some(palette) => palette.as_list(),
+        None => root
+            .get("palettes")
+            .and_then(Nbt::as_list)
+            .and_then(|palettes| palettes.first())
+            .and_then(Nbt::as_list),
+    };
+    let names = palette
+        .ok_or_else(|| invalid_data("structure has no block palette"))?
+        .iter()
+        .map(|state| state.get("Name").and_then(Nbt::as_str))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| invalid_data("structure palette entry has no name"))?;
+
+    let Some(blocks) = root.get("blocks").and_then(Nbt::as_list) else {
+        return Err(invalid_data("structure has no blocks"));
+    };
+    let blocks = blocks
+        .iter()
+        .map(|block| {
+            let position = read_position(block.get("pos")).filter(|pos| pos.cmplt(size).all());
+            let state = block.get("state").and_then(Nbt::as_int);
+            let name = state.and_then(|state| names.get(usize::try_from(state).ok()?));
+            match (position, name) {
+                (Some(position), Some(name)) => Ok((position, *name)),
+                _ => Err(invalid_data("invalid structure block")),
+            }
+        })
+        .collect::<io::Result<_>>()?;
+    Ok(SchematicBlocks { size, blocks })
+}
+
+/// Read a list of three non-negative integers
+fn read_position(tag: Option<&Nbt>) -> Option<UVec3> {
+    match tag?.as_list()? {
+        [x, y, z] => Some(UVec3::new(
+            u32::try_from(x.as_int()?).ok()?,
+            u32::try_from(y.as_int()?).ok()?,
+            u32::try_from(z.as_int()?).ok()?,
+        )),
+        _ => None,
+    }
+}
+
+/// A value of Minecraft's Named Binary Tag format. All numbers are big endian.
+#[derive(Debug)]
+enum Nbt {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    ByteArray(Vec<u8>),
+    String(String),
+    List(Vec<Nbt>),
+    Compound(HashMap<String, Nbt>),
+    /// A value schematics don't need, like floats and long arrays. Only skipped when reading.
+    Other,
+}
+
+const NBT_TAG_END: u8 = 0;
+const NBT_TAG_COMPOUND: u8 = 10;
+
+impl Nbt {
+    /// Read the root of an NBT file, which is a named compound
+    fn read_root<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let [tag] = read_array(reader)?;
+        if tag != NBT_TAG_COMPOUND {
+            return Err(invalid_data("NBT root is not a compound"));
+        }
+        let _name = read_nbt_string(reader)?;
+        Self::read_payload(reader, tag, 0)
+    }
+
+    fn read_payload<R: Read>(reader: &mut R, tag: u8, depth: usize) -> io::Result<Self> {
+        if depth > NBT_MAX_DEPTH {
+            return Err(invalid_data("NBT is nested too deeply"));
+        }
+        Ok(match tag {
+            1 => Nbt::Byte(i8::from_be_bytes(read_array(reader)?)),
+            2 => Nbt::Short(i16::from_be_bytes(read_array(reader)?)),
+            3 => Nbt::Int(i32::from_be_bytes(read_array(reader)?)),
+            4 | 6 => skip_nbt(reader, 8)?,
+            5 => skip_nbt(reader, 4)?,
+            7 => {
+                let len = read_nbt_len(reader)?;
+                let mut bytes = Vec::new();
+                reader.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+                if bytes.len() != len {
+                    return Err(invalid_data("NBT byte array is truncated"));
+                }
+                Nbt::ByteArray(bytes)
+            }
+            8 => Nbt::String(read_nbt_string(reader)?),
+            9 => {
+                let [item_tag] = read_array(reader)?;
+                let len = read_nbt_len(reader)?;
+                let items = (0..len).map(|_| Self::read_payload(reader, item_tag, depth + 1));
+                Nbt::List(items.collect::<io::Result<_>>()?)
+            }
+            NBT_TAG_COMPOUND => {
+                let mut compound = HashMap::new();
+                loop {
+                    let [tag] = read_array(reader)?;
+                    if tag == NBT_TAG_END {
+                        break;
+                    }
+                    let name = read_nbt_string(reader)?;
+                    compound.insert(name, Self::read_payload(reader, tag, depth + 1)?);
+                }
+                Nbt::Compound(compound)
+            }
+            11 => {
+                let len = read_nbt_len(reader)?;
+                skip_nbt(reader, len as u64 * 4)?
+            }
+            12 => {
+                let len = read_nbt_len(reader)?;
+                skip_nbt(reader, len as u64 * 8)?
+            }
+            tag => return Err(invalid_data(format!("unknown NBT tag {tag}"))),
+        })
+    }
+
+    /// Get a value of a compound
+    fn get(&self, name: &str) -> Option<&Nbt> {
+        match self {
+            Nbt::Compound(compound) => compound.get(name),
+            _ => None,
+        }
+    }
+
+    /// Get an integer value that fits in an `i32`
+    fn as_int(&self) -> Option<i32> {
+        match *self {
+            Nbt::Byte(value) => Some(value as i32),
+            Nbt::Short(value) => Some(value as i32),
+            Nbt::Int(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Nbt::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_list(&self) -> Option<&[Nbt]> {
+        match self {
+            Nbt::List(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/// Read the length of an NBT array or list. Negative lengths are treated as empty.
+fn read_nbt_len<R: Read>(reader: &mut R) -> io::Result<usize> {
+    Ok(i32::from_be_bytes(read_array(reader)?).max(0) as usize)
+}
+
+/// Skip `len` bytes of a value that isn't needed
+fn skip_nbt<R: Read>(reader: &mut R, len: u64) -> io::Result<Nbt> {
+    let skipped = io::copy(&mut reader.by_ref().take(len), &mut io::sink())?;
+    if skipped != len {
+        return Err(invalid_data("NBT value is truncated"));
+    }
+    Ok(Nbt::Other)
+}
+
+/// Read an NBT string. NBT uses a modified UTF-8, which is decoded lossily.
+fn read_nbt_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let len = u16::from_be_bytes(read_array(reader)?);
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Loads `Schematic` assets from Sponge `.schem` files and vanilla structure `.nbt` files.
+/// The block mapping is given through the loader settings.
+#[derive(Default)]
+pub(crate) struct SchematicLoader;
+
+impl AssetLoader for SchematicLoader {
+    type Asset = Schematic;
+    type Settings = SchematicBlockMapping;
+    type Error = io::Error;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        settings: &'a SchematicBlockMapping,
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<Schematic, io::Error>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            Schematic::read_from(bytes.as_slice(), settings)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["schem", "nbt"]
+    }
+}
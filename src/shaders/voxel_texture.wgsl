@@ -52,7 +52,10 @@ struct Vertex {
     @builtin(vertex_index) index: u32,
 #endif
 
-    @location(8) tex_idx: vec3<u32>
+    @location(8) tex_idx: vec3<u32>,
+    @location(9) light: vec2<f32>,
+    @location(10) emissive: vec3<f32>,
+    @location(11) emissive_tex_idx: u32,
 };
 
 struct CustomVertexOutput {
@@ -76,6 +79,9 @@ struct CustomVertexOutput {
 #endif
 
     @location(8) tex_idx: vec3<u32>,
+    @location(9) light: vec2<f32>,
+    @location(10) emissive: vec3<f32>,
+    @location(11) @interpolate(flat) emissive_tex_idx: u32,
 }
 
 @vertex
@@ -110,6 +116,9 @@ fn vertex(vertex: Vertex) -> CustomVertexOutput {
 #endif
 
     out.tex_idx = vertex.tex_idx;
+    out.light = vertex.light;
+    out.emissive = vertex.emissive;
+    out.emissive_tex_idx = vertex.emissive_tex_idx;
 
     return out;
 }
@@ -140,6 +149,20 @@ fn fragment(
     pbr_input.material.base_color = textureSample(mat_array_texture, mat_array_texture_sampler, in.uv, in.tex_idx[tex_face]);
     pbr_input.material.base_color = pbr_input.material.base_color * in.color;
 
+    // Voxel light: x is the skylight and y the block light. Every light level is a bit dimmer
+    // than the previous one, so light fades out gradually away from its source.
+    let voxel_light = max(in.light.x, in.light.y);
+    let light_factor = pow(0.8, (1.0 - voxel_light) * 15.0);
+    pbr_input.material.base_color = vec4<f32>(pbr_input.material.base_color.rgb * light_factor, pbr_input.material.base_color.a);
+
+    // Emission, optionally masked by a layer of the texture array. Faces without a mask use
+    // 0xffffffff as the mask index. It is added to the emission of the base material.
+    var emissive = in.emissive;
+    if in.emissive_tex_idx != 0xffffffffu {
+        emissive = emissive * textureSample(mat_array_texture, mat_array_texture_sampler, in.uv, in.emissive_tex_idx).rgb;
+    }
+    pbr_input.material.emissive = pbr_input.material.emissive + vec4<f32>(emissive, 0.0);
+
     pbr_input.material.base_color = alpha_discard(pbr_input.material, pbr_input.material.base_color);
 
 #ifdef PREPASS_PIPELINE
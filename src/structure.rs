@@ -0,0 +1,318 @@
+///
+/// Structures
+/// Seeded placement of prefabs like trees, houses and dungeons. Placements are decided per cell
+/// of a coarse grid from the seed alone, so every chunk a structure overlaps places its own part
+/// of it, and the result doesn't depend on the order chunks are generated in.
+///
+use std::sync::Arc;
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::{
+    chunk::{lod_scale, FillType},
+    configuration::{ChunkGeneratorFn, VoxelLookupFn},
+    generation::{position_hash, position_random, StageContext},
+    voxel::{VoxelData, WorldVoxel},
+    voxel_prefab::{PasteOptions, VoxelPrefab},
+};
+
+/// The height of the bottom of a structure placed with its footprint centered on a column, or
+/// `None` to not place the structure there
+pub type StructureHeightFn = Arc<dyn Fn(i32, i32) -> Option<i32> + Send + Sync>;
+
+// Salts keeping the random choices of a placement independent of each other
+const CHANCE_SALT: u32 = 0x51c3_0001;
+const ROTATION_SALT: u32 = 0x51c3_0002;
+const OFFSET_X_SALT: u32 = 0x51c3_0003;
+const OFFSET_Z_SALT: u32 = 0x51c3_0004;
+
+/// A prefab placed at random in the world, at most once per cell of a grid
+#[derive(Clone)]
+pub struct Structure<V: VoxelData = WorldVoxel> {
+    name: String,
+    prefab: Arc<VoxelPrefab<V>>,
+    spacing: u32,
+    chance: f32,
+    random_rotation: bool,
+    options: PasteOptions,
+    height: StructureHeightFn,
+}
+
+impl<V: VoxelData> Structure<V> {
+    /// A structure placed in every cell of a grid with cells `spacing` voxels wide, with its
+    /// bottom at height 0. When the spacing is at least the size of the prefab, placements of
+    /// the structure never overlap each other.
+    pub fn new(name: impl Into<String>, prefab: VoxelPrefab<V>, spacing: u32) -> Self {
+        Self {
+            name: name.into(),
+            prefab: Arc::new(prefab),
+            spacing: spacing.max(1),
+            chance: 1.0,
+            random_rotation: false,
+            options: PasteOptions::default(),
+            height: Arc::new(|_, _| Some(0)),
+        }
+    }
+
+    /// The chance of the structure being placed in a cell, between 0 and 1
+    pub fn with_chance(mut self, chance: f32) -> Self {
+        self.chance = chance;
+        self
+    }
+
+    /// Turn each placement a random number of quarter turns around the Y axis
+    pub fn with_random_rotation(mut self) -> Self {
+        self.random_rotation = true;
+        self
+    }
+
+    /// The options the prefab is pasted with. The rotation is ignored with
+    /// `with_random_rotation`.
+    pub fn with_paste_options(mut self, options: PasteOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Where the structure is placed vertically. The function gets the column at the center of
+    /// the footprint, and returns the height of the bottom of the structure, or `None` to skip
+    /// the placement. It has to be deterministic, like a lookup of the terrain height from noise
+    /// or `BiomeLayer::column`, since every chunk the structure overlaps calls it.
+    pub fn with_height(
+        mut self,
+        height: impl Fn(i32, i32) -> Option<i32> + Send + Sync + 'static,
+    ) -> Self {
+        self.height = Arc::new(height);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn prefab(&self) -> &VoxelPrefab<V> {
+        &self.prefab
+    }
+
+    pub fn spacing(&self) -> u32 {
+        self.spacing
+    }
+}
+
+/// Where a structure is placed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StructurePlacement {
+    /// The index of the structure in its `StructureLayer`
+    pub structure: usize,
+
+    /// The grid cell the structure was placed in
+    pub cell: IVec2,
+
+    /// The minimum corner of the placed prefab
+    pub position: IVec3,
+
+    /// The size of the placed prefab, after rotation
+    pub size: UVec3,
+
+    pub options: PasteOptions,
+}
+
+impl StructurePlacement {
+    /// The minimum corner of the box covered by the structure
+    pub fn min(&self) -> IVec3 {
+        self.position
+    }
+
+    /// The maximum corner of the box covered by the structure, inclusive
+    pub fn max(&self) -> IVec3 {
+        self.position + self.size.as_ivec3() - 1
+    }
+
+    /// Whether the structure overlaps the box from `min` to `max`, inclusive
+    pub fn overlaps(&self, min: IVec3, max: IVec3) -> bool {
+        self.min().cmple(max).all() && self.max().cmpge(min).all()
+    }
+}
+
+/// A set of structures placed in a world from a seed.
+///
+/// Each structure has its own grid. In every cell of the grid, the seed decides whether the
+/// structure is placed, where in the cell and how it's turned, without looking at any voxels.
+/// Every chunk overlapping a placement finds it and writes its own part of the structure, so
+/// structures can span any number of chunks. Where structures overlap, the one of the structure
+/// added last wins, and within a structure, the one in the cell with the highest z, then x.
+///
+/// ```
+/// # use bevy::prelude::*;
+/// # use bevy_voxel_world::prelude::*;
+/// let tree = VoxelPrefab::<WorldVoxel>::from_fn(UVec3::new(5, 7, 5), |position| {
+///     match position {
+///         UVec3 { x: 2, y: 0..=3, z: 2 } => WorldVoxel::Solid(1),
+///         UVec3 { y: 4.., .. } => WorldVoxel::Solid(2),
+///         _ => WorldVoxel::Unset,
+///     }
+/// });
+/// let structures = StructureLayer::new(1234).with_structure(
+///     Structure::new("tree", tree, 12)
+///         .with_chance(0.5)
+///         .with_random_rotation()
+///         .with_height(|x, z| Some(ground_height(x, z) + 1)),
+/// );
+/// # fn ground_height(_x: i32, _z: i32) -> i32 { 0 }
+/// ```
+#[derive(Clone)]
+pub struct StructureLayer<V: VoxelData = WorldVoxel> {
+    seed: u32,
+    structures: Vec<Structure<V>>,
+}
+
+impl<V: VoxelData> StructureLayer<V> {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            seed,
+            structures: Vec::new(),
+        }
+    }
+
+    /// Add a structure, placed over the structures added before it where they overlap
+    pub fn with_structure(mut self, structure: Structure<V>) -> Self {
+        self.structures.push(structure);
+        self
+    }
+
+    pub fn structures(&self) -> &[Structure<V>] {
+        &self.structures
+    }
+
+    /// The placement of a structure in a cell of its grid, if it's placed there
+    pub fn placement(&self, structure_index: usize, cell: IVec2) -> Option<StructurePlacement> {
+        let structure = self.structures.get(structure_index)?;
+        let seed = self.seed ^ (structure_index as u32).wrapping_mul(0x9e37_79b9);
+        let cell_position = IVec3::new(cell.x, 0, cell.y);
+        if position_random(seed ^ CHANCE_SALT, cell_position) >= structure.chance {
+            return None;
+        }
+
+        let mut options = structure.options;
+        if structure.random_rotation {
+            options.quarter_turns = (position_hash(seed ^ ROTATION_SALT, cell_position) % 4) as u8;
+        }
+        let size = structure.prefab.pasted_size(&options);
+        // Keep the structure inside its cell when it fits, so placements don't overlap
+        let spacing = structure.spacing;
+        let offset = |salt, size: u32| {
+            let room = spacing.saturating_sub(size) + 1;
+            (position_hash(seed ^ salt, cell_position) % room) as i32
+        };
+        let min = cell * spacing as i32
+            + IVec2::new(offset(OFFSET_X_SALT, size.x), offset(OFFSET_Z_SALT, size.z));
+        let center = min + (size.xz() / 2).as_ivec2();
+        let y = (structure.height)(center.x, center.y)?;
+        Some(StructurePlacement {
+            structure: structure_index,
+            cell,
+            position: IVec3::new(min.x, y, min.y),
+            size,
+            options,
+        })
+    }
+
+    /// All placements overlapping the box from `min` to `max`, inclusive, in the order they are
+    /// written
+    pub fn placements(&self, min: IVec3, max: IVec3) -> Vec<StructurePlacement> {
+        let mut placements = Vec::new();
+        for (index, structure) in self.structures.iter().enumerate() {
+            let spacing = structure.spacing as i32;
+            let size = structure.prefab.size();
+            // A placement covers at most this many voxels from the start of its cell
+            let reach = spacing.max(size.x.max(size.z) as i32);
+            let first = (min.xz() - reach + 1).div_euclid(IVec2::splat(spacing));
+            let last = max.xz().div_euclid(IVec2::splat(spacing));
+            for z in first.y..=last.y {
+                for x in first.x..=last.x {
+                    placements.extend(
+                        self.placement(index, IVec2::new(x, z))
+                            .filter(|placement| placement.overlaps(min, max)),
+                    );
+                }
+            }
+        }
+        placements
+    }
+
+    /// The voxels of all structures in the box from `min` to `max`, inclusive
+    pub fn voxels_in(&self, min: IVec3, max: IVec3) -> HashMap<IVec3, V> {
+        let mut voxels = HashMap::new();
+        for placement in self.placements(min, max) {
+            let prefab = &self.structures[placement.structure].prefab;
+            voxels.extend(
+                prefab
+                    .pasted_voxels(placement.position, placement.options)
+                    .filter(|(position, _)| position.cmpge(min).all() && position.cmple(max).all()),
+            );
+        }
+        voxels
+    }
+
+    /// Place the structures overlapping the chunk of a `GenerationPipeline` stage. Placements
+    /// don't read any voxels, so the stage doesn't need any reach.
+    pub fn fill_stage(&self, chunk: &mut StageContext<V>) {
+        for placement in self.placements(chunk.min(), chunk.max()) {
+            let prefab = &self.structures[placement.structure].prefab;
+            for (position, voxel) in prefab.pasted_voxels(placement.position, placement.options) {
+                chunk.set(position, voxel);
+            }
+        }
+    }
+}
+
+/// Wrap a chunk generator, placing structures over the chunks it generates
+pub(crate) fn structure_chunk_generator<V: VoxelData>(
+    structures: Arc<StructureLayer<V>>,
+    generator: ChunkGeneratorFn<V>,
+) -> ChunkGeneratorFn<V> {
+    Arc::new(move |chunk| {
+        let fill_type = generator(chunk);
+        let scale = lod_scale(chunk.lod()) as i32;
+        let min = chunk.world_position(UVec3::ZERO);
+        let max = chunk.world_position(chunk.size() - 1) + scale - 1;
+        let voxels = structures.voxels_in(min, max);
+        if voxels.is_empty() {
+            return fill_type;
+        }
+
+        match fill_type {
+            FillType::Empty => chunk.voxels_mut().fill(V::default()),
+            FillType::Uniform(voxel) => chunk.voxels_mut().fill(voxel),
+            FillType::Mixed => {}
+        }
+        for (position, voxel) in voxels {
+            // At lower levels of detail, only the first voxel of each sample is kept
+            let offset = position - min;
+            if offset.rem_euclid(IVec3::splat(scale)) == IVec3::ZERO {
+                chunk.set((offset / scale).as_uvec3(), voxel);
+            }
+        }
+        FillType::Mixed
+    })
+}
+
+/// Wrap a lookup function, placing structures over the voxels it generates. The structure
+/// voxels are worked out once for each chunk looked up in.
+pub(crate) fn structure_lookup<V: VoxelData>(
+    structures: Arc<StructureLayer<V>>,
+    chunk_size: UVec3,
+    mut lookup: VoxelLookupFn<V>,
+) -> VoxelLookupFn<V> {
+    let mut chunks: HashMap<IVec3, HashMap<IVec3, V>> = HashMap::new();
+    Box::new(move |position| {
+        let chunk_position = position.div_euclid(chunk_size.as_ivec3());
+        let voxels = chunks.entry(chunk_position).or_insert_with(|| {
+            let min = chunk_position * chunk_size.as_ivec3();
+            structures.voxels_in(min, min + chunk_size.as_ivec3() - 1)
+        });
+        match voxels.get(&position) {
+            Some(voxel) => *voxel,
+            None => lookup(position),
+        }
+    })
+}
@@ -1,31 +1,331 @@
-use bevy::prelude::*;
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bevy::{
+    ecs::system::RunSystemOnce, math::bounding::Aabb3d, prelude::*,
+    render::mesh::VertexAttributeValues, time::TimeUpdateStrategy, utils::HashMap,
+};
 
-use crate::chunk_map::ChunkMapUpdateBuffer;
+use crate::block_registry::MaterialMappers;
+use crate::chunk_map::{ChunkMap, ChunkMapUpdateBuffer};
+use crate::fluid::FluidSimulation;
+use crate::generation::{PipelineRunner, WorldGenerator};
+use crate::lighting::{ChunkLight, LightMap};
 use crate::mesh_cache::MeshCacheInsertBuffer;
+use crate::meshing::face_is_visible;
+use crate::persistence::{
+    load_voxels, read_voxels, region_file_name, save_voxels, write_voxels, EditPages,
+    REQUESTED_REGION_PASSES,
+};
+use crate::physics::{ground_check, is_grounded, overlap_capsule, overlap_sphere, sweep_aabb};
 use crate::prelude::*;
+use crate::voxel_material::{
+    ATTRIBUTE_EMISSIVE, ATTRIBUTE_EMISSIVE_TEX_INDEX, ATTRIBUTE_TEX_INDEX, ATTRIBUTE_VOXEL_LIGHT,
+    NO_EMISSIVE_TEXTURE,
+};
 use crate::voxel_traversal::voxel_line_traversal;
+use crate::voxel_world_internal::{get_chunk_voxel_position, ModifiedVoxels, WorldRoot};
 use crate::{
-    chunk::{ChunkData, FillType},
+    chunk::{lod_at_distance, next_lod, ChunkData, ChunkTask, FillType},
+    meshing::OpenBorders,
     prelude::VoxelWorldCamera,
     voxel_world::*,
 };
 
 fn _test_setup_app() -> App {
+    _test_setup_app_with_config::<DefaultWorld>()
+}
+
+fn _test_setup_app_with_config<C: VoxelWorldConfig>() -> App {
     let mut app = App::new();
-    app.add_plugins((MinimalPlugins, VoxelWorldPlugin::<DefaultWorld>::minimal()));
+    app.add_plugins((MinimalPlugins, VoxelWorldPlugin::<C>::minimal()));
     app.add_systems(Startup, |mut commands: Commands| {
         commands.spawn((
             Camera3dBundle {
                 transform: Transform::from_xyz(10.0, 10.0, 10.0).looking_at(Vec3::ZERO, Vec3::Y),
                 ..default()
             },
-            VoxelWorldCamera::<DefaultWorld>::default(),
+            VoxelWorldCamera::<C>::default(),
         ));
     });
 
     app
 }
 
+/// Generate a chunk and put it in the chunk map. Chunks are only generated when meshes are
+/// spawned, which tests don't do.
+fn _insert_generated_chunk<C: VoxelWorldConfig>(app: &mut App, position: IVec3) {
+    let config = C::default();
+    let entity = app
+        .world
+        .query::<&Chunk<C>>()
+        .iter(&app.world)
+        .find(|chunk| chunk.position == position)
+        .unwrap()
+        .entity;
+    let mut chunk_task = ChunkTask::<C>::new(entity, position, config.chunk_size(), default());
+    if config.voxel_lighting() {
+        let light = app.world.resource::<LightMap<C>>().chunk_light(position);
+        chunk_task = chunk_task.with_light(light);
+    }
+    chunk_task.generate((config.voxel_lookup_delegate())(position));
+    chunk_task.bake_light(&config.light_emission_mapper());
+    app.world.resource_mut::<ChunkMapUpdateBuffer<C>>().push((
+        position,
+        chunk_task.chunk_data,
+        ChunkWillSpawn::<C>::new(position, entity),
+    ));
+    app.update();
+}
+
+#[derive(Resource, Clone, Default)]
+struct NonCubicChunkWorld;
+
+impl VoxelWorldConfig for NonCubicChunkWorld {
+    type Voxel = WorldVoxel;
+
+    fn chunk_size(&self) -> UVec3 {
+        UVec3::new(16, 8, 16)
+    }
+}
+
+/// A voxel type with more data than `WorldVoxel`, to test worlds with custom voxel types
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, TypePath)]
+struct RotatedBlock {
+    block: u16,
+    rotation: u8,
+}
+
+const ROTATED_BLOCK_AIR: u16 = 1;
+
+impl VoxelData for RotatedBlock {
+    type Material = u16;
+    type MergeValue = (u16, u8);
+
+    fn visibility(&self) -> VoxelVisibility {
+        match self.block {
+            0 | ROTATED_BLOCK_AIR => VoxelVisibility::Empty,
+            _ => VoxelVisibility::Opaque,
+        }
+    }
+
+    fn merge_value(&self) -> Self::MergeValue {
+        (self.block, self.rotation)
+    }
+
+    fn material(&self) -> Option<Self::Material> {
+        self.is_solid().then_some(self.block)
+    }
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(&self.block.to_le_bytes())?;
+        writer.write_all(&[self.rotation])
+    }
+
+    fn read_from(reader: &mut dyn Read) -> io::Result<Self> {
+        let mut bytes = [0; 3];
+        reader.read_exact(&mut bytes)?;
+        Ok(Self {
+            block: u16::from_le_bytes([bytes[0], bytes[1]]),
+            rotation: bytes[2],
+        })
+    }
+
+    fn empty() -> Self {
+        Self {
+            block: ROTATED_BLOCK_AIR,
+            rotation: 0,
+        }
+    }
+}
+
+#[derive(Resource, Clone, Default)]
+struct RotatedBlockWorld;
+
+impl VoxelWorldConfig for RotatedBlockWorld {
+    type Voxel = RotatedBlock;
+
+    fn voxel_lookup_delegate(&self) -> VoxelLookupDelegate<RotatedBlock> {
+        Box::new(|_| {
+            Box::new(|pos: IVec3| {
+                if pos.y < 2 {
+                    RotatedBlock {
+                        block: 300,
+                        rotation: 2,
+                    }
+                } else {
+                    RotatedBlock {
+                        block: ROTATED_BLOCK_AIR,
+                        rotation: 0,
+                    }
+                }
+            })
+        })
+    }
+}
+
+#[derive(Resource, Clone, Default)]
+struct WideMaterialWorld;
+
+impl VoxelWorldConfig for WideMaterialWorld {
+    type Voxel = WorldVoxel<u16>;
+
+    fn texture_index_mapper(&self) -> TextureIndexMapperFn<WorldVoxel<u16>> {
+        Arc::new(|mat: u16| [mat as u32, mat as u32 + 1, mat as u32 + 2])
+    }
+
+    fn voxel_lookup_delegate(&self) -> VoxelLookupDelegate<WorldVoxel<u16>> {
+        Box::new(|_| {
+            Box::new(|pos: IVec3| {
+                if pos.y < 2 {
+                    WorldVoxel::Solid(1000)
+                } else {
+                    WorldVoxel::Air
+                }
+            })
+        })
+    }
+}
+
+#[derive(Resource, Clone, Default)]
+struct GlassWorld;
+
+impl VoxelWorldConfig for GlassWorld {
+    type Voxel = WorldVoxel;
+
+    // Stone ground, with two glass voxels on top
+    fn voxel_lookup_delegate(&self) -> VoxelLookupDelegate {
+        Box::new(|_| {
+            Box::new(|pos: IVec3| {
+                if pos.y < 1 {
+                    WorldVoxel::Solid(0)
+                } else if pos.y == 1 && pos.z == 0 && (pos.x == 0 || pos.x == 1) {
+                    WorldVoxel::Translucent(1)
+                } else {
+                    WorldVoxel::Air
+                }
+            })
+        })
+    }
+}
+
+#[derive(Resource, Clone, Default)]
+struct GreedyWorld;
+
+impl VoxelWorldConfig for GreedyWorld {
+    type Voxel = WorldVoxel;
+
+    fn meshing_mode(&self) -> MeshingMode {
+        MeshingMode::Greedy
+    }
+
+    // Like `GlassWorld`, with an extra stone voxel on the ground that occludes its neighbours
+    fn voxel_lookup_delegate(&self) -> VoxelLookupDelegate {
+        Box::new(|chunk_pos| {
+            let mut glass_world = (GlassWorld.voxel_lookup_delegate())(chunk_pos);
+            Box::new(move |pos: IVec3| {
+                if pos == IVec3::new(5, 1, 5) {
+                    WorldVoxel::Solid(0)
+                } else {
+                    glass_world(pos)
+                }
+            })
+        })
+    }
+}
+
+#[derive(Resource, Clone, Default)]
+struct LodWorld;
+
+impl VoxelWorldConfig for LodWorld {
+    type Voxel = WorldVoxel;
+
+    fn lod_distances(&self) -> Vec<u32> {
+        vec![2, 4]
+    }
+
+    // Flat stone ground
+    fn voxel_lookup_delegate(&self) -> VoxelLookupDelegate {
+        Box::new(|_| {
+            Box::new(|pos: IVec3| {
+                if pos.y < 1 {
+                    WorldVoxel::Solid(0)
+                } else {
+                    WorldVoxel::Air
+                }
+            })
+        })
+    }
+}
+
+#[derive(Resource, Clone, Default)]
+struct LitWorld;
+
+const LAMP: u8 = 5;
+
+impl VoxelWorldConfig for LitWorld {
+    type Voxel = WorldVoxel;
+
+    fn voxel_lighting(&self) -> bool {
+        true
+    }
+
+    fn light_emission_mapper(&self) -> LightEmissionFn {
+        Arc::new(|material| if material == LAMP { 14 } else { 0 })
+    }
+
+    // Stone ground under a wide roof, with a lamp under the roof
+    fn voxel_lookup_delegate(&self) -> VoxelLookupDelegate {
+        Box::new(|_| {
+            Box::new(|pos: IVec3| {
+                let under_roof = pos.x.abs() < 20 && pos.z.abs() < 20;
+                if pos.y < 1 || (pos.y == 4 && under_roof) {
+                    WorldVoxel::Solid(0)
+                } else if pos == IVec3::new(2, 1, 2) {
+                    WorldVoxel::Solid(LAMP)
+                } else {
+                    WorldVoxel::Air
+                }
+            })
+        })
+    }
+}
+
+#[derive(Resource, Clone, Default)]
+struct GlowingWorld;
+
+impl VoxelWorldConfig for GlowingWorld {
+    type Voxel = WorldVoxel;
+
+    fn emission_mapper(&self) -> EmissionMapperFn {
+        Arc::new(|material| match material {
+            1 => Some(VoxelEmission::new(Color::RED, 2.0)),
+            2 => Some(VoxelEmission::new(Color::WHITE, 1.0).with_texture(6)),
+            _ => None,
+        })
+    }
+
+    // Stone, lava and glowing ore voxels next to each other
+    fn voxel_lookup_delegate(&self) -> VoxelLookupDelegate {
+        Box::new(|_| {
+            Box::new(|pos: IVec3| -> WorldVoxel {
+                match pos {
+                    IVec3 { x: 0, y: 0, z: 0 } => WorldVoxel::Solid(0),
+                    IVec3 { x: 2, y: 0, z: 0 } => WorldVoxel::Solid(1),
+                    IVec3 { x: 4, y: 0, z: 0 } => WorldVoxel::Solid(2),
+                    _ => WorldVoxel::Air,
+                }
+            })
+        })
+    }
+}
+
 #[test]
 fn can_set_get_voxels() {
     let mut app = _test_setup_app();
@@ -54,6 +354,2362 @@ fn can_set_get_voxels() {
     app.update();
 }
 
+#[test]
+fn custom_voxel_type() {
+    let mut app = _test_setup_app_with_config::<RotatedBlockWorld>();
+    let voxel = RotatedBlock {
+        block: 1000,
+        rotation: 3,
+    };
+
+    app.add_systems(
+        Startup,
+        move |mut voxel_world: VoxelWorld<RotatedBlockWorld>| {
+            voxel_world.set_voxel(IVec3::new(0, 1, 0), voxel);
+            assert_eq!(voxel_world.get_voxel(IVec3::new(0, 1, 0)), voxel);
+        },
+    );
+
+    app.update();
+
+    app.add_systems(Update, move |voxel_world: VoxelWorld<RotatedBlockWorld>| {
+        let ray = Ray3d::new(Vec3::new(0.5, 5.0, 0.5), -Vec3::Y);
+        let result = voxel_world.raycast(ray, &|_| true).unwrap();
+        assert_eq!(result.voxel_pos(), IVec3::new(0, 1, 0));
+        assert_eq!(result.voxel, voxel);
+    });
+
+    app.update();
+
+    // Generated voxels keep their extra data, and the chunk gets meshed
+    let config = RotatedBlockWorld;
+    let mut chunk_task = ChunkTask::<RotatedBlockWorld>::new(
+        Entity::PLACEHOLDER,
+        IVec3::ZERO,
+        config.chunk_size(),
+        Default::default(),
+    );
+    chunk_task.generate((config.voxel_lookup_delegate())(IVec3::ZERO));
+    chunk_task.mesh(
+        config.texture_index_mapper(),
+        config.emission_mapper(),
+        config.meshing_mode(),
+    );
+    assert_eq!(
+        chunk_task.chunk_data.get_voxel(UVec3::new(1, 1, 1)),
+        RotatedBlock {
+            block: 300,
+            rotation: 2,
+        }
+    );
+    assert!(chunk_task.mesh.is_some());
+
+    let mut voxels = HashMap::new();
+    voxels.insert(IVec3::new(3, -7, 12), voxel);
+    let mut bytes = Vec::new();
+    write_voxels(&mut bytes, &voxels, UVec3::splat(32)).unwrap();
+    assert_eq!(read_voxels(bytes.as_slice()).unwrap(), voxels);
+}
+
+#[test]
+fn more_than_256_materials() {
+    let mut app = _test_setup_app_with_config::<WideMaterialWorld>();
+
+    app.add_systems(Update, |mut voxel_world: VoxelWorld<WideMaterialWorld>| {
+        voxel_world.set_voxel(IVec3::new(4, 5, 6), WorldVoxel::Solid(60000));
+        assert_eq!(
+            voxel_world.get_voxel(IVec3::new(4, 5, 6)),
+            WorldVoxel::Solid(60000)
+        );
+    });
+
+    app.update();
+
+    // Wide material ids are passed all the way to the texture index vertex attribute
+    let config = WideMaterialWorld;
+    let mut chunk_task = ChunkTask::<WideMaterialWorld>::new(
+        Entity::PLACEHOLDER,
+        IVec3::ZERO,
+        config.chunk_size(),
+        Default::default(),
+    );
+    chunk_task.generate((config.voxel_lookup_delegate())(IVec3::ZERO));
+    chunk_task.mesh(
+        config.texture_index_mapper(),
+        config.emission_mapper(),
+        config.meshing_mode(),
+    );
+    let mesh = chunk_task.mesh.unwrap();
+    let Some(VertexAttributeValues::Uint32x3(tex_indexes)) = mesh.attribute(ATTRIBUTE_TEX_INDEX)
+    else {
+        panic!("Mesh has no texture indexes")
+    };
+    assert!(!tex_indexes.is_empty());
+    assert!(tex_indexes.iter().all(|idx| *idx == [1000, 1001, 1002]));
+
+    let mut voxels = HashMap::new();
+    voxels.insert(IVec3::new(-3, 8, 1), WorldVoxel::Solid(60000u16));
+    voxels.insert(IVec3::new(-3, 9, 1), WorldVoxel::Air);
+    let mut bytes = Vec::new();
+    write_voxels(&mut bytes, &voxels, UVec3::splat(32)).unwrap();
+    assert_eq!(read_voxels(bytes.as_slice()).unwrap(), voxels);
+
+    // Edits with wide material ids can't be read as narrow ones
+    let error = read_voxels::<WorldVoxel<u8>, _>(bytes.as_slice()).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn translucent_voxels_are_meshed_separately() {
+    let config = GlassWorld;
+    let mut chunk_task = ChunkTask::<GlassWorld>::new(
+        Entity::PLACEHOLDER,
+        IVec3::ZERO,
+        config.chunk_size(),
+        Default::default(),
+    );
+    chunk_task.generate((config.voxel_lookup_delegate())(IVec3::ZERO));
+    chunk_task.mesh(
+        config.texture_index_mapper(),
+        config.emission_mapper(),
+        config.meshing_mode(),
+    );
+
+    // The faces between the two glass voxels, and between the glass and the stone below are
+    // culled, leaving 8 faces
+    let translucent_mesh = chunk_task.translucent_mesh.unwrap();
+    assert_eq!(translucent_mesh.count_vertices(), 8 * 4);
+
+    // The stone keeps its top faces below the glass, and the glass doesn't occlude them
+    let opaque_mesh = chunk_task.mesh.unwrap();
+    assert_eq!(opaque_mesh.count_vertices(), 32 * 32 * 4);
+    let Some(VertexAttributeValues::Float32x4(colors)) =
+        opaque_mesh.attribute(Mesh::ATTRIBUTE_COLOR)
+    else {
+        panic!("Mesh has no ambient occlusion colors")
+    };
+    assert!(colors.iter().all(|color| *color == [1.0, 1.0, 1.0, 1.0]));
+
+    let stone: WorldVoxel = WorldVoxel::Solid(0);
+    let glass = WorldVoxel::Translucent(1);
+    let water = WorldVoxel::Translucent(2);
+    assert!(face_is_visible(&stone, &glass));
+    assert!(!face_is_visible(&glass, &stone));
+    assert!(!face_is_visible(&glass, &glass));
+    assert!(face_is_visible(&glass, &water));
+    assert!(face_is_visible(&glass, &WorldVoxel::Air));
+}
+
+#[test]
+fn greedy_meshing_merges_faces() {
+    fn mesh_chunk<C: VoxelWorldConfig>(config: C, meshing_mode: MeshingMode) -> (Mesh, Mesh) {
+        let mut chunk_task = ChunkTask::<C>::new(
+            Entity::PLACEHOLDER,
+            IVec3::ZERO,
+            config.chunk_size(),
+            Default::default(),
+        );
+        chunk_task.generate((config.voxel_lookup_delegate())(IVec3::ZERO));
+        chunk_task.mesh(
+            config.texture_index_mapper(),
+            config.emission_mapper(),
+            meshing_mode,
+        );
+        (
+            chunk_task.mesh.unwrap(),
+            chunk_task.translucent_mesh.unwrap(),
+        )
+    }
+
+    // Returns the area and the corner colors of each quad in the mesh
+    fn quads(mesh: &Mesh) -> Vec<(f32, Vec<[f32; 4]>)> {
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            panic!("Mesh has no positions")
+        };
+        let Some(VertexAttributeValues::Float32x4(colors)) = mesh.attribute(Mesh::ATTRIBUTE_COLOR)
+        else {
+            panic!("Mesh has no ambient occlusion colors")
+        };
+        positions
+            .chunks(4)
+            .zip(colors.chunks(4))
+            .map(|(corners, colors)| {
+                let min = corners.iter().fold(Vec3::MAX, |a, c| a.min(Vec3::from(*c)));
+                let max = corners.iter().fold(Vec3::MIN, |a, c| a.max(Vec3::from(*c)));
+                let extent = (max - min).max(Vec3::ONE);
+                (extent.x * extent.y * extent.z, colors.to_vec())
+            })
+            .collect()
+    }
+
+    let (per_face_opaque, per_face_translucent) = mesh_chunk(GreedyWorld, MeshingMode::PerFace);
+    let (greedy_opaque, greedy_translucent) = mesh_chunk(GreedyWorld, MeshingMode::Greedy);
+
+    // The top faces of the two glass voxels merge. The side faces don't, since the stone below
+    // occludes their bottom corners.
+    assert_eq!(quads(&per_face_translucent).len(), 8);
+    assert_eq!(quads(&greedy_translucent).len(), 7);
+
+    // Merged quads cover exactly the same faces
+    let greedy_quads = quads(&greedy_opaque);
+    let per_face_quads = quads(&per_face_opaque);
+    assert!(greedy_quads.len() < per_face_quads.len() / 10);
+    assert_eq!(
+        greedy_quads.iter().map(|(area, _)| area).sum::<f32>(),
+        per_face_quads.len() as f32
+    );
+
+    // Faces are only merged when the occlusion is the same on all corners
+    for (area, colors) in greedy_quads {
+        if area > 1.0 {
+            assert!(colors.iter().all(|color| *color == colors[0]));
+        }
+    }
+
+    // The texture repeats over merged quads
+    let Some(VertexAttributeValues::Float32x2(uvs)) = greedy_opaque.attribute(Mesh::ATTRIBUTE_UV_0)
+    else {
+        panic!("Mesh has no uvs")
+    };
+    assert!(uvs.iter().any(|uv| uv[0] > 1.0 || uv[1] > 1.0));
+}
+
+#[test]
+fn lod_chunks_are_downsampled() {
+    fn quad_count(lod: u8, open_borders: OpenBorders) -> usize {
+        let config = LodWorld;
+        let mut chunk_task = ChunkTask::<LodWorld>::new(
+            Entity::PLACEHOLDER,
+            IVec3::ZERO,
+            config.chunk_size(),
+            Default::default(),
+        )
+        .with_lod(lod, open_borders);
+        chunk_task.generate((config.voxel_lookup_delegate())(IVec3::ZERO));
+        assert_eq!(chunk_task.chunk_data.lod_size(), UVec3::splat(32 >> lod));
+        // Voxels are looked up in the sample covering them, so the air above the ground is
+        // covered by the ground sample at lower levels of detail
+        let expected = if lod == 0 {
+            WorldVoxel::Air
+        } else {
+            WorldVoxel::Solid(0)
+        };
+        assert_eq!(
+            chunk_task.chunk_data.get_voxel(UVec3::new(4, 2, 4)),
+            expected
+        );
+        chunk_task.mesh(
+            config.texture_index_mapper(),
+            config.emission_mapper(),
+            config.meshing_mode(),
+        );
+        chunk_task.mesh.unwrap().count_vertices() / 4
+    }
+
+    assert_eq!(quad_count(0, OpenBorders::default()), 32 * 32);
+    assert_eq!(quad_count(1, OpenBorders::default()), 16 * 16);
+    assert_eq!(quad_count(3, OpenBorders::default()), 4 * 4);
+
+    // Open borders get a skirt of faces towards the neighbour
+    let mut open_borders = OpenBorders::default();
+    open_borders.open(IVec3::NEG_X);
+    assert_eq!(quad_count(1, open_borders), 16 * 16 + 16);
+}
+
+#[test]
+fn lod_switches_with_hysteresis() {
+    let lod_distances = [2, 4, 6, 8];
+    assert_eq!(lod_at_distance(&lod_distances, 0.0), 0);
+    assert_eq!(lod_at_distance(&lod_distances, 4.5), 2);
+    // There are only 3 levels of detail
+    assert_eq!(lod_at_distance(&lod_distances, 100.0), 3);
+
+    // Chunks close to a LOD distance keep their level
+    assert_eq!(next_lod(&lod_distances, 0, 2.5), 0);
+    assert_eq!(next_lod(&lod_distances, 1, 1.5), 1);
+    assert_eq!(next_lod(&lod_distances, 0, 3.5), 1);
+    assert_eq!(next_lod(&lod_distances, 2, 0.5), 0);
+}
+
+#[test]
+fn chunk_will_change_lod_event() {
+    let mut app = _test_setup_app_with_config::<LodWorld>();
+    app.update();
+
+    // Move the camera 3 chunks away from the chunks around the origin
+    app.add_systems(
+        Update,
+        |mut query: Query<&mut GlobalTransform, With<VoxelWorldCamera<LodWorld>>>| {
+            for mut transform in query.iter_mut() {
+                *transform = GlobalTransform::from(Transform::from_xyz(100.0, 10.0, 10.0));
+            }
+        },
+    );
+    app.update();
+
+    app.add_systems(
+        Update,
+        |mut ev_chunk_will_change_lod: EventReader<ChunkWillChangeLod<LodWorld>>| {
+            let changes: Vec<_> = ev_chunk_will_change_lod.read().collect();
+            assert!(changes
+                .iter()
+                .any(|ev| ev.chunk_key == IVec3::ZERO && ev.from_lod == 0 && ev.to_lod == 1));
+        },
+    );
+    app.update();
+
+    let mut query = app.world.query::<&Chunk<LodWorld>>();
+    let chunk = query
+        .iter(&app.world)
+        .find(|chunk| chunk.position == IVec3::ZERO)
+        .unwrap();
+    assert_eq!(chunk.lod, 1);
+}
+
+#[test]
+fn voxels_are_lit() {
+    let mut app = _test_setup_app_with_config::<LitWorld>();
+    app.update();
+    _insert_generated_chunk::<LitWorld>(&mut app, IVec3::ZERO);
+
+    let light_at = |app: &mut App, pos: IVec3| {
+        app.world
+            .run_system_once(move |voxel_world: VoxelWorld<LitWorld>| {
+                voxel_world.get_light(pos).unwrap()
+            })
+    };
+
+    // Sunlight above the roof, darkness under it except for the light of the lamp
+    assert_eq!(
+        light_at(&mut app, IVec3::new(0, 10, 0)),
+        VoxelLight { sky: 15, block: 0 }
+    );
+    assert_eq!(
+        light_at(&mut app, IVec3::new(2, 2, 2)),
+        VoxelLight { sky: 0, block: 13 }
+    );
+    assert_eq!(
+        light_at(&mut app, IVec3::new(0, 2, 0)),
+        VoxelLight { sky: 0, block: 9 }
+    );
+    // Sunlight spreads in under the edge of the roof
+    assert_eq!(
+        light_at(&mut app, IVec3::new(19, 2, 0)),
+        VoxelLight { sky: 14, block: 0 }
+    );
+
+    // The light ends up in the mesh
+    let config = LitWorld;
+    let light = app
+        .world
+        .resource::<LightMap<LitWorld>>()
+        .padded_light(IVec3::ZERO);
+    let mut chunk_task = ChunkTask::<LitWorld>::new(
+        Entity::PLACEHOLDER,
+        IVec3::ZERO,
+        config.chunk_size(),
+        Default::default(),
+    )
+    .with_light(ChunkLight::Lit(light));
+    chunk_task.generate((config.voxel_lookup_delegate())(IVec3::ZERO));
+    chunk_task.mesh(
+        config.texture_index_mapper(),
+        config.emission_mapper(),
+        config.meshing_mode(),
+    );
+    let Some(VertexAttributeValues::Float32x2(lights)) = chunk_task
+        .mesh
+        .unwrap()
+        .attribute(ATTRIBUTE_VOXEL_LIGHT)
+        .cloned()
+    else {
+        panic!("Mesh has no light")
+    };
+    assert!(lights.contains(&[1.0, 0.0]));
+    assert!(lights.contains(&[0.0, 13.0 / 15.0]));
+}
+
+#[test]
+fn chunks_are_lit_by_their_task() {
+    let mut app = _test_setup_app_with_config::<LitWorld>();
+    app.update();
+    _insert_generated_chunk::<LitWorld>(&mut app, IVec3::ZERO);
+    _insert_generated_chunk::<LitWorld>(&mut app, IVec3::NEG_X);
+
+    // The lamp in the first chunk lights the second one across the border
+    let light = app
+        .world
+        .run_system_once(|voxel_world: VoxelWorld<LitWorld>| {
+            voxel_world.get_light(IVec3::new(-1, 2, 0)).unwrap()
+        });
+    assert_eq!(light, VoxelLight { sky: 0, block: 8 });
+
+    // The chunk was meshed with the light its task baked, which is the light it ends up with
+    let chunk_data = app
+        .world
+        .resource::<ChunkMap<LitWorld>>()
+        .get_map()
+        .read()
+        .unwrap()
+        .get(&IVec3::NEG_X)
+        .cloned()
+        .unwrap();
+    let padded = get_chunk_voxel_position(IVec3::new(-1, 2, 0), UVec3::splat(32)).1;
+    assert_eq!(chunk_data.get_light(padded), Some(light));
+}
+
+#[test]
+fn light_is_updated_around_edits() {
+    let mut app = _test_setup_app_with_config::<LitWorld>();
+    app.update();
+    _insert_generated_chunk::<LitWorld>(&mut app, IVec3::ZERO);
+
+    let edit = |app: &mut App, change: fn(&mut VoxelWorld<LitWorld>)| {
+        app.world
+            .run_system_once(move |mut voxel_world: VoxelWorld<LitWorld>| change(&mut voxel_world));
+        app.world
+            .resource_mut::<Events<ChunkWillRemesh<LitWorld>>>()
+            .clear();
+        app.update();
+        app.world
+            .resource_mut::<Events<ChunkWillRemesh<LitWorld>>>()
+            .drain()
+            .map(|event| event.chunk_key)
+            .collect::<Vec<_>>()
+    };
+    let light_at = |app: &mut App, pos: IVec3| {
+        app.world
+            .run_system_once(move |voxel_world: VoxelWorld<LitWorld>| {
+                voxel_world.get_light(pos).unwrap()
+            })
+    };
+
+    // Opening the roof lets the sunlight in, and taking the lamp away darkens the room. Only the
+    // chunk whose light changed is remeshed.
+    let remeshed = edit(&mut app, |world| {
+        world.set_voxel(IVec3::new(0, 4, 0), WorldVoxel::Air);
+        world.set_voxel(IVec3::new(2, 1, 2), WorldVoxel::Air);
+    });
+    assert_eq!(remeshed, vec![IVec3::ZERO]);
+    assert_eq!(
+        light_at(&mut app, IVec3::new(0, 2, 0)),
+        VoxelLight { sky: 15, block: 0 }
+    );
+    assert_eq!(
+        light_at(&mut app, IVec3::new(2, 2, 2)),
+        VoxelLight { sky: 11, block: 0 }
+    );
+
+    // Closing the roof again takes the sunlight away
+    edit(&mut app, |world| {
+        world.set_voxel(IVec3::new(0, 4, 0), WorldVoxel::Solid(0));
+    });
+    assert_eq!(
+        light_at(&mut app, IVec3::new(0, 2, 0)),
+        VoxelLight::default()
+    );
+}
+
+#[test]
+fn emissive_materials_are_meshed() {
+    let config = GlowingWorld;
+    let mut chunk_task = ChunkTask::<GlowingWorld>::new(
+        Entity::PLACEHOLDER,
+        IVec3::ZERO,
+        config.chunk_size(),
+        Default::default(),
+    );
+    chunk_task.generate((config.voxel_lookup_delegate())(IVec3::ZERO));
+    chunk_task.mesh(
+        config.texture_index_mapper(),
+        config.emission_mapper(),
+        config.meshing_mode(),
+    );
+    let mesh = chunk_task.mesh.unwrap();
+
+    let Some(VertexAttributeValues::Float32x3(emissives)) = mesh.attribute(ATTRIBUTE_EMISSIVE)
+    else {
+        panic!("Mesh has no emission")
+    };
+    let Some(VertexAttributeValues::Uint32(emissive_tex_indexes)) =
+        mesh.attribute(ATTRIBUTE_EMISSIVE_TEX_INDEX)
+    else {
+        panic!("Mesh has no emissive texture indexes")
+    };
+
+    // Each voxel has 6 faces of 4 vertices
+    let count = |emissive: [f32; 3]| emissives.iter().filter(|e| **e == emissive).count();
+    assert_eq!(count([0.0, 0.0, 0.0]), 24);
+    assert_eq!(count([2.0, 0.0, 0.0]), 24);
+    assert_eq!(count([1.0, 1.0, 1.0]), 24);
+
+    let count = |index: u32| emissive_tex_indexes.iter().filter(|i| **i == index).count();
+    assert_eq!(count(6), 24);
+    assert_eq!(count(NO_EMISSIVE_TEXTURE), 48);
+}
+
+const TEST_BLOCKS: &str = r#"(
+    blocks: [
+        (name: "stone", id: 0, textures: (0, 0, 0)),
+        (name: "grass", id: 1, textures: (1, 2, 3), properties: {"hardness": 0.5}),
+        (name: "glass", id: 2, textures: (4, 4, 4)),
+    ],
+)"#;
+
+#[test]
+fn block_registry_maps_materials() {
+    let registry = BlockRegistry::from_ron(TEST_BLOCKS).unwrap();
+    assert_eq!(registry.id("grass"), Some(1));
+    assert_eq!(registry.voxel::<u8>("stone"), Some(WorldVoxel::Solid(0)));
+    assert_eq!(registry.voxel::<u8>("glass"), Some(WorldVoxel::Solid(2)));
+    assert_eq!(registry.voxel::<u8>("dirt"), None);
+    let grass = registry.get_by_name("grass").unwrap();
+    assert_eq!(grass.property::<f32>("hardness"), Some(0.5));
+    assert_eq!(grass.property::<f32>("flammability"), None);
+
+    // Blocks in the registry override the texture mapper of the config, other materials still
+    // use the mapper of the config
+    let mappers = MaterialMappers::new(&DefaultWorld, Some(&registry));
+    assert_eq!((mappers.texture_index)(1), [1, 2, 3]);
+    assert_eq!((mappers.texture_index)(3), [3, 3, 3]);
+
+    let duplicate_id = r#"(blocks: [(name: "a", id: 0), (name: "b", id: 0)])"#;
+    assert!(BlockRegistry::from_ron(duplicate_id).is_err());
+}
+
+#[test]
+fn block_registry_changes_remesh_chunks() {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        AssetPlugin::default(),
+        VoxelWorldPlugin::<DefaultWorld>::minimal(),
+    ));
+    app.world.spawn((
+        Camera3dBundle::default(),
+        VoxelWorldCamera::<DefaultWorld>::default(),
+    ));
+    app.update();
+
+    // A chunk made of grass
+    let entity = app
+        .world
+        .query::<&Chunk<DefaultWorld>>()
+        .iter(&app.world)
+        .find(|chunk| chunk.position == IVec3::ZERO)
+        .unwrap()
+        .entity;
+    app.world
+        .resource_mut::<ChunkMapUpdateBuffer<DefaultWorld>>()
+        .push((
+            IVec3::ZERO,
+            ChunkData {
+                fill_type: FillType::Uniform(WorldVoxel::Solid(1)),
+                ..ChunkData::with_entity(entity, UVec3::splat(32))
+            },
+            ChunkWillSpawn::<DefaultWorld>::new(IVec3::ZERO, entity),
+        ));
+    app.update();
+
+    let handle = app
+        .world
+        .resource_mut::<Assets<BlockRegistry>>()
+        .reserve_handle();
+    app.insert_resource(BlockRegistryHandle::<DefaultWorld>::new(handle.clone()));
+
+    let remeshed_with = |app: &mut App, blocks: &str| {
+        let registry = BlockRegistry::from_ron(blocks).unwrap();
+        app.world
+            .resource_mut::<Assets<BlockRegistry>>()
+            .insert(handle.id(), registry);
+        app.world
+            .resource_mut::<Events<ChunkWillRemesh<DefaultWorld>>>()
+            .clear();
+        // Asset events are sent at the end of the frame, so the change is applied in the next one
+        app.update();
+        app.update();
+        app.world
+            .resource_mut::<Events<ChunkWillRemesh<DefaultWorld>>>()
+            .drain()
+            .any(|event| event.chunk_key == IVec3::ZERO)
+    };
+
+    // Loading the registry remeshes the chunk, changing the glass doesn't, changing the grass does
+    assert!(remeshed_with(&mut app, TEST_BLOCKS));
+    assert!(!remeshed_with(
+        &mut app,
+        &TEST_BLOCKS.replace("(4, 4, 4)", "(5, 5, 5)")
+    ));
+    assert!(remeshed_with(
+        &mut app,
+        &TEST_BLOCKS.replace("(1, 2, 3)", "(2, 2, 2)")
+    ));
+}
+
+#[derive(Resource, Clone, Default)]
+struct HistoryWorld;
+
+impl VoxelWorldConfig for HistoryWorld {
+    type Voxel = WorldVoxel;
+
+    fn max_undo_steps(&self) -> usize {
+        2
+    }
+}
+
+#[test]
+fn undo_and_redo_transactions() {
+    let mut app = _test_setup_app_with_config::<HistoryWorld>();
+    app.update();
+
+    let edit = |app: &mut App, change: fn(&mut VoxelWorld<HistoryWorld>)| {
+        app.world
+            .run_system_once(move |mut voxel_world: VoxelWorld<HistoryWorld>| {
+                change(&mut voxel_world)
+            });
+        app.update();
+    };
+    let voxel_at = |app: &mut App, x: i32| {
+        app.world
+            .run_system_once(move |voxel_world: VoxelWorld<HistoryWorld>| {
+                voxel_world.get_voxel(IVec3::new(x, 0, 0))
+            })
+    };
+
+    edit(&mut app, |world| {
+        // Not recorded
+        world.set_voxel(IVec3::new(0, 0, 0), WorldVoxel::Solid(1));
+
+        world.begin_transaction("a");
+        world.set_voxel(IVec3::new(0, 0, 0), WorldVoxel::Solid(2));
+        world.set_voxel(IVec3::new(1, 0, 0), WorldVoxel::Solid(2));
+        world.set_voxel(IVec3::new(1, 0, 0), WorldVoxel::Solid(3));
+        world.commit_transaction();
+
+        world.begin_transaction("b");
+        world.set_voxel(IVec3::new(2, 0, 0), WorldVoxel::Solid(4));
+    });
+    assert_eq!(voxel_at(&mut app, 2), WorldVoxel::Solid(4));
+
+    edit(&mut app, |world| {
+        assert_eq!(world.undo().as_deref(), Some("b"))
+    });
+    assert_eq!(voxel_at(&mut app, 2), WorldVoxel::Unset);
+
+    edit(&mut app, |world| {
+        assert_eq!(world.undo().as_deref(), Some("a"))
+    });
+    assert_eq!(voxel_at(&mut app, 0), WorldVoxel::Solid(1));
+    assert_eq!(voxel_at(&mut app, 1), WorldVoxel::Unset);
+
+    edit(&mut app, |world| {
+        assert_eq!(world.undo(), None);
+        assert_eq!(world.redo().as_deref(), Some("a"));
+    });
+    assert_eq!(voxel_at(&mut app, 0), WorldVoxel::Solid(2));
+    assert_eq!(voxel_at(&mut app, 1), WorldVoxel::Solid(3));
+
+    // A new transaction clears the redo stack, and only the last 2 transactions are kept
+    edit(&mut app, |world| {
+        for name in ["c", "d", "e"] {
+            world.begin_transaction(name);
+            world.set_voxel(IVec3::new(3, 0, 0), WorldVoxel::Solid(5));
+        }
+        world.commit_transaction();
+
+        let history = world.edit_history();
+        assert!(!history.can_redo());
+        let names: Vec<_> = history.undo_stack().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["d", "e"]);
+    });
+
+    // Setting a voxel to unset stores an explicit edit, and undoing it removes the modification
+    let position = IVec3::new(4, 0, 0);
+    edit(&mut app, |world| {
+        world.begin_transaction("f");
+        world.set_voxel(IVec3::new(4, 0, 0), WorldVoxel::Unset);
+        world.commit_transaction();
+    });
+    let modified = app.world.resource::<ModifiedVoxels<HistoryWorld>>();
+    assert_eq!(modified.get_voxel(&position), Some(WorldVoxel::Unset));
+
+    edit(&mut app, |world| {
+        assert_eq!(world.undo().as_deref(), Some("f"))
+    });
+    let modified = app.world.resource::<ModifiedVoxels<HistoryWorld>>();
+    assert_eq!(modified.get_voxel(&position), None);
+}
+
+#[test]
+fn bulk_shape_edits() {
+    let mut app = _test_setup_app();
+    app.update();
+
+    app.world
+        .run_system_once(|mut voxel_world: VoxelWorld<DefaultWorld>| {
+            // The box crosses a chunk border
+            voxel_world.fill_box(
+                IVec3::new(30, 0, 0),
+                IVec3::new(33, 2, 2),
+                WorldVoxel::Solid(1),
+            );
+            voxel_world.fill_sphere(IVec3::new(0, 10, 0), 2.0, WorldVoxel::Solid(1));
+            voxel_world.fill_cylinder(
+                IVec3::new(0, 20, 0),
+                IVec3::new(0, 24, 0),
+                1.0,
+                WorldVoxel::Solid(1),
+            );
+            voxel_world.draw_line(
+                IVec3::new(0, 30, 0),
+                IVec3::new(3, 32, 0),
+                WorldVoxel::Solid(1),
+            );
+        });
+    app.update();
+
+    let count = |app: &mut App, min: IVec3, max: IVec3, voxel: WorldVoxel| {
+        app.world
+            .run_system_once(move |voxel_world: VoxelWorld<DefaultWorld>| {
+                let mut count = 0;
+                for x in min.x..=max.x {
+                    for y in min.y..=max.y {
+                        for z in min.z..=max.z {
+                            count += (voxel_world.get_voxel(IVec3::new(x, y, z)) == voxel) as i32;
+                        }
+                    }
+                }
+                count
+            })
+    };
+    let solid = WorldVoxel::Solid(1);
+    let around = |center: IVec3| (center - 5, center + 5);
+
+    let (min, max) = around(IVec3::new(32, 0, 0));
+    assert_eq!(count(&mut app, min, max, solid), 4 * 3 * 3);
+    let (min, max) = around(IVec3::new(0, 10, 0));
+    assert_eq!(count(&mut app, min, max, solid), 33);
+    let (min, max) = around(IVec3::new(0, 22, 0));
+    assert_eq!(count(&mut app, min, max, solid), 5 * 5);
+    // A face connected line takes one voxel per step along each axis
+    let (min, max) = around(IVec3::new(0, 31, 0));
+    assert_eq!(count(&mut app, min, max, solid), 3 + 2 + 1);
+
+    app.world
+        .run_system_once(|mut voxel_world: VoxelWorld<DefaultWorld>| {
+            voxel_world.replace_voxels(
+                IVec3::new(31, 0, 0),
+                IVec3::new(40, 0, 0),
+                WorldVoxel::Solid(1),
+                WorldVoxel::Solid(2),
+            );
+        });
+    app.update();
+
+    let (min, max) = around(IVec3::new(32, 0, 0));
+    assert_eq!(count(&mut app, min, max, WorldVoxel::Solid(2)), 3);
+    assert_eq!(count(&mut app, min, max, solid), 4 * 3 * 3 - 3);
+}
+
+#[test]
+fn prefab_capture_and_paste() {
+    let mut app = _test_setup_app();
+    app.update();
+
+    let prefab = app
+        .world
+        .run_system_once(|mut voxel_world: VoxelWorld<DefaultWorld>| {
+            voxel_world.fill_box(IVec3::ZERO, IVec3::new(2, 0, 1), WorldVoxel::Air);
+            voxel_world.set_voxel(IVec3::new(0, 0, 0), WorldVoxel::Solid(1));
+            voxel_world.set_voxel(IVec3::new(2, 0, 0), WorldVoxel::Solid(2));
+            voxel_world.set_voxel(IVec3::new(0, 0, 1), WorldVoxel::Solid(3));
+            let prefab = voxel_world.capture_prefab(IVec3::ZERO, IVec3::new(2, 0, 1));
+
+            let rotated = PasteOptions {
+                quarter_turns: 1,
+                ..default()
+            };
+            voxel_world.set_voxel(IVec3::new(101, 0, 1), WorldVoxel::Solid(9));
+            voxel_world.paste_prefab(&prefab, IVec3::new(100, 0, 0), rotated);
+
+            let mirrored = PasteOptions {
+                mirror: BVec3::new(true, false, false),
+                air_is_transparent: true,
+                ..default()
+            };
+            voxel_world.set_voxel(IVec3::new(201, 0, 0), WorldVoxel::Solid(9));
+            voxel_world.paste_prefab(&prefab, IVec3::new(200, 0, 0), mirrored);
+            prefab
+        });
+    app.update();
+
+    assert_eq!(prefab.size(), UVec3::new(3, 1, 2));
+    let rotated = PasteOptions {
+        quarter_turns: 1,
+        ..default()
+    };
+    assert_eq!(prefab.pasted_size(&rotated), UVec3::new(2, 1, 3));
+
+    app.world
+        .run_system_once(|voxel_world: VoxelWorld<DefaultWorld>| {
+            let voxel = |x, z| voxel_world.get_voxel(IVec3::new(x, 0, z));
+
+            // Turned counter-clockwise: +x becomes -z
+            assert_eq!(voxel(100, 2), WorldVoxel::Solid(1));
+            assert_eq!(voxel(100, 0), WorldVoxel::Solid(2));
+            assert_eq!(voxel(101, 2), WorldVoxel::Solid(3));
+            assert_eq!(voxel(101, 1), WorldVoxel::Air);
+
+            // Mirrored along x, without replacing voxels with air
+            assert_eq!(voxel(202, 0), WorldVoxel::Solid(1));
+            assert_eq!(voxel(200, 0), WorldVoxel::Solid(2));
+            assert_eq!(voxel(202, 1), WorldVoxel::Solid(3));
+            assert_eq!(voxel(201, 0), WorldVoxel::Solid(9));
+        });
+}
+
+#[test]
+fn prefab_storage() {
+    let prefab = VoxelPrefab::from_fn(UVec3::new(4, 3, 2), |pos| match pos.y {
+        0 => WorldVoxel::Solid(pos.x as u8),
+        _ => WorldVoxel::Air,
+    });
+    let palette = prefab.to_palette();
+    match palette.voxels() {
+        PrefabVoxels::Palette { palette, .. } => assert_eq!(palette.len(), 5),
+        PrefabVoxels::Dense(_) => panic!("prefab should use a palette"),
+    }
+    assert_eq!(palette.to_dense(), prefab);
+    assert_eq!(palette.get(UVec3::new(3, 0, 1)), WorldVoxel::Solid(3));
+    assert_eq!(palette.get(UVec3::new(4, 0, 0)), WorldVoxel::Unset);
+
+    for prefab in [prefab, palette] {
+        let mut bytes = Vec::new();
+        prefab.write_to(&mut bytes).unwrap();
+        assert_eq!(VoxelPrefab::read_from(bytes.as_slice()).unwrap(), prefab);
+    }
+    assert!(VoxelPrefab::<WorldVoxel>::read_from(&b"BVWE"[..]).is_err());
+}
+
+#[test]
+fn vox_model_import_and_export() {
+    // A 2 x 3 x 1 (x, y, z) MagicaVoxel model, Z up, with palette indices 1 and 2
+    let mut bytes = Vec::new();
+    bytes.extend(b"VOX ");
+    bytes.extend(150u32.to_le_bytes());
+    bytes.extend(b"MAIN");
+    bytes.extend([0u32, 24 + 24].map(u32::to_le_bytes).concat());
+    bytes.extend(b"SIZE");
+    bytes.extend([12u32, 0, 2, 3, 1].map(u32::to_le_bytes).concat());
+    bytes.extend(b"XYZI");
+    bytes.extend([12u32, 0, 2].map(u32::to_le_bytes).concat());
+    bytes.extend([0, 0, 0, 1, 1, 2, 0, 2]);
+
+    let mapping = VoxPaletteMapping::default().with(1, 7);
+    let model = VoxModel::read_from(bytes.as_slice(), &mapping).unwrap();
+    assert_eq!(model.size(), UVec3::new(2, 1, 3));
+    assert_eq!(
+        model.voxels(),
+        &[(UVec3::new(0, 0, 2), 7), (UVec3::new(1, 0, 0), 2)]
+    );
+    assert_eq!(model.palette(), None);
+
+    let mut written = Vec::new();
+    model.write_to(&mut written, &mapping).unwrap();
+    assert_eq!(written, bytes);
+    let unmapped = VoxModel::new(UVec3::ONE, vec![(UVec3::ZERO, 300)]);
+    assert!(unmapped.write_to(Vec::new(), &mapping).is_err());
+    assert!(VoxModel::read_from(&b"VOX"[..], &mapping).is_err());
+    // Models can be up to 256 voxels along each axis
+    let mut largest = bytes.clone();
+    largest[32..36].copy_from_slice(&256u32.to_le_bytes());
+    let largest = VoxModel::read_from(largest.as_slice(), &mapping).unwrap();
+    assert_eq!(largest.size(), UVec3::new(256, 1, 3));
+    let mut too_large = bytes.clone();
+    too_large[32..36].copy_from_slice(&257u32.to_le_bytes());
+    let error = VoxModel::read_from(too_large.as_slice(), &mapping).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    let mut app = _test_setup_app();
+    app.update();
+    let captured = app
+        .world
+        .run_system_once(move |mut voxel_world: VoxelWorld<DefaultWorld>| {
+            voxel_world.paste_vox_model(&model, IVec3::new(10, 0, 0), default(), |material| {
+                WorldVoxel::Solid(material as u8)
+            });
+            voxel_world.capture_prefab(IVec3::new(10, 0, 0), IVec3::new(11, 0, 2))
+        });
+    assert_eq!(captured.get(UVec3::new(0, 0, 2)), WorldVoxel::Solid(7));
+    assert_eq!(captured.get(UVec3::new(1, 0, 0)), WorldVoxel::Solid(2));
+    assert_eq!(
+        VoxModel::from_prefab(&captured).voxels(),
+        &[(UVec3::new(1, 0, 0), 2), (UVec3::new(0, 0, 2), 7)]
+    );
+}
+
+fn nbt_tag(bytes: &mut Vec<u8>, tag: u8, name: &str) {
+    bytes.push(tag);
+    nbt_string(bytes, name);
+}
+
+fn nbt_string(bytes: &mut Vec<u8>, value: &str) {
+    bytes.extend((value.len() as u16).to_be_bytes());
+    bytes.extend(value.as_bytes());
+}
+
+fn nbt_int_list(bytes: &mut Vec<u8>, name: &str, values: [i32; 3]) {
+    nbt_tag(bytes, 9, name);
+    bytes.push(3);
+    bytes.extend(3i32.to_be_bytes());
+    bytes.extend(values.map(i32::to_be_bytes).concat());
+}
+
+#[test]
+fn schematic_import() {
+    // A 2 x 1 x 2 Sponge schematic, version 2, stored uncompressed
+    let mut sponge = Vec::new();
+    nbt_tag(&mut sponge, 10, "Schematic");
+    for (name, value) in [("Width", 2i16), ("Height", 1), ("Length", 2)] {
+        nbt_tag(&mut sponge, 2, name);
+        sponge.extend(value.to_be_bytes());
+    }
+    nbt_tag(&mut sponge, 11, "Offset");
+    sponge.extend([3i32, 0, 0, 0].map(i32::to_be_bytes).concat());
+    nbt_tag(&mut sponge, 10, "Palette");
+    for (index, name) in [
+        "minecraft:air",
+        "minecraft:stone",
+        "minecraft:oak_stairs[facing=north]",
+        "minecraft:gold_block",
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        nbt_tag(&mut sponge, 3, name);
+        sponge.extend((index as i32).to_be_bytes());
+    }
+    sponge.push(0);
+    nbt_tag(&mut sponge, 7, "BlockData");
+    sponge.extend(4i32.to_be_bytes());
+    sponge.extend([1, 0, 2, 3]);
+    sponge.push(0);
+
+    let mapping = SchematicBlockMapping::default()
+        .with("stone", 1)
+        .with("minecraft:oak_stairs", 2);
+    let schematic = Schematic::read_from(sponge.as_slice(), &mapping).unwrap();
+    assert_eq!(schematic.size(), UVec3::new(2, 1, 2));
+    assert_eq!(
+        schematic.voxels(),
+        &[(UVec3::new(0, 0, 0), 1), (UVec3::new(0, 0, 1), 2)]
+    );
+    assert_eq!(schematic.air(), &[UVec3::new(1, 0, 0)]);
+    assert_eq!(schematic.unmapped_blocks(), &["minecraft:gold_block"]);
+    // Width, height and length of 1024 cover 2^30 positions
+    let mut too_large = sponge.clone();
+    for range in [20..22, 31..33, 42..44] {
+        too_large[range].copy_from_slice(&1024i16.to_be_bytes());
+    }
+    let error = Schematic::read_from(too_large.as_slice(), &mapping).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    // A 1 x 2 x 1 vanilla structure, gzip compressed
+    let mut structure = Vec::new();
+    nbt_tag(&mut structure, 10, "");
+    nbt_int_list(&mut structure, "size", [1, 2, 1]);
+    nbt_tag(&mut structure, 9, "palette");
+    structure.push(10);
+    structure.extend(2i32.to_be_bytes());
+    nbt_tag(&mut structure, 8, "Name");
+    nbt_string(&mut structure, "minecraft:stone");
+    structure.push(0);
+    nbt_tag(&mut structure, 8, "Name");
+    nbt_string(&mut structure, "minecraft:dirt");
+    nbt_tag(&mut structure, 10, "Properties");
+    nbt_tag(&mut structure, 8, "snowy");
+    nbt_string(&mut structure, "false");
+    structure.extend([0, 0]);
+    nbt_tag(&mut structure, 9, "blocks");
+    structure.push(10);
+    structure.extend(2i32.to_be_bytes());
+    for (y, state) in [(0, 1i32), (1, 0)] {
+        nbt_int_list(&mut structure, "pos", [0, y, 0]);
+        nbt_tag(&mut structure, 3, "state");
+        structure.extend(state.to_be_bytes());
+        structure.push(0);
+    }
+    structure.push(0);
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&structure).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mapping = mapping.with("dirt", 3);
+    let structure_bytes = structure;
+    let structure = Schematic::read_from(compressed.as_slice(), &mapping).unwrap();
+    assert_eq!(structure.size(), UVec3::new(1, 2, 1));
+    assert_eq!(
+        structure.voxels(),
+        &[(UVec3::new(0, 0, 0), 3), (UVec3::new(0, 1, 0), 1)]
+    );
+    let truncated = &compressed[..compressed.len() / 2];
+    assert!(Schematic::read_from(truncated, &mapping).is_err());
+    let mut too_large = structure_bytes;
+    too_large[15..19].copy_from_slice(&1_000_000i32.to_be_bytes());
+    too_large[23..27].copy_from_slice(&1_000_000i32.to_be_bytes());
+    let error = Schematic::read_from(too_large.as_slice(), &mapping).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    // Pasting the sparse voxels matches pasting the prefab
+    let options = PasteOptions {
+        quarter_turns: 1,
+        mirror: BVec3::new(true, false, false),
+        ..default()
+    };
+    let voxel = |material| WorldVoxel::Solid(material as u8);
+    let mut pasted: Vec<_> = schematic
+        .pasted_voxels(IVec3::ZERO, options, voxel)
+        .collect();
+    let mut pasted_prefab: Vec<_> = schematic
+        .to_prefab(voxel)
+        .pasted_voxels(IVec3::ZERO, options)
+        .collect();
+    pasted.sort_by_key(|(position, _)| position.to_array());
+    pasted_prefab.sort_by_key(|(position, _)| position.to_array());
+    assert_eq!(pasted, pasted_prefab);
+
+    let mut app = _test_setup_app();
+    app.update();
+    app.world
+        .run_system_once(move |mut voxel_world: VoxelWorld<DefaultWorld>| {
+            // Paste over solid ground, once with air clearing it and once with transparent air
+            for (x, air_is_transparent) in [(5, false), (15, true)] {
+                for position in [IVec3::new(x, 0, 5), IVec3::new(x + 1, 0, 5)] {
+                    voxel_world.set_voxel(position, WorldVoxel::Solid(9));
+                    voxel_world.set_voxel(position + IVec3::Z, WorldVoxel::Solid(9));
+                }
+                let options = PasteOptions {
+                    air_is_transparent,
+                    ..default()
+                };
+                voxel_world.paste_schematic(&schematic, IVec3::new(x, 0, 5), options, voxel);
+            }
+        });
+    app.update();
+
+    let modified = app.world.resource::<ModifiedVoxels<DefaultWorld>>();
+    let voxel = |x, z| modified.get_voxel(&IVec3::new(x, 0, z));
+    assert_eq!(voxel(5, 6), Some(WorldVoxel::Solid(2)));
+    assert_eq!(voxel(6, 5), Some(WorldVoxel::Air));
+    assert_eq!(voxel(6, 6), Some(WorldVoxel::Solid(9)));
+    assert_eq!(voxel(15, 5), Some(WorldVoxel::Solid(1)));
+    assert_eq!(voxel(16, 5), Some(WorldVoxel::Solid(9)));
+}
+
+#[derive(Resource, Clone, Default)]
+struct PipelineWorld;
+
+impl VoxelWorldConfig for PipelineWorld {
+    type Voxel = WorldVoxel;
+
+    fn generation_pipeline(&self) -> Option<GenerationPipeline> {
+        let pipeline = GenerationPipeline::new()
+            .with_stage("terrain", 0, |chunk| {
+                for position in chunk.positions() {
+                    let voxel = match position.y {
+                        y if y < 0 => WorldVoxel::Solid(0),
+                        _ => WorldVoxel::Air,
+                    };
+                    chunk.set(position, voxel);
+                }
+            })
+            .with_stage("grass", 1, |chunk| {
+                for position in chunk.positions() {
+                    let above = chunk.get(position + IVec3::Y);
+                    if chunk.get(position).is_solid() && !above.is_solid() {
+                        chunk.set(position, WorldVoxel::Solid(1));
+                    }
+                }
+                assert!(!chunk.set(chunk.max() + IVec3::ONE, WorldVoxel::Solid(9)));
+            });
+        Some(pipeline)
+    }
+}
+
+#[test]
+fn generation_pipeline_reads_neighbours() {
+    let config = PipelineWorld;
+    let generator = WorldGenerator::from_config(&config);
+    let pipeline = config.generation_pipeline().unwrap();
+    assert_eq!(pipeline.stages().collect::<Vec<_>>(), ["terrain", "grass"]);
+
+    // The top of the terrain is at the top of a chunk, so the grass stage needs to read the
+    // terrain of the chunk above
+    let mut lookup = generator.lookup_fn(&config, IVec3::ZERO);
+    assert_eq!(lookup(IVec3::new(5, -1, 5)), WorldVoxel::Solid(1));
+    assert_eq!(lookup(IVec3::new(5, -2, 5)), WorldVoxel::Solid(0));
+    assert_eq!(lookup(IVec3::new(5, 0, 5)), WorldVoxel::Air);
+
+    let position = IVec3::new(0, -1, 0);
+    let mut chunk_task = ChunkTask::<PipelineWorld>::new(
+        Entity::PLACEHOLDER,
+        position,
+        config.chunk_size(),
+        Default::default(),
+    );
+    chunk_task.generate(generator.lookup_fn(&config, position));
+    let voxel = |y| chunk_task.chunk_data.get_voxel(UVec3::new(6, y, 6));
+    assert_eq!(voxel(32), WorldVoxel::Solid(1));
+    assert_eq!(voxel(31), WorldVoxel::Solid(0));
+    assert_eq!(voxel(33), WorldVoxel::Air);
+}
+
+#[test]
+fn generation_pipeline_cache() {
+    let generated = Arc::new(AtomicUsize::new(0));
+    let counter = generated.clone();
+    let pipeline = GenerationPipeline::<WorldVoxel>::new()
+        .with_stage("terrain", 0, move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(20));
+        })
+        .with_cache_size(2);
+    let runner = PipelineRunner::new(pipeline, UVec3::splat(4));
+
+    // Tasks asking for the same chunk at the same time only generate it once
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| runner.chunk(0, IVec3::ZERO));
+        }
+    });
+    assert_eq!(generated.load(Ordering::SeqCst), 1);
+
+    // The least recently used chunk is dropped first
+    runner.chunk(0, IVec3::X);
+    runner.chunk(0, IVec3::ZERO);
+    runner.chunk(0, IVec3::Y);
+    assert_eq!(generated.load(Ordering::SeqCst), 3);
+    runner.chunk(0, IVec3::ZERO);
+    assert_eq!(generated.load(Ordering::SeqCst), 3);
+    runner.chunk(0, IVec3::X);
+    assert_eq!(generated.load(Ordering::SeqCst), 4);
+}
+
+#[derive(Resource, Clone, Default)]
+struct ChunkGeneratorWorld;
+
+impl VoxelWorldConfig for ChunkGeneratorWorld {
+    type Voxel = WorldVoxel;
+
+    fn chunk_generator(&self) -> Option<ChunkGeneratorFn> {
+        Some(Arc::new(|chunk: &mut PaddedVoxelBuffer| {
+            let size = chunk.size();
+            if chunk.world_position(UVec3::ZERO).y >= 0 {
+                return FillType::Empty;
+            }
+            if chunk.world_position(size - 1).y < 0 {
+                return FillType::Uniform(WorldVoxel::Solid(0));
+            }
+            for x in 0..size.x {
+                for y in 0..size.y {
+                    for z in 0..size.z {
+                        let sample = UVec3::new(x, y, z);
+                        let voxel = match chunk.world_position(sample).y {
+                            y if y < 0 => WorldVoxel::Solid(0),
+                            _ => WorldVoxel::Air,
+                        };
+                        chunk.set(sample, voxel);
+                    }
+                }
+            }
+            FillType::Mixed
+        }))
+    }
+}
+
+#[test]
+fn chunk_generator_fills_whole_chunks() {
+    let config = ChunkGeneratorWorld;
+    let generator = WorldGenerator::from_config(&config);
+    let generate = |position: IVec3, edits: &EditPages| {
+        let mut chunk_task = ChunkTask::<ChunkGeneratorWorld>::new(
+            Entity::PLACEHOLDER,
+            position,
+            config.chunk_size(),
+            edits.chunk_edits(position),
+        );
+        chunk_task.generate_from(generator.chunk_source(&config, position));
+        chunk_task
+    };
+    let no_edits = EditPages::new(config.chunk_size());
+
+    let chunk_task = generate(IVec3::new(0, 1, 0), &no_edits);
+    assert!(chunk_task.is_empty());
+    assert!(matches!(chunk_task.chunk_data.fill_type, FillType::Empty));
+    assert!(chunk_task.chunk_data.voxels.is_none());
+
+    let chunk_task = generate(IVec3::new(0, -2, 0), &no_edits);
+    assert!(chunk_task.is_full());
+    assert!(matches!(
+        chunk_task.chunk_data.fill_type,
+        FillType::Uniform(WorldVoxel::Solid(0))
+    ));
+    assert!(chunk_task.chunk_data.voxels.is_none());
+
+    let chunk_task = generate(IVec3::new(0, -1, 0), &no_edits);
+    assert!(matches!(chunk_task.chunk_data.fill_type, FillType::Mixed));
+    let voxel = |y| chunk_task.chunk_data.get_voxel(UVec3::new(6, y, 6));
+    assert_eq!(voxel(32), WorldVoxel::Solid(0));
+    assert_eq!(voxel(33), WorldVoxel::Air);
+
+    // Edits in a chunk reported as empty are still applied
+    let mut edits = EditPages::new(config.chunk_size());
+    edits.insert(IVec3::new(40, 40, 40), WorldVoxel::Solid(3));
+    let chunk_task = generate(IVec3::new(1, 1, 1), &edits);
+    assert!(matches!(chunk_task.chunk_data.fill_type, FillType::Mixed));
+    assert_eq!(
+        chunk_task.chunk_data.get_voxel(UVec3::new(9, 9, 9)),
+        WorldVoxel::Solid(3)
+    );
+
+    // Voxels outside of the chunk are read from the chunks the generator fills. Empty chunks
+    // are unset.
+    let mut lookup = generator.lookup_fn(&config, IVec3::ZERO);
+    assert_eq!(lookup(IVec3::new(100, -70, 3)), WorldVoxel::Solid(0));
+    assert_eq!(lookup(IVec3::new(-5, 1, 3)), WorldVoxel::Air);
+    assert_eq!(lookup(IVec3::new(-5, 70, 3)), WorldVoxel::Unset);
+}
+
+fn biome_layer(blend: f32) -> BiomeLayer {
+    BiomeLayer::new(42)
+        .with_biome(
+            Biome::new(
+                "lowlands",
+                WorldVoxel::Solid(0),
+                WorldVoxel::Solid(1),
+                WorldVoxel::Solid(2),
+            )
+            .with_climate(0.4, 0.5),
+        )
+        .with_biome(
+            Biome::new(
+                "highlands",
+                WorldVoxel::Solid(3),
+                WorldVoxel::Solid(4),
+                WorldVoxel::Solid(2),
+            )
+            .with_climate(0.6, 0.5)
+            .with_height(40.0, 0.0)
+            .with_decoration(Decoration::new(
+                [WorldVoxel::Solid(5), WorldVoxel::Solid(6)],
+                1.0,
+            )),
+        )
+        .with_blend(blend)
+}
+
+#[derive(Resource, Clone, Default)]
+struct BiomeWorld;
+
+impl VoxelWorldConfig for BiomeWorld {
+    type Voxel = WorldVoxel;
+
+    fn biomes(&self) -> Option<Arc<BiomeLayer>> {
+        Some(Arc::new(biome_layer(0.2)))
+    }
+}
+
+#[test]
+fn biomes_generate_terrain() {
+    // Without blending, the terrain jumps between the heights of the biomes
+    let hard = biome_layer(0.0);
+    let heights: Vec<i32> = (0..4096).map(|x| hard.column(x, 0).height).collect();
+    assert!(heights.iter().all(|height| *height == 0 || *height == 40));
+    let max_step = |heights: &[i32]| {
+        heights
+            .windows(2)
+            .map(|pair| (pair[0] - pair[1]).abs())
+            .max()
+            .unwrap()
+    };
+    assert_eq!(max_step(&heights), 40);
+
+    let x = (0..4096).find(|x| hard.column(*x, 0).biome == 1).unwrap();
+    let column = hard.column(x, 0);
+    assert_eq!(column.surface_biome, 1);
+    assert_eq!(hard.biome_at(IVec3::new(x, -100, 0)), Some(1));
+    assert_eq!(hard.biome(1).unwrap().name(), "highlands");
+    let voxel = |y| hard.voxel(IVec3::new(x, y, 0));
+    assert_eq!(voxel(41), WorldVoxel::Solid(5));
+    assert_eq!(voxel(42), WorldVoxel::Solid(6));
+    // Like the empty chunks above the terrain, the sky is unset
+    assert_eq!(voxel(43), WorldVoxel::Unset);
+    assert_eq!(voxel(40), WorldVoxel::Solid(3));
+    assert_eq!(voxel(37), WorldVoxel::Solid(4));
+    assert_eq!(voxel(36), WorldVoxel::Solid(2));
+
+    // Blended borders are gradual
+    let blended = biome_layer(0.2);
+    let heights: Vec<i32> = (0..4096).map(|x| blended.column(x, 0).height).collect();
+    assert!(max_step(&heights) <= 4);
+
+    // Chunks are generated by the biome layer, skipping the sky and the underground
+    let config = BiomeWorld;
+    let generator = WorldGenerator::from_config(&config);
+    let generate = |position: IVec3| {
+        let mut chunk_task = ChunkTask::<BiomeWorld>::new(
+            Entity::PLACEHOLDER,
+            position,
+            config.chunk_size(),
+            Default::default(),
+        );
+        chunk_task.generate_from(generator.chunk_source(&config, position));
+        chunk_task
+    };
+    assert!(matches!(
+        generate(IVec3::new(0, 2, 0)).chunk_data.fill_type,
+        FillType::Empty
+    ));
+    assert!(matches!(
+        generate(IVec3::new(0, -2, 0)).chunk_data.fill_type,
+        FillType::Uniform(WorldVoxel::Solid(2))
+    ));
+    let chunk_task = generate(IVec3::ZERO);
+    for x in 0..32 {
+        for y in 0..32 {
+            assert_eq!(
+                chunk_task.chunk_data.get_voxel(UVec3::new(x + 1, y + 1, 6)),
+                blended.voxel(IVec3::new(x as i32, y as i32, 5))
+            );
+        }
+    }
+
+    let mut app = _test_setup_app_with_config::<BiomeWorld>();
+    app.add_systems(Update, move |voxel_world: VoxelWorld<BiomeWorld>| {
+        let position = IVec3::new(x, 0, 0);
+        assert_eq!(voxel_world.get_biome(position), blended.biome_at(position));
+        assert!(voxel_world.biomes().is_some());
+    });
+    app.update();
+
+    let mut app = _test_setup_app();
+    app.add_systems(Update, |voxel_world: VoxelWorld<DefaultWorld>| {
+        assert_eq!(voxel_world.get_biome(IVec3::ZERO), None);
+    });
+    app.update();
+}
+
+fn structure_layer() -> StructureLayer {
+    let hut = VoxelPrefab::from_fn(UVec3::new(6, 4, 3), |position| {
+        WorldVoxel::Solid(position.x as u8 + 10)
+    });
+    StructureLayer::new(7).with_structure(
+        Structure::new("hut", hut, 20)
+            .with_chance(0.5)
+            .with_random_rotation(),
+    )
+}
+
+#[derive(Resource, Clone, Default)]
+struct StructureWorld;
+
+impl VoxelWorldConfig for StructureWorld {
+    type Voxel = WorldVoxel;
+
+    fn structures(&self) -> Option<Arc<StructureLayer>> {
+        Some(Arc::new(structure_layer()))
+    }
+}
+
+#[derive(Resource, Clone, Default)]
+struct StructureChunkWorld;
+
+impl VoxelWorldConfig for StructureChunkWorld {
+    type Voxel = WorldVoxel;
+
+    fn chunk_generator(&self) -> Option<ChunkGeneratorFn> {
+        Some(Arc::new(|_| FillType::Empty))
+    }
+
+    fn structures(&self) -> Option<Arc<StructureLayer>> {
+        Some(Arc::new(structure_layer()))
+    }
+}
+
+#[test]
+fn structures_span_chunk_borders() {
+    let layer = structure_layer();
+    let (min, max) = (IVec3::new(-64, -32, -64), IVec3::new(63, 31, 63));
+    let placements = layer.placements(min, max);
+    assert!(!placements.is_empty());
+    let chunks: Vec<IVec3> = (-2..2)
+        .flat_map(|z| (-2..2).map(move |x| IVec3::new(x, 0, z)))
+        .collect();
+
+    // Every chunk finds the placements overlapping it, in the same order
+    for chunk in chunks.iter() {
+        let chunk_min = *chunk * 32;
+        let chunk_max = chunk_min + 31;
+        let expected: Vec<_> = placements
+            .iter()
+            .copied()
+            .filter(|placement| placement.overlaps(chunk_min, chunk_max))
+            .collect();
+        assert_eq!(layer.placements(chunk_min, chunk_max), expected);
+    }
+    let crosses_border = |placement: &StructurePlacement| {
+        placement.min().div_euclid(IVec3::splat(32)) != placement.max().div_euclid(IVec3::splat(32))
+    };
+    assert!(placements.iter().any(crosses_border));
+
+    // Chunks generated in any order hold their part of every structure
+    fn generate<C: VoxelWorldConfig<Voxel = WorldVoxel>>(
+        chunks: impl Iterator<Item = IVec3>,
+    ) -> HashMap<IVec3, ChunkData> {
+        let config = C::default();
+        let generator = WorldGenerator::from_config(&config);
+        chunks
+            .map(|position| {
+                let mut chunk_task = ChunkTask::<C>::new(
+                    Entity::PLACEHOLDER,
+                    position,
+                    config.chunk_size(),
+                    Default::default(),
+                );
+                chunk_task.generate_from(generator.chunk_source(&config, position));
+                (position, chunk_task.chunk_data)
+            })
+            .collect()
+    }
+    let forward = generate::<StructureWorld>(chunks.iter().copied());
+    let backward = generate::<StructureWorld>(chunks.iter().rev().copied());
+    let from_chunks = generate::<StructureChunkWorld>(chunks.iter().copied());
+    for placement in placements.iter() {
+        let prefab = layer.structures()[placement.structure].prefab();
+        for (position, voxel) in prefab.pasted_voxels(placement.position, placement.options) {
+            let (chunk_position, local) = get_chunk_voxel_position(position, UVec3::splat(32));
+            for chunks in [&forward, &backward, &from_chunks] {
+                if let Some(chunk) = chunks.get(&chunk_position) {
+                    assert_eq!(chunk.get_voxel(local), voxel);
+                }
+            }
+        }
+    }
+    for chunk in chunks.iter() {
+        assert_eq!(forward[chunk].voxels, backward[chunk].voxels);
+    }
+}
+
+const WATER: u8 = 1;
+const LAVA: u8 = 2;
+
+#[derive(Resource, Clone, Default)]
+struct FluidWorld;
+
+impl VoxelWorldConfig for FluidWorld {
+    type Voxel = WorldVoxel;
+
+    // Fluids are stepped by hand
+    fn fluid_step_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    fn fluid_properties(&self) -> FluidPropertiesFn {
+        Arc::new(|material| match material {
+            WATER => FluidProperties {
+                infinite: true,
+                ..default()
+            },
+            LAVA => FluidProperties {
+                level_drop: 2,
+                steps_per_move: 2,
+                ..default()
+            },
+            _ => default(),
+        })
+    }
+
+    // Stone ground, with a shallow puddle of water on it
+    fn voxel_lookup_delegate(&self) -> VoxelLookupDelegate {
+        Box::new(|_| {
+            Box::new(|pos: IVec3| {
+                if pos.y < 1 {
+                    WorldVoxel::Solid(0)
+                } else if pos == IVec3::new(2, 1, 2) {
+                    WorldVoxel::Fluid(WATER, FluidLevel::Flowing(4))
+                } else {
+                    WorldVoxel::Air
+                }
+            })
+        })
+    }
+}
+
+/// A world of stone ground and air, with the voxels set in `voxels`, stepped by a fluid
+/// simulation without an app
+struct FluidTestWorld {
+    fluids: FluidSimulation<FluidWorld>,
+    voxels: HashMap<IVec3, WorldVoxel>,
+}
+
+impl FluidTestWorld {
+    fn new() -> Self {
+        Self {
+            fluids: FluidSimulation::from_config(&FluidWorld),
+            voxels: HashMap::new(),
+        }
+    }
+
+    fn get(&self, position: IVec3) -> WorldVoxel {
+        ground_or(&self.voxels, position)
+    }
+
+    fn set(&mut self, position: IVec3, voxel: WorldVoxel) {
+        self.voxels.insert(position, voxel);
+        self.fluids.schedule_around(position);
+    }
+
+    fn step(&mut self, steps: usize, is_active: impl Fn(IVec3) -> bool) {
+        for _ in 0..steps {
+            let voxels = self.voxels.clone();
+            let changes = self
+                .fluids
+                .step(&|position| ground_or(&voxels, position), &is_active);
+            for (position, voxel) in changes {
+                self.set(position, voxel);
+            }
+        }
+    }
+}
+
+fn ground_or(voxels: &HashMap<IVec3, WorldVoxel>, position: IVec3) -> WorldVoxel {
+    match voxels.get(&position) {
+        Some(voxel) => *voxel,
+        None if position.y < 1 => WorldVoxel::Solid(0),
+        None => WorldVoxel::Air,
+    }
+}
+
+#[test]
+fn fluids_spread_and_drain() {
+    let water = |level| WorldVoxel::Fluid(WATER, level);
+    let mut world = FluidTestWorld::new();
+    world.set(IVec3::new(0, 3, 0), water(FluidLevel::Source));
+    world.step(30, |_| true);
+
+    // The water falls to the ground, and flows sideways from there with a dropping level
+    assert_eq!(world.get(IVec3::new(0, 2, 0)), water(FluidLevel::Falling));
+    assert_eq!(world.get(IVec3::new(0, 1, 0)), water(FluidLevel::Falling));
+    assert_eq!(world.get(IVec3::new(1, 2, 0)), WorldVoxel::Air);
+    assert_eq!(
+        world.get(IVec3::new(1, 1, 0)),
+        water(FluidLevel::Flowing(7))
+    );
+    assert_eq!(
+        world.get(IVec3::new(3, 1, 0)),
+        water(FluidLevel::Flowing(5))
+    );
+    assert_eq!(
+        world.get(IVec3::new(2, 1, -1)),
+        water(FluidLevel::Flowing(5))
+    );
+    assert_eq!(
+        world.get(IVec3::new(7, 1, 0)),
+        water(FluidLevel::Flowing(1))
+    );
+    assert_eq!(world.get(IVec3::new(8, 1, 0)), WorldVoxel::Air);
+
+    // Water between two sources becomes a source too
+    world.set(IVec3::new(20, 1, 0), water(FluidLevel::Source));
+    world.set(IVec3::new(22, 1, 0), water(FluidLevel::Source));
+    world.step(30, |_| true);
+    assert_eq!(world.get(IVec3::new(21, 1, 0)), water(FluidLevel::Source));
+
+    // Without its source, the water drains away
+    world.set(IVec3::new(0, 3, 0), WorldVoxel::Air);
+    world.step(30, |_| true);
+    for position in [
+        IVec3::new(0, 1, 0),
+        IVec3::new(3, 1, 0),
+        IVec3::new(7, 1, 0),
+    ] {
+        assert_eq!(world.get(position), WorldVoxel::Air);
+    }
+
+    // Lava moves every other step, doesn't flow as far, and doesn't make new sources
+    let lava = |level| WorldVoxel::Fluid(LAVA, level);
+    let mut world = FluidTestWorld::new();
+    world.set(IVec3::new(0, 1, 0), lava(FluidLevel::Source));
+    world.set(IVec3::new(2, 1, 0), lava(FluidLevel::Source));
+    world.step(1, |_| true);
+    assert_eq!(world.get(IVec3::new(1, 1, 0)), WorldVoxel::Air);
+    world.step(1, |_| true);
+    assert_eq!(world.get(IVec3::new(1, 1, 0)), lava(FluidLevel::Flowing(6)));
+    world.step(30, |_| true);
+    assert_eq!(
+        world.get(IVec3::new(-3, 1, 0)),
+        lava(FluidLevel::Flowing(2))
+    );
+    assert_eq!(world.get(IVec3::new(-4, 1, 0)), WorldVoxel::Air);
+
+    // Fluid waits at the border of inactive chunks
+    let mut world = FluidTestWorld::new();
+    world.set(IVec3::new(1, 1, 5), water(FluidLevel::Source));
+    world.step(30, |chunk| chunk.x >= 0);
+    assert_eq!(
+        world.get(IVec3::new(2, 1, 5)),
+        water(FluidLevel::Flowing(7))
+    );
+    assert_eq!(world.get(IVec3::new(0, 1, 5)), WorldVoxel::Air);
+    assert!(world.fluids.pending() > 0);
+    world.step(1, |_| true);
+    assert_eq!(
+        world.get(IVec3::new(0, 1, 5)),
+        water(FluidLevel::Flowing(7))
+    );
+}
+
+#[test]
+fn fluid_simulation_writes_voxels() {
+    let mut app = _test_setup_app_with_config::<FluidWorld>();
+    app.update();
+
+    let source = IVec3::new(16, 1, 16);
+    app.world
+        .run_system_once(move |mut voxel_world: VoxelWorld<FluidWorld>| {
+            voxel_world.fill_box(IVec3::ZERO, IVec3::new(31, 0, 31), WorldVoxel::Solid(0));
+            voxel_world.set_voxel(source, WorldVoxel::Fluid(WATER, FluidLevel::Source));
+        });
+    let settle = |app: &mut App| {
+        for _ in 0..30 {
+            app.world
+                .run_system_once(|mut voxel_world: VoxelWorld<FluidWorld>| {
+                    voxel_world.step_fluids()
+                });
+        }
+        app.update();
+    };
+    settle(&mut app);
+
+    // The simulation writes like `set_voxel`, without recording its changes
+    let flowing = source + IVec3::new(2, 0, 1);
+    let modified = app.world.resource::<ModifiedVoxels<FluidWorld>>();
+    assert_eq!(
+        modified.get_voxel(&flowing),
+        Some(WorldVoxel::Fluid(WATER, FluidLevel::Flowing(5)))
+    );
+    assert_eq!(modified.get_voxel(&(source + IVec3::new(8, 0, 0))), None);
+
+    app.world
+        .run_system_once(move |mut voxel_world: VoxelWorld<FluidWorld>| {
+            voxel_world.set_voxel(source, WorldVoxel::Air);
+        });
+    settle(&mut app);
+    let modified = app.world.resource::<ModifiedVoxels<FluidWorld>>();
+    assert_eq!(modified.get_voxel(&flowing), Some(WorldVoxel::Air));
+}
+
+#[test]
+fn only_edits_near_fluids_wake_the_simulation() {
+    let water = WorldVoxel::Fluid(WATER, FluidLevel::Source);
+    let pond = IVec3::new(5, 5, 5);
+    let get_voxel = |position: IVec3| {
+        if position == pond {
+            water
+        } else {
+            WorldVoxel::Air
+        }
+    };
+
+    let mut fluids = FluidSimulation::<FluidWorld>::from_config(&FluidWorld);
+    fluids.schedule_edit(
+        &get_voxel,
+        pond + IVec3::new(3, 0, 0),
+        &WorldVoxel::Solid(0),
+    );
+    assert_eq!(fluids.pending(), 0);
+    fluids.schedule_edit(&get_voxel, pond + IVec3::X, &WorldVoxel::Solid(0));
+    assert!(fluids.pending() > 0);
+
+    // Despawned chunks forget their pending positions
+    fluids.remove_chunk(IVec3::ZERO);
+    assert_eq!(fluids.pending(), 0);
+
+    // Edits in chunks known to be dry don't look up any voxels, until fluid is placed
+    fluids.chunk_generated(IVec3::ZERO, 0, false);
+    let lookups = AtomicUsize::new(0);
+    let counting_get_voxel = |position: IVec3| {
+        lookups.fetch_add(1, Ordering::Relaxed);
+        get_voxel(position)
+    };
+    fluids.schedule_edit(&counting_get_voxel, pond, &WorldVoxel::Solid(0));
+    assert_eq!(lookups.load(Ordering::Relaxed), 0);
+    assert_eq!(fluids.pending(), 0);
+    fluids.schedule_edit(&counting_get_voxel, pond, &water);
+    fluids.remove_chunk(IVec3::ZERO);
+    fluids.schedule_edit(&counting_get_voxel, pond, &WorldVoxel::Solid(0));
+    assert!(lookups.load(Ordering::Relaxed) > 0);
+
+    // Building away from fluids leaves the simulation idle
+    let mut app = _test_setup_app_with_config::<FluidWorld>();
+    app.update();
+    app.world
+        .run_system_once(|mut voxel_world: VoxelWorld<FluidWorld>| {
+            voxel_world.fill_box(IVec3::ZERO, IVec3::new(7, 0, 7), WorldVoxel::Solid(0));
+        });
+    assert_eq!(
+        app.world
+            .resource::<FluidSimulation<FluidWorld>>()
+            .pending(),
+        0
+    );
+}
+
+#[test]
+fn fluid_surfaces_are_lowered() {
+    let config = FluidWorld;
+    let mut chunk_task = ChunkTask::<FluidWorld>::new(
+        Entity::PLACEHOLDER,
+        IVec3::ZERO,
+        config.chunk_size(),
+        Default::default(),
+    );
+    chunk_task.generate((config.voxel_lookup_delegate())(IVec3::ZERO));
+    chunk_task.mesh(
+        config.texture_index_mapper(),
+        config.emission_mapper(),
+        config.meshing_mode(),
+    );
+
+    // The puddle has its top and four sides, cut off at the height of its level
+    let translucent_mesh = chunk_task.translucent_mesh.unwrap();
+    assert_eq!(translucent_mesh.count_vertices(), 5 * 4);
+    let Some(VertexAttributeValues::Float32x3(positions)) =
+        translucent_mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+    else {
+        panic!("Mesh has no positions")
+    };
+    let bottom = positions.iter().map(|p| p[1]).fold(f32::MAX, f32::min);
+    let top = positions.iter().map(|p| p[1]).fold(f32::MIN, f32::max);
+    assert!((top - bottom - FluidLevel::Flowing(4).height()).abs() < 1e-5);
+    assert!(positions.iter().all(|p| p[1] == bottom || p[1] == top));
+
+    let water = WorldVoxel::Fluid(WATER, FluidLevel::Source);
+    let stone = WorldVoxel::Solid(0);
+    assert!(face_is_visible(&stone, &water));
+    assert!(!face_is_visible(&water, &stone));
+}
+
+const SAND: u8 = 3;
+
+#[derive(Resource, Clone, Default)]
+struct SandWorld;
+
+impl VoxelWorldConfig for SandWorld {
+    type Voxel = WorldVoxel;
+
+    fn gravity_mapper(&self) -> GravityMapperFn {
+        Arc::new(|material| material == SAND)
+    }
+}
+
+#[derive(Resource, Default)]
+struct FallingVoxelEvents {
+    fell: Vec<IVec3>,
+    landed: Vec<IVec3>,
+}
+
+#[test]
+fn unsupported_voxels_fall() {
+    let mut app = _test_setup_app_with_config::<SandWorld>();
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+        20,
+    )));
+    app.init_resource::<FallingVoxelEvents>();
+    app.add_systems(
+        Update,
+        |mut fell: EventReader<VoxelWillFall<SandWorld>>,
+         mut landed: EventReader<VoxelWillLand<SandWorld>>,
+         mut events: ResMut<FallingVoxelEvents>| {
+            events.fell.extend(fell.read().map(|ev| ev.position));
+            events.landed.extend(landed.read().map(|ev| ev.position));
+        },
+    );
+
+    // Two sand voxels on a stone pillar
+    app.add_systems(Startup, |mut voxel_world: VoxelWorld<SandWorld>| {
+        voxel_world.fill_box(
+            IVec3::new(0, 0, 0),
+            IVec3::new(0, 2, 0),
+            WorldVoxel::Solid(0),
+        );
+        voxel_world.fill_box(
+            IVec3::new(0, 3, 0),
+            IVec3::new(0, 4, 0),
+            WorldVoxel::Solid(SAND),
+        );
+    });
+    let run = |app: &mut App, frames: usize| {
+        for _ in 0..frames {
+            app.update();
+        }
+    };
+    run(&mut app, 10);
+    assert!(app.world.resource::<FallingVoxelEvents>().fell.is_empty());
+
+    // Removing the top of the pillar drops the sand on top of it, one voxel at a time
+    app.world
+        .run_system_once(|mut voxel_world: VoxelWorld<SandWorld>| {
+            voxel_world.set_voxel(IVec3::new(0, 2, 0), WorldVoxel::Air);
+        });
+    app.update();
+    let mut query = app.world.query::<(&FallingVoxel<SandWorld>, &Transform)>();
+    let falling: Vec<_> = query.iter(&app.world).collect();
+    assert_eq!(falling.len(), 1);
+    assert_eq!(falling[0].0.origin, IVec3::new(0, 3, 0));
+    assert!(falling[0].1.translation.y < 3.0);
+
+    run(&mut app, 50);
+    let events = app.world.resource::<FallingVoxelEvents>();
+    assert_eq!(events.fell, vec![IVec3::new(0, 3, 0), IVec3::new(0, 4, 0)]);
+    assert_eq!(
+        events.landed,
+        vec![IVec3::new(0, 2, 0), IVec3::new(0, 3, 0)]
+    );
+    assert_eq!(query.iter(&app.world).count(), 0);
+
+    app.world
+        .run_system_once(|voxel_world: VoxelWorld<SandWorld>| {
+            assert_eq!(
+                voxel_world.get_voxel(IVec3::new(0, 2, 0)),
+                WorldVoxel::Solid(SAND)
+            );
+            assert_eq!(
+                voxel_world.get_voxel(IVec3::new(0, 3, 0)),
+                WorldVoxel::Solid(SAND)
+            );
+            assert_eq!(voxel_world.get_voxel(IVec3::new(0, 4, 0)), WorldVoxel::Air);
+        });
+}
+
+const BEDROCK: u8 = 4;
+
+#[derive(Resource, Clone, Default)]
+struct IntegrityWorld;
+
+impl VoxelWorldConfig for IntegrityWorld {
+    type Voxel = WorldVoxel;
+
+    fn anchor_mapper(&self) -> AnchorMapperFn {
+        Arc::new(|material| material == BEDROCK)
+    }
+
+    // A generated island
+    fn voxel_lookup_delegate(&self) -> VoxelLookupDelegate {
+        Box::new(|_| {
+            Box::new(|pos: IVec3| {
+                if pos.y == 6 && pos.z == 10 && (10..=11).contains(&pos.x) {
+                    WorldVoxel::Solid(0)
+                } else {
+                    WorldVoxel::Unset
+                }
+            })
+        })
+    }
+}
+
+#[test]
+fn floating_voxels_are_found_and_detached() {
+    let mut app = _test_setup_app_with_config::<IntegrityWorld>();
+
+    // A floor with a pillar holding up a beam, and a voxel hanging from bedrock
+    app.add_systems(Startup, |mut voxel_world: VoxelWorld<IntegrityWorld>| {
+        let stone = WorldVoxel::Solid(0);
+        voxel_world.fill_box(IVec3::new(6, 0, 6), IVec3::new(12, 0, 10), stone);
+        voxel_world.fill_box(IVec3::new(8, 1, 8), IVec3::new(8, 3, 8), stone);
+        voxel_world.fill_box(IVec3::new(9, 3, 8), IVec3::new(11, 3, 8), stone);
+        voxel_world.set_voxel(IVec3::new(14, 8, 14), WorldVoxel::Solid(BEDROCK));
+        voxel_world.set_voxel(IVec3::new(14, 7, 14), stone);
+    });
+    app.update();
+    _insert_generated_chunk::<IntegrityWorld>(&mut app, IVec3::ZERO);
+
+    app.world
+        .run_system_once(|mut voxel_world: VoxelWorld<IntegrityWorld>| {
+            let options = IntegrityOptions::default();
+            assert!(voxel_world
+                .find_floating_voxels([IVec3::new(8, 2, 8)], options)
+                .is_empty());
+
+            // Cutting the pillar leaves the beam and the top of the pillar floating
+            voxel_world.set_voxel(IVec3::new(8, 2, 8), WorldVoxel::Air);
+            let floating = voxel_world.find_floating_voxels([IVec3::new(8, 2, 8)], options);
+            assert_eq!(floating.len(), 1);
+            assert_eq!(floating[0].len(), 4);
+            assert_eq!(floating[0].min, IVec3::new(8, 3, 8));
+            assert_eq!(floating[0].max, IVec3::new(11, 3, 8));
+
+            // Groups larger than the limit are assumed to be anchored
+            let small = IntegrityOptions {
+                max_group_size: 3,
+                ..options
+            };
+            assert!(voxel_world
+                .find_floating_voxels([IVec3::new(8, 2, 8)], small)
+                .is_empty());
+
+            let island = voxel_world.find_floating_voxels([IVec3::new(10, 7, 10)], options);
+            assert_eq!(island.len(), 1);
+            assert_eq!(island[0].size(), UVec3::new(2, 1, 1));
+            assert!(voxel_world
+                .find_floating_voxels([IVec3::new(14, 6, 14)], options)
+                .is_empty());
+        });
+
+    let detached = app.world.run_system_once(
+        |mut commands: Commands, mut voxel_world: VoxelWorld<IntegrityWorld>| {
+            voxel_world.detach_floating_voxels(
+                &mut commands,
+                [IVec3::new(8, 2, 8)],
+                IntegrityOptions::default(),
+            )
+        },
+    );
+    assert_eq!(detached.len(), 1);
+    let body = app
+        .world
+        .get::<VoxelBody<IntegrityWorld>>(detached[0])
+        .unwrap();
+    assert_eq!(body.origin, IVec3::new(8, 3, 8));
+    assert_eq!(body.voxels.size(), UVec3::new(4, 1, 1));
+    assert_eq!(body.voxels.get(UVec3::ZERO), WorldVoxel::Solid(0));
+    let transform = app.world.get::<Transform>(detached[0]).unwrap();
+    assert_eq!(transform.translation, Vec3::new(8.0, 3.0, 8.0));
+    let parent = app.world.get::<Parent>(detached[0]).unwrap().get();
+    assert!(app.world.get::<WorldRoot<IntegrityWorld>>(parent).is_some());
+
+    app.world
+        .run_system_once(|voxel_world: VoxelWorld<IntegrityWorld>| {
+            assert_eq!(voxel_world.get_voxel(IVec3::new(10, 3, 8)), WorldVoxel::Air);
+            assert_eq!(
+                voxel_world.get_voxel(IVec3::new(8, 1, 8)),
+                WorldVoxel::Solid(0)
+            );
+        });
+
+    // Detached generated voxels are replaced with air, instead of being generated again
+    let detached = app.world.run_system_once(
+        |mut commands: Commands, mut voxel_world: VoxelWorld<IntegrityWorld>| {
+            voxel_world.detach_floating_voxels(
+                &mut commands,
+                [IVec3::new(10, 7, 10)],
+                IntegrityOptions::default(),
+            )
+        },
+    );
+    assert_eq!(detached.len(), 1);
+    app.update();
+    app.world
+        .run_system_once(|voxel_world: VoxelWorld<IntegrityWorld>| {
+            assert_eq!(
+                voxel_world.get_voxel(IVec3::new(10, 6, 10)),
+                WorldVoxel::Air
+            );
+            assert_eq!(
+                voxel_world.get_voxel(IVec3::new(11, 6, 10)),
+                WorldVoxel::Air
+            );
+        });
+}
+
+const ROTATED_BLOCK_SAND: u16 = 301;
+
+#[derive(Resource, Clone, Default)]
+struct RotatedSandWorld;
+
+impl VoxelWorldConfig for RotatedSandWorld {
+    type Voxel = RotatedBlock;
+
+    fn gravity_mapper(&self) -> GravityMapperFn<RotatedBlock> {
+        Arc::new(|material| material == ROTATED_BLOCK_SAND)
+    }
+
+    // Stone ground, with a generated sand voxel on a stone voxel
+    fn voxel_lookup_delegate(&self) -> VoxelLookupDelegate<RotatedBlock> {
+        Box::new(|_| {
+            Box::new(|pos: IVec3| {
+                let block = match pos {
+                    IVec3 { x: 0, y: 2, z: 0 } => ROTATED_BLOCK_SAND,
+                    IVec3 { x: 0, y: 1, z: 0 } => 300,
+                    _ if pos.y < 1 => 300,
+                    _ => ROTATED_BLOCK_AIR,
+                };
+                RotatedBlock { block, rotation: 0 }
+            })
+        })
+    }
+}
+
+#[test]
+fn generated_voxels_fall_without_being_duplicated() {
+    let mut app = _test_setup_app_with_config::<RotatedSandWorld>();
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+        20,
+    )));
+    app.update();
+    _insert_generated_chunk::<RotatedSandWorld>(&mut app, IVec3::ZERO);
+
+    app.world
+        .run_system_once(|mut voxel_world: VoxelWorld<RotatedSandWorld>| {
+            let sand = voxel_world.get_voxel(IVec3::new(0, 2, 0));
+            assert_eq!(sand.block, ROTATED_BLOCK_SAND);
+            voxel_world.set_voxel(IVec3::new(0, 1, 0), RotatedBlock::empty());
+        });
+    for _ in 0..50 {
+        app.update();
+    }
+
+    // The sand was replaced with an empty voxel, instead of being generated again
+    app.world
+        .run_system_once(|voxel_world: VoxelWorld<RotatedSandWorld>| {
+            let block = |y| voxel_world.get_voxel(IVec3::new(0, y, 0)).block;
+            assert_eq!(block(1), ROTATED_BLOCK_SAND);
+            assert_eq!(block(2), ROTATED_BLOCK_AIR);
+        });
+}
+
+#[test]
+fn physics_queries() {
+    // Flat ground with a wall at x = 3
+    let get_voxel = |pos: IVec3| -> WorldVoxel {
+        if pos.y < 1 || pos.x == 3 {
+            WorldVoxel::Solid(0)
+        } else {
+            WorldVoxel::Air
+        }
+    };
+    let player = Aabb3d {
+        min: Vec3::new(0.2, 2.0, 0.2),
+        max: Vec3::new(0.8, 3.8, 0.8),
+    };
+
+    // Falling lands on the ground
+    let result = sweep_aabb(get_voxel, player, Vec3::new(0.0, -5.0, 0.0));
+    assert_eq!(result.motion, Vec3::new(0.0, -1.0, 0.0));
+    assert_eq!(result.normal, Vec3::Y);
+    assert!(is_grounded(get_voxel, result.aabb));
+    assert!(!is_grounded(get_voxel, player));
+
+    // Moving diagonally into the wall slides along it
+    let result = sweep_aabb(get_voxel, result.aabb, Vec3::new(5.0, 0.0, 2.0));
+    assert_eq!(result.aabb.max.x, 3.0);
+    assert_eq!(result.motion.z, 2.0);
+    assert_eq!(result.normal, Vec3::NEG_X);
+
+    let ground = ground_check(get_voxel, player, 2.0).unwrap();
+    assert_eq!(ground.distance, 1.0);
+    assert_eq!(ground.position, IVec3::ZERO);
+    assert!(ground_check(get_voxel, player, 0.5).is_none());
+
+    let touching = overlap_sphere(get_voxel, Vec3::new(0.5, 1.2, 0.5), 0.3);
+    assert_eq!(touching, vec![(IVec3::ZERO, WorldVoxel::Solid(0))]);
+    assert!(overlap_sphere(get_voxel, Vec3::new(0.5, 1.5, 0.5), 0.3).is_empty());
+
+    let capsule_top = Vec3::new(0.5, 3.0, 0.5);
+    let touching = overlap_capsule(get_voxel, capsule_top, Vec3::new(0.5, 1.2, 0.5), 0.3);
+    assert_eq!(touching, vec![(IVec3::ZERO, WorldVoxel::Solid(0))]);
+    assert!(overlap_capsule(get_voxel, capsule_top, Vec3::new(0.5, 1.5, 0.5), 0.3).is_empty());
+
+    // Fluids don't collide
+    let get_voxel = |pos: IVec3| -> WorldVoxel {
+        if pos.y < 1 {
+            WorldVoxel::Solid(0)
+        } else if pos.y < 3 {
+            WorldVoxel::Fluid(WATER, FluidLevel::Source)
+        } else {
+            WorldVoxel::Air
+        }
+    };
+    let result = sweep_aabb(get_voxel, player, Vec3::new(0.0, -5.0, 0.0));
+    assert_eq!(result.motion, Vec3::new(0.0, -1.0, 0.0));
+    assert_eq!(
+        ground_check(get_voxel, player, 2.0).unwrap().position,
+        IVec3::ZERO
+    );
+    assert!(overlap_sphere(get_voxel, Vec3::new(0.5, 1.5, 0.5), 0.3).is_empty());
+}
+
+#[test]
+fn character_controller_walks_on_voxels() {
+    let mut app = _test_setup_app();
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+        20,
+    )));
+
+    // A floor with a one voxel ledge at its end
+    app.add_systems(
+        Startup,
+        |mut commands: Commands, mut voxel_world: VoxelWorld<DefaultWorld>| {
+            for x in -2..=3 {
+                for z in -2..=2 {
+                    voxel_world.set_voxel(IVec3::new(x, 0, z), WorldVoxel::Solid(0));
+                    if x >= 2 {
+                        voxel_world.set_voxel(IVec3::new(x, 1, z), WorldVoxel::Solid(0));
+                    }
+                }
+            }
+            commands.spawn((
+                TransformBundle::from_transform(Transform::from_xyz(0.5, 3.0, 0.5)),
+                VoxelCharacterController::<DefaultWorld>::default(),
+            ));
+        },
+    );
+
+    let run = |app: &mut App, frames: usize, update: fn(&mut VoxelCharacterController<_>)| {
+        let mut query = app
+            .world
+            .query::<&mut VoxelCharacterController<DefaultWorld>>();
+        update(&mut query.single_mut(&mut app.world));
+        for _ in 0..frames {
+            app.update();
+        }
+        let mut query = app
+            .world
+            .query::<(&VoxelCharacterController<DefaultWorld>, &Transform)>();
+        let (controller, transform) = query.single(&app.world);
+        (controller.clone(), controller.aabb(transform.translation))
+    };
+
+    app.update();
+
+    // Falls onto the floor
+    let (controller, aabb) = run(&mut app, 50, |_| {});
+    assert!(controller.grounded);
+    assert_eq!(aabb.min.y, 1.0);
+
+    // Steps up onto the ledge, and doesn't walk off its far edge while crouching
+    let (controller, aabb) = run(&mut app, 100, |controller| {
+        controller.movement = Vec3::new(4.0, 0.0, 0.0);
+        controller.crouch = true;
+    });
+    assert!(controller.grounded);
+    assert_eq!(aabb.min.y, 2.0);
+    assert!(aabb.min.x > 3.0 && aabb.min.x < 4.0);
+
+    // Jumps
+    let (controller, aabb) = run(&mut app, 5, |controller| {
+        controller.movement = Vec3::ZERO;
+        controller.jump = true;
+    });
+    assert!(!controller.grounded);
+    assert!(aabb.min.y > 2.0);
+}
+
+#[test]
+fn chunk_voxel_position_respects_chunk_size() {
+    let chunk_size = UVec3::new(16, 8, 16);
+
+    assert_eq!(
+        get_chunk_voxel_position(IVec3::new(0, 0, 0), chunk_size),
+        (IVec3::ZERO, UVec3::ONE)
+    );
+    assert_eq!(
+        get_chunk_voxel_position(IVec3::new(17, -1, 15), chunk_size),
+        (IVec3::new(1, -1, 0), UVec3::new(2, 8, 16))
+    );
+}
+
+#[test]
+fn chunks_use_configured_size() {
+    let mut app = _test_setup_app_with_config::<NonCubicChunkWorld>();
+
+    app.add_systems(Update, |mut voxel_world: VoxelWorld<NonCubicChunkWorld>| {
+        let test_voxel = WorldVoxel::Solid(1);
+        let pos = IVec3::new(20, -3, 7);
+        voxel_world.set_voxel(pos, test_voxel);
+        assert_eq!(voxel_world.get_voxel(pos), test_voxel);
+    });
+
+    for _ in 0..10 {
+        app.update();
+    }
+
+    let mut query = app
+        .world
+        .query::<(&crate::prelude::Chunk<NonCubicChunkWorld>, &Transform)>();
+    let mut chunk_count = 0;
+    for (chunk, transform) in query.iter(&app.world) {
+        chunk_count += 1;
+        assert_eq!(chunk.size, UVec3::new(16, 8, 16));
+        assert_eq!(
+            transform.translation,
+            chunk.position.as_vec3() * Vec3::new(16.0, 8.0, 16.0) - 1.0
+        );
+    }
+    assert!(chunk_count > 0);
+}
+
+#[test]
+fn edit_file_round_trip() {
+    let mut voxels = HashMap::<IVec3, WorldVoxel>::new();
+    voxels.insert(IVec3::new(0, 0, 0), WorldVoxel::Solid(1));
+    voxels.insert(IVec3::new(-1, -40, 5), WorldVoxel::Air);
+    voxels.insert(IVec3::new(100, 200, 300), WorldVoxel::Solid(255));
+    voxels.insert(IVec3::new(15, 7, 15), WorldVoxel::Unset);
+
+    let mut bytes = Vec::new();
+    write_voxels(&mut bytes, &voxels, UVec3::new(16, 8, 16)).unwrap();
+    assert_eq!(read_voxels(bytes.as_slice()).unwrap(), voxels);
+
+    // Chunks that don't fit in world coordinates should be rejected
+    let mut far = HashMap::<IVec3, WorldVoxel>::new();
+    far.insert(IVec3::new(i32::MAX, 0, 0), WorldVoxel::Solid(1));
+    let mut far_bytes = Vec::new();
+    write_voxels(&mut far_bytes, &far, UVec3::new(16, 8, 16)).unwrap();
+    // Double the chunk size, so the chunk position no longer fits
+    far_bytes[7..11].copy_from_slice(&32u32.to_le_bytes());
+    let error = read_voxels::<WorldVoxel, _>(far_bytes.as_slice()).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    // Anything that isn't an edit file should be rejected
+    bytes[0] = b'X';
+    assert!(read_voxels::<WorldVoxel, _>(bytes.as_slice()).is_err());
+}
+
+#[test]
+fn save_and_load_world_edits() {
+    let path = std::env::temp_dir().join(format!("bvw_test_edits_{}.bin", std::process::id()));
+    let positions = vec![
+        IVec3::new(0, 0, 0),
+        IVec3::new(-10, 5, 3),
+        IVec3::new(70, -33, 1),
+    ];
+
+    let mut app = _test_setup_app();
+    let save_path = path.clone();
+    let save_positions = positions.clone();
+    app.add_systems(Update, move |mut voxel_world: VoxelWorld<DefaultWorld>| {
+        for pos in save_positions.iter() {
+            voxel_world.set_voxel(*pos, WorldVoxel::Solid(2));
+        }
+        voxel_world.save_to(&save_path).unwrap();
+    });
+    app.update();
+
+    let mut app = _test_setup_app();
+    let load_path = path.clone();
+    app.add_systems(Startup, move |mut voxel_world: VoxelWorld<DefaultWorld>| {
+        voxel_world.load_from(&load_path).unwrap();
+    });
+    app.update();
+
+    app.add_systems(Update, move |voxel_world: VoxelWorld<DefaultWorld>| {
+        for pos in positions.iter() {
+            assert_eq!(voxel_world.get_voxel(*pos), WorldVoxel::Solid(2));
+        }
+    });
+    app.update();
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn chunk_edits_only_hold_nearby_pages() {
+    let mut pages = EditPages::<WorldVoxel>::new(UVec3::splat(32));
+    pages.insert(IVec3::new(5, 5, 5), WorldVoxel::Solid(1));
+    pages.insert(IVec3::new(-1, 5, 5), WorldVoxel::Solid(2));
+    pages.insert(IVec3::new(500, 5, 5), WorldVoxel::Solid(3));
+
+    let edits = pages.chunk_edits(IVec3::ZERO);
+    assert_eq!(edits.get(&IVec3::new(5, 5, 5)), Some(WorldVoxel::Solid(1)));
+    // Neighbour pages are included for the chunk padding
+    assert_eq!(edits.get(&IVec3::new(-1, 5, 5)), Some(WorldVoxel::Solid(2)));
+    assert_eq!(edits.get(&IVec3::new(500, 5, 5)), None);
+}
+
+#[test]
+fn edit_regions_are_evicted_and_reloaded() {
+    let dir = std::env::temp_dir().join(format!("bvw_test_regions_{}", std::process::id()));
+    let chunk_size = UVec3::splat(16);
+    let region_size = UVec3::splat(2);
+    let near = IVec3::new(3, 4, 5);
+    let far = IVec3::new(1000, 4, 5);
+
+    let mut pages = EditPages::<WorldVoxel>::with_region_files(chunk_size, &dir, region_size);
+    pages.insert(near, WorldVoxel::Solid(1));
+    pages.insert(far, WorldVoxel::Solid(2));
+
+    // Moving far away from the first edit writes its region to disk and drops it from memory
+    pages.stream_regions(far / 16, 2);
+    assert!(dir.join(region_file_name(IVec3::ZERO)).exists());
+    assert_eq!(pages.get(&near), None);
+    assert_eq!(pages.get(&far), Some(WorldVoxel::Solid(2)));
+
+    // Coming back loads it again
+    pages.stream_regions(IVec3::ZERO, 2);
+    assert_eq!(pages.get(&near), Some(WorldVoxel::Solid(1)));
+    assert_eq!(pages.get(&far), None);
+
+    // A fresh storage picks up everything from disk once all regions are flushed
+    pages.flush_regions().unwrap();
+    let mut pages = EditPages::<WorldVoxel>::with_region_files(chunk_size, &dir, region_size);
+    pages.stream_regions(far / 16, 2);
+    assert_eq!(pages.get(&far), Some(WorldVoxel::Solid(2)));
+    assert_eq!(pages.get(&near), None);
+
+    // Reading a voxel of an evicted region through the world requests the region, which is then
+    // loaded by the next pass and kept in memory for a while
+    let modified = ModifiedVoxels::<DefaultWorld>::new(pages);
+    assert_eq!(modified.get_voxel(&near), None);
+    modified.write().unwrap().stream_regions(far / 16, 2);
+    assert_eq!(modified.get_voxel(&near), Some(WorldVoxel::Solid(1)));
+    for _ in 0..REQUESTED_REGION_PASSES {
+        modified.write().unwrap().stream_regions(far / 16, 2);
+    }
+    assert_eq!(modified.read().unwrap().get(&near), None);
+
+    // Saving includes the edits of regions that are only on disk
+    let save_path = dir.join("all_edits.bin");
+    modified.write().unwrap().insert(far, WorldVoxel::Solid(3));
+    modified.save_to(&save_path).unwrap();
+    let saved = load_voxels::<WorldVoxel>(&save_path).unwrap();
+    assert_eq!(saved.get(&near), Some(&WorldVoxel::Solid(1)));
+    assert_eq!(saved.get(&far), Some(&WorldVoxel::Solid(3)));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn unreadable_edit_regions_are_never_overwritten() {
+    let dir = std::env::temp_dir().join(format!("bvw_test_bad_region_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let chunk_size = UVec3::splat(16);
+    let region_size = UVec3::splat(2);
+    let near = IVec3::new(3, 4, 5);
+
+    // A region file written with a wider material id can't be read as `WorldVoxel<u8>`
+    let mut voxels = HashMap::<IVec3, WorldVoxel<u16>>::new();
+    voxels.insert(near, WorldVoxel::Solid(1000));
+    let path = dir.join(region_file_name(IVec3::ZERO));
+    save_voxels(&path, &voxels, chunk_size).unwrap();
+    let original = std::fs::read(&path).unwrap();
+
+    let mut pages = EditPages::<WorldVoxel>::with_region_files(chunk_size, &dir, region_size);
+    pages.insert(near + IVec3::X, WorldVoxel::Solid(2));
+    assert_eq!(pages.get(&(near + IVec3::X)), Some(WorldVoxel::Solid(2)));
+
+    // Neither evicting nor flushing the region writes its file
+    pages.stream_regions(IVec3::splat(1000), 2);
+    pages.flush_regions().unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), original);
+    assert!(pages.collect_all().is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
 #[test]
 fn set_voxel_can_be_found_by_2d_coordinate() {
     let mut app = _test_setup_app();
@@ -203,12 +2859,16 @@ fn raycast_finds_voxel() {
                 IVec3::new(0, 0, 0),
                 ChunkData {
                     position: IVec3::new(0, 0, 0),
-                    voxels: Some(std::sync::Arc::new([WorldVoxel::Unset; 39304])),
+                    voxels: Some(vec![WorldVoxel::Unset; 39304].into()),
                     voxels_hash: 0,
                     is_full: false,
                     is_empty: false,
                     fill_type: FillType::Mixed,
                     entity: Entity::PLACEHOLDER,
+                    chunk_size: UVec3::splat(32),
+                    lod: 0,
+                    light: None,
+                    has_fluid: false,
                 },
                 ChunkWillSpawn::<DefaultWorld>::new(IVec3::new(0, 0, 0), Entity::PLACEHOLDER),
             ));
@@ -0,0 +1,373 @@
+///
+/// MagicaVoxel models
+/// Import and export of `.vox` files. Models are loaded as `VoxModel` assets, with the palette
+/// indices of the file mapped to material ids, and can be pasted into a world like prefabs.
+///
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    utils::BoxedFuture,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    edit_storage::{invalid_data, read_array},
+    voxel::VoxelData,
+    voxel_prefab::VoxelPrefab,
+};
+
+/// Maps the palette indices of `.vox` files to material ids and back.
+///
+/// Palette indices that aren't in `materials` map to the material id with the same number.
+/// This is also the settings type of the `.vox` asset loader, so each model can be loaded with
+/// its own table through `AssetServer::load_with_settings`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct VoxPaletteMapping {
+    /// Material id for each palette index, from 1 to 255
+    pub materials: HashMap<u8, u32>,
+}
+
+impl VoxPaletteMapping {
+    /// Map the palette index `index` to `material`
+    pub fn with(mut self, index: u8, material: u32) -> Self {
+        self.materials.insert(index, material);
+        self
+    }
+
+    /// The material id of a palette index
+    pub fn material(&self, index: u8) -> u32 {
+        self.materials.get(&index).copied().unwrap_or(index as u32)
+    }
+
+    /// The palette index used when exporting a material. If several indices map to the
+    /// material, the lowest one is used. `None` if the material has no palette index.
+    pub fn palette_index(&self, material: u32) -> Option<u8> {
+        let mapped = self
+            .materials
+            .iter()
+            .filter(|(_, mapped)| **mapped == material)
+            .map(|(index, _)| *index)
+            .min();
+        mapped.or_else(|| {
+            u8::try_from(material)
+                .ok()
+                .filter(|index| *index != 0 && !self.materials.contains_key(index))
+        })
+    }
+}
+
+/// A voxel model imported from or exported to a MagicaVoxel `.vox` file.
+///
+/// Positions use the axes of Bevy, with Y up. MagicaVoxel uses Z up, so the axes are swapped
+/// when reading and writing files. Only the first model of a file is read; scene transforms,
+/// layers and materials of the file are ignored.
+#[derive(Asset, TypePath, Clone, Debug, PartialEq, Eq)]
+pub struct VoxModel {
+    size: UVec3,
+    voxels: Vec<(UVec3, u32)>,
+    palette: Option<Box<[[u8; 4]; 256]>>,
+}
+
+impl VoxModel {
+    /// Create a model from the material id of each filled position. Panics if a position is
+    /// outside of the model.
+    pub fn new(size: UVec3, voxels: Vec<(UVec3, u32)>) -> Self {
+        assert!(
+            voxels
+                .iter()
+                .all(|(position, _)| position.cmplt(size).all()),
+            "vox model voxel outside of its size"
+        );
+        Self {
+            size,
+            voxels,
+            palette: None,
+        }
+    }
+
+    /// Create a model from the voxels of a prefab that have a material. All other voxels are
+    /// left empty.
+    pub fn from_prefab<V: VoxelData>(prefab: &VoxelPrefab<V>) -> Self {
+        let voxels = prefab
+            .iter()
+            .filter_map(|(position, voxel)| Some((position, voxel.material()?.into())))
+            .collect();
+        Self::new(prefab.size(), voxels)
+    }
+
+    /// Convert the model to a prefab, creating a voxel from the material id of each filled
+    /// position with `voxel`. Empty positions are unset, so they are never pasted.
+    pub fn to_prefab<V: VoxelData>(&self, voxel: impl Fn(u32) -> V) -> VoxelPrefab<V> {
+        let voxels = self.voxels.iter();
+        VoxelPrefab::from_voxels(
+            self.size,
+            voxels.map(|(position, material)| (*position, voxel(*material))),
+        )
+    }
+
+    pub fn size(&self) -> UVec3 {
+        self.size
+    }
+
+    /// The position and material id of each filled voxel
+    pub fn voxels(&self) -> &[(UVec3, u32)] {
+        &self.voxels
+    }
+
+    /// The RGBA color of each palette index, if the model has its own palette. Index 0 is
+    /// unused. Without a palette, MagicaVoxel uses its default palette.
+    pub fn palette(&self) -> Option<&[[u8; 4]; 256]> {
+        self.palette.as_deref()
+    }
+
+    /// The color of a palette index, if the model has its own palette
+    pub fn color(&self, index: u8) -> Option<Color> {
+        let [r, g, b, a] = self.palette()?[index as usize];
+        Some(Color::rgba_u8(r, g, b, a))
+    }
+
+    /// Set the palette written with the model. See `palette`.
+    pub fn set_palette(&mut self, palette: Option<[[u8; 4]; 256]>) {
+        self.palette = palette.map(Box::new);
+    }
+
+    /// Read the first model of a `.vox` file, mapping its palette indices to material ids
+    /// with `mapping`
+    pub fn read_from<R: Read>(mut reader: R, mapping: &VoxPaletteMapping) -> io::Result<Self> {
+        let magic: [u8; 4] = read_array(&mut reader)?;
+        if magic != VOX_FILE_MAGIC {
+            return Err(invalid_data("not a MagicaVoxel file"));
+        }
+        let _version = read_u32(&mut reader)?;
+
+        let main = read_chunk_header(&mut reader)?;
+        if main.id != *b"MAIN" {
+            return Err(invalid_data("vox file has no MAIN chunk"));
+        }
+        skip(&mut reader, main.content_len)?;
+
+        let mut children = reader.take(main.children_len as u64);
+        let mut size = None;
+        let mut voxels = None;
+        let mut palette = None;
+        while children.limit() > 0 {
+            let header = read_chunk_header(&mut children)?;
+            let mut content = Vec::new();
+            (&mut children)
+                .take(header.content_len as u64)
+                .read_to_end(&mut content)?;
+            if content.len() != header.content_len as usize {
+                return Err(invalid_data("vox chunk is truncated"));
+            }
+            skip(&mut children, header.children_len)?;
+
+            let mut content = content.as_slice();
+            match &header.id {
+                b"SIZE" if size.is_none() => {
+                    let [x, y, z] = [(); 3].map(|_| read_u32(&mut content));
+                    let vox_size = UVec3::new(x?, y?, z?);
+                    if vox_size.cmpgt(UVec3::splat(VOX_MAX_SIZE)).any() {
+                        return Err(invalid_data("vox model is too large"));
+                    }
+                    size = Some(vox_size);
+                }
+                b"XYZI" if voxels.is_none() => {
+                    let Some(vox_size) = size else {
+                        return Err(invalid_data("vox XYZI chunk before SIZE chunk"));
+                    };
+                    let count = read_u32(&mut content)?;
+                    let mut model_voxels = Vec::new();
+                    for _ in 0..count {
+                        let [x, y, z, index] = read_array(&mut content)?;
+                        let vox_position = UVec3::new(x as u32, y as u32, z as u32);
+                        if index == 0 || vox_position.cmpge(vox_size).any() {
+                            continue;
+                        }
+                        model_voxels.push((from_vox_axes(vox_position, vox_size), index));
+                    }
+                    voxels = Some(model_voxels);
+                }
+                b"RGBA" => {
+                    // Entry i of the chunk is the color of palette index i + 1
+                    let mut colors = [[0; 4]; 256];
+                    for color in &mut colors[1..] {
+                        *color = read_array(&mut content)?;
+                    }
+                    palette = Some(Box::new(colors));
+                }
+                _ => {}
+            }
+        }
+
+        let (Some(vox_size), Some(voxels)) = (size, voxels) else {
+            return Err(invalid_data("vox file has no model"));
+        };
+        Ok(Self {
+            size: UVec3::new(vox_size.x, vox_size.z, vox_size.y),
+            voxels: voxels
+                .into_iter()
+                .map(|(position, index)| (position, mapping.material(index)))
+                .collect(),
+            palette,
+        })
+    }
+
+    /// Write the model as a `.vox` file, mapping its material ids to palette indices with
+    /// `mapping`. Fails if the model is larger than 256 voxels along any axis, or if a material
+    /// has no palette index.
+    pub fn write_to<W: Write>(&self, mut writer: W, mapping: &VoxPaletteMapping) -> io::Result<()> {
+        if self.size.cmpgt(UVec3::splat(VOX_MAX_SIZE)).any() {
+            return Err(invalid_input(format!(
+                "vox models can't be larger than {VOX_MAX_SIZE} voxels along any axis"
+            )));
+        }
+        let vox_size = UVec3::new(self.size.x, self.size.z, self.size.y);
+
+        let mut size = Vec::with_capacity(12);
+        for value in vox_size.to_array() {
+            size.extend(value.to_le_bytes());
+        }
+
+        let mut xyzi = Vec::with_capacity(4 + self.voxels.len() * 4);
+        xyzi.extend((self.voxels.len() as u32).to_le_bytes());
+        for (position, material) in &self.voxels {
+            let Some(index) = mapping.palette_index(*material) else {
+                return Err(invalid_input(format!(
+                    "material {material} has no vox palette index"
+                )));
+            };
+            let position = to_vox_axes(*position, self.size);
+            xyzi.extend([position.x as u8, position.y as u8, position.z as u8, index]);
+        }
+
+        let mut chunks = vec![(*b"SIZE", size), (*b"XYZI", xyzi)];
+        if let Some(palette) = &self.palette {
+            let mut rgba = palette[1..].concat();
+            rgba.extend([0; 4]);
+            chunks.push((*b"RGBA", rgba));
+        }
+
+        writer.write_all(&VOX_FILE_MAGIC)?;
+        writer.write_all(&VOX_FILE_VERSION.to_le_bytes())?;
+        let children_len: u32 = chunks
+            .iter()
+            .map(|(_, content)| VOX_CHUNK_HEADER_LEN + content.len() as u32)
+            .sum();
+        write_chunk_header(&mut writer, *b"MAIN", 0, children_len)?;
+        for (id, content) in chunks {
+            write_chunk_header(&mut writer, id, content.len() as u32, 0)?;
+            writer.write_all(&content)?;
+        }
+        writer.flush()
+    }
+
+    /// Save the model to a `.vox` file at `path`. See `write_to`.
+    pub fn save(&self, path: impl AsRef<Path>, mapping: &VoxPaletteMapping) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.write_to(BufWriter::new(file), mapping)
+    }
+
+    /// Load a model from a `.vox` file at `path`. See `read_from`.
+    pub fn load(path: impl AsRef<Path>, mapping: &VoxPaletteMapping) -> io::Result<Self> {
+        let file = File::open(path)?;
+        Self::read_from(BufReader::new(file), mapping)
+    }
+}
+
+/// Magic bytes at the start of every `.vox` file
+pub const VOX_FILE_MAGIC: [u8; 4] = *b"VOX ";
+
+/// The `.vox` file version written by `VoxModel::write_to`
+pub const VOX_FILE_VERSION: u32 = 150;
+
+/// The largest size of a `.vox` model along any axis
+pub const VOX_MAX_SIZE: u32 = 256;
+
+const VOX_CHUNK_HEADER_LEN: u32 = 12;
+
+struct ChunkHeader {
+    id: [u8; 4],
+    content_len: u32,
+    children_len: u32,
+}
+
+fn read_chunk_header<R: Read>(reader: &mut R) -> io::Result<ChunkHeader> {
+    Ok(ChunkHeader {
+        id: read_array(reader)?,
+        content_len: read_u32(reader)?,
+        children_len: read_u32(reader)?,
+    })
+}
+
+fn write_chunk_header<W: Write>(
+    writer: &mut W,
+    id: [u8; 4],
+    content_len: u32,
+    children_len: u32,
+) -> io::Result<()> {
+    writer.write_all(&id)?;
+    writer.write_all(&content_len.to_le_bytes())?;
+    writer.write_all(&children_len.to_le_bytes())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(reader)?))
+}
+
+fn skip<R: Read>(reader: &mut R, len: u32) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(len as u64), &mut io::sink())?;
+    if skipped != len as u64 {
+        return Err(invalid_data("vox chunk is truncated"));
+    }
+    Ok(())
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Convert a position in a Z up `.vox` model of size `vox_size` to Y up. Y is flipped to keep
+/// the model from being mirrored.
+fn from_vox_axes(position: UVec3, vox_size: UVec3) -> UVec3 {
+    UVec3::new(position.x, position.z, vox_size.y - 1 - position.y)
+}
+
+/// Convert a position in a Y up model of size `size` to the axes of `.vox` files
+fn to_vox_axes(position: UVec3, size: UVec3) -> UVec3 {
+    UVec3::new(position.x, size.z - 1 - position.z, position.y)
+}
+
+/// Loads `VoxModel` assets from MagicaVoxel `.vox` files. The palette mapping is given
+/// through the loader settings.
+#[derive(Default)]
+pub(crate) struct VoxModelLoader;
+
+impl AssetLoader for VoxModelLoader {
+    type Asset = VoxModel;
+    type Settings = VoxPaletteMapping;
+    type Error = io::Error;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        settings: &'a VoxPaletteMapping,
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<VoxModel, io::Error>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            VoxModel::read_from(bytes.as_slice(), settings)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["vox"]
+    }
+}
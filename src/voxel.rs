@@ -1,49 +1,284 @@
+use std::{
+    fmt::Debug,
+    hash::Hash,
+    io::{self, Read, Write},
+};
+
 use bevy::{prelude::*, render::primitives::Aabb};
-use block_mesh::{MergeVoxel, Voxel, VoxelVisibility};
 
 pub const VOXEL_SIZE: f32 = 1.;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Default)]
-pub enum WorldVoxel {
+/// How a voxel is treated when meshing
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum VoxelVisibility {
+    /// Not rendered at all
+    Empty,
+    /// Rendered, and hides the faces of any neighbouring voxels
+    Opaque,
+    /// Rendered with alpha blending, in a separate mesh. Hides the faces of identical
+    /// neighbouring voxels, but not the faces of opaque voxels behind it.
+    Translucent,
+}
+
+/// The highest level of a fluid, which sources and falling fluid have
+pub const MAX_FLUID_LEVEL: u8 = 8;
+
+/// How much fluid a voxel holds, see `VoxelData::fluid_level`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FluidLevel {
+    /// A source, which never drains and keeps the fluid around it flowing
+    Source,
+    /// Fluid falling from the fluid above it. Spreads like a source, but drains once the fluid
+    /// above it is gone.
+    Falling,
+    /// Fluid flowing sideways, from 1 to `MAX_FLUID_LEVEL - 1`. The level drops with every voxel
+    /// the fluid flows away from its source, and the fluid drains when nothing feeds it.
+    Flowing(u8),
+}
+
+impl FluidLevel {
+    /// The level the fluid spreads from
+    pub fn level(&self) -> u8 {
+        match self {
+            FluidLevel::Source | FluidLevel::Falling => MAX_FLUID_LEVEL,
+            FluidLevel::Flowing(level) => *level,
+        }
+    }
+
+    /// The height of the surface of the fluid within its voxel, between 0 and 1
+    pub fn height(&self) -> f32 {
+        match self {
+            FluidLevel::Falling => 1.0,
+            level => level.level() as f32 / (MAX_FLUID_LEVEL + 1) as f32,
+        }
+    }
+}
+
+/// The data stored for each voxel in a world. The voxel type of a world is chosen through
+/// `VoxelWorldConfig::Voxel`, and `WorldVoxel` is the built-in implementation.
+///
+/// Implement this for your own type if you need to store more per voxel, like orientation,
+/// damage or a block state id.
+///
+/// The `Default` value of a voxel type is treated as unset, meaning there is no data for
+/// that position.
+///
+/// Voxel types also need to implement `TypePath`, which can be derived, so they can be stored
+/// in assets like `VoxelPrefab`.
+pub trait VoxelData:
+    Copy + Debug + Default + PartialEq + Eq + Hash + TypePath + Send + Sync + 'static
+{
+    /// The material id passed to `VoxelWorldConfig::texture_index_mapper`
+    type Material: Copy + Debug + Eq + Hash + Into<u32> + Send + Sync + 'static;
+
+    /// Voxels can only get merged into larger quads when meshing if their merge values are equal
+    type MergeValue: Eq;
+
+    /// How the voxel should be treated when meshing
+    fn visibility(&self) -> VoxelVisibility;
+
+    fn merge_value(&self) -> Self::MergeValue;
+
+    /// The material used to render this voxel, or `None` if the voxel is not rendered
+    fn material(&self) -> Option<Self::Material>;
+
+    /// Write the voxel in a compact binary form, used when saving modified voxels
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()>;
+
+    /// Read a voxel previously written by `write_to`
+    fn read_from(reader: &mut dyn Read) -> io::Result<Self>;
+
+    /// An empty voxel, like air, written where a voxel is taken out of the world. This must not
+    /// be the unset voxel, or the generated voxel would show up again in its place.
+    fn empty() -> Self;
+
+    fn is_unset(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn is_air(&self) -> bool {
+        !self.is_unset() && self.visibility() == VoxelVisibility::Empty
+    }
+
+    /// Whether the voxel is rendered, either opaque or translucent
+    fn is_solid(&self) -> bool {
+        self.visibility() != VoxelVisibility::Empty
+    }
+
+    fn is_opaque(&self) -> bool {
+        self.visibility() == VoxelVisibility::Opaque
+    }
+
+    /// The fluid level of the voxel, or `None` if the voxel isn't a fluid. Fluid voxels are
+    /// spread by the fluid simulation, and meshed with the height of their level.
+    fn fluid_level(&self) -> Option<FluidLevel> {
+        None
+    }
+
+    /// The same fluid as this voxel, with another level. Only called on fluid voxels.
+    fn with_fluid_level(&self, _level: FluidLevel) -> Self {
+        *self
+    }
+
+    /// The voxel left behind when the fluid in this voxel drains away. Unset by default, which
+    /// makes the voxel generated again.
+    fn drained(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// Integer types that can be used as the material id of a `WorldVoxel`.
+/// Implemented for `u8`, `u16` and `u32`.
+pub trait MaterialId:
+    Copy + Debug + Default + Eq + Hash + Into<u32> + TypePath + Send + Sync + 'static
+{
+    /// Write the id as a little endian integer of its own width
+    fn write_to(self, writer: &mut dyn Write) -> io::Result<()>;
+
+    /// Read an id written by `write_to`
+    fn read_from(reader: &mut dyn Read) -> io::Result<Self>;
+}
+
+macro_rules! impl_material_id {
+    ($($t:ty),*) => {
+        $(
+            impl MaterialId for $t {
+                fn write_to(self, writer: &mut dyn Write) -> io::Result<()> {
+                    writer.write_all(&self.to_le_bytes())
+                }
+
+                fn read_from(reader: &mut dyn Read) -> io::Result<Self> {
+                    let mut bytes = [0; std::mem::size_of::<$t>()];
+                    reader.read_exact(&mut bytes)?;
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_material_id!(u8, u16, u32);
+
+/// The built-in voxel type. The material id is a `u8` by default, which allows for 256 materials.
+/// Use `WorldVoxel<u16>` or `WorldVoxel<u32>` if a world needs more than that.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Default, TypePath)]
+pub enum WorldVoxel<I = u8> {
     #[default]
     Unset,
     Air,
-    Solid(u8),
+    Solid(I),
+    /// A see-through voxel, like glass, water or leaves
+    Translucent(I),
+    /// A fluid, like water or lava, spread by the fluid simulation. Rendered like a translucent
+    /// voxel, with the height of its level.
+    Fluid(I, FluidLevel),
 }
 
-impl WorldVoxel {
-    pub fn is_unset(&self) -> bool {
-        *self == WorldVoxel::Unset
+const WORLD_VOXEL_TAG_UNSET: u8 = 0;
+const WORLD_VOXEL_TAG_AIR: u8 = 1;
+const WORLD_VOXEL_TAG_SOLID: u8 = 2;
+const WORLD_VOXEL_TAG_TRANSLUCENT: u8 = 3;
+const WORLD_VOXEL_TAG_FLUID: u8 = 4;
+
+impl<I: MaterialId> VoxelData for WorldVoxel<I> {
+    type Material = I;
+    type MergeValue = I;
+
+    fn visibility(&self) -> VoxelVisibility {
+        match self {
+            WorldVoxel::Unset | WorldVoxel::Air => VoxelVisibility::Empty,
+            WorldVoxel::Solid(_) => VoxelVisibility::Opaque,
+            WorldVoxel::Translucent(_) | WorldVoxel::Fluid(..) => VoxelVisibility::Translucent,
+        }
+    }
+
+    fn merge_value(&self) -> Self::MergeValue {
+        match self {
+            WorldVoxel::Solid(v) | WorldVoxel::Translucent(v) | WorldVoxel::Fluid(v, _) => *v,
+            _ => I::default(),
+        }
     }
 
-    pub fn is_air(&self) -> bool {
-        *self == WorldVoxel::Air
+    fn material(&self) -> Option<Self::Material> {
+        match self {
+            WorldVoxel::Solid(v) | WorldVoxel::Translucent(v) | WorldVoxel::Fluid(v, _) => Some(*v),
+            _ => None,
+        }
     }
 
-    pub fn is_solid(&self) -> bool {
-        matches!(self, WorldVoxel::Solid(_))
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        match *self {
+            WorldVoxel::Unset => writer.write_all(&[WORLD_VOXEL_TAG_UNSET]),
+            WorldVoxel::Air => writer.write_all(&[WORLD_VOXEL_TAG_AIR]),
+            WorldVoxel::Solid(material) => {
+                writer.write_all(&[WORLD_VOXEL_TAG_SOLID])?;
+                material.write_to(writer)
+            }
+            WorldVoxel::Translucent(material) => {
+                writer.write_all(&[WORLD_VOXEL_TAG_TRANSLUCENT])?;
+                material.write_to(writer)
+            }
+            WorldVoxel::Fluid(material, level) => {
+                writer.write_all(&[WORLD_VOXEL_TAG_FLUID])?;
+                material.write_to(writer)?;
+                // 0 for sources, 1 for falling fluid, and the level + 1 for flowing fluid
+                let level = match level {
+                    FluidLevel::Source => 0,
+                    FluidLevel::Falling => 1,
+                    FluidLevel::Flowing(level) => level + 1,
+                };
+                writer.write_all(&[level])
+            }
+        }
     }
-}
 
-impl Voxel for WorldVoxel {
-    fn get_visibility(&self) -> VoxelVisibility {
-        if *self == WorldVoxel::Air || *self == WorldVoxel::Unset {
-            VoxelVisibility::Empty
-        } else {
-            VoxelVisibility::Opaque
+    fn read_from(reader: &mut dyn Read) -> io::Result<Self> {
+        let mut tag = [0];
+        reader.read_exact(&mut tag)?;
+        match tag[0] {
+            WORLD_VOXEL_TAG_UNSET => Ok(WorldVoxel::Unset),
+            WORLD_VOXEL_TAG_AIR => Ok(WorldVoxel::Air),
+            WORLD_VOXEL_TAG_SOLID => Ok(WorldVoxel::Solid(I::read_from(reader)?)),
+            WORLD_VOXEL_TAG_TRANSLUCENT => Ok(WorldVoxel::Translucent(I::read_from(reader)?)),
+            WORLD_VOXEL_TAG_FLUID => {
+                let material = I::read_from(reader)?;
+                let mut level = [0];
+                reader.read_exact(&mut level)?;
+                let level = match level[0] {
+                    0 => FluidLevel::Source,
+                    1 => FluidLevel::Falling,
+                    level => FluidLevel::Flowing((level - 1).min(MAX_FLUID_LEVEL - 1)),
+                };
+                Ok(WorldVoxel::Fluid(material, level))
+            }
+            tag => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown voxel tag {tag}"),
+            )),
         }
     }
-}
 
-impl MergeVoxel for WorldVoxel {
-    type MergeValue = u8;
+    fn empty() -> Self {
+        WorldVoxel::Air
+    }
 
-    fn merge_value(&self) -> Self::MergeValue {
+    fn fluid_level(&self) -> Option<FluidLevel> {
         match self {
-            WorldVoxel::Solid(v) => *v,
-            _ => 0,
+            WorldVoxel::Fluid(_, level) => Some(*level),
+            _ => None,
         }
     }
+
+    fn with_fluid_level(&self, level: FluidLevel) -> Self {
+        match self {
+            WorldVoxel::Fluid(material, _) => WorldVoxel::Fluid(*material, level),
+            voxel => *voxel,
+        }
+    }
+
+    fn drained(&self) -> Self {
+        WorldVoxel::Air
+    }
 }
 
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
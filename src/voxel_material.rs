@@ -8,8 +8,10 @@ use bevy::{
             AsBindGroup, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError,
             VertexFormat,
         },
+        texture::{ImageAddressMode, ImageSampler, ImageSamplerDescriptor},
     },
 };
+use serde::Deserialize;
 
 /// Keeps track of the loading status of the image used for the voxel texture
 #[derive(Resource)]
@@ -21,11 +23,72 @@ pub(crate) struct LoadingTexture {
 #[derive(Resource)]
 pub(crate) struct TextureLayers(pub u32);
 
+/// The default image sampler of the app. Only inserted with greedy meshing, where the voxel
+/// texture is made to repeat.
+#[derive(Resource)]
+pub(crate) struct DefaultSampler(pub ImageSamplerDescriptor);
+
 pub const VOXEL_TEXTURE_SHADER_HANDLE: Handle<Shader> = Handle::weak_from_u128(6998301138411443008);
 
+/// Texture array indexes for the top, sides and bottom of a voxel, as returned by the texture
+/// index mapper. Stored as full 32 bit integers so that any material id can be mapped.
 pub(crate) const ATTRIBUTE_TEX_INDEX: MeshVertexAttribute =
     MeshVertexAttribute::new("TextureIndex", 989640910, VertexFormat::Uint32x3);
 
+/// The skylight and block light of a face, from 0 to 1. See `VoxelLight`.
+pub(crate) const ATTRIBUTE_VOXEL_LIGHT: MeshVertexAttribute =
+    MeshVertexAttribute::new("VoxelLight", 989640911, VertexFormat::Float32x2);
+
+/// The emitted light of a face, already multiplied by the emission strength
+pub(crate) const ATTRIBUTE_EMISSIVE: MeshVertexAttribute =
+    MeshVertexAttribute::new("Emissive", 989640912, VertexFormat::Float32x3);
+
+/// Texture array index of the emission mask of a face, or `NO_EMISSIVE_TEXTURE`
+pub(crate) const ATTRIBUTE_EMISSIVE_TEX_INDEX: MeshVertexAttribute =
+    MeshVertexAttribute::new("EmissiveTextureIndex", 989640913, VertexFormat::Uint32);
+
+/// Marks faces without an emission mask in `ATTRIBUTE_EMISSIVE_TEX_INDEX`
+pub(crate) const NO_EMISSIVE_TEXTURE: u32 = u32::MAX;
+
+/// The light a voxel material emits by itself, as returned by
+/// `VoxelWorldConfig::emission_mapper`. Emissive faces glow regardless of the lighting of the
+/// scene, so this can be used for lava or glowing ores.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub struct VoxelEmission {
+    pub color: Color,
+
+    /// Multiplier for `color`. Values above 1 make the material bright enough to bloom.
+    pub strength: f32,
+
+    /// Index into the voxel texture of a layer used as an emission mask. The emission is
+    /// multiplied by the color of the mask, so only the bright parts of the mask glow.
+    /// When `None`, the whole face glows.
+    #[serde(default)]
+    pub texture_index: Option<u32>,
+}
+
+impl VoxelEmission {
+    pub fn new(color: Color, strength: f32) -> Self {
+        Self {
+            color,
+            strength,
+            texture_index: None,
+        }
+    }
+
+    /// Use a layer of the voxel texture as the emission mask
+    pub fn with_texture(mut self, texture_index: u32) -> Self {
+        self.texture_index = Some(texture_index);
+        self
+    }
+
+    /// The emitted color in linear space, multiplied by the strength
+    pub(crate) fn linear_rgb(&self) -> [f32; 3] {
+        let [r, g, b, _] = self.color.as_linear_rgba_f32();
+        [r * self.strength, g * self.strength, b * self.strength]
+    }
+}
+
 pub fn vertex_layout() -> Vec<VertexAttributeDescriptor> {
     vec![
         Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
@@ -37,6 +100,9 @@ pub fn vertex_layout() -> Vec<VertexAttributeDescriptor> {
         //Mesh::ATTRIBUTE_JOINT_INDEX.at_shader_location(6),
         //Mesh::ATTRIBUTE_JOINT_WEIGHT.at_shader_location(7),
         ATTRIBUTE_TEX_INDEX.at_shader_location(8),
+        ATTRIBUTE_VOXEL_LIGHT.at_shader_location(9),
+        ATTRIBUTE_EMISSIVE.at_shader_location(10),
+        ATTRIBUTE_EMISSIVE_TEX_INDEX.at_shader_location(11),
     ]
 }
 #[derive(Asset, AsBindGroup, Debug, Clone, TypePath)]
@@ -72,6 +138,7 @@ pub(crate) fn prepare_texture(
     texture_layers: Res<TextureLayers>,
     mut loading_texture: ResMut<LoadingTexture>,
     mut images: ResMut<Assets<Image>>,
+    default_sampler: Option<Res<DefaultSampler>>,
 ) {
     if loading_texture.is_loaded
         || asset_server.get_load_state(loading_texture.handle.clone())
@@ -83,4 +150,20 @@ pub(crate) fn prepare_texture(
 
     let image = images.get_mut(&loading_texture.handle).unwrap();
     image.reinterpret_stacked_2d_as_array(texture_layers.0);
+    if let Some(default_sampler) = default_sampler {
+        set_repeating_sampler(image, &default_sampler.0);
+    }
+}
+
+/// Make the voxel texture repeat, so the texture tiles over quads merged by greedy meshing.
+/// Only the address modes change: an image without its own sampler gets the default sampler of
+/// the app.
+pub(crate) fn set_repeating_sampler(image: &mut Image, default_sampler: &ImageSamplerDescriptor) {
+    let mut descriptor = match &image.sampler {
+        ImageSampler::Descriptor(descriptor) => descriptor.clone(),
+        ImageSampler::Default => default_sampler.clone(),
+    };
+    descriptor.address_mode_u = ImageAddressMode::Repeat;
+    descriptor.address_mode_v = ImageAddressMode::Repeat;
+    image.sampler = ImageSampler::Descriptor(descriptor);
 }
@@ -0,0 +1,252 @@
+///
+/// Voxel physics
+/// Collision queries against the solid voxels of a world. Fluids don't collide. All queries take
+/// a voxel lookup function, like the one returned by `VoxelWorld::get_voxel_fn`, so they can also
+/// run in tasks.
+///
+use bevy::{
+    math::bounding::{Aabb3d, BoundingVolume},
+    prelude::*,
+};
+
+use crate::voxel::VoxelData;
+
+// Boxes resting exactly against a voxel face are not considered overlapping it. This is the
+// margin used for that, and for snapping to voxel faces.
+const EPSILON: f32 = 1e-4;
+
+// Number of steps used to find the point of a capsule segment closest to a voxel
+const CAPSULE_SEARCH_STEPS: usize = 32;
+
+/// The result of moving a box through the world with `sweep_aabb`
+#[derive(Clone, Copy, Debug)]
+pub struct SweepResult {
+    /// The box at the end of the movement
+    pub aabb: Aabb3d,
+
+    /// How far the box actually moved
+    pub motion: Vec3,
+
+    /// For each axis the movement was blocked on, the normal of the voxel face that was hit.
+    /// Zero on the other axes, so `normal.y > 0.0` means the box landed on something.
+    pub normal: Vec3,
+}
+
+impl SweepResult {
+    /// Whether the movement was blocked on any axis
+    pub fn collided(&self) -> bool {
+        self.normal != Vec3::ZERO
+    }
+}
+
+/// Solid ground found by `ground_check`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GroundHit<V> {
+    /// Distance from the bottom of the box down to the ground
+    pub distance: f32,
+
+    /// Position of the ground voxel closest to the center of the box
+    pub position: IVec3,
+
+    pub voxel: V,
+}
+
+/// Move a box by `motion`, stopping at solid voxels.
+///
+/// The movement is resolved one axis at a time, vertical first, so a blocked axis doesn't stop
+/// the movement along the other axes and the box slides along walls and floors. Voxels the box
+/// already overlaps don't block it, so a box stuck inside terrain can still move out.
+pub fn sweep_aabb<V: VoxelData>(
+    get_voxel: impl Fn(IVec3) -> V,
+    aabb: Aabb3d,
+    motion: Vec3,
+) -> SweepResult {
+    let mut aabb = aabb;
+    let mut moved = Vec3::ZERO;
+    let mut normal = Vec3::ZERO;
+
+    for axis in [1, 0, 2] {
+        let (distance, blocked) = match sweep_axis(&get_voxel, &aabb, axis, motion[axis]) {
+            Some((distance, _)) => (distance, true),
+            None => (motion[axis], false),
+        };
+
+        aabb.min[axis] += distance;
+        aabb.max[axis] += distance;
+        moved[axis] = distance;
+        if blocked {
+            normal[axis] = -motion[axis].signum();
+        }
+    }
+
+    SweepResult {
+        aabb,
+        motion: moved,
+        normal,
+    }
+}
+
+/// Find solid ground at most `max_distance` below the box
+pub fn ground_check<V: VoxelData>(
+    get_voxel: impl Fn(IVec3) -> V,
+    aabb: Aabb3d,
+    max_distance: f32,
+) -> Option<GroundHit<V>> {
+    let (distance, layer) = sweep_axis(&get_voxel, &aabb, 1, -max_distance)?;
+
+    // Of all the solid voxels under the box, report the one closest to its center
+    let center = aabb.center();
+    let (min, max) = overlapped_cells(&aabb);
+    let mut closest: Option<(f32, IVec3, V)> = None;
+    for x in min.x..=max.x {
+        for z in min.z..=max.z {
+            let position = IVec3::new(x, layer, z);
+            let voxel = get_voxel(position);
+            if !collides(&voxel) {
+                continue;
+            }
+            let center_distance = Vec2::new(x as f32 + 0.5, z as f32 + 0.5)
+                .distance_squared(Vec2::new(center.x, center.z));
+            if !closest.is_some_and(|(closest, _, _)| center_distance >= closest) {
+                closest = Some((center_distance, position, voxel));
+            }
+        }
+    }
+
+    closest.map(|(_, position, voxel)| GroundHit {
+        distance: -distance,
+        position,
+        voxel,
+    })
+}
+
+/// Whether the box is standing on solid ground
+pub fn is_grounded<V: VoxelData>(get_voxel: impl Fn(IVec3) -> V, aabb: Aabb3d) -> bool {
+    ground_check(get_voxel, aabb, EPSILON * 2.0).is_some()
+}
+
+/// Get all solid voxels overlapping the box
+pub fn overlap_aabb<V: VoxelData>(get_voxel: impl Fn(IVec3) -> V, aabb: Aabb3d) -> Vec<(IVec3, V)> {
+    let (min, max) = overlapped_cells(&aabb);
+    solid_voxels_in(&get_voxel, min, max).collect()
+}
+
+/// Get all solid voxels overlapping the sphere
+pub fn overlap_sphere<V: VoxelData>(
+    get_voxel: impl Fn(IVec3) -> V,
+    center: Vec3,
+    radius: f32,
+) -> Vec<(IVec3, V)> {
+    let (min, max) = overlapped_cells(&Aabb3d::new(center, Vec3::splat(radius)));
+    solid_voxels_in(&get_voxel, min, max)
+        .filter(|(position, _)| {
+            let closest = center.clamp(position.as_vec3(), position.as_vec3() + 1.0);
+            closest.distance_squared(center) < radius * radius
+        })
+        .collect()
+}
+
+/// Get all solid voxels overlapping the capsule around the segment from `start` to `end`
+pub fn overlap_capsule<V: VoxelData>(
+    get_voxel: impl Fn(IVec3) -> V,
+    start: Vec3,
+    end: Vec3,
+    radius: f32,
+) -> Vec<(IVec3, V)> {
+    let bounds = Aabb3d {
+        min: start.min(end) - radius,
+        max: start.max(end) + radius,
+    };
+    let (min, max) = overlapped_cells(&bounds);
+    solid_voxels_in(&get_voxel, min, max)
+        .filter(|(position, _)| {
+            segment_voxel_distance_squared(start, end, *position) < radius * radius
+        })
+        .collect()
+}
+
+/// Move the box along one axis until it hits a solid voxel. Returns the distance moved and the
+/// voxel layer that blocked the box, or `None` if the full distance can be moved.
+fn sweep_axis<V: VoxelData>(
+    get_voxel: &impl Fn(IVec3) -> V,
+    aabb: &Aabb3d,
+    axis: usize,
+    distance: f32,
+) -> Option<(f32, i32)> {
+    if distance == 0.0 {
+        return None;
+    }
+
+    let (min, max) = overlapped_cells(aabb);
+    let layer_is_solid = |layer: i32| {
+        let (mut layer_min, mut layer_max) = (min, max);
+        layer_min[axis] = layer;
+        layer_max[axis] = layer;
+        solid_voxels_in(get_voxel, layer_min, layer_max)
+            .next()
+            .is_some()
+    };
+
+    if distance > 0.0 {
+        let leading = aabb.max[axis];
+        let first = (leading - EPSILON).ceil() as i32;
+        let last = (leading + distance - EPSILON).ceil() as i32 - 1;
+        (first..=last)
+            .find(|layer| layer_is_solid(*layer))
+            .map(|layer| ((layer as f32 - leading).max(0.0), layer))
+    } else {
+        let leading = aabb.min[axis];
+        let first = (leading + EPSILON).floor() as i32 - 1;
+        let last = (leading + distance + EPSILON).floor() as i32;
+        (last..=first)
+            .rev()
+            .find(|layer| layer_is_solid(*layer))
+            .map(|layer| ((layer as f32 + 1.0 - leading).min(0.0), layer))
+    }
+}
+
+/// The range of voxels overlapped by the box, not counting voxels only touching its faces
+fn overlapped_cells(aabb: &Aabb3d) -> (IVec3, IVec3) {
+    (
+        (aabb.min + EPSILON).floor().as_ivec3(),
+        (aabb.max - EPSILON).floor().as_ivec3(),
+    )
+}
+
+fn solid_voxels_in<V: VoxelData>(
+    get_voxel: &impl Fn(IVec3) -> V,
+    min: IVec3,
+    max: IVec3,
+) -> impl Iterator<Item = (IVec3, V)> + '_ {
+    (min.z..=max.z)
+        .flat_map(move |z| (min.y..=max.y).map(move |y| (y, z)))
+        .flat_map(move |(y, z)| (min.x..=max.x).map(move |x| IVec3::new(x, y, z)))
+        .map(|position| (position, get_voxel(position)))
+        .filter(|(_, voxel)| collides(voxel))
+}
+
+/// Whether boxes and shapes collide with the voxel. Fluids are solid, but can be moved through.
+fn collides<V: VoxelData>(voxel: &V) -> bool {
+    voxel.is_solid() && voxel.fluid_level().is_none()
+}
+
+/// The squared distance between a line segment and a voxel. The distance to the voxel is convex
+/// along the segment, so the closest point can be found with a ternary search.
+fn segment_voxel_distance_squared(start: Vec3, end: Vec3, position: IVec3) -> f32 {
+    let (min, max) = (position.as_vec3(), position.as_vec3() + 1.0);
+    let distance_at = |t: f32| {
+        let point = start.lerp(end, t);
+        point.clamp(min, max).distance_squared(point)
+    };
+
+    let (mut low, mut high) = (0.0, 1.0);
+    for _ in 0..CAPSULE_SEARCH_STEPS {
+        let third = (high - low) / 3.0;
+        if distance_at(low + third) < distance_at(high - third) {
+            high -= third;
+        } else {
+            low += third;
+        }
+    }
+    distance_at((low + high) / 2.0)
+}
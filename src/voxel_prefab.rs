@@ -0,0 +1,384 @@
+///
+/// Voxel prefabs
+/// Boxes of voxels captured from a world, that can be pasted back at any position, rotated and
+/// mirrored. Prefabs are assets, and can be saved to and loaded from `.bvp` files.
+///
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    marker::PhantomData,
+    path::Path,
+};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    utils::{BoxedFuture, HashMap},
+};
+
+use crate::{
+    edit_storage::{invalid_data, read_array, read_varint, write_varint},
+    voxel::{VoxelData, WorldVoxel},
+};
+
+/// How the voxels of a `VoxelPrefab` are stored
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PrefabVoxels<V = WorldVoxel> {
+    /// One voxel per position
+    Dense(Vec<V>),
+
+    /// Each distinct voxel is stored once in `palette`, and each position holds an index into it
+    Palette { palette: Vec<V>, indices: Vec<u16> },
+}
+
+/// A box of voxels that can be pasted into a world with `VoxelWorld::paste_prefab`.
+///
+/// Voxels are ordered by x first, then y, then z. Unset voxels are never pasted, so they can be
+/// used for the parts of the box that aren't part of the prefab.
+#[derive(Asset, TypePath, Clone, Debug, PartialEq, Eq)]
+pub struct VoxelPrefab<V: VoxelData = WorldVoxel> {
+    size: UVec3,
+    voxels: PrefabVoxels<V>,
+}
+
+/// Options for pasting a `VoxelPrefab`. Mirroring is applied before the rotation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PasteOptions {
+    /// Number of 90 degree turns around the Y axis, counter-clockwise when seen from above
+    pub quarter_turns: u8,
+
+    /// Flip the prefab along these axes
+    pub mirror: BVec3,
+
+    /// Skip air voxels of the prefab, so they don't replace the voxels already in the world
+    pub air_is_transparent: bool,
+}
+
+impl<V: VoxelData> VoxelPrefab<V> {
+    /// Create a dense prefab of the given size. Panics if there isn't exactly one voxel for each
+    /// position of the box.
+    pub fn new(size: UVec3, voxels: Vec<V>) -> Self {
+        assert_eq!(
+            voxels.len() as u64,
+            volume(size),
+            "prefab voxel count doesn't match its size"
+        );
+        Self {
+            size,
+            voxels: PrefabVoxels::Dense(voxels),
+        }
+    }
+
+    /// Create a dense prefab of the given size, with the voxel returned by `f` at each position
+    pub fn from_fn(size: UVec3, mut f: impl FnMut(UVec3) -> V) -> Self {
+        let mut voxels = Vec::with_capacity(volume(size) as usize);
+        for z in 0..size.z {
+            for y in 0..size.y {
+                for x in 0..size.x {
+                    voxels.push(f(UVec3::new(x, y, z)));
+                }
+            }
+        }
+        Self::new(size, voxels)
+    }
+
+    /// Create a dense prefab of the given size with the given voxels. All other positions are
+    /// unset. Panics if a position is outside of the box.
+    pub fn from_voxels(size: UVec3, voxels: impl IntoIterator<Item = (UVec3, V)>) -> Self {
+        let mut dense = vec![V::default(); volume(size) as usize];
+        for (position, voxel) in voxels {
+            assert!(
+                position.cmplt(size).all(),
+                "prefab voxel outside of its size"
+            );
+            let index = position.x + position.y * size.x + position.z * size.x * size.y;
+            dense[index as usize] = voxel;
+        }
+        Self::new(size, dense)
+    }
+
+    pub fn size(&self) -> UVec3 {
+        self.size
+    }
+
+    pub fn voxels(&self) -> &PrefabVoxels<V> {
+        &self.voxels
+    }
+
+    /// Get the voxel at `position` within the prefab, or the default (unset) voxel if the
+    /// position is outside of the prefab
+    pub fn get(&self, position: UVec3) -> V {
+        if position.cmpge(self.size).any() {
+            return V::default();
+        }
+        let index = (position.x + position.y * self.size.x + position.z * self.size.x * self.size.y)
+            as usize;
+        self.voxel_at(index)
+    }
+
+    /// Iterate over all positions of the prefab and their voxels
+    pub fn iter(&self) -> impl Iterator<Item = (UVec3, V)> + '_ {
+        let size = self.size;
+        (0..volume(size) as u32).map(move |index| {
+            let position = UVec3::new(
+                index % size.x,
+                (index / size.x) % size.y,
+                index / (size.x * size.y),
+            );
+            (position, self.voxel_at(index as usize))
+        })
+    }
+
+    /// Convert the prefab to palette storage. Prefabs with more than 65536 distinct voxels are
+    /// kept dense.
+    pub fn to_palette(&self) -> Self {
+        let PrefabVoxels::Dense(voxels) = &self.voxels else {
+            return self.clone();
+        };
+        let mut palette = Vec::new();
+        let mut palette_indices = HashMap::new();
+        let mut indices = Vec::with_capacity(voxels.len());
+        for voxel in voxels {
+            let index = *palette_indices.entry(*voxel).or_insert_with(|| {
+                palette.push(*voxel);
+                palette.len() - 1
+            });
+            let Ok(index) = u16::try_from(index) else {
+                return self.clone();
+            };
+            indices.push(index);
+        }
+        Self {
+            size: self.size,
+            voxels: PrefabVoxels::Palette { palette, indices },
+        }
+    }
+
+    /// Convert the prefab to dense storage
+    pub fn to_dense(&self) -> Self {
+        let voxels = (0..volume(self.size) as usize)
+            .map(|index| self.voxel_at(index))
+            .collect();
+        Self {
+            size: self.size,
+            voxels: PrefabVoxels::Dense(voxels),
+        }
+    }
+
+    /// The size of the box covered by the prefab when pasted with the given options
+    pub fn pasted_size(&self, options: &PasteOptions) -> UVec3 {
+        match options.quarter_turns % 2 {
+            0 => self.size,
+            _ => UVec3::new(self.size.z, self.size.y, self.size.x),
+        }
+    }
+
+    /// The world positions and voxels written when pasting the prefab with its minimum corner at
+    /// `position`. Unset voxels, and air voxels if `air_is_transparent` is set, are skipped.
+    ///
+    /// Only positions are transformed. Voxel types with an orientation need to rotate their
+    /// voxels themselves.
+    pub fn pasted_voxels(
+        &self,
+        position: IVec3,
+        options: PasteOptions,
+    ) -> impl Iterator<Item = (IVec3, V)> + '_ {
+        self.iter()
+            .filter(move |(_, voxel)| is_pasted(voxel, &options))
+            .map(move |(local, voxel)| {
+                (
+                    position + pasted_position(local, self.size, &options),
+                    voxel,
+                )
+            })
+    }
+
+    /// Write the prefab in a compact binary form. Layout:
+    ///
+    /// ```text
+    /// magic "BVWP" | version: u16 | size: 3 x u32 | storage: u8, 0 for dense, 1 for palette
+    /// dense:   voxel data for each position, as written by `VoxelData::write_to`
+    /// palette: palette length: varint | voxel data for each palette entry |
+    ///          palette index for each position: varint
+    /// ```
+    ///
+    /// All fixed size integers are little endian.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&PREFAB_FILE_MAGIC)?;
+        writer.write_all(&PREFAB_FILE_VERSION.to_le_bytes())?;
+        for size in self.size.to_array() {
+            writer.write_all(&size.to_le_bytes())?;
+        }
+        match &self.voxels {
+            PrefabVoxels::Dense(voxels) => {
+                writer.write_all(&[PREFAB_STORAGE_DENSE])?;
+                for voxel in voxels {
+                    voxel.write_to(&mut writer)?;
+                }
+            }
+            PrefabVoxels::Palette { palette, indices } => {
+                writer.write_all(&[PREFAB_STORAGE_PALETTE])?;
+                write_varint(&mut writer, palette.len() as u64)?;
+                for voxel in palette {
+                    voxel.write_to(&mut writer)?;
+                }
+                for index in indices {
+                    write_varint(&mut writer, *index as u64)?;
+                }
+            }
+        }
+        writer.flush()
+    }
+
+    /// Read a prefab previously written by `write_to`
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<Self> {
+        let magic: [u8; 4] = read_array(&mut reader)?;
+        if magic != PREFAB_FILE_MAGIC {
+            return Err(invalid_data("not a voxel prefab file"));
+        }
+
+        let version = u16::from_le_bytes(read_array(&mut reader)?);
+        if version != PREFAB_FILE_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported prefab file version {version}"
+            )));
+        }
+
+        let size = UVec3::new(
+            u32::from_le_bytes(read_array(&mut reader)?),
+            u32::from_le_bytes(read_array(&mut reader)?),
+            u32::from_le_bytes(read_array(&mut reader)?),
+        );
+        let volume = volume(size);
+        if volume > u32::MAX as u64 {
+            return Err(invalid_data("prefab is too large"));
+        }
+
+        let [storage] = read_array(&mut reader)?;
+        let voxels = match storage {
+            PREFAB_STORAGE_DENSE => PrefabVoxels::Dense(
+                (0..volume)
+                    .map(|_| V::read_from(&mut reader))
+                    .collect::<io::Result<_>>()?,
+            ),
+            PREFAB_STORAGE_PALETTE => {
+                let palette_len = read_varint(&mut reader)?;
+                if palette_len > u16::MAX as u64 + 1 {
+                    return Err(invalid_data("prefab palette is too large"));
+                }
+                let palette = (0..palette_len)
+                    .map(|_| V::read_from(&mut reader))
+                    .collect::<io::Result<_>>()?;
+                let indices = (0..volume)
+                    .map(|_| match read_varint(&mut reader)? {
+                        index if index < palette_len => Ok(index as u16),
+                        _ => Err(invalid_data("prefab palette index out of bounds")),
+                    })
+                    .collect::<io::Result<_>>()?;
+                PrefabVoxels::Palette { palette, indices }
+            }
+            storage => return Err(invalid_data(format!("unknown prefab storage {storage}"))),
+        };
+
+        Ok(Self { size, voxels })
+    }
+
+    /// Save the prefab to a file at `path`. See `write_to` for the format.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.write_to(BufWriter::new(file))
+    }
+
+    /// Load a prefab from a file at `path`. See `write_to` for the format.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        Self::read_from(BufReader::new(file))
+    }
+
+    fn voxel_at(&self, index: usize) -> V {
+        match &self.voxels {
+            PrefabVoxels::Dense(voxels) => voxels[index],
+            PrefabVoxels::Palette { palette, indices } => palette[indices[index] as usize],
+        }
+    }
+}
+
+/// Magic bytes at the start of every prefab file
+pub const PREFAB_FILE_MAGIC: [u8; 4] = *b"BVWP";
+
+/// Current version of the prefab file format. Bumped whenever the layout changes.
+pub const PREFAB_FILE_VERSION: u16 = 1;
+
+const PREFAB_STORAGE_DENSE: u8 = 0;
+const PREFAB_STORAGE_PALETTE: u8 = 1;
+
+/// Whether a voxel is written when pasted with the given options
+pub(crate) fn is_pasted<V: VoxelData>(voxel: &V, options: &PasteOptions) -> bool {
+    let transparent = options.air_is_transparent && voxel.is_air();
+    !voxel.is_unset() && !transparent
+}
+
+/// Where the position `local` of a box of the given size ends up, relative to the minimum corner
+/// of the box, when it is mirrored and rotated with `options`
+pub(crate) fn pasted_position(local: UVec3, size: UVec3, options: &PasteOptions) -> IVec3 {
+    let size = size.as_ivec3();
+    let mut local = local.as_ivec3();
+    let mirror = options.mirror;
+    if mirror.x {
+        local.x = size.x - 1 - local.x;
+    }
+    if mirror.y {
+        local.y = size.y - 1 - local.y;
+    }
+    if mirror.z {
+        local.z = size.z - 1 - local.z;
+    }
+    // Turn (x, z) into (z, -x), shifted back to the positive side of each axis
+    let mut extent = size;
+    for _ in 0..options.quarter_turns % 4 {
+        local = IVec3::new(local.z, local.y, extent.x - 1 - local.x);
+        extent = IVec3::new(extent.z, extent.y, extent.x);
+    }
+    local
+}
+
+/// The number of positions in a box of the given size
+pub(crate) fn volume(size: UVec3) -> u64 {
+    size.x as u64 * size.y as u64 * size.z as u64
+}
+
+/// Loads `VoxelPrefab` assets from `.bvp` files
+pub(crate) struct VoxelPrefabLoader<V> {
+    _marker: PhantomData<fn() -> V>,
+}
+
+impl<V> Default for VoxelPrefabLoader<V> {
+    fn default() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<V: VoxelData> AssetLoader for VoxelPrefabLoader<V> {
+    type Asset = VoxelPrefab<V>;
+    type Settings = ();
+    type Error = io::Error;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<VoxelPrefab<V>, io::Error>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            VoxelPrefab::read_from(bytes.as_slice())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["bvp"]
+    }
+}
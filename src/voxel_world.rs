@@ -4,15 +4,33 @@
 ///
 use std::marker::PhantomData;
 use std::sync::Arc;
+use std::{io, path::Path};
 
-use bevy::{ecs::system::SystemParam, math::bounding::RayCast3d, prelude::*};
+use bevy::{
+    ecs::system::SystemParam,
+    math::bounding::{Aabb3d, RayCast3d},
+    prelude::*,
+    utils::HashMap,
+};
 
 use crate::{
+    biome::{BiomeId, BiomeLayer},
     chunk_map::ChunkMap,
     configuration::VoxelWorldConfig,
+    edit_history::VoxelEditHistory,
+    edit_storage,
+    falling_voxel::SupportChecks,
+    fluid::FluidSimulation,
+    generation::WorldGenerator,
+    integrity::{find_floating, FloatingVoxels, IntegrityOptions, VoxelBody},
+    lighting::{LightMap, VoxelLight},
+    schematic::Schematic,
     traversal_alg::voxel_line_traversal,
-    voxel::WorldVoxel,
-    voxel_world_internal::{get_chunk_voxel_position, ModifiedVoxels, VoxelWriteBuffer},
+    vox_model::{VoxModel, VoxPaletteMapping},
+    voxel::{VoxelData, WorldVoxel, VOXEL_SIZE},
+    voxel_physics::{self, GroundHit, SweepResult},
+    voxel_prefab::{volume, PasteOptions, VoxelPrefab},
+    voxel_world_internal::{get_chunk_voxel_position, ModifiedVoxels, VoxelWriteBuffer, WorldRoot},
 };
 
 /// This component is used to mark the Camera that bevy_voxel_world should use to determine
@@ -64,26 +82,50 @@ pub type ChunkWillSpawn<C> = ChunkEvent<C>;
 /// Fired when a chunk is about to be remeshed.
 pub type ChunkWillRemesh<C> = ChunkEvent<C>;
 
-pub trait FilterFn {
-    fn call(&self, input: (Vec3, WorldVoxel)) -> bool;
+/// Fired when a chunk is about to switch to another level of detail. The chunk keeps its current
+/// mesh until the mesh for the new level of detail is ready.
+#[derive(Event)]
+pub struct ChunkWillChangeLod<C> {
+    pub chunk_key: IVec3,
+    pub entity: Entity,
+    pub from_lod: u8,
+    pub to_lod: u8,
+    _marker: PhantomData<C>,
 }
 
-impl<F: Fn((Vec3, WorldVoxel)) -> bool> FilterFn for F {
-    fn call(&self, input: (Vec3, WorldVoxel)) -> bool {
+impl<C> ChunkWillChangeLod<C> {
+    pub fn new(chunk_key: IVec3, entity: Entity, from_lod: u8, to_lod: u8) -> Self {
+        Self {
+            chunk_key,
+            entity,
+            from_lod,
+            to_lod,
+            _marker: PhantomData,
+        }
+    }
+}
+
+pub trait FilterFn<V = WorldVoxel> {
+    fn call(&self, input: (Vec3, V)) -> bool;
+}
+
+impl<V, F: Fn((Vec3, V)) -> bool> FilterFn<V> for F {
+    fn call(&self, input: (Vec3, V)) -> bool {
         self(input)
     }
 }
 
-pub type RaycastFn = dyn Fn(Ray3d, &dyn FilterFn) -> Option<VoxelRaycastResult> + Send + Sync;
+pub type RaycastFn<V = WorldVoxel> =
+    dyn Fn(Ray3d, &dyn FilterFn<V>) -> Option<VoxelRaycastResult<V>> + Send + Sync;
 
 #[derive(Default, Debug, PartialEq, Clone)]
-pub struct VoxelRaycastResult {
+pub struct VoxelRaycastResult<V = WorldVoxel> {
     pub position: Vec3,
     pub normal: Option<Vec3>,
-    pub voxel: WorldVoxel,
+    pub voxel: V,
 }
 
-impl VoxelRaycastResult {
+impl<V> VoxelRaycastResult<V> {
     /// Get the voxel position of the raycast result
     pub fn voxel_pos(&self) -> IVec3 {
         self.position.floor().as_ivec3()
@@ -97,47 +139,473 @@ impl VoxelRaycastResult {
 
 /// Grants access to the VoxelWorld in systems
 #[derive(SystemParam)]
-pub struct VoxelWorld<'w, C: VoxelWorldConfig> {
+pub struct VoxelWorld<'w, 's, C: VoxelWorldConfig> {
     chunk_map: Res<'w, ChunkMap<C>>,
     modified_voxels: Res<'w, ModifiedVoxels<C>>,
     voxel_write_buffer: ResMut<'w, VoxelWriteBuffer<C>>,
-    #[allow(unused)]
+    edit_history: ResMut<'w, VoxelEditHistory<C>>,
+    generator: Res<'w, WorldGenerator<C>>,
+    fluids: ResMut<'w, FluidSimulation<C>>,
+    support_checks: ResMut<'w, SupportChecks<C>>,
+    light_map: Res<'w, LightMap<C>>,
     configuration: Res<'w, C>,
+    world_root: Query<'w, 's, Entity, With<WorldRoot<C>>>,
 }
 
-impl<'w, C: VoxelWorldConfig> VoxelWorld<'w, C> {
-    /// Get the voxel at the given position. The voxel will be WorldVoxel::Unset if there is no voxel at that position
-    pub fn get_voxel(&self, position: IVec3) -> WorldVoxel {
+impl<'w, 's, C: VoxelWorldConfig> VoxelWorld<'w, 's, C> {
+    /// Get the voxel at the given position. The voxel will be the default (unset) voxel if there is no voxel at that position
+    pub fn get_voxel(&self, position: IVec3) -> C::Voxel {
         self.get_voxel_fn()(position)
     }
 
     /// Set the voxel at the given position. This will create a new chunk if one does not exist at
     /// the given position.
-    pub fn set_voxel(&mut self, position: IVec3, voxel: WorldVoxel) {
-        self.voxel_write_buffer.push((position, voxel));
+    ///
+    /// While a transaction is open, the edit is recorded so it can be undone.
+    pub fn set_voxel(&mut self, position: IVec3, voxel: C::Voxel) {
+        self.write_voxels([(position, voxel)]);
+    }
+
+    /// Set all voxels in the box from `min` to `max`, inclusive
+    pub fn fill_box(&mut self, min: IVec3, max: IVec3, voxel: C::Voxel) {
+        self.fill_region(min, max, voxel, |_| true);
+    }
+
+    /// Set all voxels within `radius` of `center`
+    pub fn fill_sphere(&mut self, center: IVec3, radius: f32, voxel: C::Voxel) {
+        let reach = IVec3::splat(radius.floor() as i32);
+        self.fill_region(center - reach, center + reach, voxel, |position| {
+            (position - center).as_vec3().length_squared() <= radius * radius
+        });
+    }
+
+    /// Set all voxels within `radius` of the line segment from `start` to `end`. The ends of the
+    /// cylinder are flat, and the cylinder doesn't need to be aligned to an axis.
+    pub fn fill_cylinder(&mut self, start: IVec3, end: IVec3, radius: f32, voxel: C::Voxel) {
+        let reach = IVec3::splat(radius.floor() as i32);
+        let (start_f, axis) = (start.as_vec3(), (end - start).as_vec3());
+        let axis_length_squared = axis.length_squared();
+        self.fill_region(
+            start.min(end) - reach,
+            start.max(end) + reach,
+            voxel,
+            |position| {
+                let offset = position.as_vec3() - start_f;
+                let t = match axis_length_squared {
+                    0.0 => 0.0,
+                    _ => offset.dot(axis) / axis_length_squared,
+                };
+                (0.0..=1.0).contains(&t) && (offset - axis * t).length_squared() <= radius * radius
+            },
+        );
+    }
+
+    /// Set all voxels on the line from the voxel at `start` to the voxel at `end`. The line is
+    /// connected through voxel faces, so it has no diagonal gaps.
+    pub fn draw_line(&mut self, start: IVec3, end: IVec3, voxel: C::Voxel) {
+        let mut voxels = Vec::new();
+        if start == end {
+            voxels.push((start, voxel));
+        } else {
+            let center = Vec3::splat(VOXEL_SIZE / 2.0);
+            voxel_line_traversal(
+                start.as_vec3() + center,
+                end.as_vec3() + center,
+                |position, _, _| {
+                    voxels.push((position, voxel));
+                    position != end
+                },
+            );
+        }
+        self.write_voxels(voxels);
+    }
+
+    /// Replace all voxels equal to `from` in the box from `min` to `max`, inclusive, with `to`.
+    /// Only voxels in spawned chunks, or modified voxels, are replaced.
+    pub fn replace_voxels(&mut self, min: IVec3, max: IVec3, from: C::Voxel, to: C::Voxel) {
+        let mut voxels = Vec::new();
+        self.read_region(min, max, |position, voxel| {
+            if voxel == from {
+                voxels.push((position, to));
+            }
+        });
+        self.write_voxels(voxels);
+    }
+
+    /// Capture the voxels in the box from `min` to `max`, inclusive, into a prefab. Voxels
+    /// outside of spawned chunks that weren't modified are captured as unset. Panics if the box
+    /// holds more voxels than fit in a `usize`.
+    pub fn capture_prefab(&self, min: IVec3, max: IVec3) -> VoxelPrefab<C::Voxel> {
+        let (min, max) = (min.min(max), min.max(max));
+        let size = (max - min + 1).as_uvec3();
+        let len = usize::try_from(volume(size)).expect("captured box is too large");
+        let mut voxels = vec![C::Voxel::default(); len];
+        let [size_x, size_y, _] = size.to_array().map(|size| size as usize);
+        self.read_region(min, max, |position, voxel| {
+            let [x, y, z] = (position - min)
+                .as_uvec3()
+                .to_array()
+                .map(|local| local as usize);
+            voxels[x + size_x * (y + size_y * z)] = voxel;
+        });
+        VoxelPrefab::new(size, voxels)
+    }
+
+    /// Paste a prefab with its minimum corner at `position`. See `PasteOptions` for rotating and
+    /// mirroring the prefab. Unset voxels of the prefab are skipped.
+    ///
+    /// While a transaction is open, the paste is recorded so it can be undone.
+    pub fn paste_prefab(
+        &mut self,
+        prefab: &VoxelPrefab<C::Voxel>,
+        position: IVec3,
+        options: PasteOptions,
+    ) {
+        self.write_voxels(prefab.pasted_voxels(position, options));
+    }
+
+    /// Paste a MagicaVoxel model with its minimum corner at `position`, creating the voxel for
+    /// each material id of the model with `voxel`. Empty voxels of the model are skipped.
+    /// See `paste_prefab`.
+    pub fn paste_vox_model(
+        &mut self,
+        model: &VoxModel,
+        position: IVec3,
+        options: PasteOptions,
+        voxel: impl Fn(u32) -> C::Voxel,
+    ) {
+        self.paste_prefab(&model.to_prefab(voxel), position, options);
+    }
+
+    /// Paste a schematic with its minimum corner at `position`, creating the voxel for each
+    /// material id of the schematic with `voxel`. Air blocks clear the voxels they are pasted over,
+    /// unless `air_is_transparent` is set, and unmapped blocks are skipped. The voxels are
+    /// written like with `set_voxel`, so they are kept in the modified voxels of the world.
+    pub fn paste_schematic(
+        &mut self,
+        schematic: &Schematic,
+        position: IVec3,
+        options: PasteOptions,
+        voxel: impl Fn(u32) -> C::Voxel,
+    ) {
+        self.write_voxels(schematic.pasted_voxels(position, options, voxel));
+    }
+
+    /// Export the voxels in the box from `min` to `max`, inclusive, to a MagicaVoxel `.vox` file.
+    /// Voxels with a material are written with the palette index given by `mapping`, all other
+    /// voxels are left empty.
+    pub fn export_vox(
+        &self,
+        min: IVec3,
+        max: IVec3,
+        path: impl AsRef<Path>,
+        mapping: &VoxPaletteMapping,
+    ) -> io::Result<()> {
+        VoxModel::from_prefab(&self.capture_prefab(min, max)).save(path, mapping)
+    }
+
+    /// Call `f` with the current voxel at each position in the box from `min` to `max`. The box
+    /// is visited one chunk at a time.
+    fn read_region(&self, min: IVec3, max: IVec3, mut f: impl FnMut(IVec3, C::Voxel)) {
+        let chunk_size = self.configuration.chunk_size();
+        for_each_chunk_in(min, max, chunk_size, |chunk_pos, min, max| {
+            let chunk_data = ChunkMap::<C>::get(&chunk_pos, &self.chunk_map.get_read_lock());
+            let write_buffer = self.voxel_write_buffer.read().unwrap();
+            let modified_voxels = self.modified_voxels.read().unwrap();
+            if !modified_voxels.is_chunk_loaded(chunk_pos) {
+                modified_voxels.request_chunk(chunk_pos);
+            }
+            let current = |position: IVec3| {
+                let modified = match write_buffer.get(&position) {
+                    Some(pending) => *pending,
+                    None => modified_voxels.get(&position),
+                };
+                modified.unwrap_or_else(|| match &chunk_data {
+                    Some(chunk_data) => {
+                        chunk_data.get_voxel(get_chunk_voxel_position(position, chunk_size).1)
+                    }
+                    None => C::Voxel::default(),
+                })
+            };
+            for_each_position_in(min, max, |position| f(position, current(position)));
+        });
+    }
+
+    /// Set the voxels in the box from `min` to `max` for which `contains` returns true. The box
+    /// is visited one chunk at a time.
+    fn fill_region(
+        &mut self,
+        min: IVec3,
+        max: IVec3,
+        voxel: C::Voxel,
+        contains: impl Fn(IVec3) -> bool,
+    ) {
+        let mut voxels = Vec::new();
+        for_each_chunk_in(min, max, self.configuration.chunk_size(), |_, min, max| {
+            for_each_position_in(min, max, |position| {
+                if contains(position) {
+                    voxels.push((position, voxel));
+                }
+            });
+        });
+        self.write_voxels(voxels);
+    }
+
+    /// Put voxels in the write buffer, recording them in the open transaction if there is one
+    fn write_voxels(&mut self, voxels: impl IntoIterator<Item = (IVec3, C::Voxel)>) {
+        let recording = self.edit_history.is_recording();
+        let voxels = voxels
+            .into_iter()
+            .map(|(position, voxel)| (position, Some(voxel)));
+        self.buffer_voxels(voxels, recording);
+    }
+
+    /// Put voxels in the write buffer, and wake up the fluids and falling voxels around them.
+    /// `None` removes the modification at the position.
+    fn buffer_voxels(
+        &mut self,
+        voxels: impl IntoIterator<Item = (IVec3, Option<C::Voxel>)>,
+        record: bool,
+    ) {
+        let voxels: Vec<_> = voxels.into_iter().collect();
+        {
+            let get_voxel = self.get_voxel_fn();
+            for (position, voxel) in &voxels {
+                match voxel {
+                    Some(voxel) => self.fluids.schedule_edit(&*get_voxel, *position, voxel),
+                    // The voxel left once the modification is removed isn't known yet
+                    None => self.fluids.schedule_around(*position),
+                }
+            }
+        }
+
+        let mut write_buffer = self.voxel_write_buffer.write().unwrap();
+        for (position, voxel) in voxels {
+            self.support_checks.schedule_around(position);
+            // Removed modifications only come from undo and redo, which are never recorded
+            if let Some(after) = voxel.filter(|_| record) {
+                let before = match write_buffer.get(&position) {
+                    Some(pending) => *pending,
+                    None => self.modified_voxels.get_voxel(&position),
+                };
+                self.edit_history.record(position, before, after);
+            }
+            write_buffer.insert(position, voxel);
+        }
+    }
+
+    /// Start recording edits made with `set_voxel` in a transaction with the given name, so they
+    /// can be undone together. A transaction that is already open is committed first.
+    ///
+    /// Transactions can stay open across frames, until `commit_transaction` is called.
+    pub fn begin_transaction(&mut self, name: impl Into<String>) {
+        self.edit_history.begin(name.into());
+    }
+
+    /// Close the open transaction and push it onto the undo stack. Clears the redo stack, unless
+    /// the transaction is empty.
+    pub fn commit_transaction(&mut self) {
+        self.edit_history.commit();
+    }
+
+    /// Undo the last transaction. An open transaction is committed first. Returns the name of the
+    /// undone transaction, or `None` if there was nothing to undo.
+    ///
+    /// Only the chunks the transaction changed are remeshed.
+    pub fn undo(&mut self) -> Option<String> {
+        let transaction = self.edit_history.undo()?;
+        let changes = transaction.changes.iter();
+        self.buffer_voxels(
+            changes.map(|change| (change.position, change.before)),
+            false,
+        );
+        Some(transaction.name)
+    }
+
+    /// Redo the last undone transaction. Returns the name of the redone transaction, or `None`
+    /// if there was nothing to redo.
+    pub fn redo(&mut self) -> Option<String> {
+        let transaction = self.edit_history.redo()?;
+        let changes = transaction.changes.iter();
+        self.buffer_voxels(
+            changes.map(|change| (change.position, Some(change.after))),
+            false,
+        );
+        Some(transaction.name)
+    }
+
+    /// Run one step of the fluid simulation. Only fluids near voxels that changed since the last
+    /// step are updated, in chunks loaded at full detail. Fluid in other chunks waits until they
+    /// are loaded. The changes are written like `set_voxel`, but never recorded in transactions.
+    /// Returns the number of voxels that changed.
+    ///
+    /// Steps run on their own every `VoxelWorldConfig::fluid_step_interval`.
+    pub fn step_fluids(&mut self) -> usize {
+        let changes = {
+            let get_voxel = self.get_voxel_fn();
+            let chunk_map = &self.chunk_map;
+            let mut active = HashMap::new();
+            self.fluids.step(&*get_voxel, |chunk_pos| {
+                *active.entry(chunk_pos).or_insert_with(|| {
+                    let chunk_data = ChunkMap::<C>::get(&chunk_pos, &chunk_map.get_read_lock());
+                    chunk_data.is_some_and(|chunk_data| chunk_data.lod == 0)
+                })
+            })
+        };
+        let changed = changes.len();
+        let changes = changes
+            .into_iter()
+            .map(|(position, voxel)| (position, Some(voxel)));
+        self.buffer_voxels(changes, false);
+        changed
+    }
+
+    /// Remove the voxels affected by gravity that lost the voxel under them since the last call,
+    /// and return them. They are replaced with `VoxelData::empty`, and like the fluid simulation,
+    /// the removals are never recorded in transactions.
+    pub(crate) fn take_unsupported_voxels(&mut self) -> Vec<(IVec3, C::Voxel)> {
+        let unsupported = {
+            let get_voxel = self.get_voxel_fn();
+            let chunk_map = &self.chunk_map;
+            let chunk_size = self.configuration.chunk_size();
+            self.support_checks
+                .take_unsupported(&*get_voxel, |position| {
+                    let (chunk_pos, _) = get_chunk_voxel_position(position, chunk_size);
+                    ChunkMap::<C>::contains_chunk(&chunk_pos, &chunk_map.get_read_lock())
+                })
+        };
+        let removed = unsupported
+            .iter()
+            .map(|(position, _)| (*position, Some(C::Voxel::empty())));
+        self.buffer_voxels(removed, false);
+        unsupported
+    }
+
+    /// Put a falling voxel back in the world where it landed, without recording it
+    pub(crate) fn land_voxel(&mut self, position: IVec3, voxel: C::Voxel) {
+        self.buffer_voxels([(position, Some(voxel))], false);
+    }
+
+    /// Whether the chunk holding the given position is loaded
+    pub(crate) fn is_loaded(&self, position: IVec3) -> bool {
+        let (chunk_pos, _) = get_chunk_voxel_position(position, self.configuration.chunk_size());
+        ChunkMap::<C>::contains_chunk(&chunk_pos, &self.chunk_map.get_read_lock())
+    }
+
+    /// Find the groups of solid voxels around the edited positions that are no longer connected
+    /// to the ground or to an anchor material, see `VoxelWorldConfig::anchor_mapper`. Call it
+    /// with the positions removed by an edit, like an explosion, to find what was left floating.
+    pub fn find_floating_voxels(
+        &self,
+        edited: impl IntoIterator<Item = IVec3>,
+        options: IntegrityOptions,
+    ) -> Vec<FloatingVoxels<C::Voxel>> {
+        let get_voxel = self.get_voxel_fn();
+        let anchors = self.configuration.anchor_mapper();
+        find_floating(
+            &*get_voxel,
+            |position| self.is_loaded(position),
+            &anchors,
+            edited,
+            options,
+        )
+    }
+
+    /// Remove a group of voxels from the world, and spawn a `VoxelBody` entity holding them at
+    /// their place. The voxels are replaced with `VoxelData::empty`, like with `set_voxel`. The
+    /// body is a child of the world root, like the chunks.
+    pub fn detach_voxels(
+        &mut self,
+        commands: &mut Commands,
+        voxels: &FloatingVoxels<C::Voxel>,
+    ) -> Entity {
+        let removed = voxels
+            .voxels
+            .iter()
+            .map(|(position, _)| (*position, C::Voxel::empty()));
+        self.write_voxels(removed);
+
+        let transform = Transform::from_translation(voxels.min.as_vec3());
+        let mut body = commands.spawn((
+            VoxelBody::<C>::new(voxels.to_prefab(), voxels.min),
+            SpatialBundle::from_transform(transform),
+        ));
+        if let Ok(world_root) = self.world_root.get_single() {
+            body.set_parent(world_root);
+        }
+        body.id()
+    }
+
+    /// Find the floating voxels around the edited positions with `find_floating_voxels`, and
+    /// detach each group with `detach_voxels`. Returns the spawned entities.
+    pub fn detach_floating_voxels(
+        &mut self,
+        commands: &mut Commands,
+        edited: impl IntoIterator<Item = IVec3>,
+        options: IntegrityOptions,
+    ) -> Vec<Entity> {
+        self.find_floating_voxels(edited, options)
+            .iter()
+            .map(|voxels| self.detach_voxels(commands, voxels))
+            .collect()
+    }
+
+    /// The undo and redo stacks of the world
+    pub fn edit_history(&self) -> &VoxelEditHistory<C> {
+        &self.edit_history
+    }
+
+    /// Save all voxels modified by `set_voxel` to a file, so they can be restored later using
+    /// `load_from`. Voxels that are still pending in the write buffer are included.
+    ///
+    /// When region files are used, changed regions are written first, and the edits of all
+    /// region files are saved, including regions that aren't in memory.
+    pub fn save_to(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut voxels = self.modified_voxels.write().unwrap().collect_all()?;
+        for (position, voxel) in self.voxel_write_buffer.read().unwrap().iter() {
+            match voxel {
+                Some(voxel) => voxels.insert(*position, *voxel),
+                None => voxels.remove(position),
+            };
+        }
+        edit_storage::save_voxels(path, &voxels, self.configuration.chunk_size())
+    }
+
+    /// Load voxels previously saved with `save_to`. The loaded voxels are applied as if they
+    /// were set with `set_voxel`, so any affected chunks will get remeshed.
+    pub fn load_from(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let voxels = edit_storage::load_voxels(path)?;
+        self.write_voxels(voxels);
+        Ok(())
+    }
+
+    /// Write all modified voxels that have changed since they were loaded to their region files.
+    /// Does nothing unless `VoxelWorldConfig::region_storage_path` is set. Changed regions are
+    /// also written when they are evicted, and when the app exits.
+    pub fn flush_region_files(&self) -> io::Result<()> {
+        self.modified_voxels.write().unwrap().flush_regions()
     }
 
     /// Get a sendable closure that can be used to get the voxel at the given position
     /// This is useful for spawning tasks that need to access the voxel world
-    pub fn get_voxel_fn(&self) -> Arc<dyn Fn(IVec3) -> WorldVoxel + Send + Sync> {
+    pub fn get_voxel_fn(&self) -> Arc<dyn Fn(IVec3) -> C::Voxel + Send + Sync> {
         let chunk_map = self.chunk_map.get_map();
         let write_buffer = self.voxel_write_buffer.clone();
         let modified_voxels = self.modified_voxels.clone();
+        let chunk_size = self.configuration.chunk_size();
 
         Arc::new(move |position| {
-            let (chunk_pos, vox_pos) = get_chunk_voxel_position(position);
-
-            if let Some(voxel) = write_buffer
-                .iter()
-                .find(|(pos, _)| *pos == position)
-                .map(|(_, voxel)| *voxel)
-            {
-                return voxel;
-            }
-
-            {
-                if let Some(voxel) = modified_voxels.get_voxel(&position) {
-                    return voxel;
+            let (chunk_pos, vox_pos) = get_chunk_voxel_position(position, chunk_size);
+
+            let pending = write_buffer.read().unwrap().get(&position).copied();
+            match pending {
+                Some(Some(voxel)) => return voxel,
+                // A pending removal of the modification, so fall through to the chunk
+                Some(None) => {}
+                None => {
+                    if let Some(voxel) = modified_voxels.get_voxel(&position) {
+                        return voxel;
+                    }
                 }
             }
 
@@ -149,25 +617,63 @@ impl<'w, C: VoxelWorldConfig> VoxelWorld<'w, C> {
             if let Some(chunk_data) = chunk_opt {
                 chunk_data.get_voxel(vox_pos)
             } else {
-                WorldVoxel::Unset
+                C::Voxel::default()
             }
         })
     }
 
+    /// Get the biome at the given position. `None` if the world has no biomes, see
+    /// `VoxelWorldConfig::biomes`.
+    pub fn get_biome(&self, position: IVec3) -> Option<BiomeId> {
+        self.generator.biomes()?.biome_at(position)
+    }
+
+    /// The biome layer of the world, to look up biomes by id or the surface height of a column
+    pub fn biomes(&self) -> Option<&BiomeLayer<C::Voxel>> {
+        self.generator.biomes()
+    }
+
+    /// Get the light level at the given position. Edits are relit at the start of the next
+    /// frame. `None` if lighting is disabled, or the chunk isn't lit yet or is using a lower level
+    /// of detail.
+    pub fn get_light(&self, position: IVec3) -> Option<VoxelLight> {
+        self.light_map.get(position)
+    }
+
+    /// Move a box by `motion`, sliding along any solid voxels it hits.
+    /// See [`voxel_physics::sweep_aabb`], which can be used with `get_voxel_fn` in tasks.
+    pub fn sweep_aabb(&self, aabb: Aabb3d, motion: Vec3) -> SweepResult {
+        voxel_physics::sweep_aabb(&*self.get_voxel_fn(), aabb, motion)
+    }
+
+    /// Find solid ground at most `max_distance` below the box
+    pub fn ground_check(&self, aabb: Aabb3d, max_distance: f32) -> Option<GroundHit<C::Voxel>> {
+        voxel_physics::ground_check(&*self.get_voxel_fn(), aabb, max_distance)
+    }
+
+    /// Get all solid voxels overlapping the sphere
+    pub fn overlap_sphere(&self, center: Vec3, radius: f32) -> Vec<(IVec3, C::Voxel)> {
+        voxel_physics::overlap_sphere(&*self.get_voxel_fn(), center, radius)
+    }
+
+    /// Get all solid voxels overlapping the capsule around the segment from `start` to `end`
+    pub fn overlap_capsule(&self, start: Vec3, end: Vec3, radius: f32) -> Vec<(IVec3, C::Voxel)> {
+        voxel_physics::overlap_capsule(&*self.get_voxel_fn(), start, end, radius)
+    }
+
     /// Get the closes surface voxel to the given position
     /// Returns None if there is no surface voxel at or below the given position
-    pub fn get_closest_surface_voxel(&self, position: IVec3) -> Option<(IVec3, WorldVoxel)> {
+    pub fn get_closest_surface_voxel(&self, position: IVec3) -> Option<(IVec3, C::Voxel)> {
         let get_voxel = self.get_voxel_fn();
         let mut current_pos = position;
         let current_voxel = get_voxel(current_pos);
 
         let is_surface = |pos: IVec3| {
             let above = pos + IVec3::Y;
-            (get_voxel(pos) != WorldVoxel::Unset && get_voxel(pos) != WorldVoxel::Air)
-                && (get_voxel(above) == WorldVoxel::Unset || get_voxel(above) == WorldVoxel::Air)
+            get_voxel(pos).is_solid() && !get_voxel(above).is_solid()
         };
 
-        if current_voxel == WorldVoxel::Unset || current_voxel == WorldVoxel::Air {
+        if !current_voxel.is_solid() {
             while !is_surface(current_pos) {
                 current_pos -= IVec3::Y;
                 if current_pos.y < -256 {
@@ -187,7 +693,7 @@ impl<'w, C: VoxelWorldConfig> VoxelWorld<'w, C> {
         &self,
         position: IVec3,
         radius: u32,
-    ) -> Option<(IVec3, WorldVoxel)> {
+    ) -> Option<(IVec3, C::Voxel)> {
         let mut tries = 0;
 
         while tries < 100 {
@@ -217,7 +723,7 @@ impl<'w, C: VoxelWorldConfig> VoxelWorld<'w, C> {
     }
 
     /// Get first surface voxel at the given Vec2 position
-    pub fn get_surface_voxel_at_2d_pos(&self, pos_2d: Vec2) -> Option<(IVec3, WorldVoxel)> {
+    pub fn get_surface_voxel_at_2d_pos(&self, pos_2d: Vec2) -> Option<(IVec3, C::Voxel)> {
         self.get_closest_surface_voxel(IVec3 {
             x: pos_2d.x.floor() as i32,
             y: 256,
@@ -257,14 +763,14 @@ impl<'w, C: VoxelWorldConfig> VoxelWorld<'w, C> {
     pub fn raycast(
         &self,
         ray: Ray3d,
-        filter: &impl Fn((Vec3, WorldVoxel)) -> bool,
-    ) -> Option<VoxelRaycastResult> {
+        filter: &impl Fn((Vec3, C::Voxel)) -> bool,
+    ) -> Option<VoxelRaycastResult<C::Voxel>> {
         let raycast_fn = self.raycast_fn();
         raycast_fn(ray, filter)
     }
 
     /// Get a sendable closure that can be used to raycast into the voxel world
-    pub fn raycast_fn(&self) -> Arc<RaycastFn> {
+    pub fn raycast_fn(&self) -> Arc<RaycastFn<C::Voxel>> {
         let chunk_map = self.chunk_map.get_map();
         let get_voxel = self.get_voxel_fn();
 
@@ -323,3 +829,35 @@ impl<'w, C: VoxelWorldConfig> VoxelWorld<'w, C> {
         })
     }
 }
+
+/// Call `f` for each chunk overlapping the box from `min` to `max`, inclusive, with the part of
+/// the box inside that chunk
+fn for_each_chunk_in(
+    min: IVec3,
+    max: IVec3,
+    chunk_size: UVec3,
+    mut f: impl FnMut(IVec3, IVec3, IVec3),
+) {
+    let chunk_size = chunk_size.as_ivec3();
+    let (min_chunk, max_chunk) = (min.div_euclid(chunk_size), max.div_euclid(chunk_size));
+    for z in min_chunk.z..=max_chunk.z {
+        for y in min_chunk.y..=max_chunk.y {
+            for x in min_chunk.x..=max_chunk.x {
+                let chunk_pos = IVec3::new(x, y, z);
+                let chunk_min = chunk_pos * chunk_size;
+                let chunk_max = chunk_min + chunk_size - 1;
+                f(chunk_pos, min.max(chunk_min), max.min(chunk_max));
+            }
+        }
+    }
+}
+
+fn for_each_position_in(min: IVec3, max: IVec3, mut f: impl FnMut(IVec3)) {
+    for z in min.z..=max.z {
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                f(IVec3::new(x, y, z));
+            }
+        }
+    }
+}
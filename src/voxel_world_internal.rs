@@ -11,53 +11,130 @@ use bevy::{
 use futures_lite::future;
 use std::{
     collections::VecDeque,
+    io,
     marker::PhantomData,
-    sync::{Arc, RwLock},
+    path::Path,
+    sync::{Arc, RwLock, RwLockReadGuard},
 };
 
 use crate::{
+    block_registry::{BlockRegistryHandle, MaterialMappers},
     chunk::*,
     chunk_map::*,
     configuration::{ChunkDespawnStrategy, ChunkSpawnStrategy, VoxelWorldConfig},
+    edit_history::VoxelEditHistory,
+    edit_storage::{self, EditPages},
+    falling_voxel::SupportChecks,
+    fluid::FluidSimulation,
+    generation::WorldGenerator,
+    lighting::LightMap,
     mesh_cache::*,
-    plugin::VoxelWorldMaterialHandle,
-    voxel::WorldVoxel,
+    meshing::OpenBorders,
+    plugin::{VoxelWorldMaterialHandle, VoxelWorldTranslucentMaterialHandle},
+    voxel::VoxelData,
     voxel_material::LoadingTexture,
-    voxel_world::{ChunkWillDespawn, ChunkWillRemesh, ChunkWillSpawn, VoxelWorldCamera},
+    voxel_world::{
+        ChunkWillChangeLod, ChunkWillDespawn, ChunkWillRemesh, ChunkWillSpawn, VoxelWorldCamera,
+    },
 };
 
+// The directions of the six chunks sharing a face with a chunk
+const FACE_NEIGHBOURS: [IVec3; 6] = [
+    IVec3::NEG_X,
+    IVec3::X,
+    IVec3::NEG_Y,
+    IVec3::Y,
+    IVec3::NEG_Z,
+    IVec3::Z,
+];
+
 #[derive(SystemParam, Deref)]
 pub struct CameraInfo<'w, 's, C: VoxelWorldConfig>(
     Query<'w, 's, (&'static Camera, &'static GlobalTransform), With<VoxelWorldCamera<C>>>,
 );
 
-/// Holds a map of modified voxels that will persist between chunk spawn/despawn
+/// Holds the modified voxels that will persist between chunk spawn/despawn, paged by chunk
 #[derive(Resource, Deref, DerefMut, Clone)]
-pub struct ModifiedVoxels<C>(
-    #[deref] Arc<RwLock<HashMap<IVec3, WorldVoxel>>>,
+pub struct ModifiedVoxels<C: VoxelWorldConfig>(
+    #[deref] Arc<RwLock<EditPages<C::Voxel>>>,
     PhantomData<C>,
 );
 
 impl<C: VoxelWorldConfig> Default for ModifiedVoxels<C> {
     fn default() -> Self {
-        Self(Arc::new(RwLock::new(HashMap::new())), PhantomData)
+        Self::new(EditPages::default())
     }
 }
 
 impl<C: VoxelWorldConfig> ModifiedVoxels<C> {
-    pub fn get_voxel(&self, position: &IVec3) -> Option<WorldVoxel> {
+    pub fn new(pages: EditPages<C::Voxel>) -> Self {
+        Self(Arc::new(RwLock::new(pages)), PhantomData)
+    }
+
+    /// Create the storage described by the given configuration
+    pub fn from_config(configuration: &C) -> Self {
+        let chunk_size = configuration.chunk_size();
+        match configuration.region_storage_path() {
+            Some(path) => Self::new(EditPages::with_region_files(
+                chunk_size,
+                path,
+                configuration.region_size(),
+            )),
+            None => Self::new(EditPages::new(chunk_size)),
+        }
+    }
+
+    /// Get the modified voxel at the given position, if it is in memory. If its region was
+    /// evicted, the region is requested to be loaded again by `stream_edit_regions`, so voxel
+    /// lookups never wait on disk.
+    pub fn get_voxel(&self, position: &IVec3) -> Option<C::Voxel> {
         let modified_voxels = self.0.read().unwrap();
-        modified_voxels.get(position).cloned()
+        let (chunk_pos, _) = get_chunk_voxel_position(*position, modified_voxels.chunk_size());
+        if !modified_voxels.is_chunk_loaded(chunk_pos) {
+            modified_voxels.request_chunk(chunk_pos);
+        }
+        modified_voxels.get(position)
+    }
+
+    /// Save all modified voxels to a file, including the ones in region files that aren't in
+    /// memory. See [`edit_storage::write_voxels`] for details about the format.
+    pub fn save_to(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut modified_voxels = self.0.write().unwrap();
+        edit_storage::save_voxels(
+            path,
+            &modified_voxels.collect_all()?,
+            modified_voxels.chunk_size(),
+        )
+    }
+
+    /// Load modified voxels from a file. Loaded voxels are merged into the existing ones,
+    /// replacing any voxel that was already modified at the same position.
+    ///
+    /// Note that this does not cause any spawned chunks to be remeshed. Use
+    /// `VoxelWorld::load_from` for that.
+    pub fn load_from(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let loaded = edit_storage::load_voxels(path)?;
+        let mut modified_voxels = self.0.write().unwrap();
+        for (position, voxel) in loaded {
+            modified_voxels.insert(position, voxel);
+        }
+        Ok(())
     }
 }
 
 /// A temporary buffer for voxel modifications that will get flushed to the `ModifiedVoxels` resource
-/// at the end of the frame.
-#[derive(Resource, Deref, DerefMut, Default)]
-pub struct VoxelWriteBuffer<C>(#[deref] Vec<(IVec3, WorldVoxel)>, PhantomData<C>);
+/// at the end of the frame. Only the last voxel set at each position is kept. `None` removes the
+/// modification at the position, like when undoing the edit of a voxel that wasn't modified
+/// before. The buffer is shared with the closures returned by `VoxelWorld::get_voxel_fn`, so they
+/// see pending edits.
+#[derive(Resource, Deref, DerefMut, Clone, Default)]
+pub struct VoxelWriteBuffer<C: VoxelWorldConfig>(
+    #[deref] Arc<RwLock<HashMap<IVec3, Option<C::Voxel>>>>,
+    PhantomData<C>,
+);
 
 #[derive(Component)]
-pub(crate) struct NeedsMaterial<C>(PhantomData<C>);
+pub(crate) struct NeedsMaterial<C>(pub PhantomData<C>);
 
 pub(crate) struct Internals<C>(PhantomData<C>);
 
@@ -70,14 +147,19 @@ where
 {
     /// Init the resources used internally by bevy_voxel_world
     pub fn setup(mut commands: Commands, configuration: Res<C>) {
-        commands.init_resource::<ChunkMap<C>>();
+        commands.insert_resource(ChunkMap::<C>::new(configuration.chunk_size()));
         commands.init_resource::<ChunkMapInsertBuffer<C>>();
         commands.init_resource::<ChunkMapUpdateBuffer<C>>();
         commands.init_resource::<ChunkMapRemoveBuffer<C>>();
         commands.init_resource::<MeshCache<C>>();
         commands.init_resource::<MeshCacheInsertBuffer<C>>();
-        commands.init_resource::<ModifiedVoxels<C>>();
+        commands.insert_resource(ModifiedVoxels::<C>::from_config(&configuration));
         commands.init_resource::<VoxelWriteBuffer<C>>();
+        commands.insert_resource(VoxelEditHistory::<C>::new(configuration.max_undo_steps()));
+        commands.insert_resource(WorldGenerator::<C>::from_config(&configuration));
+        commands.insert_resource(FluidSimulation::<C>::from_config(&configuration));
+        commands.insert_resource(SupportChecks::<C>::from_config(&configuration));
+        commands.insert_resource(LightMap::<C>::new(configuration.chunk_size()));
 
         // Create the root node and allow to modify it by the configuration.
         let world_root = commands
@@ -90,6 +172,38 @@ where
         configuration.init_root(commands, world_root)
     }
 
+    /// Load region files of modified voxels close to the camera or requested by voxel lookups,
+    /// and evict the ones far away
+    pub fn stream_edit_regions(
+        modified_voxels: Res<ModifiedVoxels<C>>,
+        configuration: Res<C>,
+        camera_info: CameraInfo<C>,
+    ) {
+        let Ok((_, cam_gtf)) = camera_info.get_single() else {
+            return;
+        };
+        let chunk_at_camera =
+            cam_gtf.translation().as_ivec3() / configuration.chunk_size().as_ivec3();
+
+        modified_voxels
+            .write()
+            .unwrap()
+            .stream_regions(chunk_at_camera, configuration.spawning_distance() + 1);
+    }
+
+    /// Write all changed region files to disk before the app exits
+    pub fn flush_edit_regions_on_exit(
+        mut ev_app_exit: EventReader<bevy::app::AppExit>,
+        modified_voxels: Res<ModifiedVoxels<C>>,
+    ) {
+        if ev_app_exit.read().count() == 0 {
+            return;
+        }
+        if let Err(err) = modified_voxels.write().unwrap().flush_regions() {
+            error!("Failed to write voxel region files: {err}");
+        }
+    }
+
     /// Find and spawn chunks in need of spawning
     pub fn spawn_chunks(
         mut commands: Commands,
@@ -107,6 +221,10 @@ where
 
         let spawning_distance = configuration.spawning_distance() as i32;
         let spawning_distance_squared = spawning_distance.pow(2);
+        let lod_distances = configuration.lod_distances();
+
+        let chunk_size = configuration.chunk_size();
+        let chunk_size_i = chunk_size.as_ivec3();
 
         let viewport_size = camera.physical_viewport_size().unwrap_or_default();
 
@@ -124,8 +242,8 @@ where
                 };
                 let mut current = ray.origin;
                 let mut t = 0.0;
-                while t < (spawning_distance * CHUNK_SIZE_I) as f32 {
-                    let chunk_pos = current.as_ivec3() / CHUNK_SIZE_I;
+                while t < (spawning_distance * chunk_size_i.max_element()) as f32 {
+                    let chunk_pos = current.as_ivec3() / chunk_size_i;
                     if let Some(chunk) = ChunkMap::<C>::get(&chunk_pos, &chunk_map_read_lock) {
                         if chunk.is_full {
                             // If we hit a full chunk, we can stop the ray early
@@ -134,7 +252,7 @@ where
                     } else {
                         queue.push_back(chunk_pos);
                     }
-                    t += CHUNK_SIZE_F;
+                    t += chunk_size.min_element() as f32;
                     current = ray.origin + ray.direction * t;
                 }
             };
@@ -153,7 +271,7 @@ where
         }
 
         // We also queue the chunks closest to the camera to make sure they will always spawn early
-        let chunk_at_camera = cam_pos / CHUNK_SIZE_I;
+        let chunk_at_camera = cam_pos / chunk_size_i;
         for x in -1..=1 {
             for y in -1..=1 {
                 for z in -1..=1 {
@@ -181,15 +299,19 @@ where
             if !has_chunk {
                 let chunk_entity = commands.spawn(NeedsRemesh).id();
                 commands.entity(world_root).add_child(chunk_entity);
-                let chunk = Chunk::<C>::new(chunk_position, chunk_entity);
-
-                chunk_map_insert_buffer
-                    .push((chunk_position, ChunkData::with_entity(chunk.entity)));
-
-                commands.entity(chunk.entity).try_insert((
-                    chunk,
-                    Transform::from_translation(chunk_position.as_vec3() * CHUNK_SIZE_F - 1.0),
+                let mut chunk = Chunk::<C>::new(chunk_position, chunk_entity, chunk_size);
+                chunk.lod = lod_at_distance(
+                    &lod_distances,
+                    chunk_position.as_vec3().distance(chunk_at_camera.as_vec3()),
+                );
+
+                chunk_map_insert_buffer.push((
+                    chunk_position,
+                    ChunkData::with_entity(chunk.entity, chunk_size),
                 ));
+
+                let transform = chunk.mesh_transform(chunk.lod);
+                commands.entity(chunk.entity).try_insert((chunk, transform));
             } else {
                 continue;
             }
@@ -227,7 +349,7 @@ where
         let (_, cam_gtf) = camera_info.get_single().unwrap();
         let cam_pos = cam_gtf.translation().as_ivec3();
 
-        let chunk_at_camera = cam_pos / CHUNK_SIZE_I;
+        let chunk_at_camera = cam_pos / configuration.chunk_size().as_ivec3();
 
         let chunks_to_remove = {
             let mut remove = Vec::with_capacity(1000);
@@ -259,11 +381,68 @@ where
         }
     }
 
+    /// Switch chunks to the level of detail for their distance to the camera, and remesh them
+    pub fn update_chunk_lods(
+        mut commands: Commands,
+        mut all_chunks: Query<&mut Chunk<C>, Without<NeedsDespawn>>,
+        chunk_map: Res<ChunkMap<C>>,
+        mut light_map: ResMut<LightMap<C>>,
+        configuration: Res<C>,
+        camera_info: CameraInfo<C>,
+        mut ev_chunk_will_change_lod: EventWriter<ChunkWillChangeLod<C>>,
+    ) {
+        let lod_distances = configuration.lod_distances();
+        if lod_distances.is_empty() {
+            return;
+        }
+
+        let Ok((_, cam_gtf)) = camera_info.get_single() else {
+            return;
+        };
+        let chunk_at_camera =
+            cam_gtf.translation().as_ivec3() / configuration.chunk_size().as_ivec3();
+
+        let chunk_map_read_lock = chunk_map.get_read_lock();
+
+        for mut chunk in all_chunks.iter_mut() {
+            let distance = chunk.position.as_vec3().distance(chunk_at_camera.as_vec3());
+            let lod = next_lod(&lod_distances, chunk.lod, distance);
+            if lod == chunk.lod {
+                continue;
+            }
+
+            ev_chunk_will_change_lod.send(ChunkWillChangeLod::<C>::new(
+                chunk.position,
+                chunk.entity,
+                chunk.lod,
+                lod,
+            ));
+            chunk.lod = lod;
+            commands.entity(chunk.entity).try_insert(NeedsRemesh);
+            if lod > 0 {
+                // Only chunks at full resolution are lit
+                light_map.remove_chunk(chunk.position);
+            }
+
+            // The borders of the neighbours towards this chunk might need to open or close
+            for direction in FACE_NEIGHBOURS {
+                let neighbour_pos = chunk.position + direction;
+                if let Some(neighbour) = ChunkMap::<C>::get(&neighbour_pos, &chunk_map_read_lock) {
+                    if let Some(mut ent) = commands.get_entity(neighbour.entity) {
+                        ent.try_insert(NeedsRemesh);
+                    }
+                }
+            }
+        }
+    }
+
     /// Despawns chunks that have been tagged for despawning
     pub fn despawn_retired_chunks(
         mut commands: Commands,
         mut chunk_map_remove_buffer: ResMut<ChunkMapRemoveBuffer<C>>,
         chunk_map: Res<ChunkMap<C>>,
+        mut light_map: ResMut<LightMap<C>>,
+        mut fluids: ResMut<FluidSimulation<C>>,
         retired_chunks: Query<(Entity, &Chunk<C>), With<NeedsDespawn>>,
     ) {
         let read_lock = chunk_map.get_read_lock();
@@ -271,6 +450,8 @@ where
             if ChunkMap::<C>::contains_chunk(&chunk.position, &read_lock) {
                 commands.entity(entity).despawn_recursive();
                 chunk_map_remove_buffer.push(chunk.position);
+                light_map.remove_chunk(chunk.position);
+                fluids.remove_chunk(chunk.position);
             }
         }
     }
@@ -281,22 +462,48 @@ where
         mut commands: Commands,
         mut ev_chunk_will_remesh: EventWriter<ChunkWillRemesh<C>>,
         dirty_chunks: Query<&Chunk<C>, With<NeedsRemesh>>,
+        all_chunks: Query<&Chunk<C>>,
+        chunk_map: Res<ChunkMap<C>>,
         mesh_cache: Res<MeshCache<C>>,
         modified_voxels: Res<ModifiedVoxels<C>>,
+        light_map: Res<LightMap<C>>,
+        block_registry: Option<Res<BlockRegistryHandle<C>>>,
+        generator: Res<WorldGenerator<C>>,
         configuration: Res<C>,
     ) {
         let thread_pool = AsyncComputeTaskPool::get();
+        let mappers = MaterialMappers::new(
+            configuration.as_ref(),
+            block_registry.as_ref().and_then(|handle| handle.registry()),
+        );
+        let modified_voxels = modified_voxels.read().unwrap();
+        let chunk_map_read_lock = chunk_map.get_read_lock();
 
         for chunk in dirty_chunks.iter() {
-            let voxel_data_fn = (configuration.voxel_lookup_delegate())(chunk.position);
-            let texture_index_mapper = configuration.texture_index_mapper().clone();
-
-            let mut chunk_task =
-                ChunkTask::<C>::new(chunk.entity, chunk.position, modified_voxels.clone());
+            let chunk_source = generator.chunk_source(&configuration, chunk.position);
+            let texture_index_mapper = mappers.texture_index.clone();
+            let emission_mapper = mappers.emission.clone();
+            let light_emission = mappers.light_emission.clone();
+            let meshing_mode = configuration.meshing_mode();
+
+            let mut chunk_task = ChunkTask::<C>::new(
+                chunk.entity,
+                chunk.position,
+                chunk.size,
+                modified_voxels.chunk_edits(chunk.position),
+            )
+            .with_lod(
+                chunk.lod,
+                open_borders(chunk, &all_chunks, &chunk_map_read_lock),
+            );
+            if configuration.voxel_lighting() && chunk.lod == 0 {
+                chunk_task = chunk_task.with_light(light_map.chunk_light(chunk.position));
+            }
 
             let mesh_map = Arc::new(mesh_cache.get_map());
             let thread = thread_pool.spawn(async move {
-                chunk_task.generate(voxel_data_fn);
+                chunk_task.generate_from(chunk_source);
+                chunk_task.bake_light(&light_emission);
 
                 // No need to mesh if the chunk is empty or full
                 if chunk_task.is_empty() || chunk_task.is_full() {
@@ -309,7 +516,7 @@ where
                     .unwrap()
                     .contains_key(&chunk_task.voxels_hash());
                 if !mesh_cache_hit {
-                    chunk_task.mesh(texture_index_mapper);
+                    chunk_task.mesh(texture_index_mapper, emission_mapper, meshing_mode);
                 }
 
                 chunk_task
@@ -329,7 +536,7 @@ where
     pub fn spawn_meshes(
         mut commands: Commands,
         mut chunking_threads: Query<
-            (Entity, &mut ChunkThread<C>, &mut Chunk<C>, &Transform),
+            (Entity, &mut ChunkThread<C>, &mut Chunk<C>),
             Without<NeedsRemesh>,
         >,
         mut mesh_assets: ResMut<Assets<Mesh>>,
@@ -338,6 +545,7 @@ where
             ResMut<MeshCacheInsertBuffer<C>>,
         ),
         res: (Res<MeshCache<C>>, Res<LoadingTexture>),
+        translucent_meshes: TranslucentMeshes<C>,
     ) {
         let (mesh_cache, loading_texture) = res;
 
@@ -347,7 +555,7 @@ where
 
         let (mut chunk_map_update_buffer, mut mesh_cache_insert_buffer) = buffers;
 
-        for (entity, mut thread, chunk, transform) in &mut chunking_threads {
+        for (entity, mut thread, chunk) in &mut chunking_threads {
             let thread_result = future::block_on(future::poll_once(&mut thread.0));
 
             if thread_result.is_none() {
@@ -370,16 +578,23 @@ where
                                 continue;
                             }
                             let hash = chunk_task.voxels_hash();
-                            let mesh_ref = Arc::new(mesh_assets.add(chunk_task.mesh.unwrap()));
+                            let mesh_ref = Arc::new(ChunkMeshes {
+                                opaque: mesh_assets.add(chunk_task.mesh.unwrap()),
+                                translucent: chunk_task
+                                    .translucent_mesh
+                                    .map(|mesh| mesh_assets.add(mesh)),
+                            });
                             mesh_cache_insert_buffer.push((hash, mesh_ref.clone()));
                             mesh_ref
                         }
                     };
 
+                    // The transform only changes along with the mesh, so chunks changing their
+                    // level of detail keep showing the old mesh until the new one is ready
                     commands
                         .entity(entity)
                         .try_insert((
-                            *transform,
+                            chunk.mesh_transform(chunk_task.chunk_data.lod),
                             MeshRef(mesh_handle),
                             NeedsMaterial::<C>(PhantomData),
                         ))
@@ -396,6 +611,7 @@ where
                     .entity(entity)
                     .remove::<Handle<Mesh>>()
                     .remove::<MeshRef>();
+                despawn_translucent_meshes(&mut commands, entity, &translucent_meshes);
             }
 
             commands.entity(chunk.entity).remove::<ChunkThread<C>>();
@@ -404,18 +620,41 @@ where
 
     pub fn flush_voxel_write_buffer(
         mut commands: Commands,
-        mut buffer: ResMut<VoxelWriteBuffer<C>>,
+        buffer: ResMut<VoxelWriteBuffer<C>>,
         chunk_map: Res<ChunkMap<C>>,
         modified_voxels: ResMut<ModifiedVoxels<C>>,
+        mut light_map: ResMut<LightMap<C>>,
+        configuration: Res<C>,
     ) {
         let chunk_map_read_lock = chunk_map.get_read_lock();
         let mut modified_voxels = modified_voxels.write().unwrap();
+        let chunk_size = configuration.chunk_size();
+        let lighting = configuration.voxel_lighting();
+        let mut dirty_chunks = HashSet::new();
+        let mut buffer = buffer.write().unwrap();
 
         for (position, voxel) in buffer.iter() {
-            let (chunk_pos, _vox_pos) = get_chunk_voxel_position(*position);
-            modified_voxels.insert(*position, *voxel);
+            let (chunk_pos, _vox_pos) = get_chunk_voxel_position(*position, chunk_size);
+            match voxel {
+                Some(voxel) => {
+                    modified_voxels.insert(*position, *voxel);
+                    if lighting {
+                        light_map.queue_edit(*position);
+                    }
+                }
+                None => {
+                    modified_voxels.remove(position);
+                    if lighting {
+                        // The generated voxel is only known once the chunk is generated again
+                        light_map.queue_after_generation(*position);
+                    }
+                }
+            }
+            dirty_chunks.insert(chunk_pos);
+        }
 
-            // Mark the chunk as needing remeshing or spawn a new chunk if it doesn't exist
+        // Mark the chunks as needing remeshing
+        for chunk_pos in dirty_chunks {
             if let Some(chunk_data) = ChunkMap::<C>::get(&chunk_pos, &chunk_map_read_lock) {
                 if let Some(mut ent) = commands.get_entity(chunk_data.entity) {
                     ent.try_insert(NeedsRemesh);
@@ -425,6 +664,95 @@ where
         buffer.clear();
     }
 
+    /// Light newly generated chunks and update the light around edited voxels, then remesh the
+    /// chunks whose light changed
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_light(
+        mut commands: Commands,
+        chunks: Query<&Chunk<C>>,
+        mut light_map: ResMut<LightMap<C>>,
+        chunk_map: Res<ChunkMap<C>>,
+        modified_voxels: Res<ModifiedVoxels<C>>,
+        block_registry: Option<Res<BlockRegistryHandle<C>>>,
+        configuration: Res<C>,
+    ) {
+        if !configuration.voxel_lighting() {
+            return;
+        }
+
+        let chunk_map_read_lock = chunk_map.get_read_lock();
+        let chunk_size = configuration.chunk_size();
+        let mut dirty_chunks = HashSet::new();
+
+        for chunk_pos in light_map.take_generated() {
+            let Some(chunk_data) = ChunkMap::<C>::get(&chunk_pos, &chunk_map_read_lock) else {
+                continue;
+            };
+            let full_resolution = chunk_data.lod == 0
+                && chunks
+                    .get(chunk_data.entity)
+                    .is_ok_and(|chunk| chunk.lod == 0);
+            if full_resolution {
+                light_map.chunk_generated(chunk_pos, chunk_data.light.as_deref());
+            }
+        }
+        if !light_map.has_pending() {
+            return;
+        }
+
+        let light_emission = MaterialMappers::new(
+            configuration.as_ref(),
+            block_registry.as_ref().and_then(|handle| handle.registry()),
+        )
+        .light_emission;
+        let modified_voxels = modified_voxels.read().unwrap();
+        let mut chunk_data: Option<ChunkData<C::Voxel>> = None;
+        let changed = light_map.relight(
+            |position| {
+                if let Some(voxel) = modified_voxels.get(&position) {
+                    return voxel;
+                }
+                let (chunk_pos, vox_pos) = get_chunk_voxel_position(position, chunk_size);
+                if chunk_data.as_ref().map(|chunk_data| chunk_data.position) != Some(chunk_pos) {
+                    chunk_data = ChunkMap::<C>::get(&chunk_pos, &chunk_map_read_lock);
+                }
+                chunk_data
+                    .as_ref()
+                    .map_or_else(C::Voxel::default, |chunk_data| {
+                        chunk_data.get_voxel(vox_pos)
+                    })
+            },
+            |voxel| {
+                voxel
+                    .material()
+                    .map_or(0, |material| light_emission(material))
+            },
+        );
+
+        for position in changed {
+            let (chunk_pos, _) = get_chunk_voxel_position(position, chunk_size);
+            dirty_chunks.insert(chunk_pos);
+            // The faces of neighbouring chunks are lit by the voxels on the border
+            for direction in FACE_NEIGHBOURS {
+                let (neighbour_pos, _) = get_chunk_voxel_position(position + direction, chunk_size);
+                dirty_chunks.insert(neighbour_pos);
+            }
+        }
+
+        for chunk_pos in dirty_chunks {
+            let Some(chunk_data) = ChunkMap::<C>::get(&chunk_pos, &chunk_map_read_lock) else {
+                continue;
+            };
+            // Chunks without a mesh, or with a mesh that isn't lit, don't change
+            if chunk_data.is_empty || chunk_data.is_full || chunk_data.lod > 0 {
+                continue;
+            }
+            if let Some(mut ent) = commands.get_entity(chunk_data.entity) {
+                ent.try_insert(NeedsRemesh);
+            }
+        }
+    }
+
     pub fn flush_mesh_cache_buffers(
         mut mesh_cache_insert_buffer: ResMut<MeshCacheInsertBuffer<C>>,
         mesh_cache: Res<MeshCache<C>>,
@@ -432,42 +760,129 @@ where
         mesh_cache.apply_buffers(&mut mesh_cache_insert_buffer);
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn flush_chunk_map_buffers(
         mut chunk_map_insert_buffer: ResMut<ChunkMapInsertBuffer<C>>,
         mut chunk_map_update_buffer: ResMut<ChunkMapUpdateBuffer<C>>,
         mut chunk_map_remove_buffer: ResMut<ChunkMapRemoveBuffer<C>>,
         mut ev_chunk_will_spawn: EventWriter<ChunkWillSpawn<C>>,
         chunk_map: Res<ChunkMap<C>>,
+        mut light_map: ResMut<LightMap<C>>,
+        mut fluids: ResMut<FluidSimulation<C>>,
+        configuration: Res<C>,
     ) {
+        for (chunk_pos, chunk_data, _) in chunk_map_update_buffer.iter() {
+            fluids.chunk_generated(*chunk_pos, chunk_data.lod, chunk_data.has_fluid);
+        }
+
+        let generated: Vec<IVec3> = if configuration.voxel_lighting() {
+            chunk_map_update_buffer
+                .iter()
+                .map(|(position, _, _)| *position)
+                .collect()
+        } else {
+            Vec::new()
+        };
+
         chunk_map.apply_buffers(
             &mut chunk_map_insert_buffer,
             &mut chunk_map_update_buffer,
             &mut chunk_map_remove_buffer,
             &mut ev_chunk_will_spawn,
         );
+
+        // The buffers are kept when the chunk map is locked, and applied in a later frame
+        if chunk_map_update_buffer.is_empty() {
+            for chunk_pos in generated {
+                light_map.queue_generated(chunk_pos);
+            }
+        }
     }
 
     pub(crate) fn assign_material<M: Material>(
         mut commands: Commands,
         mut needs_material: Query<(Entity, &MeshRef, &Transform), With<NeedsMaterial<C>>>,
+        translucent_meshes: TranslucentMeshes<C>,
         material_handle: Option<Res<VoxelWorldMaterialHandle<M>>>,
+        translucent_material_handle: Option<Res<VoxelWorldTranslucentMaterialHandle<M>>>,
     ) {
         let Some(material_handle) = material_handle else {
             return;
         };
+        // Without a dedicated translucent material, translucent voxels use the regular one
+        let translucent_material = translucent_material_handle
+            .map_or_else(|| material_handle.handle.clone(), |h| h.handle.clone());
 
         for (entity, mesh_ref, transform) in needs_material.iter_mut() {
             commands
                 .entity(entity)
                 .try_insert(MaterialMeshBundle {
-                    mesh: (*mesh_ref.0).clone(),
+                    mesh: mesh_ref.0.opaque.clone(),
                     material: material_handle.handle.clone(),
                     transform: *transform,
                     ..default()
                 })
                 .remove::<NeedsMaterial<C>>();
+
+            despawn_translucent_meshes(&mut commands, entity, &translucent_meshes);
+            if let Some(translucent_mesh) = &mesh_ref.0.translucent {
+                commands.entity(entity).with_children(|parent| {
+                    parent.spawn((
+                        MaterialMeshBundle {
+                            mesh: translucent_mesh.clone(),
+                            material: translucent_material.clone(),
+                            ..default()
+                        },
+                        TranslucentChunkMesh::<C>(PhantomData),
+                    ));
+                });
+            }
+        }
+    }
+}
+
+/// Marks the child entity of a chunk that renders the translucent voxels of the chunk
+#[derive(Component)]
+pub(crate) struct TranslucentChunkMesh<C>(PhantomData<C>);
+
+#[derive(SystemParam)]
+pub(crate) struct TranslucentMeshes<'w, 's, C: VoxelWorldConfig> {
+    children: Query<'w, 's, &'static Children>,
+    meshes: Query<'w, 's, (), With<TranslucentChunkMesh<C>>>,
+}
+
+/// Despawn the translucent mesh entity of a chunk, if it has one
+fn despawn_translucent_meshes<C: VoxelWorldConfig>(
+    commands: &mut Commands,
+    chunk_entity: Entity,
+    translucent_meshes: &TranslucentMeshes<C>,
+) {
+    let Ok(children) = translucent_meshes.children.get(chunk_entity) else {
+        return;
+    };
+    for child in children.iter() {
+        if translucent_meshes.meshes.contains(*child) {
+            commands.entity(*child).despawn_recursive();
+        }
+    }
+}
+
+/// The borders of a chunk towards neighbours at another level of detail
+fn open_borders<C: VoxelWorldConfig>(
+    chunk: &Chunk<C>,
+    all_chunks: &Query<&Chunk<C>>,
+    chunk_map_read_lock: &RwLockReadGuard<ChunkMapData<C::Voxel>>,
+) -> OpenBorders {
+    let mut open_borders = OpenBorders::default();
+    for direction in FACE_NEIGHBOURS {
+        let neighbour_lod = ChunkMap::<C>::get(&(chunk.position + direction), chunk_map_read_lock)
+            .and_then(|neighbour| all_chunks.get(neighbour.entity).ok())
+            .map(|neighbour| neighbour.lod);
+        if neighbour_lod.is_some_and(|lod| lod != chunk.lod) {
+            open_borders.open(direction);
         }
     }
+    open_borders
 }
 
 /// Check if the given world point is within the camera's view
@@ -488,16 +903,13 @@ fn is_in_view(world_point: Vec3, camera: &Camera, cam_global_transform: &GlobalT
     }
 }
 
-/// Returns a tuple of the chunk position and the voxel position within the chunk.
+/// Returns a tuple of the chunk position and the voxel position within the (padded) chunk.
 #[inline]
-pub(crate) fn get_chunk_voxel_position(position: IVec3) -> (IVec3, UVec3) {
-    let chunk_position = IVec3 {
-        x: (position.x as f32 / CHUNK_SIZE_F).floor() as i32,
-        y: (position.y as f32 / CHUNK_SIZE_F).floor() as i32,
-        z: (position.z as f32 / CHUNK_SIZE_F).floor() as i32,
-    };
+pub(crate) fn get_chunk_voxel_position(position: IVec3, chunk_size: UVec3) -> (IVec3, UVec3) {
+    let chunk_size = chunk_size.as_ivec3();
+    let chunk_position = position.div_euclid(chunk_size);
 
-    let voxel_position = (position - chunk_position * CHUNK_SIZE_I).as_uvec3() + 1;
+    let voxel_position = (position - chunk_position * chunk_size).as_uvec3() + 1;
 
     (chunk_position, voxel_position)
 }